@@ -0,0 +1,21 @@
+# complete hosts and IDs using the history from the server, falling back to the
+# generated completion for everything else.
+_vellum_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    if [[ "${prev}" == "--host" ]]; then
+        COMPREPLY=( $(compgen -W "$(vellum __complete hosts 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+    if [[ "${COMP_WORDS[1]}" == "delete" && "${cur}" != -* ]]; then
+        COMPREPLY=( $(compgen -W "$(vellum __complete ids 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+    _vellum "$@"
+}
+
+if [[ "${BASH_VERSINFO[0]}" -eq 4 && "${BASH_VERSINFO[1]}" -ge 4 || "${BASH_VERSINFO[0]}" -gt 4 ]]; then
+    complete -F _vellum_dynamic -o nosort -o bashdefault -o default vellum
+else
+    complete -F _vellum_dynamic -o bashdefault -o default vellum
+fi
@@ -11,7 +11,8 @@ else
 
     VELLUM_SESSION="$(vellum init session)"
     VELLUM_SESSION_START="$(vellum init timestamp)"
-    export VELLUM_SESSION VELLUM_SESSION_START
+    VELLUM_SHELL=bash
+    export VELLUM_SESSION VELLUM_SESSION_START VELLUM_SHELL
 
     __vellum_preexec() {
         vellum store -- "$1"
@@ -1,25 +1,34 @@
 use std::{
+    collections::BTreeMap,
     fs::remove_file,
     io::{self, ErrorKind, Read, Write},
     os::unix::net::{self, UnixListener, UnixStream},
-    path::Path,
+    path::PathBuf,
     result,
+    sync::{Arc, Mutex},
     thread::sleep,
     time::{Duration, Instant},
 };
 
-use log::{debug, info};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
     config::Config,
     error::{Error, Result},
-    history::Entry,
+    history::{Analysis, CommandCount, Entry, HostChanges, PathRewrite, RebuildSummary},
 };
 
+#[derive(Debug)]
 pub struct Connection {
     s: UnixStream,
+    max_size: u64,
+    /// Held whilst a message is written, so that clones of the connection
+    /// that are used from other threads (e.g. to send sync events to a
+    /// subscriber) can't interleave their messages.
+    writing: Arc<Mutex<()>>,
 }
 
 // This version is used to detect major incompatible changes to the API. It
@@ -28,7 +37,11 @@ pub struct Connection {
 // client from talking to the server at all. Most changes to the API should be
 // able to handled by decoder errors (e.g. adding a new command, or changing the
 // parameters of an existing command).
-const CURRENT_API_VERSION: u32 = 1;
+pub const CURRENT_API_VERSION: u32 = 1;
+
+// the most messages that a request will skip over whilst waiting for the
+// response, before giving up on the connection.
+const MAX_SKIPPED_MESSAGES: usize = 100;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
@@ -37,11 +50,21 @@ pub enum Message {
         cmd: String,
         path: String,
         session: String,
+        #[serde(default)]
+        env_hash: Option<String>,
+        #[serde(default)]
+        uid: Option<u32>,
+        #[serde(default)]
+        shell: Option<String>,
+        /// Chosen by the client, so that retrying a store is a no-op if the
+        /// first attempt did reach the server.
+        #[serde(default)]
+        id: Option<Uuid>,
     },
     Error(String),
     HistoryRequest,
     History(Vec<Entry>),
-    Sync(bool),
+    Sync(SyncOptions),
     Exit(bool),
     Ping,
     Pong(u32),
@@ -50,26 +73,222 @@ pub enum Message {
         cmd: String,
         session: String,
     },
-    Rebuild,
+    /// Rebuild the sync repo, which is only done if the client says that the
+    /// user has confirmed it.
+    Rebuild(bool),
     RebuildStatus(String),
-    RebuildComplete(Option<String>),
+    RebuildComplete(result::Result<RebuildSummary, String>),
     VersionRequest,
     Version(String),
     Load(Vec<Entry>, bool),
     Loaded(usize),
+    StatusRequest,
+    Status(Status),
+    PreviewRequest,
+    Preview(Preview),
+    AnalyzeRequest,
+    Analysis(Analysis),
+    HostsRequest,
+    Hosts(Vec<String>),
+    Stored(Uuid),
+    Reclone,
+    /// Pack the daily history files older than the given age into monthly
+    /// archives.
+    Pack(Duration),
+    Packed(usize),
+    /// Rewrite the path of matching entries, which is done by a rebuild, so
+    /// also has to be confirmed. The response is the same as for Rebuild.
+    RewritePath(PathRewrite, bool),
+    /// Request the history, when only the entries after and/or before the
+    /// given times are wanted, so that the server can load older history if
+    /// it needs to. The response is the same as for HistoryRequest.
+    HistoryBetween(Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+    /// Request every record stored for an ID, before they are collapsed into
+    /// a single entry. The response is a History.
+    EntryHistory(Uuid),
+    /// Ask for sync events to be sent on this connection as they happen. The
+    /// response is an Ack, and the events follow.
+    Subscribe,
+    /// Event sent to subscribers when a sync starts.
+    SyncStarted,
+    /// Event sent to subscribers when a sync completes, with how many entries
+    /// the history grew by and how many of our entries were pushed.
+    SyncCompleted {
+        added: usize,
+        pushed: usize,
+    },
+    /// Event sent to subscribers when a sync fails.
+    SyncFailed(String),
+    /// Attach a note to an entry, replacing any existing note (an empty note
+    /// removes it). The response is an Ack.
+    Annotate {
+        id: Uuid,
+        note: String,
+        session: String,
+    },
+    /// Request the history as it was at an earlier point in the sync repo (a
+    /// git ref or RFC 3339 timestamp). The response is a History.
+    HistoryAt(String),
+    /// Request the configuration that the server is running with, which may
+    /// not match the config file if it has changed since the server started.
+    ConfigRequest,
+    /// The server's configuration, and the path it was loaded from.
+    Config(Box<Config>, Option<PathBuf>),
+    /// Request the given number of most frequently run commands.
+    TopCommandsRequest(usize),
+    TopCommands(Vec<CommandCount>),
+    /// Request the entries that have been deleted, but not yet removed by a
+    /// rebuild. The response is a History.
+    DeletedRequest,
+    /// The same as Update, but also changes the path that the command was run
+    /// in (an empty path removes it).
+    UpdateWithPath {
+        id: Uuid,
+        cmd: String,
+        path: String,
+        session: String,
+    },
+    /// Request the most recent entry, optionally only from the given session.
+    LastRequest(Option<String>),
+    /// The most recent entry and its index in the history, if there is one.
+    Last(Option<(usize, Entry)>),
+    /// Request the history, unless it is still at the given version. The
+    /// response is a VersionedHistory.
+    HistoryIfChanged(Option<u64>),
+    /// The current version of the history, and the history itself if it
+    /// isn't the version that was asked for.
+    VersionedHistory(u64, Option<Vec<Entry>>),
+    /// Record the exit status of a stored command, and how long it took to
+    /// run. The response is an Ack.
+    Finished {
+        id: Uuid,
+        exit: i32,
+        duration_ms: u64,
+        session: String,
+    },
+}
+
+impl Message {
+    /// Can this message be the response to a request? Anything else (e.g. a
+    /// progress update) that turns up whilst waiting for a response is
+    /// skipped.
+    fn is_response(&self) -> bool {
+        match self {
+            Self::Ack
+            | Self::Error(_)
+            | Self::History(_)
+            | Self::Pong(_)
+            | Self::RebuildComplete(_)
+            | Self::Version(_)
+            | Self::Loaded(_)
+            | Self::Status(_)
+            | Self::Preview(_)
+            | Self::Analysis(_)
+            | Self::Hosts(_)
+            | Self::Stored(_)
+            | Self::Packed(_)
+            | Self::Config(_, _)
+            | Self::TopCommands(_)
+            | Self::Last(_)
+            | Self::VersionedHistory(_, _) => true,
+            Self::Store { .. }
+            | Self::HistoryRequest
+            | Self::Sync(_)
+            | Self::Exit(_)
+            | Self::Ping
+            | Self::Update { .. }
+            | Self::Rebuild(_)
+            | Self::RebuildStatus(_)
+            | Self::VersionRequest
+            | Self::Load(_, _)
+            | Self::StatusRequest
+            | Self::PreviewRequest
+            | Self::AnalyzeRequest
+            | Self::HostsRequest
+            | Self::Reclone
+            | Self::Pack(_)
+            | Self::RewritePath(_, _)
+            | Self::HistoryBetween(_, _)
+            | Self::EntryHistory(_)
+            | Self::Subscribe
+            | Self::SyncStarted
+            | Self::SyncCompleted { .. }
+            | Self::SyncFailed(_)
+            | Self::Annotate { .. }
+            | Self::HistoryAt(_)
+            | Self::ConfigRequest
+            | Self::TopCommandsRequest(_)
+            | Self::DeletedRequest
+            | Self::UpdateWithPath { .. }
+            | Self::LastRequest(_)
+            | Self::HistoryIfChanged(_)
+            | Self::Finished { .. } => false,
+        }
+    }
+}
+
+/// How a sync requested by a client should be run.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct SyncOptions {
+    /// Push even if things seem to be up-to-date
+    pub force: bool,
+    /// Only read in the upstream changes, leaving local changes to be pushed
+    /// by a later sync
+    pub only_pull: bool,
+    /// Only push the local changes, without fetching the upstream changes
+    /// first
+    pub only_push: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Status {
+    /// Number of changes that have been saved, but not yet pushed
+    pub pending: usize,
+    /// When the last sync was attempted
+    pub last_sync: DateTime<Utc>,
+    /// A sync is running (pending isn't counted whilst it is, so is zero)
+    #[serde(default)]
+    pub syncing: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Preview {
+    /// Changes from upstream that haven't been merged yet, by host
+    pub incoming: BTreeMap<String, HostChanges>,
+    /// Number of changes that have been saved, but not yet pushed
+    pub pending: usize,
 }
 
 impl Connection {
     pub fn new(cfg: &Config) -> Result<Self> {
-        let path = Path::new(&cfg.state_dir).join("server.sock");
+        let path = cfg.socket_path();
         debug!("Connect to {path:#?}");
         let stream = UnixStream::connect(path)?;
-        Ok(Connection { s: stream })
+        // don't let a stuck server hang the shell forever.
+        let timeout = Some(cfg.client.timeout).filter(|t| !t.is_zero());
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        Ok(Connection {
+            s: stream,
+            max_size: cfg.max_message_size,
+            writing: Arc::default(),
+        })
+    }
+
+    /// Another handle on the same connection, e.g. so that events can be
+    /// sent from another thread.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            s: self.s.try_clone()?,
+            max_size: self.max_size,
+            writing: self.writing.clone(),
+        })
     }
 
     pub fn send(&mut self, msg: &Message) -> Result<()> {
         let data = rmp_serde::to_vec(msg)?;
         let len = data.len() as u64;
+        let _writing = self.writing.lock().unwrap();
         self.s.write_all(&len.to_le_bytes())?;
         Ok(self.s.write_all(&data)?)
     }
@@ -79,6 +298,18 @@ impl Connection {
         self.s.read_exact(&mut buf)?;
         let len = u64::from_le_bytes(buf);
 
+        // check the length before allocating anything, so that a misbehaving
+        // peer can't make us run out of memory.
+        if len > self.max_size {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "message of {len} bytes exceeds the maximum message size of {} bytes",
+                    self.max_size
+                ),
+            ));
+        }
+
         let mut data = vec![0u8; len as usize];
         self.s.read_exact(&mut data)?;
 
@@ -97,16 +328,67 @@ impl Connection {
 
     pub fn request(&mut self, msg: &Message) -> Result<Message> {
         debug!("send message: {msg:?}");
-        self.send(msg)?;
+        self.send(msg).map_err(timeout_error)?;
         debug!("receive response");
-        let data = self.read_message()?;
-        Ok(rmp_serde::from_slice(&data)?)
+        for _ in 0..=MAX_SKIPPED_MESSAGES {
+            let data = self.read_message().map_err(|e| timeout_error(e.into()))?;
+            let response: Message = rmp_serde::from_slice(&data)?;
+            if response.is_response() {
+                return Ok(response);
+            }
+            warn!("skipping unexpected message whilst waiting for response: {response:?}");
+        }
+        Err(Error::Generic(format!(
+            "no response from server after skipping {MAX_SKIPPED_MESSAGES} other messages"
+        )))
+    }
+
+    /// Replace the configured timeout for reading and writing to the server.
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.s.set_read_timeout(Some(timeout))?;
+        Ok(self.s.set_write_timeout(Some(timeout))?)
+    }
+
+    /// Replace the timeout for writing to the other end. The timeouts belong
+    /// to the socket, so this also applies to any clones of the connection.
+    pub fn set_write_timeout(&mut self, timeout: Duration) -> Result<()> {
+        Ok(self.s.set_write_timeout(Some(timeout))?)
     }
 
-    pub fn store(&mut self, cmd: String, path: String, session: String) -> Result<()> {
-        let msg = Message::Store { cmd, path, session };
+    /// Make a request that the server may take a long time to respond to, so
+    /// the read timeout is not used.
+    fn long_request(&mut self, msg: &Message) -> Result<Message> {
+        let timeout = self.s.read_timeout()?;
+        self.s.set_read_timeout(None)?;
+        let response = self.request(msg);
+        self.s.set_read_timeout(timeout)?;
+        response
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn store(
+        &mut self,
+        cmd: String,
+        path: String,
+        session: String,
+        env_hash: Option<String>,
+        uid: Option<u32>,
+        shell: Option<String>,
+        id: Option<Uuid>,
+    ) -> Result<Option<Uuid>> {
+        let msg = Message::Store {
+            cmd,
+            path,
+            session,
+            env_hash,
+            uid,
+            shell,
+            id,
+        };
         match self.request(&msg)? {
-            Message::Ack => Ok(()),
+            Message::Stored(id) => Ok(Some(id)),
+            // older servers don't tell us the ID
+            Message::Ack => Ok(None),
             Message::Error(e) => Err(Error::Generic(e)),
             m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
         }
@@ -121,6 +403,70 @@ impl Connection {
         }
     }
 
+    /// Request the history, unless the server's history is still at the given
+    /// version, returning the current version and the history (if it was
+    /// sent).
+    pub fn history_if_changed(
+        &mut self,
+        version: Option<u64>,
+    ) -> Result<(u64, Option<Vec<Entry>>)> {
+        let msg = Message::HistoryIfChanged(version);
+        match self.request(&msg)? {
+            Message::VersionedHistory(version, history) => Ok((version, history)),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn send_versioned_history(
+        &mut self,
+        version: u64,
+        history: Option<Vec<Entry>>,
+    ) -> Result<()> {
+        let msg = Message::VersionedHistory(version, history);
+        self.send(&msg)
+    }
+
+    pub fn history_between(
+        &mut self,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Entry>> {
+        let msg = Message::HistoryBetween(after, before);
+        match self.long_request(&msg)? {
+            Message::History(h) => Ok(h),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn history_at(&mut self, at: String) -> Result<Vec<Entry>> {
+        let msg = Message::HistoryAt(at);
+        match self.long_request(&msg)? {
+            Message::History(h) => Ok(h),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn entry_history(&mut self, id: Uuid) -> Result<Vec<Entry>> {
+        let msg = Message::EntryHistory(id);
+        match self.long_request(&msg)? {
+            Message::History(h) => Ok(h),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn deleted_request(&mut self) -> Result<Vec<Entry>> {
+        let msg = Message::DeletedRequest;
+        match self.request(&msg)? {
+            Message::History(h) => Ok(h),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
     pub fn send_history(&mut self, history: Vec<Entry>) -> Result<()> {
         let msg = Message::History(history);
         self.send(&msg)
@@ -128,7 +474,7 @@ impl Connection {
 
     pub fn load(&mut self, history: Vec<Entry>, all_hosts: bool) -> Result<usize> {
         let msg = Message::Load(history, all_hosts);
-        match self.request(&msg)? {
+        match self.long_request(&msg)? {
             Message::Loaded(count) => Ok(count),
             Message::Error(e) => Err(Error::Generic(e)),
             m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
@@ -140,17 +486,41 @@ impl Connection {
         self.send(&msg)
     }
 
-    pub fn sync(&mut self, force: bool) -> Result<()> {
-        let msg = Message::Sync(force);
-        match self.request(&msg)? {
+    pub fn sync(&mut self, opts: SyncOptions) -> Result<()> {
+        let msg = Message::Sync(opts);
+        match self.long_request(&msg)? {
             Message::Ack => Ok(()),
             Message::Error(e) => Err(Error::Generic(e)),
             m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
         }
     }
 
+    pub fn reclone(&mut self) -> Result<()> {
+        let msg = Message::Reclone;
+        match self.long_request(&msg)? {
+            Message::Ack => Ok(()),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn pack(&mut self, older_than: Duration) -> Result<usize> {
+        let msg = Message::Pack(older_than);
+        match self.long_request(&msg)? {
+            Message::Packed(count) => Ok(count),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn send_packed(&mut self, count: usize) -> Result<()> {
+        let msg = Message::Packed(count);
+        self.send(&msg)
+    }
+
     pub fn exit(&mut self, no_sync: bool) -> Result<()> {
         let msg = Message::Exit(no_sync);
+        // the server acks before the final sync, so this should be quick.
         match self.request(&msg)? {
             Message::Ack => Ok(()),
             Message::Error(e) => Err(Error::Generic(e)),
@@ -158,6 +528,28 @@ impl Connection {
         }
     }
 
+    pub fn send_stored(&mut self, id: Uuid) -> Result<()> {
+        let msg = Message::Stored(id);
+        self.send(&msg)
+    }
+
+    /// Ask the server to send sync events on this connection, which can then
+    /// be read with next_event.
+    pub fn subscribe(&mut self) -> Result<()> {
+        match self.request(&Message::Subscribe)? {
+            Message::Ack => Ok(()),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    /// Wait for the next event from the server, returns None if the server
+    /// has closed the connection.
+    pub fn next_event(&mut self) -> Result<Option<Message>> {
+        self.s.set_read_timeout(None)?;
+        self.receive()
+    }
+
     pub fn ack(&mut self) -> Result<()> {
         let msg = Message::Ack;
         self.send(&msg)
@@ -192,8 +584,66 @@ impl Connection {
         }
     }
 
-    pub fn rebuild(&mut self) -> Result<Rebuilder<'_>> {
-        let msg = Message::Rebuild;
+    pub fn update_with_path(
+        &mut self,
+        id: Uuid,
+        cmd: String,
+        path: String,
+        session: String,
+    ) -> Result<()> {
+        let msg = Message::UpdateWithPath {
+            id,
+            cmd,
+            path,
+            session,
+        };
+        match self.request(&msg)? {
+            Message::Ack => Ok(()),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn annotate(&mut self, id: Uuid, note: String, session: String) -> Result<()> {
+        let msg = Message::Annotate { id, note, session };
+        match self.request(&msg)? {
+            Message::Ack => Ok(()),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn finished(
+        &mut self,
+        id: Uuid,
+        exit: i32,
+        duration_ms: u64,
+        session: String,
+    ) -> Result<()> {
+        let msg = Message::Finished {
+            id,
+            exit,
+            duration_ms,
+            session,
+        };
+        match self.request(&msg)? {
+            Message::Ack => Ok(()),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn rebuild(&mut self, confirmed: bool) -> Result<Rebuilder<'_>> {
+        // the status updates can be a long way apart
+        self.s.set_read_timeout(None)?;
+        let msg = Message::Rebuild(confirmed);
+        self.send(&msg)?;
+        Ok(Rebuilder::new(self))
+    }
+
+    pub fn rewrite_path(&mut self, rewrite: PathRewrite, confirmed: bool) -> Result<Rebuilder<'_>> {
+        self.s.set_read_timeout(None)?;
+        let msg = Message::RewritePath(rewrite, confirmed);
         self.send(&msg)?;
         Ok(Rebuilder::new(self))
     }
@@ -203,11 +653,8 @@ impl Connection {
         self.send(&msg)
     }
 
-    pub fn rebuild_complete(&mut self, result: Result<()>) -> Result<()> {
-        let result = match result {
-            Ok(()) => None,
-            Err(e) => Some(format!("{e}")),
-        };
+    pub fn rebuild_complete(&mut self, result: Result<RebuildSummary>) -> Result<()> {
+        let result = result.map_err(|e| format!("{e}"));
         let msg = Message::RebuildComplete(result);
         self.send(&msg)
     }
@@ -225,30 +672,138 @@ impl Connection {
         let msg = Message::Version(version.to_string());
         self.send(&msg)
     }
+
+    pub fn status_request(&mut self) -> Result<Status> {
+        let msg = Message::StatusRequest;
+        match self.request(&msg)? {
+            Message::Status(s) => Ok(s),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn send_status(&mut self, status: Status) -> Result<()> {
+        let msg = Message::Status(status);
+        self.send(&msg)
+    }
+
+    pub fn preview_request(&mut self) -> Result<Preview> {
+        let msg = Message::PreviewRequest;
+        match self.long_request(&msg)? {
+            Message::Preview(p) => Ok(p),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn send_preview(&mut self, preview: Preview) -> Result<()> {
+        let msg = Message::Preview(preview);
+        self.send(&msg)
+    }
+
+    pub fn analyze_request(&mut self) -> Result<Analysis> {
+        let msg = Message::AnalyzeRequest;
+        match self.long_request(&msg)? {
+            Message::Analysis(a) => Ok(a),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn send_analysis(&mut self, analysis: Analysis) -> Result<()> {
+        let msg = Message::Analysis(analysis);
+        self.send(&msg)
+    }
+
+    pub fn hosts_request(&mut self) -> Result<Vec<String>> {
+        let msg = Message::HostsRequest;
+        match self.request(&msg)? {
+            Message::Hosts(h) => Ok(h),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn send_hosts(&mut self, hosts: Vec<String>) -> Result<()> {
+        let msg = Message::Hosts(hosts);
+        self.send(&msg)
+    }
+
+    pub fn config_request(&mut self) -> Result<Config> {
+        let msg = Message::ConfigRequest;
+        match self.request(&msg)? {
+            Message::Config(cfg, path) => Ok(Config { path, ..*cfg }),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    /// Send the config, without any secrets in it, since it is only used to
+    /// show the config.
+    pub fn send_config(&mut self, cfg: &Config) -> Result<()> {
+        let mut cfg = cfg.clone();
+        cfg.sync.webdav = cfg.sync.webdav.redacted();
+        let path = cfg.path.clone();
+        let msg = Message::Config(Box::new(cfg), path);
+        self.send(&msg)
+    }
+
+    pub fn top_commands_request(&mut self, n: usize) -> Result<Vec<CommandCount>> {
+        let msg = Message::TopCommandsRequest(n);
+        match self.request(&msg)? {
+            Message::TopCommands(counts) => Ok(counts),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn last_request(&mut self, session: Option<String>) -> Result<Option<(usize, Entry)>> {
+        let msg = Message::LastRequest(session);
+        match self.request(&msg)? {
+            Message::Last(last) => Ok(last),
+            Message::Error(e) => Err(Error::Generic(e)),
+            m => Err(Error::Generic(format!("unexpected response: {m:?}"))),
+        }
+    }
+
+    pub fn send_last(&mut self, last: Option<(usize, Entry)>) -> Result<()> {
+        let msg = Message::Last(last);
+        self.send(&msg)
+    }
+
+    pub fn send_top_commands(&mut self, counts: Vec<CommandCount>) -> Result<()> {
+        let msg = Message::TopCommands(counts);
+        self.send(&msg)
+    }
 }
 
 #[derive(Debug)]
 pub struct Listener {
     l: UnixListener,
+    max_size: u64,
 }
 
 impl Listener {
     pub fn new(cfg: &Config) -> Result<Self> {
-        let path = Path::new(&cfg.state_dir).join("server.sock");
+        let path = cfg.socket_path();
         debug!("Start listening: {path:#?}");
         let listener = UnixListener::bind(&path)?;
         info!("Started listening at {path:?}");
-        Ok(Listener { l: listener })
+        Ok(Listener {
+            l: listener,
+            max_size: cfg.max_message_size,
+        })
     }
 
     pub fn incoming(&self) -> Incoming<'_> {
         Incoming {
             i: self.l.incoming(),
+            max_size: self.max_size,
         }
     }
 
     pub fn remove_socket(cfg: &Config) -> Result<()> {
-        let path = Path::new(&cfg.state_dir).join("server.sock");
+        let path = cfg.socket_path();
         debug!("Removing socket {path:?}");
         Ok(remove_file(path)?)
     }
@@ -256,6 +811,7 @@ impl Listener {
 
 pub struct Incoming<'a> {
     i: net::Incoming<'a>,
+    max_size: u64,
 }
 
 impl Iterator for Incoming<'_> {
@@ -263,7 +819,11 @@ impl Iterator for Incoming<'_> {
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.i.next() {
-            Some(Ok(s)) => Some(Ok(Connection { s })),
+            Some(Ok(s)) => Some(Ok(Connection {
+                s,
+                max_size: self.max_size,
+                writing: Arc::default(),
+            })),
             Some(Err(e)) => Some(Err(Error::IO(e))),
             None => None,
         }
@@ -284,8 +844,15 @@ impl<'a> Rebuilder<'a> {
     }
 }
 
+/// A single update from a running rebuild.
+#[derive(Debug)]
+pub enum RebuildProgress {
+    Status(String),
+    Complete(RebuildSummary),
+}
+
 impl Iterator for Rebuilder<'_> {
-    type Item = Result<String>;
+    type Item = Result<RebuildProgress>;
 
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         if self.complete {
@@ -303,14 +870,14 @@ impl Iterator for Rebuilder<'_> {
             }
         };
         match msg {
-            Message::RebuildStatus(status) => Some(Ok(status)),
-            Message::RebuildComplete(result) => match result {
-                Some(msg) => {
-                    self.complete = true;
-                    Some(Err(Error::Generic(format!("server returned error: {msg}"))))
+            Message::RebuildStatus(status) => Some(Ok(RebuildProgress::Status(status))),
+            Message::RebuildComplete(result) => {
+                self.complete = true;
+                match result {
+                    Ok(summary) => Some(Ok(RebuildProgress::Complete(summary))),
+                    Err(msg) => Some(Err(Error::Generic(format!("server returned error: {msg}")))),
                 }
-                None => None,
-            },
+            }
             m => {
                 self.complete = true;
                 Some(Err(Error::Generic(format!("unexpected response: {m:?}"))))
@@ -327,11 +894,7 @@ pub fn ping(cfg: &Config, wait: Option<Duration>) -> Result<Connection> {
                 debug!("took {:?} to get response from server", start.elapsed());
                 return Ok(conn);
             }
-            Err(Error::ApiVersion(v)) => {
-                return Err(Error::Generic(format!(
-                    "Wrong API Version {v} (wanted {CURRENT_API_VERSION})"
-                )));
-            }
+            Err(e @ Error::ApiVersion(_)) => return Err(e),
             Err(e) => {
                 debug!("try_ping returned error: {e}");
                 if wait.is_none() {
@@ -356,3 +919,92 @@ fn try_ping(cfg: &Config) -> Result<Connection> {
     conn.ping()?;
     Ok(conn)
 }
+
+fn timeout_error(e: Error) -> Error {
+    match e {
+        Error::IO(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Error::from_str("server timed out")
+        }
+        e => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(max_size: u64) -> (UnixStream, Connection) {
+        let (a, b) = UnixStream::pair().unwrap();
+        (
+            a,
+            Connection {
+                s: b,
+                max_size,
+                writing: Arc::default(),
+            },
+        )
+    }
+
+    #[test]
+    fn oversized_length() {
+        let (mut peer, mut conn) = pair(1024);
+        peer.write_all(&u64::MAX.to_le_bytes()).unwrap();
+        let err = conn.read_message().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("exceeds the maximum message size"));
+    }
+
+    #[test]
+    fn length_just_over_limit() {
+        let (mut peer, mut conn) = pair(1024);
+        peer.write_all(&1025_u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            conn.read_message().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn message_within_limit() {
+        let (peer, mut conn) = pair(1024);
+        let mut peer = Connection {
+            s: peer,
+            max_size: 1024,
+            writing: Arc::default(),
+        };
+        peer.send(&Message::Ping).unwrap();
+        assert!(matches!(conn.receive().unwrap(), Some(Message::Ping)));
+    }
+
+    #[test]
+    fn clones_dont_interleave_messages() {
+        let (peer, mut conn) = pair(1 << 20);
+        let peer = Connection {
+            s: peer,
+            max_size: 1 << 20,
+            writing: Arc::default(),
+        };
+        let senders: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|c| {
+                let mut peer = peer.try_clone().unwrap();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        peer.send(&Message::Error(c.repeat(100_000))).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..200 {
+            match conn.receive().unwrap() {
+                Some(Message::Error(e)) => {
+                    assert!(e == "a".repeat(100_000) || e == "b".repeat(100_000))
+                }
+                m => panic!("unexpected message: {m:?}"),
+            }
+        }
+        for sender in senders {
+            sender.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    env,
+    fs::{self, File},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use aws_lc_rs::{
+    aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey},
+    cipher::AES_256_KEY_LEN,
+    rand,
+};
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+
+const BUNDLE_VERSION: u8 = 1;
+
+const SALT_LEN: usize = 16;
+
+/// A config file and encryption key, encrypted using a key derived from a
+/// passphrase, so that they can be carried around together.
+#[derive(Debug, Serialize, Deserialize)]
+struct Bundle {
+    version: u8,
+    #[serde(with = "serde_bytes")]
+    salt: Vec<u8>,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    #[serde(with = "serde_bytes")]
+    nonce: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+}
+
+/// The decrypted contents of a bundle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Contents {
+    /// The config file, in TOML format.
+    pub config: String,
+    /// The encryption key, in the same format as $VELLUM_KEY.
+    pub key: String,
+}
+
+impl Bundle {
+    fn derive_key(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(AES_256_KEY_LEN))?;
+        let mut key = vec![0_u8; AES_256_KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(
+            passphrase.as_bytes(),
+            &self.salt,
+            &mut key,
+        )?;
+        Ok(key)
+    }
+}
+
+/// The passphrase used to encrypt and decrypt bundles, which comes from
+/// $VELLUM_BUNDLE_PASSPHRASE so that the server (and shell hooks) can read the
+/// bundle without prompting.
+pub fn passphrase() -> Result<String> {
+    match env::var("VELLUM_BUNDLE_PASSPHRASE") {
+        Ok(passphrase) if !passphrase.is_empty() => Ok(passphrase),
+        Ok(_) | Err(env::VarError::NotPresent) => Err(Error::from_str(
+            "VELLUM_BUNDLE_PASSPHRASE must be set to use a bundle",
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read and decrypt the bundle at path.
+pub fn read<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Contents> {
+    let path = path.as_ref();
+    debug!("read bundle {path:?}");
+    let mut bundle: Bundle = rmp_serde::from_slice(&fs::read(path)?)?;
+    if bundle.version != BUNDLE_VERSION {
+        return Err(Error::Generic(format!(
+            "unsupported bundle version {} in {path:?}",
+            bundle.version
+        )));
+    }
+    let key = RandomizedNonceKey::new(&AES_256_GCM, &bundle.derive_key(passphrase)?)?;
+    let nonce = Nonce::try_assume_unique_for_key(&bundle.nonce)?;
+    let data = key
+        .open_in_place(nonce, Aad::from([bundle.version]), &mut bundle.data)
+        .map_err(|_| {
+            Error::Generic(format!(
+                "failed to decrypt {path:?} (is the passphrase correct?)"
+            ))
+        })?;
+    Ok(rmp_serde::from_slice(data)?)
+}
+
+/// Encrypt the contents and write them to a new bundle at path, which must not
+/// already exist.
+pub fn write<P: AsRef<Path>>(path: P, passphrase: &str, contents: &Contents) -> Result<()> {
+    let mut salt = vec![0_u8; SALT_LEN];
+    rand::fill(&mut salt)?;
+    let mut bundle = Bundle {
+        version: BUNDLE_VERSION,
+        salt,
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+        nonce: Vec::new(),
+        data: rmp_serde::to_vec(contents)?,
+    };
+    let key = RandomizedNonceKey::new(&AES_256_GCM, &bundle.derive_key(passphrase)?)?;
+    let nonce = key.seal_in_place_append_tag(Aad::from([bundle.version]), &mut bundle.data)?;
+    bundle.nonce = nonce.as_ref().into();
+
+    let mut f = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    f.write_all(&rmp_serde::to_vec(&bundle)?)?;
+    Ok(())
+}
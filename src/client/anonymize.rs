@@ -0,0 +1,119 @@
+use std::{collections::HashMap, fmt::Write};
+
+use aws_lc_rs::digest::{SHA256, digest};
+use regex::Regex;
+
+use crate::history::Entry;
+
+#[derive(clap::Args, Debug)]
+pub struct AnonymizeArgs {
+    /// Replace host names with pseudonyms (host1, host2, etc), and remove the
+    /// paths, notes, user IDs and environment hashes, so the history can be
+    /// shared
+    #[arg(long)]
+    anonymize: bool,
+
+    /// Also replace session IDs with a hash of the ID
+    #[arg(long, requires = "anonymize")]
+    hash_sessions: bool,
+
+    /// Replace anything in the commands (and notes) that matches the regular
+    /// expression with REDACTED (can be specified multiple times)
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    redact_regex: Vec<Regex>,
+}
+
+/// Removes identifying information from entries, as requested by the
+/// AnonymizeArgs.
+pub struct Anonymizer<'a> {
+    args: &'a AnonymizeArgs,
+    hosts: HashMap<String, String>,
+}
+
+impl<'a> Anonymizer<'a> {
+    pub fn new(args: &'a AnonymizeArgs) -> Self {
+        Self {
+            args,
+            hosts: HashMap::new(),
+        }
+    }
+
+    pub fn entry(&mut self, mut entry: Entry) -> Entry {
+        for re in self.args.redact_regex.iter() {
+            entry.cmd = re.replace_all(&entry.cmd, "REDACTED").into_owned();
+            if let Some(note) = &entry.note {
+                entry.note = Some(re.replace_all(note, "REDACTED").into_owned());
+            }
+        }
+        if !self.args.anonymize {
+            return entry;
+        }
+
+        // pseudonyms are numbered in the order the hosts are first seen, so
+        // they are stable for a given history.
+        let next = self.hosts.len() + 1;
+        entry.host = self
+            .hosts
+            .entry(entry.host)
+            .or_insert_with(|| format!("host{next}"))
+            .clone();
+        entry.path.clear();
+        entry.note = None;
+        entry.uid = None;
+        entry.env_hash = None;
+        if self.args.hash_sessions {
+            entry.session = hash(&entry.session);
+        }
+        entry
+    }
+}
+
+fn hash(s: &str) -> String {
+    let mut hash = String::with_capacity(16);
+    // a shortened hash is plenty to tell sessions apart
+    for b in digest(&SHA256, s.as_bytes()).as_ref().iter().take(8) {
+        // writing to a String can't fail
+        let _ = write!(hash, "{b:02x}");
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(clap::Parser, Debug)]
+    struct Cli {
+        #[command(flatten)]
+        args: AnonymizeArgs,
+    }
+
+    fn anonymize(args: &[&str], entry: Entry) -> Entry {
+        let cli =
+            Cli::try_parse_from(std::iter::once("export").chain(args.iter().copied())).unwrap();
+        Anonymizer::new(&cli.args).entry(entry)
+    }
+
+    fn noted(note: &str) -> Entry {
+        let mut entry = Entry::new("laptop", "curl -u me:hunter2 example.com", "/home/me", "s");
+        entry.note = Some(note.to_string());
+        entry
+    }
+
+    #[test]
+    fn notes_are_redacted() {
+        let entry = anonymize(&["--redact-regex", "hunter2"], noted("password is hunter2"));
+        assert_eq!(entry.cmd, "curl -u me:REDACTED example.com");
+        assert_eq!(entry.note.as_deref(), Some("password is REDACTED"));
+    }
+
+    #[test]
+    fn notes_are_removed() {
+        let entry = anonymize(&["--anonymize"], noted("for the /home/me project"));
+        assert_eq!(entry.host, "host1");
+        assert_eq!(entry.path, "");
+        assert_eq!(entry.note, None);
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use tempfile::TempDir;
+
+use crate::{config::Config, error::Result, server};
+
+use super::stop_server;
+
+#[derive(clap::Args, Debug)]
+pub struct BenchArgs {
+    /// Number of commands to store
+    #[arg(short, long, default_value_t = 1000)]
+    count: usize,
+}
+
+/// Store a number of synthetic commands, and report how long it took.
+///
+/// To avoid polluting the real history, this uses a temporary profile with its
+/// own server (and sync disabled), which is removed afterwards.
+pub fn bench(cfg: &Config, args: BenchArgs) -> Result<()> {
+    let dir = TempDir::with_prefix_in("bench-", &cfg.cache_dir)?;
+    let path = dir.path().join("config.toml");
+    let mut config = toml::Table::new();
+    config.insert(
+        "state_dir".into(),
+        dir.path().join("state").to_string_lossy().as_ref().into(),
+    );
+    config.insert(
+        "cache_dir".into(),
+        dir.path().join("cache").to_string_lossy().as_ref().into(),
+    );
+    config.insert("hostname".into(), "bench".into());
+    let mut sync = toml::Table::new();
+    sync.insert("enabled".into(), false.into());
+    config.insert("sync".into(), sync.into());
+    fs::write(&path, toml::to_string(&config)?)?;
+    let bench_cfg = Config::load(Some(path.to_string_lossy()))?;
+
+    info!("Starting benchmark server in {:?}", dir.path());
+    let result = run(&bench_cfg, args.count);
+
+    stop_server(&bench_cfg, true, Duration::ZERO, false)?;
+
+    result
+}
+
+fn run(cfg: &Config, count: usize) -> Result<()> {
+    let mut conn = server::ensure_ready(cfg)?;
+    let path = "/bench".to_string();
+    let session = "bench".to_string();
+
+    let mut latencies = Vec::with_capacity(count);
+    let start = Instant::now();
+    for i in 0..count {
+        let cmd = format!("echo benchmark command {i}");
+        let before = Instant::now();
+        conn.store(cmd, path.clone(), session.clone(), None, None, None, None)?;
+        latencies.push(before.elapsed());
+    }
+    let elapsed = start.elapsed();
+
+    latencies.sort();
+    let percentile = |p: usize| -> Duration {
+        match latencies.len() {
+            0 => Duration::ZERO,
+            n => latencies[(n * p / 100).min(n - 1)],
+        }
+    };
+
+    println!("Stored:     {count} commands in {elapsed:?}");
+    println!(
+        "Throughput: {:.1} commands/s",
+        count as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "Latency:    p50 {:?}, p99 {:?}, max {:?}",
+        percentile(50),
+        percentile(99),
+        percentile(100)
+    );
+
+    Ok(())
+}
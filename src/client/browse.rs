@@ -0,0 +1,284 @@
+use std::io::{IsTerminal, Write, stdin, stdout};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::format::StrftimeItems;
+use log::debug;
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Row, Table, TableState},
+};
+use uuid::Uuid;
+
+use crate::{
+    api::Connection,
+    config::Config,
+    error::{Error, Result},
+    history::Entry,
+    server,
+};
+
+use super::{DisplayTz, Filter, FilterArgs, Session, smart_case_text};
+
+// how wide the host and path columns are allowed to get.
+const MAX_HOST_WIDTH: usize = 20;
+const MAX_PATH_WIDTH: usize = 30;
+
+const PAGE_SIZE: usize = 10;
+
+const HELP: &str = "Enter: print  Ctrl-Y: copy  Delete: delete  Ctrl-S: toggle session  Esc: quit";
+
+#[derive(clap::Args, Debug)]
+pub struct BrowseArgs {
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    /// Show timestamps in the local timezone instead of UTC (overrides the
+    /// display_tz config option)
+    #[arg(long)]
+    local: bool,
+}
+
+pub fn browse(cfg: &Config, mut args: BrowseArgs) -> Result<()> {
+    if !stdin().is_terminal() || !stdout().is_terminal() {
+        return Err(Error::from_str("browse needs to be run in a terminal"));
+    }
+
+    let tz = DisplayTz::new(cfg, args.local)?;
+    // browse is interactive, so use smart case by default.
+    args.filter.default_smart_case();
+    let filter = Filter::new(cfg, &args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    let mut browser = Browser::new(history, tz, filter.smart_case(), &mut conn)?;
+
+    let mut terminal = ratatui::try_init()?;
+    let result = browser.run(&mut terminal);
+    ratatui::try_restore()?;
+
+    if let Some(cmd) = result? {
+        println!("{cmd}");
+    }
+    Ok(())
+}
+
+struct Browser<'a> {
+    /// The history, most recent first
+    history: Vec<Entry>,
+    /// Indexes into history of the entries that match the search
+    visible: Vec<usize>,
+    search: String,
+    /// Match the search using smart case
+    smart_case: bool,
+    session_only: bool,
+    session: Session,
+    state: TableState,
+    tz: DisplayTz,
+    status: String,
+    /// The command waiting for the user to confirm that it should be deleted
+    pending_delete: Option<Uuid>,
+    conn: &'a mut Connection,
+}
+
+impl<'a> Browser<'a> {
+    fn new(
+        mut history: Vec<Entry>,
+        tz: DisplayTz,
+        smart_case: bool,
+        conn: &'a mut Connection,
+    ) -> Result<Self> {
+        history.reverse();
+        let mut browser = Self {
+            history,
+            visible: Vec::new(),
+            search: String::new(),
+            smart_case,
+            session_only: false,
+            session: Session::get()?,
+            state: TableState::default(),
+            tz,
+            status: HELP.to_string(),
+            pending_delete: None,
+            conn,
+        };
+        browser.update_visible();
+        Ok(browser)
+    }
+
+    /// Run the browser until the user quits, returning the selected command
+    /// if there is one.
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<Option<String>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let Event::Key(key) = event::read()?
+                && key.kind == KeyEventKind::Press
+                && let Some(result) = self.handle_key(key)?
+            {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Handle a key press, returns Some if the browser should exit.
+    fn handle_key(&mut self, key: KeyEvent) -> Result<Option<Option<String>>> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        if let Some(id) = self.pending_delete.take() {
+            match key.code {
+                KeyCode::Char('y' | 'Y') => self.delete(id)?,
+                _ => self.status = "Not deleted".to_string(),
+            }
+            return Ok(None);
+        }
+        match key.code {
+            KeyCode::Esc => return Ok(Some(None)),
+            KeyCode::Char('c') if ctrl => return Ok(Some(None)),
+            KeyCode::Enter => return Ok(Some(self.selected().map(|e| e.cmd.clone()))),
+            KeyCode::Char('y') if ctrl => self.copy()?,
+            KeyCode::Delete => self.confirm_delete(),
+            KeyCode::Char('s') if ctrl => {
+                self.session_only = !self.session_only;
+                self.update_visible();
+            }
+            KeyCode::Up => self.move_by(-1),
+            KeyCode::Down => self.move_by(1),
+            KeyCode::PageUp => self.move_by(-(PAGE_SIZE as isize)),
+            KeyCode::PageDown => self.move_by(PAGE_SIZE as isize),
+            KeyCode::Home => self.move_by(isize::MIN),
+            KeyCode::End => self.move_by(isize::MAX),
+            KeyCode::Backspace => {
+                self.search.pop();
+                self.update_visible();
+            }
+            KeyCode::Char(c) if !ctrl => {
+                self.search.push(c);
+                self.update_visible();
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    fn selected(&self) -> Option<&Entry> {
+        let idx = self.visible.get(self.state.selected()?)?;
+        self.history.get(*idx)
+    }
+
+    fn move_by(&mut self, distance: isize) {
+        if self.visible.is_empty() {
+            return;
+        }
+        let current = self.state.selected().unwrap_or_default() as isize;
+        let max = self.visible.len() as isize - 1;
+        self.state
+            .select(Some(current.saturating_add(distance).clamp(0, max) as usize));
+    }
+
+    fn update_visible(&mut self) {
+        self.visible = self
+            .history
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !self.session_only || entry.session == self.session.id)
+            .filter(|(_, entry)| {
+                smart_case_text(self.smart_case, &self.search, &entry.cmd)
+                    .contains(self.search.as_str())
+            })
+            .map(|(idx, _)| idx)
+            .collect();
+        self.state.select((!self.visible.is_empty()).then_some(0));
+    }
+
+    /// Copy the selected command to the clipboard, using the OSC 52 escape
+    /// sequence so that it works over ssh too (if the terminal supports it).
+    fn copy(&mut self) -> Result<()> {
+        let Some(entry) = self.selected() else {
+            return Ok(());
+        };
+        let data = BASE64_STANDARD.encode(&entry.cmd);
+        let mut out = stdout();
+        write!(out, "\x1b]52;c;{data}\x07")?;
+        out.flush()?;
+        self.status = "Copied command to clipboard".to_string();
+        Ok(())
+    }
+
+    /// Ask the user to confirm that the selected command should be deleted,
+    /// the next key press is the answer.
+    fn confirm_delete(&mut self) {
+        let Some(entry) = self.selected() else {
+            return;
+        };
+        let cmd = entry.cmd.lines().next().unwrap_or_default();
+        let status = format!("Delete {cmd:?}? (y/n)");
+        self.pending_delete = Some(entry.id);
+        self.status = status;
+    }
+
+    fn delete(&mut self, id: Uuid) -> Result<()> {
+        let selected = self.state.selected().unwrap_or_default();
+        debug!("delete id: {id}");
+        self.conn
+            .update(id, "".to_string(), self.session.id.clone())?;
+        self.history.retain(|entry| entry.id != id);
+        self.update_visible();
+        self.state
+            .select((!self.visible.is_empty()).then(|| selected.min(self.visible.len() - 1)));
+        self.status = format!("Deleted {id}");
+        Ok(())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [search, table, status] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let prompt = match self.session_only {
+            true => "session> ",
+            false => "> ",
+        };
+        frame.render_widget(Line::from(format!("{prompt}{}", self.search)), search);
+
+        let entries: Vec<&Entry> = self.visible.iter().map(|idx| &self.history[*idx]).collect();
+        let host_width = entries
+            .iter()
+            .fold("HOST".len(), |max, entry| max.max(entry.host.len()))
+            .min(MAX_HOST_WIDTH);
+        let path_width = entries
+            .iter()
+            .fold("PATH".len(), |max, entry| max.max(entry.path.len()))
+            .min(MAX_PATH_WIDTH);
+
+        let rows = entries.iter().map(|entry| {
+            Row::new([
+                self.tz
+                    .format_with_items(&entry.ts, StrftimeItems::new("%Y-%m-%d %H:%M")),
+                entry.host.clone(),
+                entry.path.clone(),
+                entry.cmd.lines().next().unwrap_or_default().to_string(),
+            ])
+        });
+        let widths = [
+            Constraint::Length(16),
+            Constraint::Length(host_width as u16),
+            Constraint::Length(path_width as u16),
+            Constraint::Fill(1),
+        ];
+        let header = Row::new(["TIME", "HOST", "PATH", "COMMAND"])
+            .style(Style::new().add_modifier(Modifier::BOLD));
+        let widget = Table::new(rows, widths)
+            .header(header)
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(widget, table, &mut self.state);
+
+        frame.render_widget(Line::from(self.status.as_str()), status);
+    }
+}
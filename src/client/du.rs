@@ -0,0 +1,74 @@
+use std::cmp;
+
+use chrono::{DateTime, Utc};
+
+use crate::{config::Config, error::Result, server};
+
+use super::DisplayTz;
+
+#[derive(clap::Args, Debug)]
+pub struct DuArgs {
+    /// Don't include the headers in the output
+    #[arg(short = 'H', long)]
+    no_headers: bool,
+
+    /// Show timestamps in the local timezone instead of UTC (overrides the
+    /// display_tz config option, JSON output is always in UTC)
+    #[arg(long)]
+    local: bool,
+
+    /// Output the usage as JSON, instead of formatted for human reading.
+    #[arg(short, long)]
+    json: bool,
+}
+
+/// Show how much space each host's history is using in the sync repo, along
+/// with the size of the whole repo.
+pub fn du(cfg: &Config, args: DuArgs) -> Result<()> {
+    let tz = DisplayTz::new(cfg, args.local)?;
+    let mut conn = server::ensure_ready(cfg)?;
+    let analysis = conn.analyze_request()?;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&analysis)?);
+        return Ok(());
+    }
+
+    let ts = |ts: Option<DateTime<Utc>>| ts.map_or("-".to_string(), |ts| tz.rfc3339(&ts));
+    let rows: Vec<_> = analysis
+        .hosts
+        .iter()
+        .map(|(host, usage)| (host, usage, ts(usage.oldest), ts(usage.newest)))
+        .collect();
+    let host_size = rows
+        .iter()
+        .fold("HOST".len(), |max, r| cmp::max(max, r.0.len()));
+    let ts_size = rows.iter().fold("OLDEST".len(), |max, r| {
+        cmp::max(max, cmp::max(r.2.len(), r.3.len()))
+    });
+
+    if !args.no_headers {
+        println!(
+            "{:host_size$}\tFILES\tRECORDS\t{:>10}\t{:ts_size$}\t{:ts_size$}",
+            "HOST", "BYTES", "OLDEST", "NEWEST"
+        );
+    }
+    for (host, usage, oldest, newest) in rows.iter() {
+        print!(
+            "{host:host_size$}\t{:5}\t{:7}\t{:10}\t{oldest:ts_size$}\t{newest:ts_size$}",
+            usage.files, usage.records, usage.bytes
+        );
+        if usage.unreadable > 0 {
+            print!("\t({} unreadable files)", usage.unreadable);
+        }
+        println!();
+    }
+    println!();
+
+    let files: usize = analysis.hosts.values().map(|usage| usage.files).sum();
+    println!("History files: {files} ({} bytes)", analysis.bytes());
+    println!("Records:       {}", analysis.records());
+    println!("Repo size:     {} bytes", analysis.repo_bytes);
+
+    Ok(())
+}
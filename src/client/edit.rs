@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     env,
     fs::{self, File},
-    io::{BufRead, BufReader, Write, stdin, stdout},
+    io::{BufRead, BufReader, Read, Write, stdin, stdout},
     path::{Path, PathBuf},
     process::{Command, exit},
 };
@@ -24,11 +24,15 @@ use super::{Filter, FilterArgs, Session};
 const HEADER: &str = r#"# This file lists the commands that matched the provided options.
 #
 # Lines starting with '#' and blank lines are ignored, otherwise each line
-# consists of an ID and the command, separated by a tab. Any other lines will
-# cause an error.
+# consists of an ID and the command, separated by a tab (or with --with-path,
+# the ID, the path the command was run in and the command, separated by tabs).
+# Any other lines will cause an error.
 #
-# To edit an entry simply change the command, to delete an entry remove the
-# line.
+# To edit an entry simply change the command (or path, an empty path removes
+# it), to delete an entry remove the line.
+#
+# Newlines in commands and paths are written as '\n', tabs in paths as '\t',
+# and backslashes as '\\'.
 #
 # If an ID is edited then it will be ignored if it was not originally selected
 # for editing (i.e. only IDs in the file as originally written will be
@@ -53,10 +57,39 @@ pub struct EditArgs {
     /// Don't show the changes being made
     #[arg(short, long)]
     quiet: bool,
+
+    /// Read the edited commands from FILE (or stdin if FILE is -) instead of
+    /// running an editor. The file uses the same format as the editor would,
+    /// with each line consisting of an ID and command separated by a tab (or
+    /// the ID, path and command with --with-path), with newlines escaped as
+    /// \n, and backslashes as \\. Only the entries listed in the file are
+    /// changed, entries that are missing from it are left alone. To delete an
+    /// entry, give it an empty command (an empty path removes just the path).
+    #[arg(long, value_name = "FILE", value_hint = clap::ValueHint::FilePath)]
+    from: Option<PathBuf>,
+
+    /// Include the path that each command was run in as an editable column,
+    /// between the ID and the command
+    #[arg(long)]
+    with_path: bool,
+}
+
+/// An entry as read back from the edited file.
+struct Edit {
+    path: Option<String>,
+    cmd: String,
 }
 
 pub fn edit(cfg: &Config, args: EditArgs) -> Result<()> {
-    let filter = Filter::new(args.filter)?;
+    // if the edits come from stdin, then we can't use it to ask for
+    // confirmation as well.
+    if args.from.as_deref() == Some(Path::new("-")) && !args.force && !args.dry_run {
+        return Err(Error::from_str(
+            "--force or --dry-run is required when reading edits from stdin",
+        ));
+    }
+
+    let filter = Filter::new(cfg, args.filter)?;
     let mut conn = server::ensure_ready(cfg)?;
     let history: Vec<Entry> = filter.history_request(&mut conn)?;
 
@@ -65,7 +98,10 @@ pub fn edit(cfg: &Config, args: EditArgs) -> Result<()> {
         return Ok(());
     }
 
-    let changes = edit_history(&cfg.cache_dir, history)?;
+    let changes = match &args.from {
+        Some(path) => get_changes(history, read_edits(path, args.with_path)?, false),
+        None => edit_history(&cfg.cache_dir, history, args.with_path)?,
+    };
     match changes.len() {
         0 => {
             info!("no entries modified");
@@ -76,21 +112,24 @@ pub fn edit(cfg: &Config, args: EditArgs) -> Result<()> {
     };
 
     if !args.quiet {
-        show_changes(&changes);
+        show_changes(&changes, args.with_path);
     }
 
     if args.dry_run {
         return Ok(());
     }
 
-    if !args.force && !confirm_changes()? {
+    if !args.force && !confirm("Apply changes?")? {
         info!("changes aborted");
         exit(0);
     }
 
     let session = Session::get()?.id;
     for entry in changes {
-        conn.update(entry.id, entry.cmd, session.clone())?;
+        match args.with_path {
+            true => conn.update_with_path(entry.id, entry.cmd, entry.path, session.clone())?,
+            false => conn.update(entry.id, entry.cmd, session.clone())?,
+        }
     }
 
     info!("changes saved");
@@ -98,26 +137,43 @@ pub fn edit(cfg: &Config, args: EditArgs) -> Result<()> {
     Ok(())
 }
 
-fn edit_history<P: AsRef<Path>>(dir: P, history: Vec<Entry>) -> Result<Vec<Entry>> {
-    let temp_file = write_temp_file(dir, &history)?;
+fn edit_history<P: AsRef<Path>>(
+    dir: P,
+    history: Vec<Entry>,
+    with_path: bool,
+) -> Result<Vec<Entry>> {
+    let temp_file = write_temp_file(dir, &history, with_path)?;
 
     edit_file(temp_file.path())?;
 
-    let edited = parse_file(temp_file.path())?;
+    let edited = parse_file(temp_file.path(), with_path)?;
 
     // make sure temp_file exists until we have read the file back in
     drop(temp_file);
 
-    Ok(get_changes(history, edited))
+    Ok(get_changes(history, edited, true))
 }
 
-fn write_temp_file<P: AsRef<Path>>(dir: P, history: &[Entry]) -> Result<NamedTempFile> {
+fn write_temp_file<P: AsRef<Path>>(
+    dir: P,
+    history: &[Entry],
+    with_path: bool,
+) -> Result<NamedTempFile> {
     fs::create_dir_all(dir.as_ref())?;
     let mut temp_file = NamedTempFile::new_in(dir)?;
     debug!("temp file: {:?}", temp_file.path());
     writeln!(temp_file, "{}", HEADER)?;
     for entry in history {
-        writeln!(temp_file, "{}\t{}", entry.id, entry.cmd)?;
+        match with_path {
+            true => writeln!(
+                temp_file,
+                "{}\t{}\t{}",
+                entry.id,
+                escape(&entry.path).replace('\t', "\\t"),
+                escape(&entry.cmd)
+            )?,
+            false => writeln!(temp_file, "{}\t{}", entry.id, escape(&entry.cmd))?,
+        }
     }
     temp_file.flush()?;
     Ok(temp_file)
@@ -152,9 +208,23 @@ fn get_editor() -> Result<PathBuf> {
     Err(Error::from_str("unable to find editor"))
 }
 
-fn parse_file<P: AsRef<Path>>(path: P) -> Result<HashMap<Uuid, String>> {
+fn read_edits(path: &Path, with_path: bool) -> Result<HashMap<Uuid, Edit>> {
+    if path == Path::new("-") {
+        debug!("read edits from stdin");
+        parse_edits(stdin().lock(), with_path)
+    } else {
+        debug!("read edits from {path:?}");
+        parse_file(path, with_path)
+    }
+}
+
+fn parse_file<P: AsRef<Path>>(path: P, with_path: bool) -> Result<HashMap<Uuid, Edit>> {
+    parse_edits(File::open(path)?, with_path)
+}
+
+fn parse_edits<R: Read>(r: R, with_path: bool) -> Result<HashMap<Uuid, Edit>> {
     let mut entries = HashMap::new();
-    for line in BufReader::new(File::open(path)?).lines() {
+    for line in BufReader::new(r).lines() {
         let line = line?;
         if line.starts_with("#") || line.is_empty() {
             continue;
@@ -168,49 +238,113 @@ fn parse_file<P: AsRef<Path>>(path: P) -> Result<HashMap<Uuid, String>> {
             }
         };
         let id = Uuid::parse_str(id)?;
-        entries.insert(id, cmd.to_string());
+        let (path, cmd) = match with_path {
+            true => match cmd.split_once('\t') {
+                Some((path, cmd)) => (Some(unescape(path)), cmd),
+                None => {
+                    return Err(Error::Generic(format!(
+                        "line does not have a path and command: {line}"
+                    )));
+                }
+            },
+            false => (None, cmd),
+        };
+        entries.insert(
+            id,
+            Edit {
+                path,
+                cmd: unescape(cmd),
+            },
+        );
     }
     Ok(entries)
 }
 
-fn get_changes(history: Vec<Entry>, edited: HashMap<Uuid, String>) -> Vec<Entry> {
+/// Escape a command so that it fits on a single line.
+fn escape(cmd: &str) -> String {
+    cmd.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Reverse the escaping done by escape. Unrecognised escapes are left as-is.
+fn unescape(cmd: &str) -> String {
+    let mut out = String::with_capacity(cmd.len());
+    let mut chars = cmd.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// Work out which entries have been changed. If delete_missing is set then
+/// entries that aren't in the edits are deleted (i.e. their line was removed
+/// in the editor), otherwise they are left alone and only an empty command
+/// deletes an entry.
+fn get_changes(
+    history: Vec<Entry>,
+    mut edited: HashMap<Uuid, Edit>,
+    delete_missing: bool,
+) -> Vec<Entry> {
     let mut changes = Vec::new();
     for mut entry in history {
-        let changed = match edited.get(&entry.id) {
-            Some(cmd) => {
-                if &entry.cmd == cmd {
+        let (cmd, path) = match edited.remove(&entry.id) {
+            Some(edit) => {
+                if entry.cmd == edit.cmd
+                    && edit.path.as_ref().is_none_or(|path| &entry.path == path)
+                {
                     continue;
-                } else {
-                    cmd.as_str()
                 }
+                (edit.cmd, edit.path)
             }
-            None => "",
+            None if delete_missing => (String::new(), None),
+            None => continue,
         };
-        if changed.is_empty() {
+        if cmd.is_empty() {
             debug!("Entry {} was deleted", entry.id);
         } else {
-            debug!("Entry {} changed to: {}", entry.id, changed);
+            debug!("Entry {} changed to: {} (in {:?})", entry.id, cmd, path);
+        }
+        entry.cmd = cmd;
+        if let Some(path) = path {
+            entry.path = path;
         }
-        entry.cmd = changed.to_string();
         changes.push(entry);
     }
     changes
 }
 
-fn show_changes(changes: &[Entry]) {
+fn show_changes(changes: &[Entry], with_path: bool) {
     for entry in changes {
         if entry.cmd.is_empty() {
             info!("{}: <deleted>", entry.id);
+        } else if with_path {
+            info!("{}: {}\t{}", entry.id, entry.path, entry.cmd);
         } else {
             info!("{}: {}", entry.id, entry.cmd);
         }
     }
 }
 
-fn confirm_changes() -> Result<bool> {
+/// Ask the user a yes/no question, returning true if they answer yes.
+pub fn confirm(question: &str) -> Result<bool> {
     let mut buf = String::with_capacity(1024);
     loop {
-        print!("Apply changes? (yes/no): ");
+        print!("{question} (yes/no): ");
         stdout().flush()?;
         buf.clear();
         stdin().read_line(&mut buf)?;
@@ -0,0 +1,52 @@
+use std::{env, fmt::Write};
+
+use aws_lc_rs::digest::{Context, SHA256};
+use rustix::process::geteuid;
+
+use crate::config::Config;
+
+/// Calculate a hash of the environment variables listed in the `env_vars`
+/// config option.
+///
+/// The hash is calculated on the client, so that the values (which may well be
+/// secret) are never sent to the server. Returns None if no variables are
+/// configured.
+pub fn env_hash(cfg: &Config) -> Option<String> {
+    if cfg.history.env_vars.is_empty() {
+        return None;
+    }
+    let mut ctx = Context::new(&SHA256);
+    for name in cfg.history.env_vars.iter() {
+        ctx.update(name.as_bytes());
+        // distinguish between a variable that is unset, and one that is set
+        // to an empty value.
+        match env::var_os(name) {
+            Some(value) => {
+                ctx.update(b"=");
+                ctx.update(value.as_encoded_bytes());
+            }
+            None => ctx.update(b"!"),
+        }
+        ctx.update(b"\0");
+    }
+    let mut hash = String::with_capacity(64);
+    for b in ctx.finish().as_ref() {
+        // writing to a String can't fail
+        let _ = write!(hash, "{b:02x}");
+    }
+    Some(hash)
+}
+
+/// The effective uid that the client is running as, this is recorded with each
+/// command so that commands run as root can be found later.
+pub fn uid() -> u32 {
+    geteuid().as_raw()
+}
+
+/// The shell that the client was run from, as set in $VELLUM_SHELL by the
+/// shell integration.
+pub fn shell() -> Option<String> {
+    env::var("VELLUM_SHELL")
+        .ok()
+        .filter(|shell| !shell.is_empty())
+}
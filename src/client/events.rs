@@ -0,0 +1,74 @@
+use std::io::{Write, stdout};
+
+use chrono::{DateTime, Utc};
+use log::{debug, info};
+use serde::Serialize;
+
+use crate::{api::Message, config::Config, error::Result, server};
+
+#[derive(clap::Args, Debug)]
+pub struct EventsArgs {
+    /// Output each event as a line of JSON
+    #[arg(short, long)]
+    json: bool,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event")]
+enum Event {
+    #[serde(rename = "sync_started")]
+    Started { ts: DateTime<Utc> },
+    #[serde(rename = "sync_completed")]
+    Completed {
+        ts: DateTime<Utc>,
+        added: usize,
+        pushed: usize,
+    },
+    #[serde(rename = "sync_failed")]
+    Failed { ts: DateTime<Utc>, err: String },
+}
+
+impl Event {
+    fn from_message(msg: Message) -> Option<Self> {
+        let ts = Utc::now();
+        match msg {
+            Message::SyncStarted => Some(Self::Started { ts }),
+            Message::SyncCompleted { added, pushed } => Some(Self::Completed { ts, added, pushed }),
+            Message::SyncFailed(err) => Some(Self::Failed { ts, err }),
+            _ => None,
+        }
+    }
+}
+
+/// Print the sync events from the server as they happen, until the server
+/// exits.
+pub fn events(cfg: &Config, args: EventsArgs) -> Result<()> {
+    let mut conn = server::ensure_ready(cfg)?;
+    conn.subscribe()?;
+    debug!("subscribed to events");
+
+    let mut out = stdout();
+    while let Some(msg) = conn.next_event()? {
+        let Some(event) = Event::from_message(msg) else {
+            continue;
+        };
+        if args.json {
+            writeln!(out, "{}", serde_json::to_string(&event)?)?;
+        } else {
+            match event {
+                Event::Started { ts } => writeln!(out, "{} sync started", ts.to_rfc3339())?,
+                Event::Completed { ts, added, pushed } => writeln!(
+                    out,
+                    "{} sync completed: {added} added, {pushed} pushed",
+                    ts.to_rfc3339()
+                )?,
+                Event::Failed { ts, err } => {
+                    writeln!(out, "{} sync failed: {err}", ts.to_rfc3339())?
+                }
+            }
+        }
+        out.flush()?;
+    }
+    info!("server closed the connection");
+    Ok(())
+}
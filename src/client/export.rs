@@ -0,0 +1,85 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write, stdout},
+};
+
+use clap::{ArgGroup, ValueHint};
+use log::debug;
+
+use crate::{config::Config, error::Result, history::Entry, server};
+
+use super::{AnonymizeArgs, Anonymizer, Filter, FilterArgs};
+
+#[derive(clap::Args, Debug)]
+#[command(group(ArgGroup::new("output").required(true).args(["histfile", "json"])))]
+pub struct ExportArgs {
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    /// Write to a file rather than stdout
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    file: Option<String>,
+
+    /// Export the history as a shell history file
+    #[arg(long)]
+    histfile: bool,
+
+    /// Export the history as JSON, in the same format as the save command
+    #[arg(long)]
+    json: bool,
+
+    /// The shell history file format to use
+    #[arg(long, value_enum, default_value = "zsh")]
+    format: HistFormat,
+
+    #[command(flatten)]
+    anonymize: AnonymizeArgs,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum HistFormat {
+    /// zsh extended history (`: <epoch>:0;<cmd>`), with newlines in commands
+    /// escaped with a backslash
+    Zsh,
+    /// bash history with timestamps (`#<epoch>` before each command), which
+    /// bash uses to find the end of multi-line commands
+    Bash,
+}
+
+impl HistFormat {
+    fn write<W: Write>(&self, w: &mut W, entry: &Entry) -> Result<()> {
+        let ts = entry.ts.timestamp();
+        match self {
+            Self::Zsh => writeln!(w, ": {ts}:0;{}", entry.cmd.replace('\n', "\\\n"))?,
+            Self::Bash => writeln!(w, "#{ts}\n{}", entry.cmd)?,
+        }
+        Ok(())
+    }
+}
+
+pub fn export(cfg: &Config, args: ExportArgs) -> Result<()> {
+    let filter = Filter::new(cfg, &args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match args.file {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(stdout()),
+    });
+
+    let mut anonymizer = Anonymizer::new(&args.anonymize);
+    let history = history.into_iter().map(|entry| anonymizer.entry(entry));
+
+    if args.json {
+        serde_json::to_writer(&mut writer, &history.collect::<Vec<_>>())?;
+    } else {
+        for entry in history {
+            args.format.write(&mut writer, &entry)?;
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
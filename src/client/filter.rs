@@ -1,22 +1,66 @@
-use std::{borrow::Borrow, env::current_dir, time::Duration};
+use std::{
+    borrow::{Borrow, Cow},
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    env::current_dir,
+    fs,
+    io::Write,
+    path::Path,
+    rc::Rc,
+    time::Duration,
+};
 
 use chrono::{DateTime, Utc};
 use clap::ValueHint;
+use itertools::Itertools;
+use log::{debug, warn};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
 
 use crate::{
     api::Connection,
+    config::Config,
     error::{Error, Result},
-    history::Entry,
+    history::{self, Entry},
 };
 
-use super::Session;
+use super::{Session, env_hash};
 
-#[derive(clap::Args, Debug, Clone)]
+#[derive(clap::Args, Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
 pub struct FilterArgs {
+    /// Use the filters from the named query in the queries config section,
+    /// any filters given on the command line take precedence
+    #[arg(long, value_name = "NAME", value_hint = ValueHint::Other)]
+    #[serde(skip)]
+    query: Option<String>,
+
     /// Only include commands stored by the current session
     #[arg(short, long)]
     session: bool,
 
+    /// Exclude commands stored by the specified session (can be specified
+    /// multiple times, and takes precedence over --session)
+    #[arg(long, value_name = "ID", value_hint = ValueHint::Other)]
+    exclude_session: Option<Vec<String>>,
+
+    /// Only include commands stored by the specified session, which doesn't
+    /// have to be the current one (can be specified multiple times)
+    #[arg(long, value_name = "ID", value_hint = ValueHint::Other)]
+    session_id: Option<Vec<String>>,
+
+    /// Only include commands stored between the first and last commands of
+    /// the specified session, by any session (to see what else was happening
+    /// at the time)
+    #[arg(long, value_name = "ID", value_hint = ValueHint::Other)]
+    session_range: Option<String>,
+
+    /// Only include commands stored since the current session started (from
+    /// $VELLUM_SESSION_START), unlike --session this doesn't include the
+    /// commands from before the session started
+    #[arg(long)]
+    since_session_start: bool,
+
     /// Only include commands stored on or after this time (RFC 3339 timestamp)
     #[arg(long, value_name = "TIMESTAMP", value_hint = ValueHint::Other)]
     after: Option<DateTime<Utc>>,
@@ -30,6 +74,11 @@ pub struct FilterArgs {
     #[arg(long, value_hint = ValueHint::Hostname)]
     host: Option<Vec<String>>,
 
+    /// How the hosts given with --host are matched against the host that
+    /// stored each command [default: exact]
+    #[arg(long, value_enum, value_name = "MATCH")]
+    host_match: Option<HostMatch>,
+
     /// Only include commands that were run in the specified path (can be
     /// specified multiple times)
     #[arg(long, value_hint = ValueHint::DirPath)]
@@ -41,10 +90,12 @@ pub struct FilterArgs {
 
     /// Only include commands that were stored more than the given duration ago
     #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION", value_hint = ValueHint::Other)]
+    #[serde(with = "humantime_serde")]
     min_age: Option<Duration>,
 
     /// Only include commands that were stored within the specified duration
     #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION", value_hint = ValueHint::Other)]
+    #[serde(with = "humantime_serde")]
     max_age: Option<Duration>,
 
     /// Only include commands that match the given prefix
@@ -54,6 +105,138 @@ pub struct FilterArgs {
     /// Only include commands that include the given string
     #[arg(long, value_hint = ValueHint::Other)]
     search: Option<String>,
+
+    /// Only include commands that have the given argument, matching whole
+    /// (shell) words rather than substrings, so git matches "git status" but
+    /// not "legit" (can be specified multiple times, commands must have all
+    /// of them)
+    #[arg(long = "arg", value_name = "TOKEN", value_hint = ValueHint::Other)]
+    #[serde(rename = "arg")]
+    args: Option<Vec<String>>,
+
+    /// Only include commands that start with the given (shell) words, e.g.
+    /// --subcommand 'git push'
+    #[arg(long, value_name = "WORDS", value_hint = ValueHint::Other)]
+    subcommand: Option<String>,
+
+    /// Only include commands that were run with the same values for the
+    /// environment variables listed in the history.env_vars config option as
+    /// are currently set
+    #[arg(long)]
+    env: bool,
+
+    /// Only include commands that were run as root
+    #[arg(long, conflicts_with = "user")]
+    root: bool,
+
+    /// Only include commands that were not run as root (including commands
+    /// stored before the user was recorded)
+    #[arg(long)]
+    user: bool,
+
+    /// Only include commands in the specified category (e.g. git, docker,
+    /// editing, navigation, package-mgmt or other, can be specified multiple
+    /// times). Commands stored before categories were added have no category.
+    #[arg(long, value_hint = ValueHint::Other)]
+    category: Option<Vec<String>>,
+
+    /// Only include commands that were run from the specified shell (e.g. bash
+    /// or zsh, can be specified multiple times). Commands stored before the
+    /// shell was recorded don't match.
+    #[arg(long, value_hint = ValueHint::Other)]
+    shell: Option<Vec<String>>,
+
+    /// Only include commands that have a note attached (with vellum annotate)
+    #[arg(long)]
+    has_note: bool,
+
+    /// Match --host, --path, --prefix and --search case-insensitively if they
+    /// are all lowercase, and case-sensitively otherwise (on by default for
+    /// browse, use --smart-case=false to turn it off)
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", value_name = "BOOL")]
+    smart_case: Option<bool>,
+
+    /// Read the history as it was at this point in the sync repo, instead of
+    /// the current history.
+    #[arg(skip)]
+    #[serde(skip)]
+    at: Option<String>,
+
+    /// The host to use if no hosts are given on the command line or in the
+    /// query.
+    #[arg(skip)]
+    #[serde(skip)]
+    default_host: Option<String>,
+}
+
+impl FilterArgs {
+    /// Only include commands stored by the given host, unless hosts were
+    /// explicitly requested.
+    pub fn default_host<S: Into<String>>(&mut self, host: S) {
+        self.default_host = Some(host.into());
+    }
+
+    /// Only include commands stored by the given session.
+    pub fn session_id<S: Into<String>>(&mut self, id: S) {
+        self.session_id = Some(vec![id.into()]);
+    }
+
+    /// Read the history as it was at the given point in the sync repo (a git
+    /// ref or RFC 3339 timestamp).
+    pub fn at<S: Into<String>>(&mut self, at: S) {
+        self.at = Some(at.into());
+    }
+
+    /// Use smart case matching, unless it was explicitly turned off.
+    pub fn default_smart_case(&mut self) {
+        self.smart_case.get_or_insert(true);
+    }
+
+    /// Load the named query from the config.
+    fn load_query(cfg: &Config, name: &str) -> Result<Self> {
+        let query = cfg
+            .queries
+            .get(name)
+            .ok_or_else(|| Error::Generic(format!("no query called {name:?} in the config")))?;
+        debug!("query {name}: {query:?}");
+        toml::Value::Table(query.clone())
+            .try_into()
+            .map_err(|e| Error::Generic(format!("invalid query {name:?}: {e}")))
+    }
+
+    /// Fill in any filters that weren't given on the command line from the
+    /// query.
+    fn merge(self, query: Self) -> Self {
+        Self {
+            query: self.query,
+            session: self.session || query.session,
+            exclude_session: self.exclude_session.or(query.exclude_session),
+            session_id: self.session_id.or(query.session_id),
+            session_range: self.session_range.or(query.session_range),
+            since_session_start: self.since_session_start || query.since_session_start,
+            after: self.after.or(query.after),
+            before: self.before.or(query.before),
+            host: self.host.or(query.host),
+            host_match: self.host_match.or(query.host_match),
+            path: self.path.or(query.path),
+            current_path: self.current_path || query.current_path,
+            min_age: self.min_age.or(query.min_age),
+            max_age: self.max_age.or(query.max_age),
+            prefix: self.prefix.or(query.prefix),
+            search: self.search.or(query.search),
+            args: self.args.or(query.args),
+            subcommand: self.subcommand.or(query.subcommand),
+            env: self.env || query.env,
+            root: self.root || query.root,
+            user: self.user || query.user,
+            category: self.category.or(query.category),
+            shell: self.shell.or(query.shell),
+            has_note: self.has_note || query.has_note,
+            smart_case: self.smart_case.or(query.smart_case),
+            at: self.at,
+            default_host: self.default_host,
+        }
+    }
 }
 
 pub struct Filter {
@@ -63,83 +246,264 @@ pub struct Filter {
     max_age: Option<DateTime<Utc>>,
     current_session: Session,
     current_path: String,
+    env_hash: Option<String>,
+    // the times of the first and last commands of the --session-range
+    // session, found once the history has been fetched
+    session_range: Cell<Option<(DateTime<Utc>, DateTime<Utc>)>>,
+    // the words that --subcommand matches
+    subcommand: Option<Vec<String>>,
+    // commands split into words, for matching --arg and --subcommand
+    tokens: RefCell<HashMap<String, Rc<Vec<String>>>>,
 }
 
 impl Filter {
-    pub fn new<F: Borrow<FilterArgs>>(args: F) -> Result<Self> {
-        let args = args.borrow();
+    pub fn new<F: Borrow<FilterArgs>>(cfg: &Config, args: F) -> Result<Self> {
+        let mut args = args.borrow().clone();
+        if let Some(name) = &args.query {
+            let query = FilterArgs::load_query(cfg, name)?;
+            args = args.merge(query);
+        }
+        if args.host.is_none() {
+            args.host = args.default_host.take().map(|host| vec![host]);
+        }
+        if args.root && args.user {
+            return Err(Error::from_str("--root and --user can't be used together"));
+        }
+        let env_hash = match args.env {
+            true => Some(env_hash(cfg).ok_or_else(|| {
+                Error::from_str("--env requires history.env_vars to be configured")
+            })?),
+            false => None,
+        };
+        if args.current_path && !cfg.history.store_path {
+            warn!("history.store_path is disabled, new commands won't match --current-path");
+        }
         let current_session = Session::get()?;
+        if args.since_session_start {
+            // this is the same as --after with the session start time, so that
+            // the server only sends the commands we need.
+            let start = current_session.start.ok_or_else(|| {
+                Error::from_str("--since-session-start requires VELLUM_SESSION_START to be set")
+            })?;
+            args.after = args.after.max(Some(start));
+        }
+        let subcommand = args
+            .subcommand
+            .as_deref()
+            .map(|words| {
+                shlex::split(words)
+                    .ok_or_else(|| Error::Generic(format!("can't split --subcommand {words:?}")))
+            })
+            .transpose()?;
         let now = Utc::now();
         let min_age = args.min_age.map(|d| now - d);
         let max_age = args.max_age.map(|d| now - d);
-        let current_path = current_dir()?
-            .to_str()
-            .ok_or_else(|| Error::from_str("failed to convert current directory to string"))?
-            .to_owned();
+        // paths that aren't valid UTF-8 are stored with the invalid bytes
+        // replaced, so the current path has to be converted the same way.
+        let current_path = current_dir()?.to_string_lossy().into_owned();
         Ok(Self {
-            args: args.clone(),
+            args,
             min_age,
             max_age,
             current_session,
             current_path,
+            env_hash,
+            session_range: Cell::new(None),
+            subcommand,
+            tokens: RefCell::new(HashMap::new()),
         })
     }
 
+    /// Are we only including commands from the current session?
+    pub fn current_session(&self) -> bool {
+        self.args.session
+    }
+
+    /// Is smart case matching being used?
+    pub fn smart_case(&self) -> bool {
+        self.args.smart_case.unwrap_or_default()
+    }
+
+    fn host_match(&self) -> HostMatch {
+        self.args.host_match.unwrap_or_default()
+    }
+
+    /// Get the text to match the pattern against, which is lowercased if smart
+    /// case is being used and the pattern is all lowercase.
+    fn case<'a>(&self, pattern: &str, text: &'a str) -> Cow<'a, str> {
+        smart_case_text(self.smart_case(), pattern, text)
+    }
+
+    /// Split a command into shell words, falling back to splitting on
+    /// whitespace if it can't be parsed (e.g. it has unbalanced quotes).
+    fn tokens(&self, cmd: &str) -> Rc<Vec<String>> {
+        self.tokens
+            .borrow_mut()
+            .entry(cmd.to_string())
+            .or_insert_with(|| {
+                Rc::new(
+                    shlex::split(cmd)
+                        .unwrap_or_else(|| cmd.split_whitespace().map(String::from).collect()),
+                )
+            })
+            .clone()
+    }
+
     pub fn entry(&self, entry: &Entry) -> bool {
+        if let Some(exclude) = &self.args.exclude_session
+            && exclude.contains(&entry.session)
+        {
+            return false;
+        }
         if self.args.session && !self.current_session.includes_entry(entry) {
             return false;
         }
-        if let Some(after) = self.args.after {
-            if entry.ts < after {
-                return false;
-            }
+        if let Some(ids) = &self.args.session_id
+            && !ids.contains(&entry.session)
+        {
+            return false;
         }
-        if let Some(before) = self.args.before {
-            if entry.ts >= before {
-                return false;
-            }
+        if let Some((start, end)) = self.session_range.get()
+            && (entry.ts < start || entry.ts > end)
+        {
+            return false;
         }
-        if let Some(host) = &self.args.host {
-            if !host.contains(&entry.host) {
-                return false;
-            }
+        if let Some(after) = self.args.after
+            && entry.ts < after
+        {
+            return false;
         }
-        if let Some(path) = &self.args.path {
-            if !path.contains(&entry.path) {
-                return false;
-            }
+        if let Some(before) = self.args.before
+            && entry.ts >= before
+        {
+            return false;
         }
-        if self.args.current_path {
-            if entry.path != self.current_path {
-                return false;
-            }
+        if let Some(host) = &self.args.host
+            && !host.iter().any(|host| {
+                self.host_match()
+                    .matches(host, &self.case(host, &entry.host))
+            })
+        {
+            return false;
         }
-        if let Some(min_age) = self.min_age {
-            if entry.ts >= min_age {
-                return false;
-            }
+        if let Some(path) = &self.args.path
+            && !path
+                .iter()
+                .any(|path| *path == self.case(path, &entry.path))
+        {
+            return false;
         }
-        if let Some(max_age) = self.max_age {
-            if entry.ts < max_age {
-                return false;
-            }
+        if self.args.current_path && entry.path != self.current_path {
+            return false;
         }
-        if let Some(prefix) = &self.args.prefix {
-            if !entry.cmd.starts_with(prefix) {
-                return false;
-            }
+        if let Some(min_age) = self.min_age
+            && entry.ts >= min_age
+        {
+            return false;
         }
-        if let Some(search) = &self.args.search {
-            if !entry.cmd.contains(search) {
-                return false;
-            }
+        if let Some(max_age) = self.max_age
+            && entry.ts < max_age
+        {
+            return false;
+        }
+        if let Some(prefix) = &self.args.prefix
+            && !self.case(prefix, &entry.cmd).starts_with(prefix.as_str())
+        {
+            return false;
+        }
+        if let Some(search) = &self.args.search
+            && !self.case(search, &entry.cmd).contains(search.as_str())
+        {
+            return false;
+        }
+        if let Some(args) = &self.args.args
+            && !args.iter().all({
+                let tokens = self.tokens(&entry.cmd);
+                move |arg| tokens.contains(arg)
+            })
+        {
+            return false;
+        }
+        if let Some(subcommand) = &self.subcommand
+            && !self.tokens(&entry.cmd).starts_with(subcommand)
+        {
+            return false;
+        }
+        if self.env_hash.is_some() && entry.env_hash != self.env_hash {
+            return false;
+        }
+        if self.args.root && entry.uid != Some(0) {
+            return false;
+        }
+        if let Some(category) = &self.args.category
+            && !entry
+                .category
+                .as_ref()
+                .is_some_and(|entry_category| category.contains(entry_category))
+        {
+            return false;
+        }
+        if let Some(shell) = &self.args.shell
+            && !entry
+                .shell
+                .as_ref()
+                .is_some_and(|entry_shell| shell.contains(entry_shell))
+        {
+            return false;
+        }
+        if self.args.user && entry.uid == Some(0) {
+            return false;
+        }
+        if self.args.has_note && entry.note.is_none() {
+            return false;
         }
         true
     }
 
-    pub fn enumerate_history_request(&self, conn: &mut Connection) -> Result<Vec<(usize, Entry)>> {
+    /// Request the history from the server, letting it know the time range
+    /// that we want if there is one.
+    fn request(&self, conn: &mut Connection) -> Result<Vec<Entry>> {
+        let history = match (&self.args.at, self.args.after, self.args.before) {
+            (Some(at), _, _) => conn.history_at(at.clone())?,
+            (None, None, None) => conn.history_request()?,
+            (None, after, before) => conn.history_between(after, before)?,
+        };
+        self.find_session_range(&history)?;
+        Ok(history)
+    }
+
+    /// Find the first and last commands of the --session-range session, which
+    /// has to be done before any entries are filtered.
+    fn find_session_range(&self, history: &[Entry]) -> Result<()> {
+        let Some(id) = &self.args.session_range else {
+            return Ok(());
+        };
+        let range = history
+            .iter()
+            .filter(|entry| &entry.session == id)
+            .map(|entry| entry.ts)
+            .minmax()
+            .into_option()
+            .ok_or_else(|| Error::Generic(format!("no commands found for session {id}")))?;
+        debug!("session {id} range: {range:?}");
+        self.session_range.set(Some(range));
+        Ok(())
+    }
+
+    /// Request the entries that have been deleted but not yet removed by a
+    /// rebuild, filtered in the same way as the history. This has to be done
+    /// after the history has been requested.
+    pub fn deleted_request(&self, conn: &mut Connection) -> Result<Vec<Entry>> {
         Ok(conn
-            .history_request()?
+            .deleted_request()?
+            .into_iter()
+            .filter(|entry| self.entry(entry))
+            .collect())
+    }
+
+    pub fn enumerate_history_request(&self, conn: &mut Connection) -> Result<Vec<(usize, Entry)>> {
+        Ok(self
+            .request(conn)?
             .into_iter()
             .enumerate()
             .filter(|(_, entry)| self.entry(entry))
@@ -147,10 +511,312 @@ impl Filter {
     }
 
     pub fn history_request(&self, conn: &mut Connection) -> Result<Vec<Entry>> {
-        Ok(conn
-            .history_request()?
+        Ok(self
+            .request(conn)?
             .into_iter()
             .filter(|entry| self.entry(entry))
             .collect())
     }
+
+    /// Like history_request, but keeps an encrypted copy of the history in
+    /// the cache file, which is used instead of fetching the history again as
+    /// long as the server's history hasn't changed. Only the current history
+    /// is cached, so requests for a time range or an earlier point always go
+    /// to the server.
+    pub fn cached_history_request(
+        &self,
+        cfg: &Config,
+        conn: &mut Connection,
+        cache: &Path,
+    ) -> Result<Vec<Entry>> {
+        if self.args.at.is_some() || self.args.after.is_some() || self.args.before.is_some() {
+            return self.history_request(conn);
+        }
+        let cached = read_history_cache(cfg, cache);
+        let (version, history) = conn.history_if_changed(cached.as_ref().map(|(v, _)| *v))?;
+        let history = match (history, cached) {
+            (Some(history), _) => {
+                debug!("history changed, now at version {version}");
+                if let Err(e) = write_history_cache(cfg, cache, version, &history) {
+                    warn!("Failed to write history cache {cache:?}: {e}");
+                }
+                history
+            }
+            (None, Some((_, history))) => {
+                debug!("using cached history at version {version}");
+                history
+            }
+            (None, None) => return Err(Error::from_str("server didn't send the history")),
+        };
+        self.find_session_range(&history)?;
+        Ok(history
+            .into_iter()
+            .filter(|entry| self.entry(entry))
+            .collect())
+    }
+}
+
+/// Read the cached history and its version, if there is a usable cache.
+fn read_history_cache(cfg: &Config, path: &Path) -> Option<(u64, Vec<Entry>)> {
+    let data = fs::read(path)
+        .inspect_err(|e| debug!("no history cache {path:?}: {e}"))
+        .ok()?;
+    history::decrypt_cache(cfg, &data)
+        .inspect_err(|e| warn!("Ignoring invalid history cache {path:?}: {e}"))
+        .ok()
+}
+
+/// Replace the history cache, the new cache is written alongside and renamed
+/// into place so that other sessions never see a partial cache. The cache is
+/// encrypted with the history key, and temporary files are only readable by
+/// us.
+fn write_history_cache(cfg: &Config, path: &Path, version: u64, history: &[Entry]) -> Result<()> {
+    let data = history::encrypt_cache(cfg, version, history)?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| Error::from_str("history cache has no parent directory"))?;
+    fs::create_dir_all(dir)?;
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    tmp.write_all(&data)?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[derive(clap::ValueEnum, Deserialize, Default, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum HostMatch {
+    /// The host must be exactly the same
+    #[default]
+    Exact,
+    /// The host must start with the given name (e.g. laptop matches
+    /// laptop.home.arpa)
+    Prefix,
+    /// The host must contain the given name
+    Substring,
+}
+
+impl HostMatch {
+    fn matches(&self, want: &str, host: &str) -> bool {
+        match self {
+            Self::Exact => host == want,
+            Self::Prefix => host.starts_with(want),
+            Self::Substring => host.contains(want),
+        }
+    }
+}
+
+/// Get the text to match the pattern against. With smart case an all
+/// lowercase pattern matches case-insensitively, so the text is lowercased
+/// too, otherwise the text is matched as it is.
+pub fn smart_case_text<'a>(smart_case: bool, pattern: &str, text: &'a str) -> Cow<'a, str> {
+    match smart_case && !pattern.chars().any(char::is_uppercase) {
+        true => Cow::Owned(text.to_lowercase()),
+        false => Cow::Borrowed(text),
+    }
+}
+
+/// Which commands count as duplicates of each other when removing duplicates.
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum DedupScope {
+    /// The same command stored anywhere
+    Global,
+    /// The same command stored by the same host
+    Host,
+    /// The same command stored by the same session
+    Session,
+}
+
+impl DedupScope {
+    fn key<'a>(&self, entry: &'a Entry) -> (&'a str, &'a str) {
+        match self {
+            Self::Global => ("", &entry.cmd),
+            Self::Host => (&entry.host, &entry.cmd),
+            Self::Session => (&entry.session, &entry.cmd),
+        }
+    }
+}
+
+/// Remove duplicate commands from the given history, which must be in
+/// chronological order. Only the most recent occurrence of each command (within
+/// the scope) is kept, and otherwise the order is preserved.
+pub fn dedup<T, F>(history: Vec<T>, scope: DedupScope, entry: F) -> Vec<T>
+where
+    F: Fn(&T) -> &Entry,
+{
+    let mut seen = HashSet::new();
+    let mut deduped: Vec<T> = history
+        .into_iter()
+        .rev()
+        .filter(|item| {
+            let (scope, cmd) = scope.key(entry(item));
+            seen.insert((scope.to_owned(), cmd.to_owned()))
+        })
+        .collect();
+    deduped.reverse();
+    deduped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(host: &str, session: &str, cmd: &str) -> Entry {
+        Entry::new(host, cmd, "", session)
+    }
+
+    fn cmds(history: &[Entry]) -> Vec<(&str, &str)> {
+        history
+            .iter()
+            .map(|entry| (entry.host.as_str(), entry.cmd.as_str()))
+            .collect()
+    }
+
+    /// A command with the filters followed by a positional argument, like
+    /// the FIRST argument of history.
+    #[derive(clap::Parser, Debug)]
+    struct Cli {
+        #[command(flatten)]
+        filter: FilterArgs,
+        first: Option<String>,
+    }
+
+    fn parse(args: &[&str]) -> Cli {
+        <Cli as clap::Parser>::parse_from(["vellum"].iter().chain(args))
+    }
+
+    fn history() -> Vec<Entry> {
+        vec![
+            entry("a", "1", "ls"),
+            entry("a", "1", "pwd"),
+            entry("b", "2", "ls"),
+            entry("a", "2", "cd"),
+            entry("a", "1", "ls"),
+        ]
+    }
+
+    #[test]
+    fn dedup_global_keeps_most_recent() {
+        let deduped = dedup(history(), DedupScope::Global, |entry| entry);
+        assert_eq!(cmds(&deduped), vec![("a", "pwd"), ("a", "cd"), ("a", "ls")]);
+    }
+
+    #[test]
+    fn dedup_host() {
+        let deduped = dedup(history(), DedupScope::Host, |entry| entry);
+        assert_eq!(
+            cmds(&deduped),
+            vec![("a", "pwd"), ("b", "ls"), ("a", "cd"), ("a", "ls")]
+        );
+    }
+
+    #[test]
+    fn dedup_session() {
+        let deduped = dedup(history(), DedupScope::Session, |entry| entry);
+        assert_eq!(
+            cmds(&deduped),
+            vec![("a", "pwd"), ("b", "ls"), ("a", "cd"), ("a", "ls")]
+        );
+        let sessions: Vec<&str> = deduped.iter().map(|e| e.session.as_str()).collect();
+        assert_eq!(sessions, vec!["1", "2", "2", "1"]);
+    }
+
+    #[test]
+    fn dedup_with_indexes() {
+        // history dedups (index, entry) pairs in places, and the indexes have
+        // to follow the entries that are kept.
+        let indexed: Vec<(usize, Entry)> = history().into_iter().enumerate().collect();
+        let deduped = dedup(indexed, DedupScope::Global, |(_, entry)| entry);
+        let indexes: Vec<usize> = deduped.iter().map(|(i, _)| *i).collect();
+        assert_eq!(indexes, vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn dedup_empty() {
+        assert!(dedup(Vec::<Entry>::new(), DedupScope::Global, |entry| entry).is_empty());
+    }
+
+    #[test]
+    fn smart_case_doesnt_take_positional() {
+        let cli = parse(&["--smart-case", "5"]);
+        assert_eq!(cli.filter.smart_case, Some(true));
+        assert_eq!(cli.first.as_deref(), Some("5"));
+
+        let cli = parse(&["--smart-case=false", "5"]);
+        assert_eq!(cli.filter.smart_case, Some(false));
+        assert_eq!(cli.first.as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn subcommand_doesnt_take_positional() {
+        let cli = parse(&["--subcommand", "git push", "5"]);
+        assert_eq!(cli.filter.subcommand.as_deref(), Some("git push"));
+        assert_eq!(cli.first.as_deref(), Some("5"));
+    }
+
+    fn matching<'a>(args: &[&str], history: &'a [Entry]) -> Vec<&'a str> {
+        let filter = Filter::new(&Config::default(), parse(args).filter).unwrap();
+        history
+            .iter()
+            .filter(|entry| filter.entry(entry))
+            .map(|entry| entry.cmd.as_str())
+            .collect()
+    }
+
+    fn commands() -> Vec<Entry> {
+        [
+            "git status",
+            "legit status",
+            "git push origin main",
+            "git commit -m 'git push'",
+            "echo \"unbalanced",
+        ]
+        .into_iter()
+        .map(|cmd| entry("a", "s", cmd))
+        .collect()
+    }
+
+    #[test]
+    fn arg_matches_whole_words() {
+        let history = commands();
+        assert_eq!(
+            matching(&["--arg", "git"], &history),
+            vec![
+                "git status",
+                "git push origin main",
+                "git commit -m 'git push'"
+            ]
+        );
+        assert_eq!(
+            matching(&["--arg", "status", "--arg", "legit"], &history),
+            vec!["legit status"]
+        );
+        assert_eq!(
+            matching(&["--arg", "git push"], &history),
+            vec!["git commit -m 'git push'"]
+        );
+        // commands that can't be split as shell words are split on whitespace.
+        assert_eq!(
+            matching(&["--arg", "\"unbalanced"], &history),
+            vec!["echo \"unbalanced"]
+        );
+    }
+
+    #[test]
+    fn subcommand_matches_leading_words() {
+        let history = commands();
+        assert_eq!(
+            matching(&["--subcommand", "git push"], &history),
+            vec!["git push origin main"]
+        );
+        assert_eq!(
+            matching(&["--subcommand", "git"], &history),
+            vec![
+                "git status",
+                "git push origin main",
+                "git commit -m 'git push'"
+            ]
+        );
+        assert!(matching(&["--subcommand", "git stat"], &history).is_empty());
+        assert!(Filter::new(&Config::default(), parse(&["--subcommand", "'git"]).filter).is_err());
+    }
 }
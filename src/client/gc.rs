@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use clap::ValueHint;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    server,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct GcArgs {
+    /// Report how much space a rebuild would save, without changing anything
+    #[arg(long)]
+    analyze: bool,
+
+    /// Output the analysis as JSON
+    #[arg(short, long, requires = "analyze")]
+    json: bool,
+
+    /// Pack the daily history files for all hosts into one archive file per
+    /// month, to reduce the number of files that have to be read. Only days
+    /// older than --older-than are packed.
+    #[arg(long, conflicts_with = "analyze")]
+    pack: bool,
+
+    /// How old days have to be before they are packed
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30days", value_name = "DURATION", value_hint = ValueHint::Other, requires = "pack")]
+    older_than: Duration,
+}
+
+pub fn gc(cfg: &Config, args: GcArgs) -> Result<()> {
+    if args.pack {
+        let mut conn = server::ensure_ready(cfg)?;
+        let packed = conn.pack(args.older_than)?;
+        println!("Packed {packed} daily history files into monthly archives.");
+        return Ok(());
+    }
+    if !args.analyze {
+        return Err(Error::from_str(
+            "one of --analyze or --pack is required, use rebuild to compact the sync repo",
+        ));
+    }
+
+    let mut conn = server::ensure_ready(cfg)?;
+    let analysis = conn.analyze_request()?;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&analysis)?);
+        return Ok(());
+    }
+
+    let host_size = analysis
+        .hosts
+        .keys()
+        .fold("HOST".len(), |max, host| max.max(host.len()));
+    println!("{:host_size$}\tFILES\tRECORDS\tBYTES", "HOST");
+    for (host, usage) in analysis.hosts.iter() {
+        print!(
+            "{host:host_size$}\t{}\t{}\t{}",
+            usage.files, usage.records, usage.bytes
+        );
+        if usage.unreadable > 0 {
+            print!("\t({} unreadable files)", usage.unreadable);
+        }
+        println!();
+    }
+    println!();
+
+    let records = analysis.records();
+    let bytes = analysis.bytes();
+    let estimated = analysis.estimated_bytes();
+    println!("Stored records:   {records}");
+    println!(
+        "Distinct entries: {} ({} superseded records)",
+        analysis.entries,
+        records - analysis.entries
+    );
+    println!("Deleted entries:  {}", analysis.deleted);
+    println!(
+        "After rebuild:    {} entries ({} records reclaimable)",
+        analysis.live(),
+        records - analysis.live()
+    );
+    println!("Current size:     {bytes} bytes");
+    println!(
+        "Estimated size:   {estimated} bytes (saving {} bytes)",
+        bytes - estimated
+    );
+
+    Ok(())
+}
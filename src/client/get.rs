@@ -1,23 +1,50 @@
+use std::{env, process::Command};
+
+use log::{debug, warn};
 use uuid::Uuid;
 
 use crate::{
+    api::Connection,
     config::Config,
     error::{Error, Result},
     server,
 };
 
+use super::{current_path, run_command, store_command};
+
 #[derive(clap::Args, Debug)]
 pub struct GetArgs {
     /// Get history entry by ID instead of index.
     #[arg(long)]
     id: bool,
 
+    /// Show every version of the entry (the original, and any edits or
+    /// deletions from any host), with the host and time of each, instead of
+    /// just the current command.
+    #[arg(long)]
+    show_history: bool,
+
+    /// Run the command with $SHELL -c (or sh if SHELL isn't set), storing it
+    /// as a new entry (with its exit status and how long it took) and exiting
+    /// with its exit status. The command is run exactly as it is stored, so
+    /// only use this with history that you trust.
+    #[arg(long, conflicts_with = "show_history")]
+    exec: bool,
+
     /// The history entry to get (index, or ID if --id given).
     entry: String,
 }
 
 pub fn get(cfg: &Config, args: GetArgs) -> Result<()> {
     let mut conn = server::ensure_ready(cfg)?;
+
+    if args.show_history && args.id {
+        // the entry might have been deleted, so ask for its history directly
+        // rather than looking for it in the current history.
+        let id = Uuid::parse_str(&args.entry)?;
+        return show_history(&mut conn, id);
+    }
+
     let history = conn.history_request()?;
 
     let entry = if args.id {
@@ -33,7 +60,54 @@ pub fn get(cfg: &Config, args: GetArgs) -> Result<()> {
             .ok_or_else(|| Error::Generic(format!("history entry with index {idx} not found")))?
     };
 
+    if args.show_history {
+        return show_history(&mut conn, entry.id);
+    }
+
+    if args.exec {
+        return exec(cfg, entry.cmd.clone());
+    }
+
     println!("{}", entry.cmd);
 
     Ok(())
 }
+
+/// Store the command again as a new entry (so history.ignore still applies),
+/// and then run it with the user's shell.
+fn exec(cfg: &Config, cmd: String) -> Result<()> {
+    let path = match cfg.history.store_path {
+        true => current_path()?,
+        false => String::new(),
+    };
+    // failing to store the command shouldn't stop it from being run.
+    let id = store_command(cfg, cmd.clone(), path).unwrap_or_else(|e| {
+        warn!("Failed to store command: {e}");
+        None
+    });
+
+    let shell = env::var_os("SHELL")
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "sh".into());
+    debug!("run {cmd:?} with {shell:?}");
+    let mut command = Command::new(shell);
+    command.arg("-c").arg(cmd);
+    run_command(cfg, command, id)
+}
+
+fn show_history(conn: &mut Connection, id: Uuid) -> Result<()> {
+    let versions = conn.entry_history(id)?;
+    if versions.is_empty() {
+        return Err(Error::Generic(format!(
+            "history entry with ID {id} not found"
+        )));
+    }
+    for version in versions {
+        let cmd = match version.cmd.as_str() {
+            "" => "(deleted)",
+            cmd => cmd,
+        };
+        println!("{}\t{}\t{cmd}", version.ts.to_rfc3339(), version.host);
+    }
+    Ok(())
+}
@@ -1,6 +1,22 @@
-use std::{cmp, collections::HashSet};
+use std::{
+    borrow::Cow,
+    cmp,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    io::{self, BufWriter, ErrorKind, Write, stdout},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+};
 
-use log::debug;
+use chrono::{DateTime, Utc, format::StrftimeItems};
+use clap::ValueHint;
+use log::{debug, warn};
+use schemars::schema_for;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tempfile::NamedTempFile;
+use uuid::Uuid;
 
 use crate::{
     config::Config,
@@ -9,7 +25,7 @@ use crate::{
     server,
 };
 
-use super::{Filter, FilterArgs};
+use super::{DedupScope, DisplayTz, Filter, FilterArgs, Session, Template, dedup};
 
 #[derive(clap::Args, Debug)]
 pub struct HistoryArgs {
@@ -20,7 +36,8 @@ pub struct HistoryArgs {
     #[arg(long)]
     id: bool,
 
-    /// Show the history index numbers
+    /// Show the history index numbers (when combined with --session, the
+    /// per-session command numbers are shown instead)
     #[arg(short, long)]
     number: bool,
 
@@ -36,7 +53,13 @@ pub struct HistoryArgs {
     #[arg(short = 'D', long)]
     no_duplicates: bool,
 
-    /// Output the most recent command first instead of last
+    /// Which commands count as duplicates when removing them (also used by
+    /// fzf output, which never includes duplicates)
+    #[arg(long, value_enum, default_value = "global", value_name = "SCOPE")]
+    dedup_scope: DedupScope,
+
+    /// Output the most recent command first instead of last (fzf output is
+    /// most recent first by default, so this reverses it to oldest first)
     #[arg(short, long)]
     reverse: bool,
 
@@ -48,15 +71,115 @@ pub struct HistoryArgs {
     #[arg(short = 'p', long)]
     show_path: bool,
 
+    /// Show the notes attached to commands with vellum annotate, after the
+    /// command
+    #[arg(long)]
+    with_notes: bool,
+
+    /// Show the command that was run before each command in the same session,
+    /// out of the commands matching the filters (JSON output gets an extra
+    /// "prev" field)
+    #[arg(long, conflicts_with_all = ["fzf", "template", "porcelain", "group_by", "duplicates_only"])]
+    with_prev: bool,
+
+    /// Show the history as it was at an earlier point, given as a git ref of
+    /// the sync repo (e.g. HEAD~5) or an RFC 3339 timestamp. This includes
+    /// commands that have since been deleted, if they haven't been removed
+    /// by a rebuild yet. Only works with git sync.
+    #[arg(long, value_name = "REF|TIMESTAMP", value_hint = ValueHint::Other)]
+    at: Option<String>,
+
+    /// Also include commands that have been deleted, but not yet removed by a
+    /// rebuild, marked as deleted (only for JSON and verbose output, JSON
+    /// output gets an extra "deleted" field)
+    #[arg(long, conflicts_with_all = ["at", "fzf", "template", "porcelain", "group_by", "duplicates_only"])]
+    include_deleted: bool,
+
+    /// Include commands from all hosts, even if history.default_host_only is
+    /// set in the config
+    #[arg(long)]
+    all_hosts: bool,
+
+    /// Mark commands that were stored by other hosts with a '*' (ignored for
+    /// JSON, fzf and template output)
+    #[arg(long)]
+    mark_remote: bool,
+
     /// Output the history information as JSON, instead of formatted for human
     /// reading.
     #[arg(short, long)]
     json: bool,
 
+    /// Only include the given comma separated fields (e.g. id,cmd,ts) in each
+    /// entry of the JSON output, to keep it small
+    #[arg(long, value_delimiter = ',', value_name = "FIELDS", requires = "json", conflicts_with_all = ["group_by", "duplicates_only"])]
+    fields: Option<Vec<String>>,
+
     /// Format the output in the way expected by fzf
     #[arg(long)]
     fzf: bool,
 
+    /// Print the JSON schema of the --json output (including which fields are
+    /// optional) instead of the history, for tools that consume it
+    #[arg(long)]
+    describe: bool,
+
+    /// Output a stable format for scripts, with one NUL terminated record per
+    /// entry made up of tab separated fields: id, ts (seconds since the unix
+    /// epoch), host, path and cmd. Backslashes, tabs and NULs in the fields
+    /// are escaped as \\, \t and \0. Fields will never be reordered or
+    /// removed, new fields will only be added at the end. FIRST and LAST are
+    /// ignored.
+    #[arg(long, conflicts_with_all = ["json", "fzf", "template", "group_by", "duplicates_only", "verbose"])]
+    porcelain: bool,
+
+    /// Only send the N most recent commands to fzf, to keep it fast with very
+    /// large histories
+    #[arg(long, value_name = "N", requires = "fzf")]
+    limit: Option<usize>,
+
+    /// Show timestamps in the local timezone instead of UTC (overrides the
+    /// display_tz config option, JSON output is always in UTC)
+    #[arg(long)]
+    local: bool,
+
+    /// Write the output to a file instead of stdout. The file is only replaced
+    /// once all the output has been written, so it is never left incomplete.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    output: Option<PathBuf>,
+
+    /// Terminate each entry with a NUL character instead of a newline, so that
+    /// commands containing newlines can be reliably processed
+    #[arg(long)]
+    null: bool,
+
+    /// Format each entry using a custom template, e.g. '{index} {ts:%H:%M}
+    /// {host} {cmd}'. Available placeholders are index, id, ts, host, cmd,
+    /// path, session, seq, shell, note, exit and duration (in milliseconds,
+    /// exit and duration are empty if they weren't recorded). The ts
+    /// placeholder takes an optional strftime format after a colon. Use '{{'
+    /// and '}}' for literal braces.
+    #[arg(long, value_name = "TEMPLATE", value_hint = ValueHint::Other)]
+    template: Option<String>,
+
+    /// Format the output with an external command, which is run with $SHELL
+    /// -c (or sh if SHELL isn't set) and is sent the entries as JSON, one per
+    /// line (using the --describe schema) on stdin. Its output is printed.
+    /// FIRST and LAST are ignored.
+    #[arg(long, value_name = "COMMAND", value_hint = ValueHint::CommandString, conflicts_with_all = ["json", "fzf", "template", "porcelain", "group_by", "duplicates_only", "verbose", "include_deleted", "with_prev"])]
+    exec_format: Option<String>,
+
+    /// Instead of listing commands, show how many of the entries matching the
+    /// filters there are in each group (FIRST and LAST are ignored)
+    #[arg(long, value_enum, value_name = "GROUP", conflicts_with_all = ["fzf", "template"])]
+    group_by: Option<GroupBy>,
+
+    /// Instead of listing commands, show the commands that appear more than
+    /// once, along with how many times, most frequent first (FIRST and LAST
+    /// are ignored)
+    #[arg(long, conflicts_with_all = ["fzf", "template", "group_by", "no_duplicates"])]
+    duplicates_only: bool,
+
     /// The first entry in the history to show, negative values count back from
     /// the end (after filters have been applied).
     #[arg(default_value = "-10")]
@@ -69,28 +192,127 @@ pub struct HistoryArgs {
     last: isize,
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum GroupBy {
+    Host,
+    Day,
+    Path,
+    Session,
+    Category,
+}
+
+impl GroupBy {
+    fn header(&self) -> &'static str {
+        match self {
+            Self::Host => "HOST",
+            Self::Day => "DAY",
+            Self::Path => "PATH",
+            Self::Session => "SESSION",
+            Self::Category => "CATEGORY",
+        }
+    }
+
+    fn key(&self, entry: &Entry, tz: DisplayTz) -> String {
+        match self {
+            Self::Host => entry.host.clone(),
+            Self::Day => tz.format_with_items(&entry.ts, StrftimeItems::new("%Y-%m-%d")),
+            Self::Path => entry.path.clone(),
+            Self::Session => entry.session.clone(),
+            Self::Category => entry.category.clone().unwrap_or_else(|| "-".to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Duplicate<'a> {
+    cmd: &'a str,
+    count: usize,
+    last_ts: DateTime<Utc>,
+}
+
 impl HistoryArgs {
-    fn get_cmd(&self, entry: &Entry) -> String {
+    fn get_cmd(&self, entry: &Entry) -> Result<String> {
         if self.cd && !entry.path.is_empty() {
-            format!("cd \"{}\" && {}", entry.path, entry.cmd)
+            Ok(format!("{} && {}", cd_command(&entry.path)?, entry.cmd))
         } else {
-            entry.cmd.clone()
+            Ok(entry.cmd.clone())
         }
     }
 }
 
-pub fn history(cfg: &Config, args: HistoryArgs) -> Result<()> {
-    if args.fzf {
-        fzf_history(cfg, args)
+/// The command to change to the given path, which is quoted so that the
+/// shell doesn't expand anything in it.
+pub fn cd_command(path: &str) -> Result<String> {
+    let path = shlex::try_quote(path)
+        .map_err(|e| Error::Generic(format!("can't quote the path {path:?}: {e}")))?;
+    Ok(format!("cd {path}"))
+}
+
+pub fn history(cfg: &Config, mut args: HistoryArgs) -> Result<()> {
+    if cfg.history.default_host_only && !args.all_hosts {
+        args.filter
+            .default_host(cfg.hostname.to_string_lossy().to_string());
+    }
+    if let Some(at) = args.at.take() {
+        args.filter.at(at);
+    }
+    if args.include_deleted && !(args.json || args.verbose) {
+        return Err(Error::from_str(
+            "--include-deleted can only be used with --json or --verbose",
+        ));
+    }
+    if (args.cd || args.show_path) && !cfg.history.store_path {
+        warn!("history.store_path is disabled, new commands won't have a path");
+    }
+    match args.output.take() {
+        Some(path) => write_atomic(&path, |out| write_history(cfg, args, out)),
+        None => write_history(cfg, args, &mut stdout().lock()),
+    }
+}
+
+fn write_history(cfg: &Config, args: HistoryArgs, out: &mut dyn Write) -> Result<()> {
+    if args.describe {
+        let schema = schema_for!(Vec<Entry>);
+        writeln!(out, "{}", serde_json::to_string_pretty(&schema)?)?;
+        Ok(())
+    } else if let Some(group_by) = args.group_by {
+        group_history(cfg, group_by, args, out)
+    } else if args.duplicates_only {
+        duplicates_history(cfg, args, out)
+    } else if args.fzf {
+        fzf_history(cfg, args, out)
+    } else if let Some(command) = &args.exec_format {
+        exec_format_history(cfg, command, &args, out)
     } else if args.json {
-        json_history(cfg, args)
+        json_history(cfg, args, out)
+    } else if args.porcelain {
+        porcelain_history(cfg, args, out)
     } else {
-        text_history(cfg, args)
+        text_history(cfg, args, out)
     }
 }
 
-fn fzf_history(cfg: &Config, args: HistoryArgs) -> Result<()> {
-    let filter = Filter::new(&args.filter)?;
+/// Write the output to a temporary file alongside path, which is only renamed
+/// into place once all the output has been written.
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp = NamedTempFile::new_in(dir)?;
+    let mut out = BufWriter::new(tmp.as_file_mut());
+    write(&mut out)?;
+    out.flush()?;
+    drop(out);
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+fn fzf_history(cfg: &Config, args: HistoryArgs, out: &mut dyn Write) -> Result<()> {
+    let filter = Filter::new(cfg, &args.filter)?;
     let mut conn = server::ensure_ready(cfg)?;
 
     let history = filter.enumerate_history_request(&mut conn)?;
@@ -98,46 +320,377 @@ fn fzf_history(cfg: &Config, args: HistoryArgs) -> Result<()> {
 
     let index_size = (history.len() + 1).to_string().len().next_multiple_of(8);
 
-    let mut seen = HashSet::new();
-    for (index, entry) in history
-        .iter()
-        .rev()
-        .filter(|(_, entry)| seen.insert(&entry.cmd))
-    {
-        let cmd = args.get_cmd(entry);
+    // fzf always gets the most recent version of each command, most recent
+    // first unless reversed.
+    let mut history = dedup(history, args.dedup_scope, |(_, entry)| entry);
+    if let Some(limit) = args.limit {
+        history.drain(..history.len().saturating_sub(limit));
+    }
+    if !args.reverse {
+        history.reverse();
+    }
+    for (index, entry) in history.iter() {
+        let cmd = args.get_cmd(entry)?;
         if args.show_path {
-            print!("{:<index_size$} {}\t{}\x00", index + 1, entry.path, cmd);
+            write!(
+                out,
+                "{:<index_size$} {}\t{}\x00",
+                index + 1,
+                entry.path,
+                cmd
+            )?;
         } else {
-            print!("{}\t{}\x00", index + 1, cmd);
+            write!(out, "{}\t{}\x00", index + 1, cmd)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn json_history(cfg: &Config, args: HistoryArgs, out: &mut dyn Write) -> Result<()> {
+    if let Some(fields) = &args.fields {
+        check_fields(fields)?;
+    }
+
+    let filter = Filter::new(cfg, args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let mut history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    let prev = match args.with_prev {
+        true => Some(previous_commands(&history)),
+        false => None,
+    };
+    let mut deleted = HashSet::new();
+    if args.include_deleted {
+        for entry in filter.deleted_request(&mut conn)? {
+            deleted.insert(entry.id);
+            history.push(entry);
         }
+        // the sort is stable, so the history stays in the same order.
+        history.sort_by_key(|entry| entry.ts);
+    }
+    if args.reverse {
+        history.reverse();
     }
 
+    let json = match (&args.fields, args.include_deleted || args.with_prev) {
+        (None, false) => serde_json::to_string(&history)?,
+        (fields, _) => serde_json::to_string(&project_fields(
+            &history,
+            fields.as_deref(),
+            &deleted,
+            prev.as_ref(),
+        )?)?,
+    };
+    writeln!(out, "{json}")?;
+
     Ok(())
 }
 
-fn json_history(cfg: &Config, args: HistoryArgs) -> Result<()> {
-    let filter = Filter::new(args.filter)?;
+fn exec_format_history(
+    cfg: &Config,
+    command: &str,
+    args: &HistoryArgs,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let filter = Filter::new(cfg, &args.filter)?;
     let mut conn = server::ensure_ready(cfg)?;
 
     let mut history = filter.history_request(&mut conn)?;
     debug!("got filtered history with {} entries", history.len());
 
+    if args.no_duplicates {
+        history = dedup(history, args.dedup_scope, |entry| entry);
+    }
     if args.reverse {
         history.reverse();
     }
 
-    let json = serde_json::to_string(&history)?;
-    println!("{json}");
+    let shell = env::var_os("SHELL")
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "sh".into());
+    debug!("run {command:?} with {shell:?} to format the history");
+    let mut child = Command::new(shell)
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // write the entries from another thread, so that a command that writes
+    // output before it has read all of its input can't block us both.
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| Error::from_str("format command has no stdin"))?;
+    let writer = thread::spawn(move || -> Result<()> {
+        let mut stdin = BufWriter::new(&mut stdin);
+        for entry in history.iter() {
+            stdin.write_all(&serde_json::to_vec(entry)?)?;
+            stdin.write_all(b"\n")?;
+        }
+        stdin.flush()?;
+        Ok(())
+    });
+
+    if let Some(mut stdout) = child.stdout.take() {
+        io::copy(&mut stdout, out)?;
+    }
+    let status = child.wait()?;
+    match writer.join() {
+        Ok(Ok(())) => (),
+        // the command doesn't have to read all of the entries.
+        Ok(Err(Error::IO(e))) if e.kind() == ErrorKind::BrokenPipe => (),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => {
+            return Err(Error::from_str(
+                "failed to send the entries to the format command",
+            ));
+        }
+    }
+    if !status.success() {
+        return Err(Error::Generic(format!("format command failed ({status})")));
+    }
 
     Ok(())
 }
 
-fn text_history(cfg: &Config, args: HistoryArgs) -> Result<()> {
-    let filter = Filter::new(&args.filter)?;
+/// Check that all the fields requested for the JSON output exist, using the
+/// schema so that the list doesn't get out of date when fields are added.
+fn check_fields(fields: &[String]) -> Result<()> {
+    let schema = schema_for!(Entry);
+    let known = schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .ok_or_else(|| Error::from_str("history entry schema has no properties"))?;
+    for field in fields {
+        if !known.contains_key(field) {
+            let names: Vec<&str> = known.keys().map(String::as_str).collect();
+            return Err(Error::Generic(format!(
+                "unknown field {field:?}, expected one of: {}",
+                names.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Get the command that was run before each entry in the same session, keyed
+/// by entry ID. The history must be in chronological order, and entries that
+/// are the first in their session aren't included.
+fn previous_commands<'a>(history: impl IntoIterator<Item = &'a Entry>) -> HashMap<Uuid, String> {
+    let mut last: HashMap<&str, &str> = HashMap::new();
+    let mut prev = HashMap::new();
+    for entry in history {
+        if let Some(cmd) = last.insert(&entry.session, &entry.cmd) {
+            prev.insert(entry.id, cmd.to_string());
+        }
+    }
+    prev
+}
+
+/// Convert each entry to a JSON object that only contains the given fields
+/// (or all of them), with "deleted" set on the deleted entries, and "prev" set
+/// to the previous command in the session if prev is given.
+fn project_fields(
+    history: &[Entry],
+    fields: Option<&[String]>,
+    deleted: &HashSet<Uuid>,
+    prev: Option<&HashMap<Uuid, String>>,
+) -> Result<Vec<Map<String, Value>>> {
+    history
+        .iter()
+        .map(|entry| {
+            let Value::Object(mut map) = serde_json::to_value(entry)? else {
+                return Err(Error::from_str("history entry isn't a JSON object"));
+            };
+            map.retain(|key, _| fields.is_none_or(|fields| fields.contains(key)));
+            if deleted.contains(&entry.id) {
+                map.insert("deleted".to_string(), Value::Bool(true));
+            }
+            if let Some(prev) = prev {
+                let cmd = prev
+                    .get(&entry.id)
+                    .cloned()
+                    .map_or(Value::Null, Value::String);
+                map.insert("prev".to_string(), cmd);
+            }
+            Ok(map)
+        })
+        .collect()
+}
+
+fn porcelain_history(cfg: &Config, args: HistoryArgs, out: &mut dyn Write) -> Result<()> {
+    let filter = Filter::new(cfg, args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let mut history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    if args.no_duplicates {
+        history = dedup(history, args.dedup_scope, |entry| entry);
+    }
+    if args.reverse {
+        history.reverse();
+    }
+
+    for entry in history.iter() {
+        write!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\0",
+            entry.id,
+            entry.ts.timestamp(),
+            porcelain_escape(&entry.host),
+            porcelain_escape(&entry.path),
+            porcelain_escape(&entry.cmd)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Escape the characters that separate the porcelain fields and records, and
+/// the backslashes used to escape them, so that every record can be split.
+fn porcelain_escape(field: &str) -> Cow<'_, str> {
+    if !field.contains(['\\', '\t', '\0']) {
+        return Cow::Borrowed(field);
+    }
+    let mut escaped = String::with_capacity(field.len() + 2);
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn group_history(
+    cfg: &Config,
+    group_by: GroupBy,
+    args: HistoryArgs,
+    out: &mut dyn Write,
+) -> Result<()> {
+    // JSON output always groups days using UTC, like the timestamps in the
+    // normal JSON output.
+    let tz = match args.json {
+        true => DisplayTz::Utc,
+        false => DisplayTz::new(cfg, args.local)?,
+    };
+
+    let filter = Filter::new(cfg, &args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    let mut groups: BTreeMap<String, usize> = BTreeMap::new();
+    for entry in history.iter() {
+        *groups.entry(group_by.key(entry, tz)).or_default() += 1;
+    }
+
+    if args.json {
+        writeln!(out, "{}", serde_json::to_string(&groups)?)?;
+        return Ok(());
+    }
+
+    let key_size = groups
+        .keys()
+        .fold(group_by.header().len(), |max, key| cmp::max(max, key.len()));
+
+    if !args.no_headers {
+        writeln!(out, "{:key_size$}\tCOUNT", group_by.header())?;
+    }
+    let mut groups: Vec<_> = groups.into_iter().collect();
+    if args.reverse {
+        groups.reverse();
+    }
+    for (key, count) in groups {
+        writeln!(out, "{key:key_size$}\t{count}")?;
+    }
+
+    Ok(())
+}
+
+fn duplicates_history(cfg: &Config, args: HistoryArgs, out: &mut dyn Write) -> Result<()> {
+    let filter = Filter::new(cfg, &args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    let mut counts: HashMap<&str, Duplicate> = HashMap::new();
+    for entry in history.iter() {
+        let dup = counts.entry(&entry.cmd).or_insert(Duplicate {
+            cmd: &entry.cmd,
+            count: 0,
+            last_ts: entry.ts,
+        });
+        dup.count += 1;
+        dup.last_ts = cmp::max(dup.last_ts, entry.ts);
+    }
+
+    // most frequent first, with the most recently used first for commands
+    // that have the same count.
+    let mut duplicates: Vec<Duplicate> = counts.into_values().filter(|d| d.count > 1).collect();
+    duplicates.sort_by(|a, b| b.count.cmp(&a.count).then(b.last_ts.cmp(&a.last_ts)));
+    if args.reverse {
+        duplicates.reverse();
+    }
+
+    if args.json {
+        writeln!(out, "{}", serde_json::to_string(&duplicates)?)?;
+        return Ok(());
+    }
+
+    let eol = match args.null {
+        true => '\0',
+        false => '\n',
+    };
+
+    if !args.no_headers {
+        write!(out, "COUNT\tCOMMAND{eol}")?;
+    }
+    for dup in duplicates {
+        write!(out, "{:5}\t{}{eol}", dup.count, dup.cmd)?;
+    }
+
+    Ok(())
+}
+
+fn text_history(cfg: &Config, args: HistoryArgs, out: &mut dyn Write) -> Result<()> {
+    // parse the template before talking to the server, so that we fail fast
+    // on a bad template.
+    let template = args.template.as_deref().map(Template::parse).transpose()?;
+    let tz = DisplayTz::new(cfg, args.local)?;
+
+    let filter = Filter::new(cfg, &args.filter)?;
     let mut conn = server::ensure_ready(cfg)?;
 
     let history = filter.enumerate_history_request(&mut conn)?;
     debug!("got filtered history with {} entries", history.len());
+    let deleted = match args.include_deleted {
+        true => filter.deleted_request(&mut conn)?,
+        false => Vec::new(),
+    };
+
+    // when only showing the current session, number the commands using the
+    // per-session sequence numbers instead of the global index.
+    let session = match filter.current_session() {
+        true => Some(Session::get()?.id),
+        false => None,
+    };
+
+    // terminate each record with a NUL instead of a newline if requested, so
+    // that multi-line commands can be reliably separated.
+    let eol = match args.null {
+        true => '\0',
+        false => '\n',
+    };
 
     let index_size = (history.len() + 1).to_string().len();
     let host_size = history
@@ -146,28 +699,51 @@ fn text_history(cfg: &Config, args: HistoryArgs) -> Result<()> {
     let path_size = history
         .iter()
         .fold(0, |max, (_, entry)| cmp::max(max, entry.path.len()));
-
-    if args.verbose && !args.no_headers {
+    let category_size = history.iter().fold("CATEGORY".len(), |max, (_, entry)| {
+        cmp::max(max, entry.category.as_deref().map_or(1, str::len))
+    });
+    let shell_size = history.iter().fold("SHELL".len(), |max, (_, entry)| {
+        cmp::max(max, entry.shell.as_deref().map_or(1, str::len))
+    });
+
+    let prev = match args.with_prev {
+        true => previous_commands(history.iter().map(|(_, entry)| entry)),
+        false => HashMap::new(),
+    };
+    let prev_size = prev
+        .values()
+        .fold("PREVIOUS".len(), |max, cmd| cmp::max(max, cmd.len()));
+    let prev_header = match args.with_prev {
+        true => format!("{:prev_size$}\t", "PREVIOUS"),
+        false => String::new(),
+    };
+
+    let host = cfg.hostname.to_string_lossy();
+
+    if template.is_none() && args.verbose && !args.no_headers {
+        if args.mark_remote {
+            write!(out, "  ")?;
+        }
         if args.id {
-            println!(
-                "{:36}\t{:host_size$}\t{:35}\t{:path_size$}\tCOMMAND",
-                "ID", "HOST", "TIMESTAMP", "PATH"
-            );
+            write!(
+                out,
+                "{:36}\t{:host_size$}\t{:35}\t{:path_size$}\t{:category_size$}\t{:shell_size$}\t{prev_header}COMMAND{eol}",
+                "ID", "HOST", "TIMESTAMP", "PATH", "CATEGORY", "SHELL"
+            )?;
         } else {
-            println!(
-                "{:index_size$}\t{:host_size$}\t{:35}\t{:path_size$}\tCOMMAND",
-                "INDEX", "HOST", "TIMESTAMP", "PATH"
-            );
+            write!(
+                out,
+                "{:index_size$}\t{:host_size$}\t{:35}\t{:path_size$}\t{:category_size$}\t{:shell_size$}\t{prev_header}COMMAND{eol}",
+                "INDEX", "HOST", "TIMESTAMP", "PATH", "CATEGORY", "SHELL"
+            )?;
         }
     }
 
-    let mut seen = HashSet::new();
-    let mut filtered: Vec<&(usize, Entry)> = history
-        .iter()
-        .rev()
-        .filter(|(_, entry)| !args.no_duplicates || seen.insert(&entry.cmd))
-        .collect();
-    if !args.reverse {
+    let mut filtered: Vec<&(usize, Entry)> = history.iter().collect();
+    if args.no_duplicates {
+        filtered = dedup(filtered, args.dedup_scope, |(_, entry)| entry);
+    }
+    if args.reverse {
         filtered.reverse();
     }
 
@@ -182,42 +758,112 @@ fn text_history(cfg: &Config, args: HistoryArgs) -> Result<()> {
         )));
     }
 
-    for (index, entry) in filtered {
-        if index < &first || index > &last {
+    let mut rows: Vec<(Option<usize>, &Entry)> = filtered
+        .iter()
+        .filter(|(index, _)| *index >= first && *index <= last)
+        .map(|(index, entry)| (Some(*index), entry))
+        .collect();
+    if args.include_deleted {
+        // deleted entries don't have an index, so they are shown if they are
+        // between the first and last entries being shown (or before/after
+        // them, if they are the first/last entries in the history).
+        let (oldest, newest) = match args.reverse {
+            true => (filtered.last(), filtered.first()),
+            false => (filtered.first(), filtered.last()),
+        };
+        let from = match oldest {
+            Some((index, _)) if *index != first => rows.iter().map(|(_, entry)| entry.ts).min(),
+            _ => None,
+        };
+        let to = match newest {
+            Some((index, _)) if *index != last => rows.iter().map(|(_, entry)| entry.ts).max(),
+            _ => None,
+        };
+        rows.extend(
+            deleted
+                .iter()
+                .filter(|entry| {
+                    from.is_none_or(|from| entry.ts >= from) && to.is_none_or(|to| entry.ts <= to)
+                })
+                .map(|entry| (None, entry)),
+        );
+        // the sort is stable, so the history stays in the same order.
+        rows.sort_by_key(|(_, entry)| entry.ts);
+        if args.reverse {
+            rows.reverse();
+        }
+    }
+
+    for (index, entry) in rows {
+        // --include-deleted can't be used with a template, so only deleted
+        // entries don't have an index.
+        if let (Some(template), Some(index)) = (&template, index) {
+            write!(out, "{}{eol}", template.render(index + 1, entry, tz))?;
             continue;
         }
+        if args.mark_remote {
+            match entry.host == host {
+                true => write!(out, "  ")?,
+                false => write!(out, "* ")?,
+            }
+        }
+        let prev = match args.with_prev {
+            true => prev.get(&entry.id).map_or("-", String::as_str),
+            false => "",
+        };
+        let note = match (&entry.note, args.with_notes) {
+            (Some(note), true) => format!("\t# {note}"),
+            _ => String::new(),
+        };
         if args.verbose {
-            if args.id {
-                println!(
-                    "{:36}\t{:host_size$}\t{:35}\t{:path_size$}\t{}",
-                    entry.id,
-                    entry.host,
-                    entry.ts.to_rfc3339(),
-                    entry.path,
-                    entry.cmd
-                );
-            } else {
-                println!(
-                    "{:index_size$}\t{:host_size$}\t{:35}\t{:path_size$}\t{}",
-                    index + 1,
-                    entry.host,
-                    entry.ts.to_rfc3339(),
-                    entry.path,
-                    entry.cmd
-                );
+            let category = entry.category.as_deref().unwrap_or("-");
+            let shell = entry.shell.as_deref().unwrap_or("-");
+            let cmd = match index {
+                Some(_) => entry.cmd.clone(),
+                None => format!("[deleted] {}", entry.cmd),
+            };
+            match (args.id, index) {
+                (true, _) => write!(out, "{:36}\t", entry.id)?,
+                (false, Some(index)) => write!(out, "{:index_size$}\t", index + 1)?,
+                (false, None) => write!(out, "{:>index_size$}\t", "-")?,
             }
+            let prev = match args.with_prev {
+                true => format!("{prev:prev_size$}\t"),
+                false => String::new(),
+            };
+            write!(
+                out,
+                "{:host_size$}\t{:35}\t{:path_size$}\t{category:category_size$}\t{shell:shell_size$}\t{prev}{cmd}{note}{eol}",
+                entry.host,
+                tz.rfc3339(&entry.ts),
+                entry.path,
+            )?;
             continue;
         }
+        // only verbose output includes deleted entries, and everything else
+        // has an index.
+        let Some(index) = index else {
+            continue;
+        };
         if args.number {
-            print!("{:index_size$}\t", index + 1);
+            match &session {
+                Some(session) => match entry.seq {
+                    Some(seq) if &entry.session == session => write!(out, "{seq:index_size$}\t")?,
+                    _ => write!(out, "{:index_size$}\t", "")?,
+                },
+                None => write!(out, "{:index_size$}\t", index + 1)?,
+            }
         }
         if args.id {
-            print!("{:36}\t", entry.id);
+            write!(out, "{:36}\t", entry.id)?;
         }
         if args.show_path {
-            print!("{:path_size$}\t", entry.path);
+            write!(out, "{:path_size$}\t", entry.path)?;
         }
-        println!("{}", args.get_cmd(entry));
+        if args.with_prev {
+            write!(out, "{prev}\t")?;
+        }
+        write!(out, "{}{note}{eol}", args.get_cmd(entry)?)?;
     }
 
     Ok(())
@@ -259,3 +905,24 @@ fn index_of_last_n(n: usize, history: &[&(usize, Entry)]) -> Result<usize> {
     debug!("got index: {idx}");
     Ok(idx)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn porcelain_escapes_separators() {
+        assert_eq!(porcelain_escape("ls -la"), "ls -la");
+        assert_eq!(porcelain_escape("printf 'a\tb'"), "printf 'a\\tb'");
+        assert_eq!(porcelain_escape("a\0b"), "a\\0b");
+        assert_eq!(porcelain_escape("echo \\t"), "echo \\\\t");
+    }
+
+    #[test]
+    fn cd_command_quotes_path() {
+        assert_eq!(cd_command("/tmp").unwrap(), "cd /tmp");
+        assert_eq!(cd_command("/tmp/a b").unwrap(), "cd '/tmp/a b'");
+        assert_eq!(cd_command("/tmp/$HOME").unwrap(), "cd '/tmp/$HOME'");
+        assert_eq!(cd_command("/tmp/\"x").unwrap(), "cd '/tmp/\"x'");
+    }
+}
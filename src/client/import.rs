@@ -1,13 +1,28 @@
 use std::{
-    fs::File,
-    io::{BufRead, BufReader, stdin},
+    collections::HashSet,
+    fmt::Write,
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind, stdin},
+    path::PathBuf,
+    process::Command,
 };
 
+use aws_lc_rs::digest::{SHA256, digest};
+use chrono::DateTime;
 use clap::ValueHint;
+use log::{debug, info, warn};
+use serde::Deserialize;
+use uuid::Uuid;
+use which::which;
 
-use crate::{config::Config, error::Result, server};
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    history::{Entry, parse_key, read_foreign_history},
+    server,
+};
 
-use super::Session;
+use super::{Session, lossy_string, read_secret, rebuild};
 
 #[derive(clap::Args, Debug)]
 pub struct ImportArgs {
@@ -15,13 +30,126 @@ pub struct ImportArgs {
     #[arg(short, long, value_hint = ValueHint::FilePath)]
     file: Option<String>,
 
-    /// Import into the current session, rather than marking as imported
+    /// The format of the file being imported
+    #[arg(long, value_enum, default_value = "lines")]
+    format: ImportFormat,
+
+    /// Import from a copy of another vellum host's history directory (e.g.
+    /// hosts.v1/<host> from their sync repository), instead of plain
+    /// commands. The encryption key used by the other host is read from stdin
+    /// (or prompted for)
+    #[arg(long, value_hint = ValueHint::DirPath, conflicts_with = "file")]
+    vellum_dir: Option<String>,
+
+    /// Only import commands that start with the given prefix (only used with
+    /// --vellum-dir)
+    #[arg(long, requires = "vellum_dir")]
+    prefix: Option<String>,
+
+    /// Rebuild the history after importing (which rewrites the sync repo for
+    /// all hosts), so that the imported commands are written out with the
+    /// rest of the history from the same time, rather than as if they were
+    /// just run (only used with --vellum-dir or --format atuin)
+    #[arg(long)]
+    merge_timestamps: bool,
+
+    /// Don't ask for confirmation before rebuilding
+    #[arg(short, long, requires = "merge_timestamps")]
+    yes: bool,
+
+    /// Import into the current session, rather than marking as imported (or
+    /// keeping Atuin's sessions with --format atuin)
     #[arg(long)]
     current_session: bool,
+
+    /// Skip commands that were already in the history before the import, so
+    /// that importing the same file again doesn't duplicate anything (repeats
+    /// within the file are still imported)
+    #[arg(long, conflicts_with = "vellum_dir")]
+    dedup: bool,
+
+    /// Only skip commands that are already in the history of the session being
+    /// imported into
+    #[arg(long, requires = "dedup")]
+    dedup_session: bool,
+
+    /// Record how many lines of the file have been imported, so that if the
+    /// import is interrupted running it again skips the lines that were
+    /// already imported
+    #[arg(long, requires = "file")]
+    resume: bool,
+
+    /// Skip lines that are longer than this many bytes (with a warning), so
+    /// that importing a binary file doesn't use up all the memory
+    #[arg(long, default_value_t = 1024 * 1024, conflicts_with = "vellum_dir")]
+    max_line_bytes: usize,
+
+    /// Stop after importing this many commands
+    #[arg(long)]
+    max_entries: Option<usize>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ImportFormat {
+    /// One command per line
+    Lines,
+    /// Atuin's SQLite history database (usually
+    /// ~/.local/share/atuin/history.db), which needs the sqlite3 command
+    Atuin,
+}
+
+/// Keeps track of how many lines of a file have been imported, in a file in
+/// the cache directory named after the path and size of the file being
+/// imported (so a different file at the same path starts from the
+/// beginning).
+struct Progress {
+    path: PathBuf,
+}
+
+impl Progress {
+    /// Returns the number of lines that have already been imported.
+    fn load(cfg: &Config, file: &str) -> Result<(Self, usize)> {
+        let size = fs::metadata(file)?.len();
+        let key = format!("{}\0{size}", fs::canonicalize(file)?.to_string_lossy());
+        let mut name = "import-".to_string();
+        for b in digest(&SHA256, key.as_bytes()).as_ref().iter().take(8) {
+            // writing to a String can't fail
+            let _ = write!(name, "{b:02x}");
+        }
+        name.push_str(".progress");
+        fs::create_dir_all(&cfg.cache_dir)?;
+        let path = cfg.cache_dir.join(name);
+        let lines = match fs::read_to_string(&path) {
+            Ok(data) => data.trim().parse().map_err(|e| {
+                Error::Generic(format!("invalid import progress file {path:?}: {e}"))
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        debug!("import progress {path:?}: {lines} lines");
+        Ok((Self { path }, lines))
+    }
+
+    fn save(&self, lines: usize) -> Result<()> {
+        fs::write(&self.path, format!("{lines}\n"))?;
+        Ok(())
+    }
 }
 
 pub fn import(cfg: &Config, args: ImportArgs) -> Result<()> {
-    let reader: Box<dyn BufRead> = match args.file {
+    if let Some(dir) = &args.vellum_dir {
+        return import_vellum_dir(cfg, dir, &args);
+    }
+    if args.format == ImportFormat::Atuin {
+        return import_atuin(cfg, &args);
+    }
+    if args.merge_timestamps {
+        return Err(Error::from_str(
+            "--merge-timestamps can only be used with --vellum-dir or --format atuin",
+        ));
+    }
+
+    let reader: Box<dyn BufRead> = match &args.file {
         Some(path) => {
             let f = File::open(path)?;
             Box::new(BufReader::new(f))
@@ -31,15 +159,317 @@ pub fn import(cfg: &Config, args: ImportArgs) -> Result<()> {
 
     let mut conn = server::ensure_ready(cfg)?;
 
-    let session = if args.current_session {
-        Session::get()?.id
-    } else {
-        "IMPORTED".to_string()
+    let session = import_session(&args)?;
+
+    // fetch the existing commands once up front, rather than checking each
+    // line with the server. Repeats within the file are still imported, since
+    // they are part of the history being imported.
+    let existing: HashSet<String> = match args.dedup {
+        true => conn
+            .history_request()?
+            .into_iter()
+            .filter(|entry| !args.dedup_session || entry.session == session)
+            .map(|entry| entry.cmd)
+            .collect(),
+        false => HashSet::new(),
+    };
+    let mut skipped = 0;
+    let mut too_long = 0;
+    let mut imported = 0;
+    let max_entries = args.max_entries.unwrap_or(usize::MAX);
+
+    let (progress, resume_from) = match (args.resume, &args.file) {
+        (true, Some(file)) => {
+            let (progress, lines) = Progress::load(cfg, file)?;
+            (Some(progress), lines)
+        }
+        _ => (None, 0),
+    };
+    if resume_from > 0 {
+        info!("skipping the {resume_from} lines that were already imported");
+    }
+
+    let mut lines = LimitedLines::new(reader, args.max_line_bytes);
+    let mut idx = 0;
+    while imported < max_entries
+        && let Some(line) = lines.next_line()?
+    {
+        idx += 1;
+        if idx <= resume_from {
+            continue;
+        }
+        let line = match line {
+            Line::Complete(line) => lossy_string(&line, "line"),
+            Line::TooLong(len) => {
+                warn!(
+                    "skipping line {idx}, which is {len} bytes long (more than --max-line-bytes)"
+                );
+                too_long += 1;
+                if let Some(progress) = &progress {
+                    progress.save(idx)?;
+                }
+                continue;
+            }
+        };
+        if existing.contains(&line) {
+            skipped += 1;
+        } else {
+            imported += 1;
+            conn.store(
+                line,
+                "".to_string(),
+                session.clone(),
+                None,
+                None,
+                None,
+                None,
+            )?;
+        }
+        if let Some(progress) = &progress {
+            progress.save(idx)?;
+        }
+    }
+
+    if resume_from > 0 {
+        println!("Skipped {resume_from} lines that were already imported.");
+    }
+    if args.dedup {
+        println!("Skipped {skipped} commands that were already in the history.");
+    }
+    if too_long > 0 {
+        println!("Skipped {too_long} lines that were too long.");
+    }
+    if imported == max_entries {
+        println!("Stopped after importing {imported} commands.");
+    }
+    Ok(())
+}
+
+enum Line {
+    Complete(Vec<u8>),
+    /// A line that was longer than the limit, with its full length.
+    TooLong(usize),
+}
+
+/// Split the input into lines like lossy_lines, but without ever holding more
+/// than max bytes of a line in memory, so that a huge "line" (e.g. from a
+/// binary file with no newlines) is skipped rather than read in.
+struct LimitedLines<R> {
+    reader: R,
+    max: usize,
+}
+
+impl<R: BufRead> LimitedLines<R> {
+    fn new(reader: R, max: usize) -> Self {
+        Self { reader, max }
+    }
+
+    fn next_line(&mut self) -> Result<Option<Line>> {
+        let mut line = Vec::new();
+        let mut len = 0;
+        let mut found = false;
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            found = true;
+            let (end, newline) = match buf.iter().position(|b| *b == b'\n') {
+                Some(pos) => (pos, true),
+                None => (buf.len(), false),
+            };
+            if len + end <= self.max {
+                line.extend_from_slice(&buf[..end]);
+            } else {
+                line.clear();
+            }
+            len += end;
+            self.reader.consume(end + usize::from(newline));
+            if newline {
+                break;
+            }
+        }
+        if !found {
+            return Ok(None);
+        }
+        if len > self.max {
+            return Ok(Some(Line::TooLong(len)));
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(Line::Complete(line)))
+    }
+}
+
+fn import_vellum_dir(cfg: &Config, dir: &str, args: &ImportArgs) -> Result<()> {
+    let key = parse_key(&read_secret("Key for the other host")?)?;
+    let history = read_foreign_history(cfg, dir, &key)?;
+    debug!("read {} entries from {dir:?}", history.len());
+
+    // the entries become ours, we keep the original IDs and timestamps so that
+    // importing the same directory again doesn't duplicate anything.
+    let host = cfg.hostname.to_string_lossy().to_string();
+    let session = import_session(args)?;
+    let entries: Vec<Entry> = history
+        .into_iter()
+        .filter(|entry| {
+            args.prefix
+                .as_ref()
+                .is_none_or(|prefix| entry.cmd.starts_with(prefix))
+        })
+        .take(args.max_entries.unwrap_or(usize::MAX))
+        .map(|entry| Entry {
+            host: host.clone(),
+            session: session.clone(),
+            seq: None,
+            env_hash: None,
+            uid: None,
+            ..entry
+        })
+        .collect();
+
+    let mut conn = server::ensure_ready(cfg)?;
+    let count = conn.load(entries, false)?;
+
+    println!("Imported {count} new/updated entries.");
+
+    // the imported entries were added to the active chunk, they only end up
+    // in the chunks for their own timestamps when the history is rebuilt.
+    if args.merge_timestamps && count > 0 {
+        drop(conn);
+        rebuild(cfg, args.yes)?;
+    }
+
+    Ok(())
+}
+
+/// A row of Atuin's history table, as output by `sqlite3 -json`.
+#[derive(Deserialize, Debug)]
+struct AtuinRow {
+    id: String,
+    /// Nanoseconds since the epoch.
+    timestamp: i64,
+    /// Nanoseconds, or -1 if the command didn't finish.
+    duration: i64,
+    exit: i64,
+    command: String,
+    cwd: String,
+    session: String,
+    /// "<host>:<user>"
+    hostname: String,
+}
+
+const ATUIN_QUERY: &str = "SELECT id, timestamp, duration, exit, command, cwd, session, hostname \
+    FROM history WHERE deleted_at IS NULL ORDER BY timestamp";
+
+/// Read the history from an Atuin database with the sqlite3 command.
+fn read_atuin(file: &str) -> Result<Vec<AtuinRow>> {
+    if which("sqlite3").is_err() {
+        return Err(Error::from_str(
+            "importing from Atuin needs sqlite3, which wasn't found in PATH",
+        ));
+    }
+    // sqlite3 would create an empty database if the file doesn't exist.
+    fs::metadata(file)?;
+    let output = Command::new("sqlite3")
+        .args(["-readonly", "-json", file, ATUIN_QUERY])
+        .output()
+        .map_err(|e| Error::Generic(format!("failed to run sqlite3: {e}")))?;
+    if !output.status.success() {
+        return Err(Error::Generic(format!(
+            "failed to read the Atuin history from {file:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    // there's no output at all (rather than an empty array) if there are no
+    // rows.
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_slice(&output.stdout)?)
+}
+
+fn import_atuin(cfg: &Config, args: &ImportArgs) -> Result<()> {
+    let Some(file) = &args.file else {
+        return Err(Error::from_str(
+            "--format atuin needs the database to be given with --file",
+        ));
     };
+    let rows = read_atuin(file)?;
+    debug!("read {} entries from {file:?}", rows.len());
 
-    for line in reader.lines() {
-        let line = line?;
-        conn.store(line, "".to_string(), session.clone())?;
+    // Atuin's database has the history of every host that it syncs with, but
+    // the server only stores commands from this host, so the others are
+    // skipped (they can be imported by running this on each host).
+    let host = cfg.hostname.to_string_lossy().to_string();
+    let current_session = match args.current_session {
+        true => Some(Session::get()?.id),
+        false => None,
+    };
+    let mut other_hosts = 0;
+    let mut entries = Vec::new();
+    for row in rows {
+        if entries.len() >= args.max_entries.unwrap_or(usize::MAX) {
+            break;
+        }
+        let row_host = row
+            .hostname
+            .split_once(':')
+            .map_or(&*row.hostname, |(h, _)| h);
+        if row_host != host {
+            other_hosts += 1;
+            continue;
+        }
+        // we keep Atuin's IDs (which are UUIDs) so that importing the same
+        // database again doesn't duplicate anything.
+        let Ok(id) = Uuid::parse_str(&row.id) else {
+            warn!("skipping Atuin entry with an invalid ID {:?}", row.id);
+            continue;
+        };
+        // Atuin records the command before it is run, with an exit status
+        // and duration of -1 until it finishes.
+        let finished = row.duration >= 0;
+        entries.push(Entry {
+            id,
+            ts: DateTime::from_timestamp_nanos(row.timestamp),
+            host: host.clone(),
+            cmd: row.command,
+            path: match cfg.history.store_path {
+                true => row.cwd,
+                false => "".to_string(),
+            },
+            session: current_session.clone().unwrap_or(row.session),
+            seq: None,
+            env_hash: None,
+            uid: None,
+            category: None,
+            shell: None,
+            note: None,
+            exit: finished.then(|| i32::try_from(row.exit).ok()).flatten(),
+            duration_ms: finished.then_some(row.duration as u64 / 1_000_000),
+            path_cleared: false,
+        });
+    }
+    let mut conn = server::ensure_ready(cfg)?;
+    let count = conn.load(entries, false)?;
+
+    println!("Imported {count} new/updated entries.");
+    if other_hosts > 0 {
+        println!("Skipped {other_hosts} commands that were run on other hosts.");
     }
+
+    if args.merge_timestamps && count > 0 {
+        drop(conn);
+        rebuild(cfg, args.yes)?;
+    }
+
     Ok(())
 }
+
+fn import_session(args: &ImportArgs) -> Result<String> {
+    Ok(match args.current_session {
+        true => Session::get()?.id,
+        false => "IMPORTED".to_string(),
+    })
+}
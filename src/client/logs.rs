@@ -0,0 +1,86 @@
+use std::{
+    collections::VecDeque,
+    env,
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write, stdout},
+    path::PathBuf,
+    thread::sleep,
+    time::Duration,
+};
+
+use clap::ValueHint;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+};
+
+/// How often to check the log file for new lines with --follow.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(clap::Args, Debug)]
+pub struct LogsArgs {
+    /// Keep printing new lines as they are added to the log
+    #[arg(short, long)]
+    follow: bool,
+
+    /// How many lines from the end of the log to show (0 shows the whole log)
+    #[arg(short = 'n', long, default_value_t = 10)]
+    lines: usize,
+
+    /// Read this log file, instead of the server's default log (defaults to
+    /// $VELLUM_LOG_FILE if set)
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    file: Option<PathBuf>,
+}
+
+/// Show the end of the server log, optionally following it as new lines are
+/// written.
+pub fn logs(cfg: &Config, args: LogsArgs) -> Result<()> {
+    let path = args
+        .file
+        .or_else(|| env::var_os("VELLUM_LOG_FILE").map(PathBuf::from))
+        .unwrap_or_else(|| cfg.log_path());
+    let mut file = File::open(&path)
+        .map_err(|e| Error::Generic(format!("failed to open log file {path:?}: {e}")))?;
+
+    let mut out = stdout().lock();
+    let mut pos = 0;
+    let mut last = VecDeque::new();
+    let mut reader = BufReader::new(&mut file);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        pos += line.len() as u64;
+        if args.lines > 0 && last.len() == args.lines {
+            last.pop_front();
+        }
+        last.push_back(String::from_utf8_lossy(&line).into_owned());
+        line.clear();
+    }
+    for line in last {
+        out.write_all(line.as_bytes())?;
+    }
+    out.flush()?;
+
+    if !args.follow {
+        return Ok(());
+    }
+
+    let mut buf = Vec::new();
+    loop {
+        sleep(FOLLOW_INTERVAL);
+        let len = file.metadata()?.len();
+        if len < pos {
+            // the log has been truncated, start again from the beginning.
+            pos = 0;
+        }
+        if len == pos {
+            continue;
+        }
+        file.seek(SeekFrom::Start(pos))?;
+        buf.clear();
+        pos += (&mut file).take(len - pos).read_to_end(&mut buf)? as u64;
+        out.write_all(String::from_utf8_lossy(&buf).as_bytes())?;
+        out.flush()?;
+    }
+}
@@ -1,73 +1,368 @@
-use std::{env::current_dir, time::Duration};
+use std::{
+    env::current_dir,
+    ffi::OsString,
+    io::{self, BufRead, IsTerminal, Write, stderr, stdin},
+    os::unix::ffi::OsStrExt,
+    time::{Duration, Instant},
+};
 
 use clap::crate_version;
-use log::{debug, info};
+use log::{debug, info, warn};
+use rustix::termios::{self, LocalModes, OptionalActions};
 use serde::Serialize;
+use sysinfo::Signal;
 use uuid::Uuid;
 
 use crate::{
-    api::{self, Connection},
+    api::{self, Connection, RebuildProgress, Rebuilder, SyncOptions},
     config::Config,
     error::{Error, Result},
-    process::{server_is_running, wait_for_server_exit},
+    history::{RebuildSummary, read_history_file},
+    process::{server_is_running, signal_server, wait_for_server_exit},
     server,
 };
 
+/// How long to wait for the server to exit after each signal when killing it.
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+mod anonymize;
+#[cfg(feature = "bench")]
+mod bench;
+mod browse;
+mod du;
 mod edit;
+mod env;
+mod events;
+mod export;
 mod filter;
+mod gc;
 mod get;
 mod history;
 mod import;
+mod logs;
 mod r#move;
+mod paths;
+mod prompt;
+mod replay_session;
+mod rewrite_path;
+mod run;
 mod save;
 mod session;
+mod sessions;
+mod stats;
+mod status;
+mod template;
+mod tz;
+mod verify;
+mod whoami;
 
+#[cfg(feature = "bench")]
+pub use bench::*;
+pub use browse::*;
+pub use du::*;
 pub use edit::*;
+pub use events::*;
+pub use export::*;
+pub use gc::*;
 pub use get::*;
 pub use history::*;
 pub use import::*;
+pub use logs::*;
 pub use r#move::*;
+pub use paths::*;
+pub use prompt::*;
+pub use replay_session::*;
+pub use rewrite_path::*;
+pub use run::*;
 pub use save::*;
+pub use sessions::*;
+pub use stats::*;
+pub use status::*;
+pub use verify::*;
+pub use whoami::*;
 
+use anonymize::*;
+use env::*;
 use filter::*;
 use session::*;
+use template::*;
+use tz::*;
 
-pub fn store(cfg: &Config, cmd: String) -> Result<()> {
+pub fn store(
+    cfg: &Config,
+    cmd: Option<OsString>,
+    path: Option<OsString>,
+    print_id: bool,
+    from_stdin: bool,
+) -> Result<()> {
+    let path = match path {
+        _ if !cfg.history.store_path => String::new(),
+        Some(path) => lossy_string(path.as_bytes(), "path"),
+        None => current_path()?,
+    };
+    if from_stdin {
+        return store_stdin(cfg, path, print_id);
+    }
+    let cmd = lossy_string(cmd.unwrap_or_default().as_bytes(), "command");
     if cmd.is_empty() {
         return Ok(());
     }
-    let path = current_dir()?
-        .to_str()
-        .ok_or_else(|| Error::from_str("failed to convert current directory to string"))?
-        .to_owned();
+    let id = store_command(cfg, cmd, path)?;
+    if print_id {
+        print_stored_id(id)?;
+    }
+    Ok(())
+}
+
+/// Store each line read from stdin as a separate command, in the current
+/// session and timestamped as they are stored, as if they had been typed.
+fn store_stdin(cfg: &Config, path: String, print_id: bool) -> Result<()> {
     let mut conn = server::ensure_ready(cfg)?;
-    conn.store(cmd, path, Session::get()?.id)
+    let mut count = 0;
+    for line in lossy_lines(stdin().lock()) {
+        let cmd = line?;
+        if cmd.trim().is_empty() {
+            continue;
+        }
+        let id = store_command_with(cfg, &mut conn, cmd, path.clone())?;
+        if print_id {
+            print_stored_id(id)?;
+        }
+        count += 1;
+    }
+    debug!("stored {count} commands from stdin");
+    Ok(())
+}
+
+/// Convert a command or path that isn't valid UTF-8 (e.g. a binary paste, or
+/// an odd filename) into a String with the invalid bytes replaced, so that it
+/// is still stored rather than the store failing.
+fn lossy_string(bytes: &[u8], what: &str) -> String {
+    match str::from_utf8(bytes) {
+        Ok(s) => s.to_string(),
+        Err(_) => {
+            warn!("{what} is not valid UTF-8, the invalid bytes will be replaced");
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+    }
+}
+
+/// The current directory as a String, see lossy_string.
+fn current_path() -> Result<String> {
+    Ok(lossy_string(current_dir()?.as_os_str().as_bytes(), "path"))
+}
+
+/// Split the input into lines (without the line endings) like
+/// BufRead::lines, but with any lines that aren't valid UTF-8 converted by
+/// lossy_string instead of returning an error.
+fn lossy_lines<R: BufRead>(r: R) -> impl Iterator<Item = Result<String>> {
+    r.split(b'\n').map(|line| {
+        let mut line = line?;
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(lossy_string(&line, "line"))
+    })
+}
+
+/// Read a secret (e.g. a key) from stdin, rather than taking it on the
+/// command line where other users can see it and it ends up in the shell
+/// history. If stdin is a terminal the user is prompted for it, and what they
+/// type isn't echoed.
+pub fn read_secret(prompt: &str) -> Result<String> {
+    let mut buf = String::new();
+    let input = stdin();
+    if !input.is_terminal() {
+        input.read_line(&mut buf)?;
+        return Ok(buf.trim().to_string());
+    }
+    eprint!("{prompt}: ");
+    stderr().flush()?;
+    let echo = termios::tcgetattr(&input).map_err(io::Error::from)?;
+    let mut quiet = echo.clone();
+    quiet.local_modes.remove(LocalModes::ECHO);
+    termios::tcsetattr(&input, OptionalActions::Now, &quiet).map_err(io::Error::from)?;
+    let read = input.read_line(&mut buf);
+    termios::tcsetattr(&input, OptionalActions::Now, &echo).map_err(io::Error::from)?;
+    eprintln!();
+    read?;
+    Ok(buf.trim().to_string())
 }
 
-pub fn stop_server(cfg: &Config, no_sync: bool) -> Result<()> {
+fn print_stored_id(id: Option<Uuid>) -> Result<()> {
+    let id = id.ok_or_else(|| {
+        Error::from_str(
+            "server didn't return an ID, either the command matched history.ignore, or the server needs restarting to use --print-id",
+        )
+    })?;
+    println!("{id}");
+    Ok(())
+}
+
+/// Send a command to the server to be stored. If the connection is lost before
+/// the server responds, then the store is retried once on a new connection.
+/// The ID is chosen here, so that the server can ignore the retry if the first
+/// attempt did get stored.
+fn store_command(cfg: &Config, cmd: String, path: String) -> Result<Option<Uuid>> {
+    store_command_with(cfg, &mut server::ensure_ready(cfg)?, cmd, path)
+}
+
+/// Same as store_command, but using an existing connection, which is replaced
+/// if the store has to be retried.
+fn store_command_with(
+    cfg: &Config,
+    conn: &mut Connection,
+    cmd: String,
+    path: String,
+) -> Result<Option<Uuid>> {
+    let id = Uuid::now_v7();
+    let session = Session::get()?.id;
+    let store = |conn: &mut Connection| {
+        conn.store(
+            cmd.clone(),
+            path.clone(),
+            session.clone(),
+            env_hash(cfg),
+            Some(uid()),
+            shell(),
+            Some(id),
+        )
+    };
+    match store(conn) {
+        Err(Error::IO(e)) => {
+            warn!("lost connection to server whilst storing command, retrying: {e}");
+            *conn = server::ensure_ready(cfg)?;
+            store(conn)
+        }
+        result => result,
+    }
+}
+
+pub fn dump_chunk(cfg: &Config, file: String) -> Result<()> {
+    let entries = read_history_file(cfg, file)?;
+    println!("{}", serde_json::to_string_pretty(&entries)?);
+    Ok(())
+}
+
+/// Ask the server to exit, and wait for it to do so. A zero timeout waits
+/// forever, otherwise if force is set the server is killed if it hasn't exited
+/// in time.
+pub fn stop_server(cfg: &Config, no_sync: bool, timeout: Duration, force: bool) -> Result<()> {
     if !server_is_running(cfg)? {
         debug!("server isn't running");
         return Ok(());
     }
     debug!("server is running");
-    let mut conn = Connection::new(cfg)?;
-    conn.exit(no_sync)?;
-    debug!("wait for server exit");
-    wait_for_server_exit(cfg)
+    let timeout = Some(timeout).filter(|t| !t.is_zero());
+    let request_exit = || -> Result<()> {
+        let mut conn = Connection::new(cfg)?;
+        if let Some(timeout) = timeout {
+            conn.set_timeout(timeout)?;
+        }
+        conn.exit(no_sync)
+    };
+    let exited = match request_exit() {
+        Ok(()) => {
+            debug!("wait for server exit");
+            wait_for_server_exit(cfg, timeout)?
+        }
+        Err(e) if force => {
+            warn!("Failed to ask the server to exit: {e}");
+            false
+        }
+        Err(e) => return Err(e),
+    };
+    if exited {
+        return Ok(());
+    }
+    if !force {
+        return Err(Error::from_str(
+            "server didn't exit in time, use --force to kill it",
+        ));
+    }
+    kill_server(cfg)
+}
+
+fn kill_server(cfg: &Config) -> Result<()> {
+    for signal in [Signal::Term, Signal::Kill] {
+        warn!("Sending {signal} to the server");
+        if !signal_server(cfg, signal)? || wait_for_server_exit(cfg, Some(KILL_TIMEOUT))? {
+            break;
+        }
+    }
+    if server_is_running(cfg)? {
+        return Err(Error::from_str("failed to kill the server"));
+    }
+    if cfg.socket_path().try_exists()? {
+        api::Listener::remove_socket(cfg)?;
+    }
+    Ok(())
+}
+
+pub fn sync(cfg: &Config, opts: SyncOptions) -> Result<()> {
+    let mut conn = server::ensure_ready(cfg)?;
+    conn.sync(opts)
+}
+
+pub fn sync_reclone(cfg: &Config) -> Result<()> {
+    let mut conn = server::ensure_ready(cfg)?;
+    conn.reclone()
 }
 
-pub fn sync(cfg: &Config, force: bool) -> Result<()> {
+pub fn sync_preview(cfg: &Config) -> Result<()> {
     let mut conn = server::ensure_ready(cfg)?;
-    conn.sync(force)
+    let preview = conn.preview_request()?;
+
+    if preview.incoming.is_empty() {
+        println!("No incoming changes");
+    }
+    for (host, changes) in preview.incoming.iter() {
+        println!(
+            "{host}: {} new, {} updated, {} deleted",
+            changes.new, changes.updated, changes.deleted
+        );
+    }
+    println!("Local changes to push: {}", preview.pending);
+
+    Ok(())
 }
 
-pub fn ping(cfg: &Config, wait: bool) -> Result<()> {
+pub fn ping(cfg: &Config, wait: bool, count: Option<u32>) -> Result<()> {
     let wait = match wait {
         true => Some(Duration::from_secs(30)),
         false => None,
     };
-    api::ping(cfg, wait)?;
+    let mut conn = api::ping(cfg, wait)?;
     info!("got pong from server");
+
+    let count = match count {
+        Some(count) if count > 0 => count,
+        _ => return Ok(()),
+    };
+
+    let mut times = Vec::new();
+    for seq in 1..=count {
+        let start = Instant::now();
+        conn.ping()?;
+        let elapsed = start.elapsed();
+        println!("pong {seq}: time={elapsed:?}");
+        times.push(elapsed);
+    }
+
+    // times can't be empty, since count is at least one.
+    let min = times.iter().min().unwrap();
+    let max = times.iter().max().unwrap();
+    let avg = times.iter().sum::<Duration>() / count;
+    println!("{count} pings: min={min:?} avg={avg:?} max={max:?}");
+
+    let start = Instant::now();
+    let history = conn.history_request()?;
+    println!(
+        "history request: time={:?} ({} entries)",
+        start.elapsed(),
+        history.len()
+    );
+
     Ok(())
 }
 
@@ -82,15 +377,61 @@ pub fn delete(cfg: &Config, ids: Vec<String>) -> Result<()> {
     Ok(())
 }
 
-pub fn rebuild(cfg: &Config) -> Result<()> {
+/// Display the configuration of the running server, without starting one if
+/// it isn't running (since it would just be using the config file).
+pub fn server_config(cfg: &Config, json: bool) -> Result<()> {
+    let mut conn = api::ping(cfg, None)
+        .map_err(|e| Error::Generic(format!("failed to connect to the server: {e}")))?;
+    conn.config_request()?.show(json)
+}
+
+pub fn annotate(cfg: &Config, id: String, note: String) -> Result<()> {
     let mut conn = server::ensure_ready(cfg)?;
-    for status in conn.rebuild()? {
-        let status = status?;
-        info!("{status}");
+    let session = Session::get()?;
+    let id = Uuid::parse_str(&id)?;
+    debug!("annotate id: {id}");
+    conn.annotate(id, note, session.id)
+}
+
+pub fn rebuild(cfg: &Config, yes: bool) -> Result<()> {
+    if !yes {
+        if !stdin().is_terminal() {
+            return Err(Error::from_str(
+                "rebuild rewrites the sync repo for all hosts, use --yes to confirm",
+            ));
+        }
+        if !confirm("Rebuild rewrites the sync repo for all hosts, continue?")? {
+            info!("rebuild aborted");
+            return Ok(());
+        }
     }
+    let mut conn = server::ensure_ready(cfg)?;
+    show_rebuild(conn.rebuild(true)?)?;
     Ok(())
 }
 
+/// Log the progress of a rebuild, and print the summary once it is complete.
+fn show_rebuild(rebuilder: Rebuilder) -> Result<Option<RebuildSummary>> {
+    for progress in rebuilder {
+        match progress? {
+            RebuildProgress::Status(status) => info!("{status}"),
+            RebuildProgress::Complete(summary) => {
+                println!("Records before rebuild: {}", summary.records_before);
+                println!("Entries after rebuild:  {}", summary.entries_after);
+                println!("Deleted entries purged: {}", summary.deleted);
+                for (host, chunks) in summary.chunks.iter() {
+                    println!("Chunks written for {host}: {chunks}");
+                }
+                if let Some(commit) = &summary.commit {
+                    println!("Pushed commit: {commit}");
+                }
+                return Ok(Some(summary));
+            }
+        }
+    }
+    Ok(None)
+}
+
 #[derive(Debug, Serialize)]
 struct Version {
     client: String,
@@ -112,3 +453,28 @@ pub fn version(cfg: &Config, json: bool) -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossy_string_valid() {
+        assert_eq!(lossy_string("ls -l ü".as_bytes(), "command"), "ls -l ü");
+    }
+
+    #[test]
+    fn lossy_string_invalid() {
+        assert_eq!(
+            lossy_string(b"cat \xff\xfe.txt", "command"),
+            "cat \u{fffd}\u{fffd}.txt"
+        );
+    }
+
+    #[test]
+    fn lossy_lines_invalid() {
+        let input = &b"ls\ncat \xff\r\npwd"[..];
+        let lines: Vec<String> = lossy_lines(input).collect::<Result<_>>().unwrap();
+        assert_eq!(lines, vec!["ls", "cat \u{fffd}", "pwd"]);
+    }
+}
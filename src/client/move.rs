@@ -1,12 +1,17 @@
-use std::collections::HashSet;
-
+use chrono::{DateTime, Utc};
 use clap::ValueHint;
 use log::debug;
+use serde::Serialize;
 use uuid::Uuid;
 
-use crate::{config::Config, error::Result, history::Entry, server};
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    history::Entry,
+    server,
+};
 
-use super::{Filter, FilterArgs};
+use super::{DedupScope, Filter, FilterArgs, dedup};
 
 #[derive(clap::Args, Debug)]
 pub struct MoveArgs {
@@ -21,6 +26,27 @@ pub struct MoveArgs {
     #[arg(short = 'D', long)]
     no_duplicates: bool,
 
+    /// Which commands count as duplicates when removing them
+    #[arg(long, value_enum, default_value = "global", value_name = "SCOPE")]
+    dedup_scope: DedupScope,
+
+    /// Wrap around to the other end of the history when moving past either
+    /// end, instead of outputting an empty line
+    #[arg(long)]
+    wrap: bool,
+
+    /// Output the selected entry (id, cmd, path, ts and host) as JSON, or an
+    /// empty object when moving past the end of the history
+    #[arg(short, long, conflicts_with = "with_id")]
+    json: bool,
+
+    /// Output the selected command as a line for the shell to eval (e.g.
+    /// `eval "$(vellum move --run -- -1)"` in a key binding to run the last
+    /// command again), or an empty line when moving past the end of the
+    /// history. The command is run exactly as it is stored.
+    #[arg(long, conflicts_with_all = ["with_id", "json"])]
+    run: bool,
+
     /// How far to move through the history relative to the start
     #[clap(value_hint = ValueHint::Other)]
     distance: isize,
@@ -33,15 +59,27 @@ pub struct MoveArgs {
     start: Option<String>,
 }
 
+#[derive(Serialize, Debug)]
+struct Moved<'a> {
+    id: Uuid,
+    cmd: &'a str,
+    path: &'a str,
+    ts: DateTime<Utc>,
+    host: &'a str,
+}
+
 pub fn do_move(cfg: &Config, args: MoveArgs) -> Result<()> {
     debug!("move: {args:?}");
 
     let mut conn = server::ensure_ready(cfg)?;
-    let filter = Filter::new(args.filter)?;
-    let mut history: Vec<Entry> = filter.history_request(&mut conn)?;
+    let filter = Filter::new(cfg, args.filter)?;
+    let mut history: Vec<Entry> = match cfg.client.move_cache {
+        true => filter.cached_history_request(cfg, &mut conn, &cfg.cache_dir.join("move.cache"))?,
+        false => filter.history_request(&mut conn)?,
+    };
 
     if args.no_duplicates {
-        history = remove_duplicates(history);
+        history = dedup(history, args.dedup_scope, |entry| entry);
     }
 
     let start = match args.start {
@@ -55,7 +93,14 @@ pub fn do_move(cfg: &Config, args: MoveArgs) -> Result<()> {
         _ => history.len(),
     };
 
-    let want = start.saturating_add_signed(args.distance);
+    let want = match args.wrap {
+        // with an empty history there is nothing to wrap around to, so we
+        // just fall through to outputting an empty line.
+        true if !history.is_empty() => (start as isize)
+            .saturating_add(args.distance)
+            .rem_euclid(history.len() as isize) as usize,
+        _ => start.saturating_add_signed(args.distance),
+    };
     debug!(
         "history has {} entries, start at {}, move by {}, so we want {}",
         history.len(),
@@ -65,11 +110,29 @@ pub fn do_move(cfg: &Config, args: MoveArgs) -> Result<()> {
     );
 
     if want >= history.len() {
-        println!();
+        match args.json {
+            true => println!("{{}}"),
+            false => println!(),
+        }
         return Ok(());
     }
 
     let entry = &history[want];
+    if args.json {
+        let moved = Moved {
+            id: entry.id,
+            cmd: &entry.cmd,
+            path: &entry.path,
+            ts: entry.ts,
+            host: &entry.host,
+        };
+        println!("{}", serde_json::to_string(&moved)?);
+        return Ok(());
+    }
+    if args.run {
+        println!("{}", eval_command(&entry.cmd)?);
+        return Ok(());
+    }
     if args.with_id {
         print!("{}|", entry.id);
     }
@@ -78,13 +141,26 @@ pub fn do_move(cfg: &Config, args: MoveArgs) -> Result<()> {
     Ok(())
 }
 
-fn remove_duplicates(history: Vec<Entry>) -> Vec<Entry> {
-    let mut seen = HashSet::new();
-    let mut filtered: Vec<Entry> = history
-        .into_iter()
-        .rev()
-        .filter(|entry| seen.insert(entry.cmd.clone()))
-        .collect();
-    filtered.reverse();
-    filtered
+/// A line that runs the command when it is given to the shell's eval. The
+/// command is quoted, so that the line is a single eval even when the command
+/// spans several lines.
+fn eval_command(cmd: &str) -> Result<String> {
+    let cmd = shlex::try_quote(cmd)
+        .map_err(|e| Error::Generic(format!("can't quote the command {cmd:?}: {e}")))?;
+    Ok(format!("eval {cmd}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_command_quotes_command() {
+        assert_eq!(eval_command("ls").unwrap(), "eval ls");
+        assert_eq!(eval_command("ls -l $HOME").unwrap(), "eval 'ls -l $HOME'");
+        assert_eq!(
+            eval_command("echo 'a'\necho b").unwrap(),
+            "eval \"echo 'a'\necho b\""
+        );
+    }
 }
@@ -0,0 +1,127 @@
+use std::{cmp, collections::HashMap};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{config::Config, error::Result, server};
+
+use super::{DisplayTz, Filter, FilterArgs};
+
+#[derive(clap::Args, Debug)]
+pub struct PathsArgs {
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    /// Only show the N highest ranked paths
+    #[arg(long, value_name = "N")]
+    limit: Option<usize>,
+
+    /// Don't include the headers in the output
+    #[arg(short = 'H', long)]
+    no_headers: bool,
+
+    /// Output the highest ranked path last instead of first
+    #[arg(short, long)]
+    reverse: bool,
+
+    /// Show timestamps in the local timezone instead of UTC (overrides the
+    /// display_tz config option, JSON output is always in UTC)
+    #[arg(long)]
+    local: bool,
+
+    /// Output the path information as JSON, instead of formatted for human
+    /// reading.
+    #[arg(short, long)]
+    json: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct PathInfo<'a> {
+    path: &'a str,
+    count: usize,
+    last_ts: DateTime<Utc>,
+    rank: f64,
+}
+
+impl PathInfo<'_> {
+    /// Work out the rank from the count and how recently the path was used,
+    /// using the same weights as z, so that a path that was used a lot a long
+    /// time ago doesn't always beat one that is being used now.
+    fn set_rank(&mut self, now: DateTime<Utc>) {
+        let age = now - self.last_ts;
+        let weight = match age {
+            _ if age < TimeDelta::hours(1) => 4.0,
+            _ if age < TimeDelta::days(1) => 2.0,
+            _ if age < TimeDelta::weeks(1) => 0.5,
+            _ => 0.25,
+        };
+        self.rank = self.count as f64 * weight;
+    }
+}
+
+pub fn paths(cfg: &Config, args: PathsArgs) -> Result<()> {
+    if !cfg.history.store_path {
+        warn!("history.store_path is disabled, new commands won't have a path");
+    }
+    let tz = DisplayTz::new(cfg, args.local)?;
+    let filter = Filter::new(cfg, &args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    let mut paths: HashMap<&str, PathInfo> = HashMap::new();
+    for entry in history.iter().filter(|entry| !entry.path.is_empty()) {
+        let info = paths.entry(&entry.path).or_insert(PathInfo {
+            path: &entry.path,
+            count: 0,
+            last_ts: entry.ts,
+            rank: 0.0,
+        });
+        info.count += 1;
+        info.last_ts = cmp::max(info.last_ts, entry.ts);
+    }
+
+    // highest ranked first, with the most recently used first for paths that
+    // have the same rank.
+    let now = Utc::now();
+    let mut paths: Vec<PathInfo> = paths.into_values().collect();
+    for info in paths.iter_mut() {
+        info.set_rank(now);
+    }
+    paths.sort_by(|a, b| {
+        b.rank
+            .total_cmp(&a.rank)
+            .then(b.last_ts.cmp(&a.last_ts))
+            .then(a.path.cmp(b.path))
+    });
+    if let Some(limit) = args.limit {
+        paths.truncate(limit);
+    }
+    if args.reverse {
+        paths.reverse();
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string(&paths)?);
+        return Ok(());
+    }
+
+    let rows: Vec<_> = paths
+        .iter()
+        .map(|p| (p.path, p.count, tz.rfc3339(&p.last_ts)))
+        .collect();
+    let ts_size = rows
+        .iter()
+        .fold("LAST".len(), |max, r| cmp::max(max, r.2.len()));
+
+    if !args.no_headers {
+        println!("COUNT\t{:ts_size$}\tPATH", "LAST");
+    }
+    for (path, count, last) in rows {
+        println!("{count:5}\t{last:ts_size$}\t{path}");
+    }
+
+    Ok(())
+}
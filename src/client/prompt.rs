@@ -0,0 +1,46 @@
+use clap::ValueHint;
+
+use crate::{config::Config, error::Result, server};
+
+use super::{DisplayTz, Session, Template};
+
+#[derive(clap::Args, Debug)]
+pub struct PromptArgs {
+    /// Format the last command using this template instead of the
+    /// client.prompt_template config option (using the same placeholders as
+    /// history --template)
+    #[arg(long, value_name = "TEMPLATE", value_hint = ValueHint::Other)]
+    template: Option<String>,
+
+    /// Only look at the commands from the current session
+    #[arg(short, long)]
+    session: bool,
+
+    /// Show timestamps in the local timezone instead of UTC (overrides the
+    /// display_tz config option)
+    #[arg(long)]
+    local: bool,
+}
+
+/// Show the last command using a template, with a single request to the
+/// server, so that it is cheap enough to run every time the prompt is drawn.
+/// Nothing is shown if there is no last command.
+pub fn prompt(cfg: &Config, args: PromptArgs) -> Result<()> {
+    let template = Template::parse(
+        args.template
+            .as_deref()
+            .unwrap_or(&cfg.client.prompt_template),
+    )?;
+    let tz = DisplayTz::new(cfg, args.local)?;
+    let session = match args.session {
+        true => Some(Session::get()?.id),
+        false => None,
+    };
+
+    let mut conn = server::ensure_ready(cfg)?;
+    if let Some((index, entry)) = conn.last_request(session)? {
+        println!("{}", template.render(index + 1, &entry, tz));
+    }
+
+    Ok(())
+}
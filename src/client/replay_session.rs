@@ -0,0 +1,113 @@
+use std::{
+    io::{Write, stdout},
+    path::PathBuf,
+};
+
+use clap::ValueHint;
+use log::debug;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    history::Entry,
+    server,
+};
+
+use super::{Filter, FilterArgs, cd_command, write_atomic};
+
+#[derive(clap::Args, Debug)]
+pub struct ReplaySessionArgs {
+    /// Start the script with a #! line for the given interpreter (defaults to
+    /// the shell the session was using, or /bin/sh if it wasn't recorded).
+    /// The interpreter has to be given as --shebang=INTERPRETER.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "", value_name = "INTERPRETER", value_hint = ValueHint::CommandName)]
+    shebang: Option<String>,
+
+    /// Write the script to a file instead of stdout
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    out: Option<PathBuf>,
+
+    /// The ID of the session to replay
+    #[arg(value_name = "ID", value_hint = ValueHint::Other)]
+    id: String,
+}
+
+/// Print the commands stored by a session, in the order they were run, as a
+/// shell script. Whenever the recorded path changes a cd to the new path is
+/// added before the command.
+pub fn replay_session(cfg: &Config, args: ReplaySessionArgs) -> Result<()> {
+    let mut filter_args = FilterArgs::default();
+    filter_args.session_id(&args.id);
+    let filter = Filter::new(cfg, filter_args)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let history = filter.history_request(&mut conn)?;
+    debug!("got {} entries for session {}", history.len(), args.id);
+    if history.is_empty() {
+        return Err(Error::Generic(format!(
+            "no commands found for session {}",
+            args.id
+        )));
+    }
+
+    match &args.out {
+        Some(path) => write_atomic(path, |out| write_script(&args, &history, out)),
+        None => write_script(&args, &history, &mut stdout().lock()),
+    }
+}
+
+fn write_script(args: &ReplaySessionArgs, history: &[Entry], out: &mut dyn Write) -> Result<()> {
+    if let Some(interpreter) = &args.shebang {
+        let shell = history.iter().find_map(|entry| entry.shell.as_deref());
+        match (interpreter.as_str(), shell) {
+            ("", Some(shell)) => writeln!(out, "#!/usr/bin/env {shell}")?,
+            ("", None) => writeln!(out, "#!/bin/sh")?,
+            (interpreter, _) => writeln!(out, "#!{interpreter}")?,
+        }
+    }
+    writeln!(out, "# commands from vellum session {}", args.id)?;
+
+    let mut path = "";
+    for entry in history {
+        if !entry.path.is_empty() && entry.path != path {
+            path = &entry.path;
+            writeln!(out, "{}", cd_command(path)?)?;
+        }
+        writeln!(out, "{}", entry.cmd)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(clap::Parser, Debug)]
+    struct Cli {
+        #[command(flatten)]
+        args: ReplaySessionArgs,
+    }
+
+    fn parse(args: &[&str]) -> ReplaySessionArgs {
+        Cli::try_parse_from(std::iter::once("replay-session").chain(args.iter().copied()))
+            .unwrap()
+            .args
+    }
+
+    #[test]
+    fn shebang_does_not_take_the_id() {
+        let args = parse(&["--shebang", "abc123"]);
+        assert_eq!(args.shebang.as_deref(), Some(""));
+        assert_eq!(args.id, "abc123");
+
+        let args = parse(&["--shebang=/bin/bash", "abc123"]);
+        assert_eq!(args.shebang.as_deref(), Some("/bin/bash"));
+        assert_eq!(args.id, "abc123");
+
+        let args = parse(&["abc123"]);
+        assert_eq!(args.shebang, None);
+    }
+}
@@ -0,0 +1,61 @@
+use std::io::{IsTerminal, stdin};
+
+use clap::ValueHint;
+use log::info;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    history::PathRewrite,
+    server,
+};
+
+use super::{confirm, show_rebuild};
+
+#[derive(clap::Args, Debug)]
+pub struct RewritePathArgs {
+    /// The path to rewrite
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    from: String,
+
+    /// The path to replace it with
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    to: String,
+
+    /// Also rewrite the paths of directories below --from
+    #[arg(long)]
+    prefix: bool,
+
+    /// Only rewrite commands stored by the given host
+    #[arg(long, value_hint = ValueHint::Hostname)]
+    host: Option<String>,
+
+    /// Don't ask for confirmation before rebuilding
+    #[arg(short, long)]
+    yes: bool,
+}
+
+pub fn rewrite_path(cfg: &Config, args: RewritePathArgs) -> Result<()> {
+    if !args.yes {
+        if !stdin().is_terminal() {
+            return Err(Error::from_str(
+                "rewriting paths rebuilds the sync repo for all hosts, use --yes to confirm",
+            ));
+        }
+        if !confirm("Rewriting paths rebuilds the sync repo for all hosts, continue?")? {
+            info!("rewrite aborted");
+            return Ok(());
+        }
+    }
+    let rewrite = PathRewrite {
+        from: args.from,
+        to: args.to,
+        prefix: args.prefix,
+        host: args.host,
+    };
+    let mut conn = server::ensure_ready(cfg)?;
+    if let Some(summary) = show_rebuild(conn.rewrite_path(rewrite, true)?)? {
+        println!("Paths rewritten: {}", summary.paths_rewritten);
+    }
+    Ok(())
+}
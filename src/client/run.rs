@@ -0,0 +1,111 @@
+use std::{
+    env::current_dir,
+    ffi::OsString,
+    io::ErrorKind,
+    os::unix::process::ExitStatusExt,
+    process::{Command, exit},
+    sync::{Arc, atomic::AtomicBool},
+    time::{Duration, Instant},
+};
+
+use clap::ValueHint;
+use log::{debug, error, warn};
+use signal_hook::consts::{SIGINT, SIGQUIT};
+
+use uuid::Uuid;
+
+use crate::{config::Config, error::Result, server};
+
+use super::{Session, store_command};
+
+#[derive(clap::Args, Debug)]
+pub struct RunArgs {
+    /// The command to store and run, and its arguments
+    #[arg(required = true, trailing_var_arg = true, value_hint = ValueHint::CommandWithArguments)]
+    command: Vec<OsString>,
+}
+
+pub fn run(cfg: &Config, args: RunArgs) -> Result<()> {
+    let cmd = args
+        .command
+        .iter()
+        .map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // failing to store the command shouldn't stop it from being run.
+    let id = store(cfg, cmd).unwrap_or_else(|e| {
+        warn!("Failed to store command: {e}");
+        None
+    });
+
+    let mut command = Command::new(&args.command[0]);
+    command.args(&args.command[1..]);
+    run_command(cfg, command, id)
+}
+
+/// Run the command, record its exit status and how long it took against the
+/// stored entry with the given ID (if it was stored), and then exit with its
+/// exit status.
+pub(super) fn run_command(cfg: &Config, mut command: Command, id: Option<Uuid>) -> Result<()> {
+    // the child gets interrupts from the terminal too, so we just need to
+    // survive them long enough to report how it exited.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGQUIT] {
+        signal_hook::flag::register(signal, interrupted.clone())?;
+    }
+
+    let start = Instant::now();
+    let code = match command.status() {
+        Ok(status) => {
+            debug!("command exited with {status} after {:?}", start.elapsed());
+            // follow the shell convention for commands killed by a signal.
+            match (status.code(), status.signal()) {
+                (Some(code), _) => code,
+                (None, Some(signal)) => 128 + signal,
+                (None, None) => 1,
+            }
+        }
+        Err(e) => {
+            error!("Failed to run {:?}: {e}", command.get_program());
+            // use the same exit codes as the shell would.
+            match e.kind() {
+                ErrorKind::NotFound => 127,
+                _ => 126,
+            }
+        }
+    };
+
+    // failing to record the exit status shouldn't change how we exit.
+    if let Some(id) = id
+        && let Err(e) = finished(cfg, id, code, start.elapsed())
+    {
+        warn!("Failed to record exit status: {e}");
+    }
+
+    exit(code)
+}
+
+fn store(cfg: &Config, cmd: String) -> Result<Option<Uuid>> {
+    let path = match cfg.history.store_path {
+        true => current_dir()?.to_string_lossy().to_string(),
+        false => String::new(),
+    };
+    store_command(cfg, cmd, path)
+}
+
+fn finished(cfg: &Config, id: Uuid, code: i32, duration: Duration) -> Result<()> {
+    let session = Session::get()?.id;
+    let duration_ms = duration.as_millis().try_into().unwrap_or(u64::MAX);
+    server::ensure_ready(cfg)?.finished(id, code, duration_ms, session)
+}
+
+/// Quote an argument so that the stored command can be pasted back into a
+/// shell.
+fn quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
@@ -9,6 +9,8 @@ use clap::ValueHint;
 
 use crate::{config::Config, error::Result, server};
 
+use super::{AnonymizeArgs, Anonymizer};
+
 #[derive(clap::Args, Debug)]
 pub struct SaveArgs {
     /// Write to a file rather than stdout
@@ -18,6 +20,9 @@ pub struct SaveArgs {
     /// Save commands for all hosts, not just the current
     #[arg(short, long)]
     all_hosts: bool,
+
+    #[command(flatten)]
+    anonymize: AnonymizeArgs,
 }
 
 pub fn save(cfg: &Config, args: SaveArgs) -> Result<()> {
@@ -37,6 +42,9 @@ pub fn save(cfg: &Config, args: SaveArgs) -> Result<()> {
         history.retain(|entry| entry.host == host);
     }
 
+    let mut anonymizer = Anonymizer::new(&args.anonymize);
+    let history: Vec<_> = history.into_iter().map(|e| anonymizer.entry(e)).collect();
+
     serde_json::to_writer(writer, &history)?;
 
     Ok(())
@@ -23,10 +23,10 @@ impl Session {
     }
 
     pub fn includes_entry(&self, entry: &Entry) -> bool {
-        if let Some(start) = self.start {
-            if entry.ts < start {
-                return true;
-            }
+        if let Some(start) = self.start
+            && entry.ts < start
+        {
+            return true;
         };
         entry.session == self.id
     }
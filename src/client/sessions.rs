@@ -0,0 +1,116 @@
+use std::{cmp, collections::HashMap};
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::Serialize;
+
+use crate::{config::Config, error::Result, server};
+
+use super::{DisplayTz, Filter, FilterArgs};
+
+#[derive(clap::Args, Debug)]
+pub struct SessionsArgs {
+    #[command(flatten)]
+    filter: FilterArgs,
+
+    /// Don't include the headers in the output
+    #[arg(short = 'H', long)]
+    no_headers: bool,
+
+    /// Output the most recently used session first instead of last
+    #[arg(short, long)]
+    reverse: bool,
+
+    /// Show timestamps in the local timezone instead of UTC (overrides the
+    /// display_tz config option, JSON output is always in UTC)
+    #[arg(long)]
+    local: bool,
+
+    /// Output the session information as JSON, instead of formatted for human
+    /// reading.
+    #[arg(short, long)]
+    json: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct SessionInfo<'a> {
+    session: &'a str,
+    hosts: Vec<&'a str>,
+    first_ts: DateTime<Utc>,
+    last_ts: DateTime<Utc>,
+    count: usize,
+}
+
+pub fn sessions(cfg: &Config, args: SessionsArgs) -> Result<()> {
+    let tz = DisplayTz::new(cfg, args.local)?;
+    let filter = Filter::new(cfg, &args.filter)?;
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let history = filter.history_request(&mut conn)?;
+    debug!("got filtered history with {} entries", history.len());
+
+    let mut sessions: HashMap<&str, SessionInfo> = HashMap::new();
+    for entry in history.iter() {
+        let info = sessions.entry(&entry.session).or_insert(SessionInfo {
+            session: &entry.session,
+            hosts: Vec::new(),
+            first_ts: entry.ts,
+            last_ts: entry.ts,
+            count: 0,
+        });
+        if !info.hosts.contains(&entry.host.as_str()) {
+            info.hosts.push(&entry.host);
+        }
+        info.first_ts = cmp::min(info.first_ts, entry.ts);
+        info.last_ts = cmp::max(info.last_ts, entry.ts);
+        info.count += 1;
+    }
+
+    // ordered like the history, with the most recently used session last.
+    let mut sessions: Vec<SessionInfo> = sessions.into_values().collect();
+    sessions.sort_by(|a, b| a.last_ts.cmp(&b.last_ts).then(a.session.cmp(b.session)));
+    if args.reverse {
+        sessions.reverse();
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string(&sessions)?);
+        return Ok(());
+    }
+
+    let rows: Vec<_> = sessions
+        .iter()
+        .map(|s| {
+            (
+                s.session,
+                s.hosts.join(","),
+                tz.rfc3339(&s.first_ts),
+                tz.rfc3339(&s.last_ts),
+                s.count,
+            )
+        })
+        .collect();
+    let session_size = rows
+        .iter()
+        .fold("SESSION".len(), |max, r| cmp::max(max, r.0.len()));
+    let host_size = rows
+        .iter()
+        .fold("HOST".len(), |max, r| cmp::max(max, r.1.len()));
+    let ts_size = rows.iter().fold("FIRST".len(), |max, r| {
+        cmp::max(max, r.2.len().max(r.3.len()))
+    });
+
+    if !args.no_headers {
+        println!(
+            "{:session_size$}\t{:host_size$}\t{:ts_size$}\t{:ts_size$}\tCOUNT",
+            "SESSION", "HOST", "FIRST", "LAST"
+        );
+    }
+    for (session, hosts, first, last, count) in rows {
+        println!(
+            "{session:session_size$}\t{hosts:host_size$}\t{first:ts_size$}\t{last:ts_size$}\t{count}"
+        );
+    }
+
+    Ok(())
+}
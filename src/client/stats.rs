@@ -0,0 +1,178 @@
+use std::{
+    io::{IsTerminal, Write, stdout},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use chrono::{TimeDelta, Utc};
+use clap::ValueHint;
+use humantime::format_duration;
+use log::debug;
+use ratatui::crossterm::{
+    cursor::MoveTo,
+    execute,
+    terminal::{Clear, ClearType},
+};
+
+use crate::{
+    api::{Connection, Message},
+    config::Config,
+    error::{Error, Result},
+    history::CommandCount,
+    server,
+};
+
+use super::DisplayTz;
+
+/// How many of the most recent commands --watch shows.
+const RECENT_COMMANDS: usize = 5;
+
+#[derive(clap::Args, Debug)]
+pub struct StatsArgs {
+    /// How many commands to show
+    #[arg(short, long, default_value_t = 10)]
+    top: usize,
+
+    /// Show timestamps in the local timezone instead of UTC (overrides the
+    /// display_tz config option, JSON output is always in UTC)
+    #[arg(long)]
+    local: bool,
+
+    /// Output the commands as JSON, instead of formatted for human reading.
+    #[arg(short, long, conflicts_with = "watch")]
+    json: bool,
+
+    /// Keep showing the stats, along with the most recent commands and the
+    /// sync status, refreshing them every interval and whenever a sync
+    /// finishes (until interrupted)
+    #[arg(short, long)]
+    watch: bool,
+
+    /// How often to refresh the stats with --watch
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5s", value_name = "DURATION", value_hint = ValueHint::Other, requires = "watch")]
+    interval: Duration,
+}
+
+/// Show the most frequently run commands, with how many times they have been
+/// run and when they were last run.
+pub fn stats(cfg: &Config, args: StatsArgs) -> Result<()> {
+    let tz = DisplayTz::new(cfg, args.local)?;
+    if args.watch {
+        return watch(cfg, &args, tz);
+    }
+
+    let mut conn = server::ensure_ready(cfg)?;
+    let counts = conn.top_commands_request(args.top)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&counts)?);
+        return Ok(());
+    }
+
+    write_counts(&mut stdout().lock(), &counts, tz)
+}
+
+fn write_counts(out: &mut dyn Write, counts: &[CommandCount], tz: DisplayTz) -> Result<()> {
+    let count_size = counts
+        .iter()
+        .map(|c| c.count.to_string().len())
+        .max()
+        .unwrap_or_default();
+    for count in counts {
+        writeln!(
+            out,
+            "{:>count_size$}\t{}\t{}",
+            count.count,
+            tz.rfc3339(&count.last),
+            count.cmd
+        )?;
+    }
+    Ok(())
+}
+
+/// Redraw the stats every interval, or as soon as a sync finishes.
+fn watch(cfg: &Config, args: &StatsArgs, tz: DisplayTz) -> Result<()> {
+    if !stdout().is_terminal() {
+        return Err(Error::from_str("--watch needs to be run in a terminal"));
+    }
+
+    let mut conn = server::ensure_ready(cfg)?;
+
+    let (tx, rx) = mpsc::channel();
+    let events_cfg = cfg.clone();
+    thread::spawn(move || {
+        if let Err(e) = forward_syncs(&events_cfg, tx) {
+            debug!("stopped watching for syncs: {e}");
+        }
+    });
+
+    let mut out = stdout();
+    loop {
+        // render to a buffer first, so that the screen is only cleared once
+        // we have something to replace it with.
+        let mut screen = Vec::new();
+        render(&mut screen, &mut conn, args, tz)?;
+        execute!(out, MoveTo(0, 0), Clear(ClearType::All))?;
+        out.write_all(&screen)?;
+        out.flush()?;
+
+        match rx.recv_timeout(args.interval) {
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+            // we aren't getting events any more, so just poll.
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(args.interval),
+        }
+    }
+}
+
+fn render(
+    out: &mut dyn Write,
+    conn: &mut Connection,
+    args: &StatsArgs,
+    tz: DisplayTz,
+) -> Result<()> {
+    let now = Utc::now();
+    writeln!(
+        out,
+        "{}  (refreshing every {})",
+        tz.rfc3339(&now),
+        format_duration(args.interval)
+    )?;
+    writeln!(out)?;
+
+    let status = conn.status_request()?;
+    let since = (now - status.last_sync).to_std().unwrap_or_default();
+    writeln!(out, "Pending changes: {}", status.pending)?;
+    writeln!(
+        out,
+        "Last sync:       {} ({} ago)",
+        tz.rfc3339(&status.last_sync),
+        format_duration(Duration::from_secs(since.as_secs()))
+    )?;
+    writeln!(out)?;
+
+    writeln!(out, "Recent commands:")?;
+    let recent = conn.history_between(Some(now - TimeDelta::days(1)), None)?;
+    for entry in recent.iter().rev().take(RECENT_COMMANDS).rev() {
+        writeln!(out, "{}\t{}", tz.rfc3339(&entry.ts), entry.cmd)?;
+    }
+    writeln!(out)?;
+
+    writeln!(out, "Most frequent commands:")?;
+    write_counts(out, &conn.top_commands_request(args.top)?, tz)
+}
+
+/// Send a message to tx whenever a sync finishes, until the server goes away
+/// or the receiver is dropped.
+fn forward_syncs(cfg: &Config, tx: Sender<()>) -> Result<()> {
+    let mut conn = server::ensure_ready(cfg)?;
+    conn.subscribe()?;
+    while let Some(msg) = conn.next_event()? {
+        if matches!(msg, Message::SyncCompleted { .. } | Message::SyncFailed(_))
+            && tx.send(()).is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
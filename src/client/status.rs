@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use humantime::format_duration;
+use log::debug;
+
+use crate::{api::Connection, config::Config, error::Result, server};
+
+#[derive(clap::Args, Debug)]
+pub struct StatusArgs {
+    /// Display the status as JSON
+    #[arg(short, long)]
+    json: bool,
+
+    /// Display the status as a single line for shell prompts, one of "ok
+    /// pending=N lag=Ns" (lag is the seconds since the last sync), "syncing"
+    /// or "offline". The server isn't started if it isn't running.
+    #[arg(short, long, conflicts_with = "json")]
+    compact: bool,
+}
+
+pub fn status(cfg: &Config, args: StatusArgs) -> Result<()> {
+    if args.compact {
+        return compact_status(cfg);
+    }
+
+    let mut conn = server::ensure_ready(cfg)?;
+    let status = conn.status_request()?;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&status)?);
+        return Ok(());
+    }
+
+    let since = (Utc::now() - status.last_sync).to_std().unwrap_or_default();
+    match status.syncing {
+        true => println!("Pending changes: - (sync in progress)"),
+        false => println!("Pending changes: {}", status.pending),
+    }
+    println!(
+        "Last sync:       {} ({} ago)",
+        status.last_sync.to_rfc3339(),
+        format_duration(Duration::from_secs(since.as_secs()))
+    );
+
+    Ok(())
+}
+
+/// Print the status in a stable single line format, which is cheap for a
+/// prompt to get and parse.
+fn compact_status(cfg: &Config) -> Result<()> {
+    let mut conn = match Connection::new(cfg) {
+        Ok(conn) => conn,
+        Err(e) => {
+            debug!("failed to connect to server: {e}");
+            println!("offline");
+            return Ok(());
+        }
+    };
+    let status = conn.status_request()?;
+
+    if status.syncing {
+        println!("syncing");
+        return Ok(());
+    }
+    let lag = (Utc::now() - status.last_sync).num_seconds().max(0);
+    println!("ok pending={} lag={lag}s", status.pending);
+
+    Ok(())
+}
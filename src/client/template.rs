@@ -0,0 +1,180 @@
+use chrono::format::{Item, StrftimeItems};
+
+use crate::{
+    error::{Error, Result},
+    history::Entry,
+};
+
+use super::DisplayTz;
+
+#[derive(Debug, Clone)]
+enum Field {
+    Index,
+    Id,
+    Ts(Option<Vec<Item<'static>>>),
+    Host,
+    Cmd,
+    Path,
+    Session,
+    Seq,
+    Shell,
+    Note,
+    Exit,
+    Duration,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed output template, used to format history entries.
+///
+/// Templates consist of literal text and placeholders wrapped in braces (e.g.
+/// `{host}`), timestamps can optionally include a strftime format (e.g.
+/// `{ts:%H:%M}`). Literal braces can be included by doubling them (`{{` or
+/// `}}`).
+#[derive(Debug, Clone)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => {
+                                return Err(Error::Generic(format!(
+                                    "unterminated placeholder in template: {{{placeholder}"
+                                )));
+                            }
+                        }
+                    }
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(literal.clone()));
+                        literal.clear();
+                    }
+                    segments.push(Segment::Field(parse_field(&placeholder)?));
+                }
+                '}' => {
+                    return Err(Error::from_str(
+                        "unmatched '}' in template (use '}}' for a literal brace)",
+                    ));
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self { segments })
+    }
+
+    pub fn render(&self, index: usize, entry: &Entry, tz: DisplayTz) -> String {
+        let mut out = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(s) => out.push_str(s),
+                Segment::Field(Field::Index) => out.push_str(&index.to_string()),
+                Segment::Field(Field::Id) => out.push_str(&entry.id.to_string()),
+                Segment::Field(Field::Ts(None)) => out.push_str(&tz.rfc3339(&entry.ts)),
+                Segment::Field(Field::Ts(Some(items))) => {
+                    // the format items have already been validated.
+                    out.push_str(&tz.format_with_items(&entry.ts, items.iter()));
+                }
+                Segment::Field(Field::Host) => out.push_str(&entry.host),
+                Segment::Field(Field::Cmd) => out.push_str(&entry.cmd),
+                Segment::Field(Field::Path) => out.push_str(&entry.path),
+                Segment::Field(Field::Session) => out.push_str(&entry.session),
+                Segment::Field(Field::Seq) => {
+                    if let Some(seq) = entry.seq {
+                        out.push_str(&seq.to_string());
+                    }
+                }
+                Segment::Field(Field::Shell) => {
+                    if let Some(shell) = &entry.shell {
+                        out.push_str(shell);
+                    }
+                }
+                Segment::Field(Field::Note) => {
+                    if let Some(note) = &entry.note {
+                        out.push_str(note);
+                    }
+                }
+                Segment::Field(Field::Exit) => {
+                    if let Some(exit) = entry.exit {
+                        out.push_str(&exit.to_string());
+                    }
+                }
+                Segment::Field(Field::Duration) => {
+                    if let Some(duration_ms) = entry.duration_ms {
+                        out.push_str(&duration_ms.to_string());
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+fn parse_field(placeholder: &str) -> Result<Field> {
+    let (name, format) = match placeholder.split_once(':') {
+        Some((name, format)) => (name, Some(format)),
+        None => (placeholder, None),
+    };
+    match (name, format) {
+        ("index", None) => Ok(Field::Index),
+        ("id", None) => Ok(Field::Id),
+        ("ts", None) => Ok(Field::Ts(None)),
+        ("ts", Some(format)) => {
+            let items = StrftimeItems::new(format).parse_to_owned().map_err(|_| {
+                Error::Generic(format!("invalid timestamp format in template: {format}"))
+            })?;
+            Ok(Field::Ts(Some(items)))
+        }
+        ("host", None) => Ok(Field::Host),
+        ("cmd", None) => Ok(Field::Cmd),
+        ("path", None) => Ok(Field::Path),
+        ("session", None) => Ok(Field::Session),
+        ("seq", None) => Ok(Field::Seq),
+        ("shell", None) => Ok(Field::Shell),
+        ("note", None) => Ok(Field::Note),
+        ("exit", None) => Ok(Field::Exit),
+        ("duration", None) => Ok(Field::Duration),
+        (name, Some(_))
+            if [
+                "index", "id", "host", "cmd", "path", "session", "seq", "shell", "note", "exit",
+                "duration",
+            ]
+            .contains(&name) =>
+        {
+            Err(Error::Generic(format!(
+                "placeholder {{{name}}} in template doesn't take a format"
+            )))
+        }
+        _ => Err(Error::Generic(format!(
+            "unknown placeholder in template: {{{placeholder}}}"
+        ))),
+    }
+}
@@ -0,0 +1,66 @@
+use std::borrow::Borrow;
+
+use chrono::{DateTime, Local, Utc, format::Item};
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+};
+
+/// The timezone that timestamps are displayed in. History is always stored in
+/// UTC, this only affects how timestamps are shown to the user.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DisplayTz {
+    #[default]
+    Utc,
+    Local,
+    Zone(chrono_tz::Tz),
+}
+
+impl DisplayTz {
+    /// Work out the display timezone, --local overrides the `display_tz`
+    /// config option.
+    pub fn new(cfg: &Config, local: bool) -> Result<Self> {
+        if local {
+            return Ok(Self::Local);
+        }
+        match cfg.history.display_tz.as_deref() {
+            None => Ok(Self::Utc),
+            Some(tz) => Self::parse(tz),
+        }
+    }
+
+    fn parse(tz: &str) -> Result<Self> {
+        match tz.to_lowercase().as_str() {
+            "utc" => Ok(Self::Utc),
+            "local" => Ok(Self::Local),
+            _ => tz
+                .parse()
+                .map(Self::Zone)
+                .map_err(|_| Error::Generic(format!("unknown timezone: {tz}"))),
+        }
+    }
+
+    pub fn rfc3339(&self, ts: &DateTime<Utc>) -> String {
+        match self {
+            Self::Utc => ts.to_rfc3339(),
+            Self::Local => ts.with_timezone(&Local).to_rfc3339(),
+            Self::Zone(tz) => ts.with_timezone(tz).to_rfc3339(),
+        }
+    }
+
+    pub fn format_with_items<'a, I, B>(&self, ts: &DateTime<Utc>, items: I) -> String
+    where
+        I: Iterator<Item = B> + Clone,
+        B: Borrow<Item<'a>>,
+    {
+        match self {
+            Self::Utc => ts.format_with_items(items).to_string(),
+            Self::Local => ts
+                .with_timezone(&Local)
+                .format_with_items(items)
+                .to_string(),
+            Self::Zone(tz) => ts.with_timezone(tz).format_with_items(items).to_string(),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    history,
+    process::server_is_running,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    /// Rewrite any files containing bad chunks without them (the server must
+    /// be stopped first)
+    #[arg(long)]
+    repair: bool,
+
+    /// Output the results as JSON
+    #[arg(short, long)]
+    json: bool,
+}
+
+pub fn verify(cfg: &Config, args: VerifyArgs) -> Result<()> {
+    if args.repair && server_is_running(cfg)? {
+        return Err(Error::from_str(
+            "the server is running, stop it before using --repair",
+        ));
+    }
+
+    let verification = history::verify(cfg, cfg.sync_path(), args.repair)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string(&verification)?);
+    } else {
+        for bad in verification.bad.iter() {
+            println!(
+                "{}: offset {}: {}",
+                bad.path.display(),
+                bad.offset,
+                bad.error
+            );
+        }
+        println!(
+            "Checked {} chunks in {} files, {} bad",
+            verification.chunks,
+            verification.files,
+            verification.bad.len()
+        );
+        if verification.repaired > 0 {
+            println!("Repaired {} files", verification.repaired);
+        }
+    }
+
+    if !verification.bad.is_empty() && !args.repair {
+        return Err(Error::Generic(format!(
+            "found {} bad chunks, use --repair to remove them",
+            verification.bad.len()
+        )));
+    }
+
+    Ok(())
+}
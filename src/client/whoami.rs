@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{
+    config::{Config, SyncBackend},
+    error::Result,
+};
+
+use super::Session;
+
+#[derive(clap::Args, Debug)]
+pub struct WhoamiArgs {
+    /// Display the identity information as JSON
+    #[arg(short, long)]
+    json: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct Identity {
+    hostname: String,
+    session: String,
+    state_dir: PathBuf,
+    sync_path: PathBuf,
+    sync_backend: &'static str,
+}
+
+pub fn whoami(cfg: &Config, args: WhoamiArgs) -> Result<()> {
+    let identity = Identity {
+        hostname: cfg.hostname.to_string_lossy().to_string(),
+        session: Session::get()?.id,
+        state_dir: cfg.state_dir.clone(),
+        sync_path: cfg.sync_path(),
+        sync_backend: match (cfg.sync.enabled, cfg.sync.backend) {
+            (false, _) => "local",
+            (true, SyncBackend::Git) => "git",
+            (true, SyncBackend::Webdav) => "webdav",
+        },
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string(&identity)?);
+        return Ok(());
+    }
+
+    println!("Hostname:     {}", identity.hostname);
+    println!("Session:      {}", identity.session);
+    println!("State dir:    {}", identity.state_dir.display());
+    println!("Sync path:    {}", identity.sync_path.display());
+    println!("Sync backend: {}", identity.sync_backend);
+
+    Ok(())
+}
@@ -1,9 +1,22 @@
-use std::io::stdout;
+use std::io::{Write, stdout};
 
 use clap::Command;
 use clap_complete::{Shell, generate};
+use log::debug;
 
-use crate::error::Result;
+use crate::{
+    api::Connection,
+    assets,
+    config::Config,
+    error::{Error, Result},
+    process::server_is_running,
+};
+
+/// The hidden command that the completion scripts run to get the values.
+pub const VALUES_COMMAND: &str = "__complete";
+
+/// How many of the most recent IDs are offered when completing IDs.
+const MAX_IDS: usize = 50;
 
 #[derive(clap::Args, Debug)]
 pub struct Args {
@@ -12,8 +25,68 @@ pub struct Args {
     shell: Shell,
 }
 
-pub fn complete(args: Args, mut cmd: Command) -> Result<()> {
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum Values {
+    /// Hosts that have stored commands
+    Hosts,
+    /// IDs of the most recent commands
+    Ids,
+}
+
+pub fn complete(args: Args, cmd: Command) -> Result<()> {
+    // the bash generator joins the names of nested subcommands with __, so it
+    // can't cope with a name that starts with it. The command is hidden, so
+    // the name that it is generated with doesn't matter.
+    let mut cmd = cmd.mut_subcommand(VALUES_COMMAND, |sub| sub.name("complete-values"));
     let bin_name = cmd.get_name().to_string();
-    generate(args.shell, &mut cmd, bin_name, &mut stdout());
+    let mut buf = Vec::new();
+    generate(args.shell, &mut cmd, bin_name, &mut buf);
+
+    // the generated completion is static, so hook in dynamic completion of
+    // values from the server where we can. The dynamic completion replaces
+    // the code at the end of the generated script that registers it.
+    let (dynamic, trailer) = match args.shell {
+        Shell::Bash => (Some("complete.bash"), "\nif [[ \"${BASH_VERSINFO[0]}\""),
+        Shell::Zsh => (
+            Some("complete.zsh"),
+            "\nif [ \"$funcstack[1]\" = \"_vellum\" ]",
+        ),
+        _ => (None, ""),
+    };
+    if let Some(name) = dynamic {
+        let script = assets::get_file(name)
+            .ok_or_else(|| Error::Generic(format!("{name} completion script missing")))?;
+        let generated = String::from_utf8_lossy(&buf);
+        if let Some(idx) = generated.rfind(trailer) {
+            buf.truncate(idx + 1);
+        }
+        buf.extend_from_slice(script.contents());
+    }
+
+    stdout().write_all(&buf)?;
+    Ok(())
+}
+
+/// Output completion candidates, one per line, for use by the completion
+/// scripts. Nothing is output if the server isn't running, as we don't want to
+/// start the server (and wait for it) while completing.
+pub fn values(cfg: &Config, values: Values) -> Result<()> {
+    if !server_is_running(cfg)? {
+        debug!("server isn't running, no completions");
+        return Ok(());
+    }
+    let mut conn = Connection::new(cfg)?;
+    match values {
+        Values::Hosts => {
+            for host in conn.hosts_request()? {
+                println!("{host}");
+            }
+        }
+        Values::Ids => {
+            for entry in conn.history_request()?.iter().rev().take(MAX_IDS) {
+                println!("{}", entry.id);
+            }
+        }
+    }
     Ok(())
 }
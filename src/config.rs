@@ -1,18 +1,31 @@
 use serde::{Deserialize, Serialize};
 use std::{
-    env, fs,
+    collections::BTreeMap,
+    env, fmt, fs,
     path::{Path, PathBuf},
     time::Duration,
 };
 use xdg::BaseDirectories;
 
+use crate::{bundle, history};
+
 pub type Result = crate::error::Result<Config>;
 
+/// System-wide config file, which the user config file is layered on top of.
+const SYSTEM_CONFIG: &str = "/etc/vellum/config.toml";
+
+// shown in place of a secret in a config that has been redacted.
+const REDACTED: &str = "<redacted>";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     #[serde(skip)]
     pub path: Option<PathBuf>,
 
+    // set if path was layered on top of the system config file
+    #[serde(skip)]
+    pub layered: bool,
+
     #[serde(default = "default_cache_dir")]
     pub cache_dir: PathBuf,
 
@@ -22,8 +35,28 @@ pub struct Config {
     #[serde(default = "default_hostname")]
     pub hostname: PathBuf,
 
+    /// The largest message (in bytes) that will be accepted over the socket
+    /// between the client and server.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: u64,
+
     #[serde(default)]
     pub sync: Sync,
+
+    #[serde(default)]
+    pub history: History,
+
+    #[serde(default)]
+    pub client: Client,
+
+    #[serde(default)]
+    pub server: Server,
+
+    /// Saved filters (e.g. [queries.k8s]), which can be used with --query
+    /// NAME. The keys are the filter options, with underscores instead of
+    /// dashes, and are checked when the query is used.
+    #[serde(default)]
+    pub queries: BTreeMap<String, toml::Table>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -32,7 +65,12 @@ pub struct Sync {
     #[serde(default = "default_sync_enabled")]
     pub enabled: bool,
 
-    /// URL of upstream git repository
+    /// What to sync with: "git" (the default) or "webdav"
+    #[serde(default)]
+    pub backend: SyncBackend,
+
+    /// URL of upstream git repository, or of the directory on the WebDAV
+    /// server when backend is "webdav"
     #[serde(default)]
     pub url: String,
 
@@ -40,6 +78,12 @@ pub struct Sync {
     #[serde(default)]
     pub ssh_key: String,
 
+    /// Branch of the upstream git repository to use for sync, if not set then
+    /// the default branch of the repository will be used. The branch will be
+    /// created if it doesn't already exist.
+    #[serde(default)]
+    pub branch: String,
+
     /// How often should we run an automatic sync?
     #[serde(default = "default_sync_interval")]
     #[serde(with = "humantime_serde")]
@@ -55,6 +99,407 @@ pub struct Sync {
     /// state directory.
     #[serde(default = "default_sync_path")]
     path: PathBuf,
+
+    /// How long to wait for another host to finish a rebuild (which locks the
+    /// sync repo) before giving up on a sync.
+    #[serde(default = "default_lock_timeout")]
+    #[serde(with = "humantime_serde")]
+    pub lock_timeout: Duration,
+
+    /// How old a lock has to be before it is assumed to have been left behind
+    /// by a host that died during a rebuild, and is removed by the hosts that
+    /// are waiting for it. 0 means that locks are never removed.
+    #[serde(default = "default_lock_stale_after")]
+    #[serde(with = "humantime_serde")]
+    pub lock_stale_after: Duration,
+
+    /// The longest a rebuild will wait, after locking the sync repo, for syncs
+    /// from other hosts that were already in progress to finish. The wait ends
+    /// early once the upstream history stops changing, and 0 disables it.
+    #[serde(default = "default_rebuild_drain")]
+    #[serde(with = "humantime_serde")]
+    pub rebuild_drain: Duration,
+
+    /// Message used for the commits made when syncing, {host}, {count} (the
+    /// number of new entries) and {timestamp} are replaced with their values.
+    #[serde(default = "default_commit_template")]
+    pub commit_template: String,
+
+    /// Sync using a private git worktree (in the state directory) of the
+    /// checkout at path, instead of the checkout itself. This allows path to
+    /// be a checkout that is also used manually, but requires branch to be set
+    /// to a branch that isn't checked out there.
+    #[serde(default)]
+    pub worktree: bool,
+
+    /// A shell command that is run before each background sync, if it exits
+    /// with a non-zero status then the sync is skipped (e.g. to avoid syncing
+    /// on metered networks). New commands are still saved locally. The
+    /// command is killed, and the sync skipped, if it takes longer than 10
+    /// seconds.
+    #[serde(default)]
+    pub precondition_command: String,
+
+    /// The most new entries that will be included in a single commit, if
+    /// there are more (e.g. after a script has run lots of commands) then
+    /// they are committed and pushed in batches. Zero means no limit.
+    #[serde(default)]
+    pub max_entries_per_push: usize,
+
+    /// How many times to pull and retry a push that was rejected because
+    /// another host pushed first, before giving up on the sync.
+    #[serde(default = "default_push_retries")]
+    pub push_retries: u32,
+
+    /// Namespace for the tag used to lock the sync repo during a rebuild, so
+    /// that several independent groups of hosts can share a repo (on
+    /// different branches) without locking each other out. If set, the lock
+    /// is refs/tags/vellum/<namespace>/lock instead of refs/tags/lock.
+    #[serde(default)]
+    pub namespace: String,
+
+    /// Settings for specific hosts (e.g. [sync.hosts.laptop]), which override
+    /// the settings above when the hostname matches.
+    #[serde(default)]
+    pub hosts: BTreeMap<String, SyncHost>,
+
+    /// Extra copies of the synced history (e.g. [[sync.mirrors]]), which are
+    /// updated after each push. A failure to update a mirror is logged, but
+    /// doesn't fail the sync.
+    #[serde(default)]
+    pub mirrors: Vec<SyncMirror>,
+
+    /// Authentication for the WebDAV server, when backend is "webdav"
+    #[serde(default)]
+    pub webdav: WebDav,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncBackend {
+    /// Sync using a git repository.
+    #[default]
+    Git,
+    /// Sync using a directory on a WebDAV server (e.g. Nextcloud).
+    Webdav,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ActiveChunk {
+    /// Write the active chunk to a file in the state directory, so that
+    /// commands that haven't been synced yet survive a restart.
+    #[default]
+    Disk,
+    /// Only keep the active chunk in memory, so commands that haven't been
+    /// synced yet are lost if the server stops (e.g. for ephemeral hosts).
+    Memory,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "aes-128-gcm")]
+    #[value(name = "aes-128-gcm")]
+    Aes128Gcm,
+    /// Faster than AES on hardware without AES instructions.
+    Chacha20Poly1305,
+}
+
+impl Cipher {
+    /// The length of the key (in bytes) that the cipher needs.
+    pub fn key_len(&self) -> usize {
+        match self {
+            Self::Aes256Gcm | Self::Chacha20Poly1305 => 32,
+            Self::Aes128Gcm => 16,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebDav {
+    /// Username for basic auth
+    #[serde(default)]
+    pub username: String,
+
+    /// Password for basic auth
+    #[serde(default)]
+    pub password: String,
+
+    /// Token for bearer auth, used instead of the username and password
+    #[serde(default)]
+    pub token: String,
+
+    /// How long a single request to the server can take before it is given
+    /// up on, 0 means no limit
+    #[serde(default = "default_webdav_timeout")]
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl Default for WebDav {
+    fn default() -> Self {
+        Self {
+            username: String::new(),
+            password: String::new(),
+            token: String::new(),
+            timeout: default_webdav_timeout(),
+        }
+    }
+}
+
+impl WebDav {
+    /// A copy with the password and token replaced, so that it can be shown.
+    pub fn redacted(&self) -> Self {
+        let redact = |secret: &str| match secret.is_empty() {
+            true => String::new(),
+            false => REDACTED.to_string(),
+        };
+        Self {
+            username: self.username.clone(),
+            password: redact(&self.password),
+            token: redact(&self.token),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl fmt::Debug for WebDav {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the config is logged at debug level, so leave out the secrets
+        write!(f, "WebDav{{username: {:?}}}", self.username)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncHost {
+    /// URL of upstream git repository, instead of sync.url
+    #[serde(default)]
+    pub url: Option<String>,
+
+    /// SSH private key file used for SSH git auth, instead of sync.ssh_key
+    #[serde(default)]
+    pub ssh_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncMirror {
+    /// Directory that holds the mirrored history files, non-absolute paths are
+    /// relative to the state directory.
+    pub path: PathBuf,
+
+    /// URL of a git repository to push the mirror to, if not set then the
+    /// mirror is just a local directory.
+    #[serde(default)]
+    pub url: String,
+
+    /// Branch of the mirror git repository to use, if not set then the
+    /// default branch of the repository will be used.
+    #[serde(default)]
+    pub branch: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Server {
+    /// When the server starts, encrypt a test chunk, write it to the state
+    /// directory and read it back, so that a bad key or an unwritable state
+    /// directory stops the server straight away with a clear error.
+    #[serde(default)]
+    pub self_test: bool,
+
+    /// Set to "replica" to only read the history from other hosts, without
+    /// storing any commands or pushing anything (e.g. on a shared bastion
+    /// host).
+    #[serde(default)]
+    pub mode: ServerMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerMode {
+    /// Store commands and sync them with the other hosts.
+    #[default]
+    Normal,
+    /// Pull the history from the other hosts, but never store or push.
+    Replica,
+}
+
+/// The configuration, along with the paths that are derived from it.
+#[derive(Serialize, Debug)]
+struct Resolved<'a> {
+    #[serde(flatten)]
+    config: &'a Config,
+    config_path: Option<&'a PathBuf>,
+    runtime_dir: PathBuf,
+    socket_path: PathBuf,
+    pid_path: PathBuf,
+    log_path: PathBuf,
+    sync_path: PathBuf,
+    sync_url: &'a str,
+    sync_ssh_key: &'a str,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Client {
+    /// How long to wait for the server to respond to a request before giving
+    /// up, zero waits forever. Long running requests (e.g. sync) always wait.
+    #[serde(default = "default_client_timeout")]
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+
+    /// The template used by vellum prompt to show the last command, using the
+    /// same placeholders as vellum history --template.
+    #[serde(default = "default_prompt_template")]
+    pub prompt_template: String,
+
+    /// Keep a copy of the history in the cache directory for vellum move,
+    /// which is only fetched again when the history changes, so that moving
+    /// through a large history is faster. The copy is encrypted with the
+    /// history key.
+    #[serde(default)]
+    pub move_cache: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct History {
+    /// How far in the future another host's entries can be dated before we
+    /// warn about clock skew.
+    #[serde(default = "default_max_clock_skew")]
+    #[serde(with = "humantime_serde")]
+    pub max_clock_skew: Duration,
+
+    /// Environment variables that are hashed and stored along with each
+    /// command, so that commands run with the same environment can be found
+    /// later. The values themselves are never stored.
+    #[serde(default)]
+    pub env_vars: Vec<String>,
+
+    /// If set, then any error reading the history files will stop the history
+    /// from loading, instead of the host or file being skipped with a warning.
+    /// A rebuild always reads strictly, since it would remove anything that
+    /// was skipped.
+    #[serde(default)]
+    pub strict_read: bool,
+
+    /// The timezone to display timestamps in, either "local" or an IANA
+    /// timezone name (e.g. "Europe/London"). Timestamps are always stored in
+    /// UTC, and shown in UTC if this isn't set.
+    #[serde(default)]
+    pub display_tz: Option<String>,
+
+    /// The largest chunk (in bytes) that will be read from a history file,
+    /// anything larger is treated as corruption.
+    #[serde(default = "default_max_chunk_size")]
+    pub max_chunk_size: u64,
+
+    /// How often changes to the active chunk are written to disk. By default
+    /// they are written as soon as a command is stored, setting an interval
+    /// makes storing commands faster, at the cost of losing up to that much
+    /// history if the server crashes.
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub flush_interval: Duration,
+
+    /// Where the active chunk (the commands that haven't been synced yet) is
+    /// kept, "disk" (the default) or "memory". With "memory" the active chunk
+    /// file is never written or read, so storing commands doesn't touch the
+    /// disk, but any commands that haven't been synced are lost when the
+    /// server stops.
+    #[serde(default)]
+    pub active_chunk: ActiveChunk,
+
+    /// Only load history from the last load_window when the server starts
+    /// (e.g. "90d"), older history is left on disk. It isn't shown unless
+    /// load_on_demand is set, and can't be edited or annotated (deleting it
+    /// still works). By default all of the history is loaded.
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub load_window: Duration,
+
+    /// How long to keep history for (e.g. "2y"). Older entries are hidden
+    /// straight away, and are purged from the sync repo (by a rebuild) at
+    /// most once a day, after a sync. By default history is kept forever.
+    #[serde(default)]
+    #[serde(with = "humantime_serde")]
+    pub retention: Duration,
+
+    /// Keep a count of how many times each command has been run, updated as
+    /// commands are stored and saved (encrypted) in the state directory, so
+    /// that `vellum stats` doesn't have to go through the whole history.
+    #[serde(default)]
+    pub frequency_counters: bool,
+
+    /// When load_window is set, read older history from disk for requests
+    /// that reach back past the window (e.g. history --after with an older
+    /// time), without keeping it in memory afterwards.
+    #[serde(default)]
+    pub load_on_demand: bool,
+
+    /// Record the directory each command was run in. If disabled, then an
+    /// empty path is stored instead (this is enforced by the server, whatever
+    /// the client sends).
+    #[serde(default = "default_store_path")]
+    pub store_path: bool,
+
+    /// Only show commands stored by this host in the history command, unless
+    /// --all-hosts or --host is used.
+    #[serde(default)]
+    pub default_host_only: bool,
+
+    /// Commands from this host are put first when commands from several
+    /// hosts have the same timestamp, instead of the order they were stored
+    /// in.
+    #[serde(default)]
+    pub primary_host: Option<String>,
+
+    /// Extra categories for stored commands, mapping a command prefix (e.g.
+    /// "make deploy") to a category. These take precedence over the built-in
+    /// categories.
+    #[serde(default)]
+    pub categories: BTreeMap<String, String>,
+
+    /// Name new history files in the sync repo after a keyed hash of the day,
+    /// instead of the day itself, so that listing the repo doesn't show which
+    /// days each host was active. This makes reading new history slower, as
+    /// files with opaque names can't be skipped based on their name. The git
+    /// commit timestamps still show when each host synced.
+    #[serde(default)]
+    pub opaque_filenames: bool,
+
+    /// Instead of the full command, store a keyed hash of the program that it
+    /// runs, so that how often each program is used can still be seen (e.g.
+    /// with history --duplicates-only), without keeping the command itself.
+    /// This is enforced by the server, and only applies to new commands.
+    #[serde(default)]
+    pub hash_commands: bool,
+
+    /// The AEAD cipher used to encrypt new history chunks, which has to match
+    /// the length of the key (aes-128-gcm needs a 16 byte key, the others a
+    /// 32 byte key). Chunks are always read using the cipher that they were
+    /// written with, so hosts sharing a key can use different ciphers.
+    #[serde(default)]
+    pub cipher: Cipher,
+
+    /// If set, the server also appends each command that it stores to this
+    /// file, as a tab separated line of the timestamp, host, path and
+    /// command, so that it can be followed with tail -f or searched with
+    /// grep. This file is NOT encrypted, and is never synced.
+    #[serde(default)]
+    pub plaintext_log: Option<PathBuf>,
+
+    /// Regular expressions for commands that shouldn't be stored (e.g. ones
+    /// that contain secrets), this is enforced by the server.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+
+    /// Regular expressions for commands that are always stored, even if they
+    /// also match one of the ignore patterns.
+    #[serde(default)]
+    pub always: Vec<String>,
 }
 
 impl Config {
@@ -76,7 +521,22 @@ impl Config {
         Ok(cfg)
     }
 
+    /// Load the config and encryption key from the bundle at path.
+    fn open_bundle<P: AsRef<Path>>(path: P) -> Result {
+        let contents = bundle::read(path, &bundle::passphrase()?)?;
+        history::set_key(history::parse_key(&contents.key)?)?;
+        Ok(toml::from_str(&contents.config)?)
+    }
+
+    /// Load the default config. If VELLUM_BUNDLE is set, then the config (and
+    /// key) come from that bundle. If VELLUM_CONFIG is set, then exactly that
+    /// file is used. Otherwise the user config file (from the XDG config
+    /// directories) is layered on top of the system config file, with settings
+    /// from the user config taking precedence.
     fn open_default() -> Result {
+        if let Some(path) = env::var_os("VELLUM_BUNDLE") {
+            return Self::open_bundle(path);
+        }
         if let Ok(path) = env::var("VELLUM_CONFIG") {
             if fs::exists(&path)? {
                 return Self::open(path);
@@ -85,32 +545,199 @@ impl Config {
         };
 
         let dirs = BaseDirectories::with_prefix("vellum")?;
+        let user = dirs.find_config_file("config.toml");
+        let system = match fs::exists(SYSTEM_CONFIG)? {
+            true => Some(PathBuf::from(SYSTEM_CONFIG)),
+            false => None,
+        };
 
-        match dirs.find_config_file("config.toml") {
-            Some(path) => Self::open(path),
-            None => Ok(Self::default()),
+        match (system, user) {
+            (Some(system), Some(user)) => Self::open_layered(system, user),
+            (None, Some(path)) | (Some(path), None) => Self::open(path),
+            (None, None) => Ok(Self::default()),
         }
     }
 
-    pub fn show(&self) -> crate::error::Result<()> {
-        let cfg = toml::to_string_pretty(self)?;
-        print!("{cfg}");
+    fn open_layered<P: AsRef<Path>, Q: AsRef<Path>>(base: P, path: Q) -> Result {
+        let p = path.as_ref();
+
+        let mut merged: toml::Table = toml::from_str(&fs::read_to_string(base)?)?;
+        let user: toml::Table = toml::from_str(&fs::read_to_string(p)?)?;
+        merge_tables(&mut merged, user);
+
+        let mut cfg: Config = merged.try_into()?;
+        cfg.path = Some(p.to_path_buf());
+        cfg.layered = true;
+
+        Ok(cfg)
+    }
+
+    /// The config file to pass to another vellum process (e.g. the server)
+    /// with --config so that it uses the same config. A layered config is
+    /// left for the other process to find again, since passing the user
+    /// config file would lose the system config underneath it.
+    pub fn config_arg(&self) -> Option<&Path> {
+        match self.layered {
+            true => None,
+            false => self.path.as_deref(),
+        }
+    }
+
+    pub fn show(&self, json: bool) -> crate::error::Result<()> {
+        if json {
+            let resolved = Resolved {
+                config: self,
+                config_path: self.path.as_ref(),
+                runtime_dir: self.runtime_dir(),
+                socket_path: self.socket_path(),
+                pid_path: self.pid_path(),
+                log_path: self.log_path(),
+                sync_path: self.sync_path(),
+                sync_url: self.sync_url(),
+                sync_ssh_key: self.sync_ssh_key(),
+            };
+            println!("{}", serde_json::to_string(&resolved)?);
+        } else {
+            let cfg = toml::to_string_pretty(self)?;
+            print!("{cfg}");
+        }
         Ok(())
     }
 
+    /// The overrides from sync.hosts for this host, if there are any.
+    fn sync_host(&self) -> Option<&SyncHost> {
+        self.sync
+            .hosts
+            .get(self.hostname.to_string_lossy().as_ref())
+    }
+
+    /// The URL of the upstream git repository for this host.
+    pub fn sync_url(&self) -> &str {
+        match self.sync_host().and_then(|host| host.url.as_ref()) {
+            Some(url) => url,
+            None => &self.sync.url,
+        }
+    }
+
+    /// The SSH private key file used for SSH git auth for this host.
+    pub fn sync_ssh_key(&self) -> &str {
+        match self.sync_host().and_then(|host| host.ssh_key.as_ref()) {
+            Some(ssh_key) => ssh_key,
+            None => &self.sync.ssh_key,
+        }
+    }
+
+    /// The config used to sync a mirror, which is the same as this config, but
+    /// with the sync settings replaced by the mirror's.
+    pub fn mirror_config(&self, mirror: &SyncMirror) -> Config {
+        let mut cfg = self.clone();
+        cfg.sync.enabled = !mirror.url.is_empty();
+        cfg.sync.backend = SyncBackend::Git;
+        cfg.sync.url = mirror.url.clone();
+        cfg.sync.branch = mirror.branch.clone();
+        cfg.sync.path = mirror.path.clone();
+        cfg.sync.worktree = false;
+        cfg.sync.hosts = BTreeMap::new();
+        cfg.sync.mirrors = Vec::new();
+        cfg
+    }
+
+    /// The directory that holds the synced history files.
     pub fn sync_path(&self) -> PathBuf {
+        if self.sync.enabled && self.sync.worktree {
+            return self.state_dir.join("sync.worktree");
+        }
+        self.sync_repo_path()
+    }
+
+    /// The git checkout used for sync, which is the same as sync_path unless
+    /// sync.worktree is set.
+    pub fn sync_repo_path(&self) -> PathBuf {
         Path::new(&self.state_dir).join(&self.sync.path)
     }
+
+    /// Directory used for the server socket and pid file. This is
+    /// $XDG_RUNTIME_DIR/vellum when XDG_RUNTIME_DIR is set, so that they are
+    /// cleaned up on logout/reboot. Otherwise, or if state_dir has been
+    /// changed from the default (so that separate configurations don't end up
+    /// sharing a server), the state directory is used. The state directory is
+    /// also used while a server started by an older version (which always put
+    /// its socket there) is still listening, so that it can be found and
+    /// stopped.
+    pub fn runtime_dir(&self) -> PathBuf {
+        if self.state_dir == default_state_dir()
+            && let Some(dir) = env::var_os("XDG_RUNTIME_DIR")
+            && !dir.is_empty()
+        {
+            let dir = Path::new(&dir).join("vellum");
+            let old_socket = self.state_dir.join("server.sock");
+            if !dir.join("server.sock").exists() && old_socket.exists() {
+                return self.state_dir.clone();
+            }
+            return dir;
+        }
+        self.state_dir.clone()
+    }
+
+    pub fn socket_path(&self) -> PathBuf {
+        self.runtime_dir().join("server.sock")
+    }
+
+    pub fn pid_path(&self) -> PathBuf {
+        self.runtime_dir().join("server.pid")
+    }
+
+    /// File that the server writes the progress of the initial clone of the
+    /// sync repo to, so that clients waiting for it can show the progress.
+    pub fn clone_progress_path(&self) -> PathBuf {
+        self.runtime_dir().join("clone.progress")
+    }
+
+    pub fn log_path(&self) -> PathBuf {
+        Path::new(&self.state_dir).join("server.log")
+    }
+}
+
+/// Merge the values from `overlay` into `base`, with values from `overlay`
+/// taking precedence. Tables are merged recursively, all other values
+/// (including arrays) are replaced.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             path: None,
+            layered: false,
             cache_dir: default_cache_dir(),
             state_dir: default_state_dir(),
             hostname: default_hostname(),
+            max_message_size: default_max_message_size(),
             sync: Sync::default(),
+            history: History::default(),
+            client: Client::default(),
+            server: Server::default(),
+            queries: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self {
+            timeout: default_client_timeout(),
+            prompt_template: default_prompt_template(),
+            move_cache: false,
         }
     }
 }
@@ -119,11 +746,53 @@ impl Default for Sync {
     fn default() -> Self {
         Self {
             enabled: default_sync_enabled(),
+            backend: SyncBackend::Git,
             url: "".to_string(),
             ssh_key: "".to_string(),
+            branch: "".to_string(),
             interval: default_sync_interval(),
             watchdog_timeout: default_watchdog_timeout(),
             path: default_sync_path(),
+            lock_timeout: default_lock_timeout(),
+            lock_stale_after: default_lock_stale_after(),
+            rebuild_drain: default_rebuild_drain(),
+            commit_template: default_commit_template(),
+            worktree: false,
+            precondition_command: String::new(),
+            max_entries_per_push: 0,
+            push_retries: default_push_retries(),
+            namespace: String::new(),
+            hosts: BTreeMap::new(),
+            mirrors: Vec::new(),
+            webdav: WebDav::default(),
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            max_clock_skew: default_max_clock_skew(),
+            env_vars: Vec::new(),
+            strict_read: false,
+            display_tz: None,
+            max_chunk_size: default_max_chunk_size(),
+            flush_interval: Duration::ZERO,
+            active_chunk: ActiveChunk::default(),
+            load_window: Duration::ZERO,
+            retention: Duration::ZERO,
+            frequency_counters: false,
+            load_on_demand: false,
+            store_path: default_store_path(),
+            default_host_only: false,
+            primary_host: None,
+            categories: BTreeMap::new(),
+            opaque_filenames: false,
+            hash_commands: false,
+            cipher: Cipher::default(),
+            plaintext_log: None,
+            ignore: Vec::new(),
+            always: Vec::new(),
         }
     }
 }
@@ -149,6 +818,10 @@ fn default_hostname() -> PathBuf {
     }
 }
 
+fn default_max_message_size() -> u64 {
+    256 * 1024 * 1024
+}
+
 fn default_sync_enabled() -> bool {
     true
 }
@@ -161,6 +834,50 @@ fn default_watchdog_timeout() -> Duration {
     Duration::from_secs(300)
 }
 
+fn default_client_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_prompt_template() -> String {
+    "{cmd}".to_string()
+}
+
+fn default_webdav_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_lock_timeout() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_lock_stale_after() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_push_retries() -> u32 {
+    3
+}
+
+fn default_rebuild_drain() -> Duration {
+    Duration::from_secs(5)
+}
+
 fn default_sync_path() -> PathBuf {
     Path::new("sync").into()
 }
+
+fn default_commit_template() -> String {
+    "update {host}".to_string()
+}
+
+fn default_store_path() -> bool {
+    true
+}
+
+fn default_max_clock_skew() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_max_chunk_size() -> u64 {
+    64 * 1024 * 1024
+}
@@ -12,6 +12,8 @@ use aws_lc_rs::error::{KeyRejected, Unspecified};
 use base64::DecodeError;
 use xdg::BaseDirectoriesError;
 
+use crate::api::CURRENT_API_VERSION;
+
 #[derive(Debug)]
 pub enum Error {
     Daemon(i32),
@@ -33,12 +35,42 @@ pub enum Error {
     ParseTime(chrono::ParseError),
     Rounding(chrono::RoundingError),
     ApiVersion(u32),
+    Keyring(keyring::Error),
+    Kdf(argon2::Error),
 }
 
 impl Error {
     pub fn from_str(s: &str) -> Self {
         Self::Generic(s.to_string())
     }
+
+    /// A stable name for the type of error, for use in machine readable
+    /// output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Daemon(_) => "daemon",
+            Self::IO(_) => "io",
+            Self::Encoding(_) => "encoding",
+            Self::Encode(_) => "encode",
+            Self::Decode(_) => "decode",
+            Self::Parse(_) => "parse",
+            Self::Format(_) => "format",
+            Self::Lookup(_) => "lookup",
+            Self::Generic(_) => "generic",
+            Self::CryptKey(_) => "crypt_key",
+            Self::Crypt => "crypt",
+            Self::Git(_) => "git",
+            Self::Base64(_) => "base64",
+            Self::EnvVar(_) => "env_var",
+            Self::Uuid(_) => "uuid",
+            Self::ParseInt(_) => "parse_int",
+            Self::ParseTime(_) => "parse_time",
+            Self::Rounding(_) => "rounding",
+            Self::ApiVersion(_) => "api_version",
+            Self::Keyring(_) => "keyring",
+            Self::Kdf(_) => "kdf",
+        }
+    }
 }
 
 impl Display for Error {
@@ -62,7 +94,11 @@ impl Display for Error {
             Self::ParseInt(e) => write!(f, "PARSE INT ERROR: {e}"),
             Self::ParseTime(e) => write!(f, "PARSE TIME ERROR: {e}"),
             Self::Rounding(e) => write!(f, "ROUNDING ERROR: {e}"),
-            Self::ApiVersion(v) => write!(f, "WRONG API VERSION: {v}"),
+            Self::ApiVersion(v) => {
+                write!(f, "Wrong API Version {v} (wanted {CURRENT_API_VERSION})")
+            }
+            Self::Keyring(e) => write!(f, "KEYRING ERROR: {e}"),
+            Self::Kdf(e) => write!(f, "KEY DERIVATION ERROR: {e}"),
         }
     }
 }
@@ -89,6 +125,8 @@ impl error::Error for Error {
             Self::ParseTime(e) => Some(e),
             Self::Rounding(e) => Some(e),
             Self::ApiVersion(_) => None,
+            Self::Keyring(e) => Some(e),
+            Self::Kdf(e) => Some(e),
         }
     }
 }
@@ -202,3 +240,15 @@ impl From<chrono::RoundingError> for Error {
 }
 
 pub type Result<T> = result::Result<T, Error>;
+
+impl From<keyring::Error> for Error {
+    fn from(value: keyring::Error) -> Self {
+        Self::Keyring(value)
+    }
+}
+
+impl From<argon2::Error> for Error {
+    fn from(value: argon2::Error) -> Self {
+        Self::Kdf(value)
+    }
+}
@@ -0,0 +1,61 @@
+use std::{collections::BTreeMap, path::Path};
+
+/// The built-in categories, keyed by the program that is run.
+const CATEGORIES: &[(&str, &[&str])] = &[
+    ("git", &["git", "gh", "gitk", "hub", "tig"]),
+    (
+        "docker",
+        &["docker", "docker-compose", "podman", "buildah", "nerdctl"],
+    ),
+    (
+        "editing",
+        &[
+            "vi", "vim", "nvim", "nano", "emacs", "hx", "micro", "code", "ed",
+        ],
+    ),
+    (
+        "navigation",
+        &["cd", "ls", "ll", "la", "pwd", "pushd", "popd", "z", "tree"],
+    ),
+    (
+        "package-mgmt",
+        &[
+            "apt", "apt-get", "dnf", "yum", "zypper", "pacman", "apk", "brew", "snap", "flatpak",
+            "nix-env", "npm", "pnpm", "yarn", "pip", "pip3", "pipx", "gem",
+        ],
+    ),
+];
+
+const OTHER: &str = "other";
+
+/// Work out the category of a command. Categories from the config (keyed by
+/// command prefix) take precedence, with the longest matching prefix winning,
+/// otherwise the built-in categories are used based on the program being run.
+pub fn classify(cmd: &str, categories: &BTreeMap<String, String>) -> String {
+    let cmd = cmd.trim_start();
+    if let Some((_, category)) = categories
+        .iter()
+        .filter(|(prefix, _)| cmd.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+    {
+        return category.clone();
+    }
+
+    let Some(program) = program(cmd) else {
+        return OTHER.to_string();
+    };
+    CATEGORIES
+        .iter()
+        .find(|(_, programs)| programs.contains(&program))
+        .map_or(OTHER, |(category, _)| category)
+        .to_string()
+}
+
+/// Find the name of the program run by a command, skipping any leading
+/// variable assignments and sudo.
+pub fn program(cmd: &str) -> Option<&str> {
+    let word = cmd
+        .split_whitespace()
+        .find(|word| !word.contains('=') && *word != "sudo")?;
+    Path::new(word).file_name()?.to_str()
+}
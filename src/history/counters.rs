@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+use super::{Entry, store::Store};
+
+const PURPOSE: &str = "vellum command counters";
+
+/// How many times a command has been run, and when it was last run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommandCount {
+    pub cmd: String,
+    pub count: usize,
+    pub last: DateTime<Utc>,
+}
+
+/// The number of times each command in the merged history has been run, kept
+/// up to date as commands are stored (if history.frequency_counters is set),
+/// so that the most frequent commands can be found without going through the
+/// whole history.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(super) struct Counters {
+    // the number of records and merged entries that the counts were made
+    // from, so that saved counters that don't match the history can be
+    // spotted and thrown away.
+    records: usize,
+    entries: usize,
+    counts: HashMap<String, (usize, DateTime<Utc>)>,
+}
+
+impl Counters {
+    pub(super) fn count(entries: &[Entry], records: usize) -> Self {
+        let mut counters = Self {
+            records,
+            ..Default::default()
+        };
+        for entry in entries {
+            counters.add(entry);
+        }
+        // add counts each entry as a new record too
+        counters.records = records;
+        counters
+    }
+
+    /// Count a newly stored entry.
+    pub(super) fn add(&mut self, entry: &Entry) {
+        self.records += 1;
+        self.entries += 1;
+        self.increment(entry);
+    }
+
+    /// Count a record that changed an entry in the merged history, from old
+    /// to new (None if the entry wasn't in the merged history before, or has
+    /// been deleted). entries is the number of merged entries afterwards.
+    ///
+    /// The last time a command was run isn't moved back when an entry is
+    /// removed, since that would mean searching the history for it.
+    pub(super) fn update(&mut self, old: Option<&Entry>, new: Option<&Entry>, entries: usize) {
+        self.records += 1;
+        self.entries = entries;
+        if let Some(old) = old {
+            self.decrement(old);
+        }
+        if let Some(new) = new {
+            self.increment(new);
+        }
+    }
+
+    fn increment(&mut self, entry: &Entry) {
+        if entry.cmd.is_empty() {
+            return;
+        }
+        let (count, last) = self
+            .counts
+            .entry(entry.cmd.clone())
+            .or_insert((0, entry.ts));
+        *count += 1;
+        *last = (*last).max(entry.ts);
+    }
+
+    fn decrement(&mut self, entry: &Entry) {
+        if let Some((count, _)) = self.counts.get_mut(&entry.cmd) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&entry.cmd);
+            }
+        }
+    }
+
+    /// Were these counts made from a history with the given number of records
+    /// and merged entries?
+    pub(super) fn matches(&self, records: usize, entries: usize) -> bool {
+        self.records == records && self.entries == entries
+    }
+
+    /// The most frequently run commands, most frequent first (and most recent
+    /// first for the same count).
+    pub(super) fn top(&self, n: usize) -> Vec<CommandCount> {
+        let mut counts: Vec<_> = self.counts.iter().collect();
+        counts.sort_by(|(_, (a, a_last)), (_, (b, b_last))| b.cmp(a).then(b_last.cmp(a_last)));
+        counts
+            .into_iter()
+            .take(n)
+            .map(|(cmd, (count, last))| CommandCount {
+                cmd: cmd.clone(),
+                count: *count,
+                last: *last,
+            })
+            .collect()
+    }
+
+    /// Read counters saved by save, returns None if there aren't any (or they
+    /// can't be read, since they can always be counted again).
+    pub(super) fn load(path: &Path, store: &Store) -> Option<Self> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read command counters {path:?}: {e}");
+                return None;
+            }
+        };
+        let counters = store
+            .decrypt_data(&data, PURPOSE)
+            .and_then(|data| Ok(rmp_serde::from_slice(&data)?));
+        match counters {
+            Ok(counters) => Some(counters),
+            Err(e) => {
+                warn!("Ignoring invalid command counters {path:?}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Save the counters encrypted with the history key, since they contain
+    /// the commands. The file is written alongside and renamed into place.
+    pub(super) fn save(&self, path: &Path, store: &Store) -> Result<()> {
+        debug!("save command counters to {path:?}");
+        let data = store.encrypt_data(rmp_serde::to_vec(self)?, PURPOSE)?;
+        let tmp = path.with_extension("tmp");
+        let mut f = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        f.write_all(&data)?;
+        f.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
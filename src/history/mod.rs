@@ -1,78 +1,570 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    path::Path,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs, mem,
+    path::{Path, PathBuf},
     time::Duration,
 };
 
-use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use chrono::{DateTime, DurationRound, NaiveDate, TimeDelta, Utc};
+use humantime::format_duration;
 use itertools::Itertools;
-use log::{debug, error};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::error::{Error, Result};
+use crate::{
+    config::{self, ActiveChunk, Config},
+    error::{Error, Result},
+};
 
+mod category;
+mod counters;
 mod store;
 
-use store::{Chunk, Store};
-pub use store::{Entry, generate_key, get_key};
+pub use counters::CommandCount;
+use counters::Counters;
+
+pub use store::{BadChunk, Entry, generate_key, get_key, parse_key, set_key, set_keyring_key};
+use store::{Chunk, Store, decrypt_dir, decrypt_file, list_files};
+
+/// The purpose used to encrypt the commands saved by save_queued.
+const QUEUED_PURPOSE: &str = "vellum queued commands";
+const CACHE_PURPOSE: &str = "vellum history cache";
+
+/// How far before the start of a requested range older history is read from,
+/// since a chunk can hold entries from after its start time.
+const LOAD_MARGIN: TimeDelta = TimeDelta::days(1);
+
+/// A summary of the changes from a single host that have not yet been merged.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HostChanges {
+    pub new: usize,
+    pub updated: usize,
+    pub deleted: usize,
+}
+
+/// How much space a single host is using in the sync repo.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HostUsage {
+    pub files: usize,
+    pub bytes: u64,
+    pub records: usize,
+    pub unreadable: usize,
+    /// The timestamps of the oldest and newest records stored by the host
+    #[serde(default)]
+    pub oldest: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub newest: Option<DateTime<Utc>>,
+}
+
+/// An analysis of the sync repo, used to work out what a rebuild would save.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Analysis {
+    pub hosts: BTreeMap<String, HostUsage>,
+    /// Number of distinct entries (i.e. after updates have been collapsed)
+    pub entries: usize,
+    /// Number of distinct entries that have been deleted
+    pub deleted: usize,
+    /// Size of the whole sync repo on disk, including the git data
+    #[serde(default)]
+    pub repo_bytes: u64,
+}
+
+impl Analysis {
+    pub fn records(&self) -> usize {
+        self.hosts.values().map(|usage| usage.records).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.hosts.values().map(|usage| usage.bytes).sum()
+    }
+
+    /// The number of entries that would be kept by a rebuild.
+    pub fn live(&self) -> usize {
+        self.entries - self.deleted
+    }
+
+    /// A rough estimate of the size of the history files after a rebuild,
+    /// assuming that every record takes up the same amount of space.
+    pub fn estimated_bytes(&self) -> u64 {
+        match self.records() {
+            0 => 0,
+            records => self.bytes() * self.live() as u64 / records as u64,
+        }
+    }
+}
+
+/// A summary of the changes made by rewriting the history files.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RebuildSummary {
+    /// Number of records stored before the rebuild (including updates and
+    /// deletions)
+    pub records_before: usize,
+    /// Number of entries kept by the rebuild
+    pub entries_after: usize,
+    /// Number of deleted entries that were purged
+    pub deleted: usize,
+    /// Number of chunks written for each host
+    pub chunks: BTreeMap<String, usize>,
+    /// The commit that the rewritten files were pushed in, if any
+    pub commit: Option<String>,
+    /// Number of entries that had their path rewritten
+    #[serde(default)]
+    pub paths_rewritten: usize,
+}
+
+/// A change to the path of stored entries, e.g. after a directory has been
+/// moved.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PathRewrite {
+    pub from: String,
+    pub to: String,
+    /// Also rewrite paths below from, not just from itself
+    pub prefix: bool,
+    /// Only rewrite entries stored by this host
+    pub host: Option<String>,
+}
+
+impl PathRewrite {
+    /// The new path for an entry, if it should be rewritten.
+    fn apply(&self, entry: &Entry) -> Option<String> {
+        if self.host.as_ref().is_some_and(|host| host != &entry.host) {
+            return None;
+        }
+        let (from, to) = (trim_path(&self.from), trim_path(&self.to));
+        if entry.path == from {
+            return Some(to.to_string());
+        }
+        if !self.prefix {
+            return None;
+        }
+        // only match whole path components, so /code doesn't match /codebase
+        let rest = entry.path.strip_prefix(from)?;
+        if !rest.starts_with('/') {
+            return None;
+        }
+        Some(match to {
+            "/" => rest.to_string(),
+            to => format!("{to}{rest}"),
+        })
+    }
+}
+
+/// Remove any trailing slash from a path (other than the root directory).
+fn trim_path(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    }
+}
 
 #[derive(Debug)]
 pub struct History {
     host: String,
+    cfg: config::History,
     store: Store,
     history: HashMap<String, Vec<Chunk>>,
     merged: Vec<Entry>,
+    // the IDs of the entries in merged, so that they can be looked up quickly
+    merged_ids: HashSet<Uuid>,
+    seqs: HashMap<String, u64>,
     last_write: DateTime<Utc>,
+    // the active chunk has changed since it was last written to disk
+    dirty: bool,
+    // only history from within the load window has been read
+    windowed: bool,
+    // the start of the load window, chunks from before this haven't been read
+    window_start: DateTime<Utc>,
+    // all of the history has been read with strict reads, so nothing was
+    // skipped, and it can be used to rewrite the history files
+    complete: bool,
+    // the command counts, if history.frequency_counters is set
+    counters: Option<Counters>,
+    // the counters saved by the last server, until the history has been read
+    saved_counters: Option<Counters>,
+    counters_path: PathBuf,
+    // changes whenever the merged history does, so that clients can tell if
+    // their copy is out of date (it starts from a random value, so that it
+    // doesn't repeat when the server is restarted)
+    version: u64,
 }
 
 impl History {
-    fn new<H: Into<String>, S: AsRef<Path>>(host: H, state: S) -> Result<Self> {
+    fn new(cfg: &Config) -> Result<Self> {
         Ok(Self {
-            host: host.into(),
-            store: Store::new(state)?,
+            host: cfg.hostname.to_string_lossy().to_string(),
+            cfg: cfg.history.clone(),
+            store: Store::new(cfg)?,
             history: HashMap::new(),
             merged: Vec::new(),
+            merged_ids: HashSet::new(),
+            seqs: HashMap::new(),
             last_write: Utc::now(),
+            dirty: false,
+            windowed: !cfg.history.load_window.is_zero(),
+            window_start: Utc::now() - cfg.history.load_window,
+            complete: false,
+            counters: None,
+            saved_counters: None,
+            counters_path: cfg.state_dir.join("counters.bin"),
+            version: rand::random(),
         })
     }
 
-    pub fn load<H: Into<String>, S: AsRef<Path>, P: AsRef<Path>>(
-        host: H,
-        state: S,
-        path: P,
-    ) -> Result<Self> {
-        let mut s = Self::new(host, state)?;
+    pub fn load<P: AsRef<Path>>(cfg: &Config, path: P) -> Result<Self> {
+        let mut s = Self::new(cfg)?;
+        if s.cfg.frequency_counters {
+            s.saved_counters = Counters::load(&s.counters_path, &s.store);
+        }
+        // the counters used to be saved unencrypted
+        let plaintext = cfg.state_dir.join("counters.json");
+        if plaintext.try_exists()? {
+            fs::remove_file(plaintext)?;
+        }
         s.read(path)?;
-        s.read_active_chunk()?;
+        match s.cfg.active_chunk {
+            ActiveChunk::Disk => s.read_active_chunk()?,
+            ActiveChunk::Memory if s.store.state_path().try_exists()? => warn!(
+                "history.active_chunk is \"memory\", ignoring the active chunk file {:?}",
+                s.store.state_path()
+            ),
+            ActiveChunk::Memory => (),
+        }
+        s.add_saved_queue(&queued_path(cfg))?;
+        if s.cfg.frequency_counters && s.counters.is_none() {
+            // there wasn't any history to read, but the counters still need
+            // to be kept up to date from now on.
+            s.counters = Some(s.count_commands());
+        }
+        // the saved counters are only any use when the history hasn't changed
+        // since they were saved.
+        s.saved_counters = None;
         Ok(s)
     }
 
-    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        self.write(path)?;
-        self.write_active_chunk();
+    /// Add the commands saved by save_queued, if there are any.
+    fn add_saved_queue(&mut self, path: &Path) -> Result<()> {
+        let entries = read_queued(path, &self.store)?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Adding {} commands that were queued at shutdown",
+            entries.len()
+        );
+        for entry in entries {
+            if !self.contains(entry.id) {
+                self.add_entry(entry);
+            }
+        }
+        self.flush();
+        fs::remove_file(path)?;
         Ok(())
     }
 
-    pub fn sync<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        self.write(path.as_ref())?;
-        self.write_active_chunk();
-        self.read(path.as_ref())
+    /// Read the merged history from a copy of the sync directory (e.g. one
+    /// written by Syncer::snapshot), ignoring the load window and without
+    /// touching the active chunk.
+    pub fn read_snapshot<P: AsRef<Path>>(cfg: &Config, path: P) -> Result<Vec<Entry>> {
+        let mut s = Self::new(cfg)?;
+        s.windowed = false;
+        s.read(path)?;
+        Ok(s.merged)
+    }
+
+    /// Write out any new entries to the sync path, returning the number of
+    /// entries written.
+    pub fn save<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let written = self.write(path)?;
+        // the active chunk has just been written out, so the state file has to
+        // be updated straight away, even if writes are being deferred.
+        self.dirty = true;
+        self.flush();
+        Ok(written)
+    }
+
+    /// Like save, but only writes out up to max new entries (all of them if max
+    /// is zero), so that they can be committed in batches. Returns the number
+    /// of entries written, and whether there are more left to write.
+    pub fn save_batch<P: AsRef<Path>>(&mut self, path: P, max: usize) -> Result<(usize, bool)> {
+        if max == 0 {
+            return Ok((self.save(path)?, false));
+        }
+        let last_write = self.last_write;
+        let Some(chunks) = self.history.get_mut(&self.host) else {
+            return Ok((0, false));
+        };
+        split_chunks(chunks, last_write, max);
+
+        // take whole chunks until the batch is full, but always at least one.
+        let mut entries = 0;
+        let mut end = None;
+        for (i, chunk) in chunks.iter().enumerate() {
+            if chunk.start <= last_write {
+                continue;
+            }
+            if end.is_some() && entries + chunk.len() > max {
+                break;
+            }
+            entries += chunk.len();
+            end = Some(i);
+        }
+        let Some(end) = end else {
+            return Ok((0, false));
+        };
+        let more = chunks[end + 1..]
+            .iter()
+            .any(|chunk| chunk.start > last_write);
+        let batch_end = chunks[end].start;
+
+        let written = self
+            .store
+            .write_chunks(path, &self.host, &chunks[..=end], last_write)?;
+
+        // the chunks after the batch are still unwritten, so they stay in the
+        // active chunk file until they are written too.
+        self.last_write = match more {
+            true => batch_end,
+            false => Utc::now(),
+        };
+        self.dirty = true;
+        self.flush();
+        Ok((written, more))
+    }
+
+    /// Like save, but also reads in any changes from other hosts.
+    pub fn sync<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+        let written = self.write(path.as_ref())?;
+        self.dirty = true;
+        self.flush();
+        self.read(path.as_ref())?;
+        Ok(written)
+    }
+
+    /// Read in any changes from other hosts, without writing out our own.
+    pub fn pull<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.read(path)
+    }
+
+    /// Write the active chunk to disk, if it has changed since it was last
+    /// written (and it isn't only being kept in memory).
+    pub fn flush(&mut self) {
+        if !self.dirty || self.cfg.active_chunk == ActiveChunk::Memory {
+            return;
+        }
+        match self.store.write_state(&self.unwritten_chunks(), &self.host) {
+            Ok(()) => self.dirty = false,
+            Err(e) => error!("Failed to write active chunk: {e}"),
+        }
     }
 
     pub fn history(&self) -> Vec<Entry> {
         self.merged.clone()
     }
 
+    /// The current version of the merged history, which changes whenever the
+    /// history does.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Whether the merged history has an entry with the given ID.
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.merged_ids.contains(&id)
+    }
+
+    /// The number of entries in the merged history.
+    pub fn len(&self) -> usize {
+        self.merged.len()
+    }
+
+    /// The history for a request that only wants the entries from after and/or
+    /// before the given times. If only the load window has been read, and the
+    /// request reaches back past it, then (if history.load_on_demand is set)
+    /// the older history is read from disk for just this request, rather than
+    /// being kept in memory.
+    pub fn history_between<P: AsRef<Path>>(
+        &self,
+        path: P,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Entry>> {
+        let reaches_back = match (after, before) {
+            (Some(after), _) => after < self.window_start,
+            (None, Some(before)) => before <= self.window_start,
+            (None, None) => false,
+        };
+        if !self.windowed || !self.cfg.load_on_demand || !reaches_back {
+            return Ok(self.history());
+        }
+
+        let from = after
+            .and_then(|after| after.checked_sub_signed(LOAD_MARGIN))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let until = before.map_or(self.window_start, |before| before.min(self.window_start));
+        debug!("load history from {from} to {until} on demand");
+
+        let mut older = Vec::new();
+        for entry in self.store.get_hosts(path)? {
+            let (host, path) = match entry {
+                Ok(entry) => entry,
+                Err(e) if !self.cfg.strict_read => {
+                    warn!("Skipping unreadable host directory: {e}");
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            match self
+                .store
+                .read_chunk_range(path, &host, from, Some(until), self.cfg.strict_read)
+            {
+                Ok(chunks) => older.extend(chunks),
+                Err(e) if !self.cfg.strict_read => {
+                    warn!("Skipping history for host {host}: {e}");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        debug!("loaded {} older chunks", older.len());
+
+        Ok(merge(
+            older.iter().chain(self.history.values().flatten()),
+            self.cfg.primary_host.as_deref(),
+        ))
+    }
+
+    /// Every record stored for the given ID (i.e. the original entry, and any
+    /// updates or deletions from any host), oldest first.
+    pub fn entry_history<I: Into<Uuid>>(&self, id: I) -> Vec<Entry> {
+        let id = id.into();
+        self.history
+            .values()
+            .flatten()
+            .flat_map(|chunk| chunk.entries.iter())
+            .filter(|entry| entry.id == id)
+            .cloned()
+            .sorted()
+            .collect()
+    }
+
+    /// The entries that have been deleted but not yet removed by a rebuild,
+    /// with the command from before they were deleted (if the record with the
+    /// command is still around), sorted by time.
+    pub fn deleted(&self) -> Vec<Entry> {
+        let mut records: BTreeMap<Uuid, Vec<&Entry>> = BTreeMap::new();
+        for entry in self
+            .history
+            .values()
+            .flatten()
+            .flat_map(|chunk| chunk.entries.iter())
+        {
+            records.entry(entry.id).or_default().push(entry);
+        }
+        records
+            .into_values()
+            .filter(|records| records.iter().any(|entry| entry.cmd.is_empty()))
+            .map(|records| {
+                let cmd = records
+                    .iter()
+                    .sorted()
+                    .rev()
+                    .map(|entry| &entry.cmd)
+                    .find(|cmd| !cmd.is_empty())
+                    .cloned()
+                    .unwrap_or_default();
+                Entry {
+                    cmd,
+                    ..collapse_entries(records.into_iter().cloned().collect())
+                }
+            })
+            .sorted_by(|a, b| a.ts.cmp(&b.ts).then(a.id.cmp(&b.id)))
+            .collect()
+    }
+
+    /// The most recent entry in the history, along with its index, optionally
+    /// only looking at the given session.
+    pub fn last(&self, session: Option<&str>) -> Option<(usize, Entry)> {
+        self.merged
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, entry)| session.is_none_or(|session| entry.session == session))
+            .map(|(index, entry)| (index, entry.clone()))
+    }
+
+    /// The distinct hosts that have stored the current history, sorted.
+    pub fn hosts(&self) -> Vec<String> {
+        self.merged
+            .iter()
+            .map(|entry| &entry.host)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Add a new command to the history. If the caller supplies the ID, and
+    /// there is already an entry with that ID, then this is a retry of a store
+    /// that has already been done, so nothing is added.
+    #[allow(clippy::too_many_arguments)]
     pub fn add<C: Into<String>, P: Into<String>, S: Into<String>>(
         &mut self,
         cmd: C,
         path: P,
         session: S,
-    ) {
-        let entry = Entry::new(&self.host, cmd, path, session);
+        env_hash: Option<String>,
+        uid: Option<u32>,
+        shell: Option<String>,
+        id: Option<Uuid>,
+    ) -> Uuid {
+        let mut entry = match id {
+            Some(id) if self.merged_ids.contains(&id) => {
+                debug!("command {id} has already been stored");
+                return id;
+            }
+            Some(id) => Entry::existing(id, &self.host, cmd, path, session),
+            None => Entry::new(&self.host, cmd, path, session),
+        };
+        entry.env_hash = env_hash;
+        entry.uid = uid;
+        entry.shell = shell;
+        self.add_entry(entry)
+    }
+
+    /// Add a new entry that was created earlier (e.g. whilst the history was
+    /// busy), the session sequence number is assigned now. The path is
+    /// dropped here if history.store_path is disabled.
+    pub fn add_entry(&mut self, mut entry: Entry) -> Uuid {
+        if !self.cfg.store_path {
+            entry.path.clear();
+        }
+        entry.category = Some(category::classify(&entry.cmd, &self.cfg.categories));
+        let seq = self.seqs.entry(entry.session.clone()).or_default();
+        *seq += 1;
+        entry.seq = Some(*seq);
+        self.hash_command(&mut entry);
+        let id = entry.id;
         self.get_active_chunk().push(entry.clone());
+        if let Some(counters) = &mut self.counters {
+            counters.add(&entry);
+        }
+        self.merged_ids.insert(entry.id);
         self.merged.push(entry);
+        self.changed();
         self.write_active_chunk();
+        id
+    }
+
+    /// Replace the command with its hash, if history.hash_commands is set. The
+    /// category is worked out from the full command first, so it is kept.
+    /// Deletions (empty commands) are left alone.
+    fn hash_command(&self, entry: &mut Entry) {
+        if entry.cmd.is_empty() {
+            return;
+        }
+        if let Some(hash) = self.store.hash_command(&entry.cmd) {
+            entry.cmd = hash;
+        }
     }
 
     pub fn update<I: Into<Uuid>, C: Into<String>, S: Into<String>>(
@@ -81,13 +573,120 @@ impl History {
         cmd: C,
         session: S,
     ) -> Result<()> {
-        let id = id.into();
-        if !self.merged.iter().any(|entry| entry.id == id) {
-            return Err(Error::Generic(format!("unknown ID: {id}")));
+        self.update_entry(id.into(), cmd.into(), None, session.into())
+    }
+
+    /// Update the command for an entry, and the path that it was run in. An
+    /// empty path removes the path, any other path is dropped (leaving the
+    /// path unchanged) if history.store_path is disabled.
+    pub fn update_with_path<I, C, P, S>(&mut self, id: I, cmd: C, path: P, session: S) -> Result<()>
+    where
+        I: Into<Uuid>,
+        C: Into<String>,
+        P: Into<String>,
+        S: Into<String>,
+    {
+        self.update_entry(id.into(), cmd.into(), Some(path.into()), session.into())
+    }
+
+    /// Update the command for an entry, and the path unless it is None.
+    fn update_entry(
+        &mut self,
+        id: Uuid,
+        cmd: String,
+        path: Option<String>,
+        session: String,
+    ) -> Result<()> {
+        let path = match path {
+            Some(path) if !path.is_empty() && !self.cfg.store_path => {
+                warn!("history.store_path is disabled, not changing the path of {id}");
+                None
+            }
+            path => path,
+        };
+        let mut entry = Entry::existing(id, &self.host, cmd, "", session);
+        if let Some(path) = path {
+            entry.path_cleared = path.is_empty();
+            entry.path = path;
+        }
+        if !entry.cmd.is_empty() {
+            entry.category = Some(category::classify(&entry.cmd, &self.cfg.categories));
+        }
+        self.hash_command(&mut entry);
+        if !self.merged_ids.contains(&id) {
+            if !entry.cmd.is_empty() {
+                return Err(self.unknown_id(id));
+            }
+            // we allow deleting unknown IDs, since the entry may belong to
+            // another host that we haven't synced with yet. The tombstone will
+            // hide the entry if it turns up later.
+            warn!("deleting unknown ID: {id}");
         }
-        let entry = Entry::existing(id, &self.host, cmd, "", session);
         self.get_active_chunk().push(entry);
-        self.rebuild_merged();
+        self.rebuild_merged_for(id);
+        self.write_active_chunk();
+        Ok(())
+    }
+
+    /// The error for an ID that isn't in the merged history, which may be
+    /// because it is from before the load window.
+    fn unknown_id(&self, id: Uuid) -> Error {
+        match self.windowed {
+            true => Error::Generic(format!(
+                "unknown ID: {id} (it may be from before history.load_window, which hasn't been loaded)"
+            )),
+            false => Error::Generic(format!("unknown ID: {id}")),
+        }
+    }
+
+    /// Attach a note to an entry, by adding a record for it that carries the
+    /// current command along with the note. An empty note removes any
+    /// existing note.
+    pub fn annotate<I: Into<Uuid>, N: Into<String>, S: Into<String>>(
+        &mut self,
+        id: I,
+        note: N,
+        session: S,
+    ) -> Result<()> {
+        let id = id.into();
+        let current = self
+            .merged
+            .iter()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| self.unknown_id(id))?;
+        // the command can't be left empty, since that would delete the entry,
+        // but it is ignored when the records are collapsed.
+        let mut entry = Entry::existing(id, &self.host, current.cmd.clone(), "", session);
+        entry.category = current.category.clone();
+        entry.note = Some(note.into());
+        self.get_active_chunk().push(entry);
+        self.rebuild_merged_for(id);
+        self.write_active_chunk();
+        Ok(())
+    }
+
+    /// Record the exit status of a command, and how long it took to run.
+    pub fn finished<I: Into<Uuid>, S: Into<String>>(
+        &mut self,
+        id: I,
+        exit: i32,
+        duration_ms: u64,
+        session: S,
+    ) -> Result<()> {
+        let id = id.into();
+        let current = self
+            .merged
+            .iter()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| self.unknown_id(id))?;
+        // as with annotate, the command is ignored when the records are
+        // collapsed.
+        let mut entry = Entry::existing(id, &self.host, current.cmd.clone(), "", session);
+        entry.category = current.category.clone();
+        entry.exit = Some(exit);
+        entry.duration_ms = Some(duration_ms);
+        self.get_active_chunk().push(entry);
+        self.rebuild_merged_for(id);
         self.write_active_chunk();
         Ok(())
     }
@@ -107,6 +706,14 @@ impl History {
 
         let host = self.host.clone();
 
+        let entries: Vec<Entry> = entries
+            .into_iter()
+            .map(|mut entry| {
+                self.hash_command(&mut entry);
+                entry
+            })
+            .collect();
+
         let active = self.get_active_chunk();
         let before = active.len();
 
@@ -137,28 +744,156 @@ impl History {
         // whilst we are throwing all the "new" entries in the active chunk,
         // they may be updates, and may be out of order. So we want to rebuild
         // merged rather than assume that we can append the entries.
-        self.rebuild_merged();
+        self.rebuild_merged_and_count();
 
         self.write_active_chunk();
 
         Ok(count)
     }
 
-    pub fn rewrite_all_files<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
-        self.rebuild_chunks()?;
-        self.store.rewrite_all_chunks(path, &self.history)?;
+    /// Summarise the changes in the given history files (e.g. fetched from
+    /// upstream), compared to the history that we currently have.
+    pub fn preview(&self, files: &[Vec<u8>]) -> Result<BTreeMap<String, HostChanges>> {
+        let mut seen: HashSet<(Uuid, DateTime<Utc>)> = HashSet::new();
+        let mut known: HashSet<Uuid> = HashSet::new();
+        for entry in self
+            .history
+            .values()
+            .flatten()
+            .flat_map(|c| c.entries.iter())
+        {
+            seen.insert((entry.id, entry.ts));
+            known.insert(entry.id);
+        }
+
+        let mut changes: BTreeMap<String, HostChanges> = BTreeMap::new();
+
+        for data in files {
+            for chunk in self.store.decode_chunks(data)? {
+                for entry in chunk.entries {
+                    if !seen.insert((entry.id, entry.ts)) {
+                        // we already have this entry
+                        continue;
+                    }
+                    let host = changes.entry(entry.host).or_default();
+                    if entry.cmd.is_empty() {
+                        host.deleted += 1;
+                    } else if !known.insert(entry.id) {
+                        host.updated += 1;
+                    } else {
+                        host.new += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Change the path of the matching entries in the merged history,
+    /// returning the number changed. Updates can't change the path, so the
+    /// change only lasts if the files are then rewritten (i.e. by a rebuild).
+    pub fn rewrite_paths<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        rewrite: &PathRewrite,
+    ) -> Result<usize> {
+        // the whole history has to be rewritten, not just what is in the load
+        // window.
+        self.read_complete(path.as_ref())?;
+        let mut count = 0;
+        for entry in self.merged.iter_mut() {
+            if let Some(new) = rewrite.apply(entry) {
+                entry.path = new;
+                count += 1;
+            }
+        }
+        self.changed();
+        Ok(count)
+    }
+
+    /// Rewrite the files for all hosts from the merged history.
+    ///
+    /// Hosts are rebuilt and written one at a time, with progress reported
+    /// through the given function. Since each host's files are only replaced
+    /// once the new chunks for that host are ready, an interrupted rewrite
+    /// leaves the remaining hosts untouched, and can simply be run again.
+    pub fn rewrite_all_files<P, F>(&mut self, path: P, mut progress: F) -> Result<RebuildSummary>
+    where
+        P: AsRef<Path>,
+        F: FnMut(String) -> Result<()>,
+    {
+        let path = path.as_ref();
+
+        // the rewritten files are built from the history in memory, so we need
+        // all of it, not just what is in the load window. Anything that was
+        // skipped because it couldn't be read would be removed by the rewrite.
+        if !self.complete {
+            progress("Reading all of the history ...".to_string())?;
+            self.read_complete(path)?;
+        }
+
+        let mut summary = RebuildSummary {
+            entries_after: self.merged.len(),
+            ..Default::default()
+        };
+        let mut ids = HashSet::new();
+        for entry in self
+            .history
+            .values()
+            .flatten()
+            .flat_map(|c| c.entries.iter())
+        {
+            summary.records_before += 1;
+            ids.insert(entry.id);
+        }
+        // merged doesn't include deleted entries
+        summary.deleted = ids.len().saturating_sub(self.merged.len());
+        drop(ids);
+
+        // build the new chunks separately and only replace the in-memory
+        // history once every host has been rewritten, so that a failure part
+        // way through leaves the history we started with.
+        let mut history = HashMap::new();
+        let hosts: BTreeSet<String> = self.merged.iter().map(|e| e.host.clone()).collect();
+        let total = hosts.len();
+
+        for (i, host) in hosts.iter().enumerate() {
+            progress(format!(
+                "Rewriting history for {host} ({}/{total}) ...",
+                i + 1
+            ))?;
+            let chunks = self.host_chunks(host)?;
+            self.store.rewrite_host_chunks(path, host, &chunks)?;
+            summary.chunks.insert(host.clone(), chunks.len());
+            history.insert(host.clone(), chunks);
+        }
+        self.history = history;
+
+        // remove any hosts that no longer have any entries (i.e. they have all
+        // been deleted).
+        for entry in self.store.get_hosts(path)? {
+            let (host, _) = entry?;
+            if !hosts.contains(&host) {
+                progress(format!("Removing history for {host} ..."))?;
+                self.store.remove_host(path, &host)?;
+            }
+        }
+
         self.last_write = Utc::now();
         self.write_active_chunk();
-        Ok(())
+        Ok(summary)
     }
 
-    fn active_chunk(&self) -> Option<&Chunk> {
+    /// The chunks that haven't been written to the sync path yet, which is
+    /// normally just the active chunk, unless a save_batch is in progress.
+    fn unwritten_chunks(&self) -> Vec<&Chunk> {
         match self.history.get(&self.host) {
-            Some(chunks) => match chunks.last() {
-                Some(last) if last.start > self.last_write => Some(last),
-                _ => None,
-            },
-            None => None,
+            Some(chunks) => chunks
+                .iter()
+                .filter(|chunk| chunk.start > self.last_write)
+                .collect(),
+            None => Vec::new(),
         }
     }
 
@@ -177,6 +912,9 @@ impl History {
 
     fn read<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let mut added = false;
+        if !self.cfg.strict_read {
+            self.complete = false;
+        }
 
         // if we have no history data at all, then we want to read our own past
         // history too, as this is probably a new server start.
@@ -184,22 +922,71 @@ impl History {
 
         // read any new data from disk
         for entry in self.store.get_hosts(&path)? {
-            let (host, path) = entry?;
+            let (host, path) = match entry {
+                Ok(entry) => entry,
+                Err(e) if !self.cfg.strict_read => {
+                    warn!("Skipping unreadable host directory: {e}");
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
             if empty || host != self.host {
-                added |= self.read_host(path, host)?;
+                match self.read_host(path, &host) {
+                    Ok(a) => added |= a,
+                    Err(e) if !self.cfg.strict_read => {
+                        warn!("Skipping history for host {host}: {e}");
+                    }
+                    Err(e) => return Err(e),
+                }
             }
         }
 
         // if we added any new entries, then we need to rebuild merged
         if added {
-            self.rebuild_merged();
+            self.rebuild_merged_and_count();
         }
 
         Ok(())
     }
 
+    /// Replace the history in memory with everything on disk, ignoring the
+    /// load window. Everything must have been written out first.
+    fn read_all(&mut self, path: &Path) -> Result<()> {
+        self.windowed = false;
+        self.history.clear();
+        self.merged.clear();
+        self.merged_ids.clear();
+        self.changed();
+        self.read(path)
+    }
+
+    /// Read all of the history with strict reads (regardless of
+    /// history.strict_read), unless that has already been done, so that
+    /// nothing is dropped when the history files are rewritten from it.
+    fn read_complete(&mut self, path: &Path) -> Result<()> {
+        if self.complete {
+            return Ok(());
+        }
+        let strict = mem::replace(&mut self.cfg.strict_read, true);
+        let result = self.read_all(path);
+        self.cfg.strict_read = strict;
+        if let Err(e) = result {
+            // read everything again the usual way, so that the history that
+            // could be read is still there.
+            self.read_all(path)?;
+            return Err(e);
+        }
+        self.complete = true;
+        Ok(())
+    }
+
     fn last_read(&self, host: &str) -> DateTime<Utc> {
-        let epoch = DateTime::from_timestamp_nanos(0);
+        // when we haven't read anything for a host, then we start from the
+        // beginning of the load window (if there is one).
+        let epoch = match self.windowed {
+            true => self.window_start,
+            false => DateTime::from_timestamp_nanos(0),
+        };
         let chunks = match self.history.get(host) {
             Some(c) => c,
             None => return epoch,
@@ -215,13 +1002,19 @@ impl History {
         debug!("read chunks for {host}");
 
         let last_read = self.last_read(&host);
-        let new_chunks = self.store.read_chunks(path, last_read)?;
+        let new_chunks = self
+            .store
+            .read_chunks(path, &host, last_read, self.cfg.strict_read)?;
 
         if new_chunks.is_empty() {
             debug!("added=false");
             return Ok(false);
         }
 
+        if host != self.host {
+            self.check_clock_skew(&host, &new_chunks);
+        }
+
         let chunks = self.history.entry(host).or_default();
         chunks.extend(new_chunks);
 
@@ -234,16 +1027,40 @@ impl History {
         Ok(true)
     }
 
+    fn check_clock_skew(&self, host: &str, chunks: &[Chunk]) {
+        let newest = match chunks
+            .iter()
+            .flat_map(|chunk| chunk.entries.iter())
+            .map(|entry| entry.ts)
+            .max()
+        {
+            Some(ts) => ts,
+            None => return,
+        };
+
+        // entries from other hosts should never be from the future, if they
+        // are then that host's clock is probably wrong, which will mess up
+        // ordering (and which chunks are considered to be new).
+        let skew = newest - Utc::now();
+        match skew.to_std() {
+            Ok(skew) if skew > self.cfg.max_clock_skew => warn!(
+                "Host {host} has entries dated {} in the future, its clock is probably wrong!",
+                format_duration(Duration::from_secs(skew.as_secs()))
+            ),
+            _ => (),
+        }
+    }
+
     fn read_active_chunk(&mut self) -> Result<()> {
         let chunks = self.history.entry(self.host.clone()).or_default();
 
         // this function should never be called when there is already an active chunk.
-        if let Some(last) = chunks.last() {
-            if last.start > self.last_write {
-                return Err(Error::from_str(
-                    "read_active_chunk called, but there is already an active chunk!",
-                ));
-            }
+        if let Some(last) = chunks.last()
+            && last.start > self.last_write
+        {
+            return Err(Error::from_str(
+                "read_active_chunk called, but there is already an active chunk!",
+            ));
         }
 
         let chunks = self.store.read_state()?;
@@ -271,97 +1088,708 @@ impl History {
         if added > 0 {
             // if we have loaded any entries then we need to rebuild the
             // merged list.
-            self.rebuild_merged();
+            self.rebuild_merged_and_count();
         }
 
         Ok(())
     }
 
-    fn write<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+    fn write<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
         // First we need to make sure that there is actually anything to write.
         let chunks = match self.history.get(&self.host) {
             Some(c) => c,
-            None => return Ok(()),
+            None => return Ok(0),
         };
 
-        self.store
+        let written = self
+            .store
             .write_chunks(path, &self.host, chunks, self.last_write)?;
 
         self.last_write = Utc::now();
 
-        Ok(())
+        Ok(written)
     }
 
-    fn write_active_chunk(&self) {
-        if let Err(e) = self.store.write_state(self.active_chunk()) {
-            error!("Failed to write active chunk: {e}");
+    /// Write the active chunk to disk, unless writes are being deferred, in
+    /// which case it will be written by the next flush.
+    fn write_active_chunk(&mut self) {
+        self.dirty = true;
+        if self.cfg.flush_interval.is_zero() {
+            self.flush();
         }
     }
 
     fn rebuild_merged(&mut self) {
-        let mut entries: BTreeMap<Uuid, Vec<Entry>> = BTreeMap::new();
+        let chunks = self.history.values().flatten();
 
-        for (_, chunks) in self.history.iter() {
-            for chunk in chunks {
-                chunk
-                    .entries
-                    .iter()
-                    .for_each(|entry| entries.entry(entry.id).or_default().push(entry.clone()));
+        // the next sequence number for a session carries on from the highest
+        // that we have seen, so that it survives server restarts.
+        for entry in chunks.clone().flat_map(|chunk| chunk.entries.iter()) {
+            if let Some(seq) = entry.seq {
+                let max = self.seqs.entry(entry.session.clone()).or_default();
+                *max = (*max).max(seq);
             }
         }
 
-        let mut new_merged: Vec<Entry> = entries
-            .into_values()
-            .map(collapse_entries)
-            .filter(|entry| !entry.cmd.is_empty())
-            .collect();
+        self.merged = merge(chunks, self.cfg.primary_host.as_deref());
+        if let Some(cutoff) = self.retention_cutoff() {
+            self.merged.retain(|entry| entry.ts >= cutoff);
+        }
+        self.merged_ids = self.merged.iter().map(|entry| entry.id).collect();
+        self.changed();
+    }
 
-        new_merged.sort();
-        self.merged = new_merged;
+    /// Rebuild the merged history after new history has been read (or
+    /// loaded), counting the commands again.
+    fn rebuild_merged_and_count(&mut self) {
+        self.rebuild_merged();
+        if self.cfg.frequency_counters {
+            self.counters = Some(self.count_commands());
+        }
     }
 
-    fn get_chunk_by_hour<'a>(
-        &self,
-        history: &'a mut HashMap<String, Vec<Chunk>>,
-        host: String,
-        ts: &DateTime<Utc>,
-    ) -> Result<&'a mut Chunk> {
-        let hour = ts.duration_trunc(TimeDelta::hours(1))?;
-        let chunks = history.entry(host).or_default();
-        // create a new chunk if chunks is empty, or if the most recent chunk
-        // has already been written.
-        match chunks.last() {
-            Some(last) if last.start == hour => (),
-            _ => chunks.push(Chunk::with_start(hour)),
+    /// Rebuild the merged history after a record changing the entry with the
+    /// given ID has been stored, updating the command counts in place.
+    fn rebuild_merged_for(&mut self, id: Uuid) {
+        let old = self.merged.iter().find(|entry| entry.id == id).cloned();
+        self.rebuild_merged();
+        if let Some(counters) = &mut self.counters {
+            let new = self.merged.iter().find(|entry| entry.id == id);
+            counters.update(old.as_ref(), new, self.merged.len());
+        }
+    }
+
+    /// Note that the merged history has changed.
+    fn changed(&mut self) {
+        self.version = self.version.wrapping_add(1);
+    }
+
+    /// Count the commands in the merged history, or use the saved counters if
+    /// they were made from the same history.
+    fn count_commands(&mut self) -> Counters {
+        let records = self
+            .history
+            .values()
+            .flatten()
+            .map(|chunk| chunk.entries.len())
+            .sum();
+        if self
+            .saved_counters
+            .as_ref()
+            .is_some_and(|saved| saved.matches(records, self.merged.len()))
+            && let Some(saved) = self.saved_counters.take()
+        {
+            debug!("using saved command counters");
+            return saved;
+        }
+        Counters::count(&self.merged, records)
+    }
+
+    /// The n most frequently run commands. Without history.frequency_counters
+    /// the whole history is counted each time.
+    pub fn top_commands(&self, n: usize) -> Vec<CommandCount> {
+        match &self.counters {
+            Some(counters) => counters.top(n),
+            None => Counters::count(&self.merged, 0).top(n),
+        }
+    }
+
+    /// Save the command counters, so that the next server doesn't have to
+    /// count them again.
+    pub fn save_counters(&self) {
+        if let Some(counters) = &self.counters
+            && let Err(e) = counters.save(&self.counters_path, &self.store)
+        {
+            error!("Failed to save command counters: {e}");
+        }
+    }
+
+    /// Entries from before this time are past history.retention, if it is set.
+    fn retention_cutoff(&self) -> Option<DateTime<Utc>> {
+        if self.cfg.retention.is_zero() {
+            return None;
+        }
+        // a retention that goes back further than a DateTime can (e.g.
+        // "300000y") keeps everything.
+        let cutoff = TimeDelta::from_std(self.cfg.retention)
+            .ok()
+            .and_then(|retention| Utc::now().checked_sub_signed(retention))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        Some(cutoff)
+    }
+
+    /// Are there any records that are past history.retention, and so should be
+    /// purged? Only the history that has been loaded is checked, so with
+    /// history.load_window set this only finds records inside the window.
+    pub fn has_expired(&self) -> bool {
+        let Some(cutoff) = self.retention_cutoff() else {
+            return false;
         };
-        // at this point chunks will *always* have at least one entry, so
-        // last_mut will never return None.
-        Ok(chunks.last_mut().unwrap())
+        self.history
+            .values()
+            .flatten()
+            .flat_map(|chunk| chunk.entries.iter())
+            .any(|entry| entry.ts < cutoff)
+    }
+
+    /// Pack the daily history files from before the given day into monthly
+    /// archives, for all hosts. Returns the number of daily files packed.
+    pub fn pack<P: AsRef<Path>>(&self, path: P, before: NaiveDate) -> Result<usize> {
+        let mut packed = 0;
+        for host in self.store.get_hosts(path)? {
+            let (host, dir) = host?;
+            let count = self.store.pack_files(&dir, before)?;
+            debug!("packed {count} files for {host}");
+            packed += count;
+        }
+        Ok(packed)
     }
 
-    fn rebuild_chunks(&mut self) -> Result<()> {
-        let mut new_history = HashMap::new();
+    /// Build a new set of hourly chunks for the given host from the merged
+    /// history.
+    fn host_chunks(&self, host: &str) -> Result<Vec<Chunk>> {
+        let mut chunks: Vec<Chunk> = Vec::new();
 
-        for entry in self.merged.iter() {
-            let chunk = self.get_chunk_by_hour(&mut new_history, entry.host.clone(), &entry.ts)?;
-            chunk.push(entry.clone());
+        for entry in self.merged.iter().filter(|entry| entry.host == host) {
+            let hour = entry.ts.duration_trunc(TimeDelta::hours(1))?;
+            // create a new chunk if chunks is empty, or if the entry is from a
+            // later hour than the most recent chunk.
+            match chunks.last_mut() {
+                Some(last) if last.start == hour => last.push(entry.clone()),
+                _ => {
+                    let mut chunk = Chunk::with_start(hour);
+                    chunk.push(entry.clone());
+                    chunks.push(chunk);
+                }
+            };
         }
 
-        self.history = new_history;
+        Ok(chunks)
+    }
+}
 
-        Ok(())
+/// Split any of the unwritten chunks that have more than max entries into
+/// smaller chunks. The new chunks start a nanosecond apart, so that they stay
+/// in order.
+fn split_chunks(chunks: &mut Vec<Chunk>, last_write: DateTime<Utc>, max: usize) {
+    if !chunks
+        .iter()
+        .any(|chunk| chunk.start > last_write && chunk.len() > max)
+    {
+        return;
+    }
+    let mut split = Vec::with_capacity(chunks.len());
+    for chunk in chunks.drain(..) {
+        if chunk.start <= last_write || chunk.len() <= max {
+            split.push(chunk);
+            continue;
+        }
+        let mut start = chunk.start;
+        for entries in &chunk.entries.into_iter().chunks(max) {
+            let mut piece = Chunk::with_start(start);
+            for entry in entries {
+                piece.push(entry);
+            }
+            split.push(piece);
+            start += TimeDelta::nanoseconds(1);
+        }
     }
+    *chunks = split;
 }
 
+/// Check that a chunk can be encrypted with the configured key, written to the
+/// state directory, and read back again.
+pub fn self_test(cfg: &Config) -> Result<()> {
+    let store = Store::new(cfg)?;
+    store.self_test(&cfg.hostname.to_string_lossy())
+}
+
+/// Analyse the history files in the sync repo, without changing anything.
+///
+/// The files are read one at a time, and only the ID (and whether it has been
+/// deleted) is kept for each entry, so that large repos can be analysed
+/// without loading the whole history into memory.
+pub fn analyze<P: AsRef<Path>>(cfg: &Config, path: P) -> Result<Analysis> {
+    let store = Store::new(cfg)?;
+    let mut analysis = Analysis::default();
+    let mut deleted: HashMap<Uuid, bool> = HashMap::new();
+
+    for host in store.get_hosts(path)? {
+        let (host, dir) = host?;
+        debug!("analyze history files for {host} in {dir:?}");
+        let usage = analysis.hosts.entry(host.clone()).or_default();
+        for file in list_files(dir)? {
+            usage.files += 1;
+            usage.bytes += fs::metadata(&file)?.len();
+            let chunks = match store.read_all_chunks(&file, &host) {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    warn!("Skipping unreadable history file {file:?}: {e}");
+                    usage.unreadable += 1;
+                    continue;
+                }
+            };
+            for entry in chunks.iter().flat_map(|chunk| chunk.entries.iter()) {
+                usage.records += 1;
+                usage.oldest = Some(usage.oldest.map_or(entry.ts, |ts| ts.min(entry.ts)));
+                usage.newest = Some(usage.newest.map_or(entry.ts, |ts| ts.max(entry.ts)));
+                *deleted.entry(entry.id).or_default() |= entry.cmd.is_empty();
+            }
+        }
+    }
+
+    analysis.entries = deleted.len();
+    analysis.deleted = deleted.values().filter(|deleted| **deleted).count();
+    Ok(analysis)
+}
+
+/// The result of checking that all the history files can be read.
+#[derive(Serialize, Debug, Default)]
+pub struct Verification {
+    pub files: usize,
+    pub chunks: usize,
+    pub bad: Vec<BadChunk>,
+    /// Number of files that were rewritten without their bad chunks
+    pub repaired: usize,
+}
+
+/// Check every chunk in the history files in the sync repo, and the active
+/// chunk file, can be decrypted. This reads the files directly, so doesn't
+/// need the server, but the server must not be running if repair is set.
+pub fn verify<P: AsRef<Path>>(cfg: &Config, path: P, repair: bool) -> Result<Verification> {
+    let store = Store::new(cfg)?;
+    let mut verification = Verification::default();
+
+    let mut files = Vec::new();
+    for host in store.get_hosts(path)? {
+        let (host, dir) = host?;
+        for file in list_files(dir)? {
+            files.push((host.clone(), file));
+        }
+    }
+    if store.state_path().try_exists()? {
+        let host = cfg.hostname.to_string_lossy().to_string();
+        files.push((host, store.state_path().to_owned()));
+    }
+
+    for (host, file) in files {
+        debug!("verify history file {file:?} for {host}");
+        let (chunks, mut bad) = store.verify_file(&file, &host, repair)?;
+        verification.files += 1;
+        verification.chunks += chunks;
+        if repair && !bad.is_empty() {
+            verification.repaired += 1;
+        }
+        verification.bad.append(&mut bad);
+    }
+
+    Ok(verification)
+}
+
+fn queued_path(cfg: &Config) -> PathBuf {
+    cfg.state_dir.join("queued")
+}
+
+fn read_queued(path: &Path, store: &Store) -> Result<Vec<Entry>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let data = store.decrypt_data(&data, QUEUED_PURPOSE)?;
+    Ok(rmp_serde::from_slice(&data)?)
+}
+
+/// Save commands that couldn't be added to the history because it was busy
+/// (e.g. with a rebuild that was still running at shutdown), without needing
+/// the history itself. They are added the next time that it is loaded.
+pub fn save_queued(cfg: &Config, entries: Vec<Entry>) -> Result<()> {
+    let store = Store::new(cfg)?;
+    let path = queued_path(cfg);
+    let mut queued = read_queued(&path, &store)?;
+    queued.extend(entries);
+    let data = store.encrypt_data(rmp_serde::to_vec(&queued)?, QUEUED_PURPOSE)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Encrypt a copy of the history kept by a client (e.g. the cache used by
+/// move), along with the server's version of it, with the history key.
+pub fn encrypt_cache(cfg: &Config, version: u64, history: &[Entry]) -> Result<Vec<u8>> {
+    Store::new(cfg)?.encrypt_data(rmp_serde::to_vec(&(version, history))?, CACHE_PURPOSE)
+}
+
+/// Decrypt a copy of the history written by encrypt_cache.
+pub fn decrypt_cache(cfg: &Config, data: &[u8]) -> Result<(u64, Vec<Entry>)> {
+    let data = Store::new(cfg)?.decrypt_data(data, CACHE_PURPOSE)?;
+    Ok(rmp_serde::from_slice(&data)?)
+}
+
+/// Read the history from a copy of another vellum host's history directory
+/// (e.g. `hosts.v1/<host>`), which is encrypted with the given key rather than
+/// ours. Returns the current version of each command, oldest first.
+pub fn read_foreign_history<P: AsRef<Path>>(
+    cfg: &Config,
+    path: P,
+    key: &[u8],
+) -> Result<Vec<Entry>> {
+    Ok(merge(
+        &decrypt_dir(path, key, cfg.history.max_chunk_size)?,
+        cfg.history.primary_host.as_deref(),
+    ))
+}
+
+/// Read all the entries from a single history file (or the active chunk state
+/// file), exactly as they are stored, for debugging.
+pub fn read_history_file<P: AsRef<Path>>(cfg: &Config, path: P) -> Result<Vec<Entry>> {
+    Ok(
+        decrypt_file(path, &get_key(cfg)?, cfg.history.max_chunk_size)?
+            .into_iter()
+            .flat_map(|chunk| chunk.entries)
+            .collect(),
+    )
+}
+
+/// Merge the records from a set of chunks into the current version of each
+/// command, oldest first. All the records for an ID are collapsed into a
+/// single entry, and deleted entries are dropped.
+fn merge<'a, I: IntoIterator<Item = &'a Chunk>>(
+    chunks: I,
+    primary_host: Option<&str>,
+) -> Vec<Entry> {
+    let mut entries: BTreeMap<Uuid, Vec<Entry>> = BTreeMap::new();
+    for entry in chunks.into_iter().flat_map(|chunk| chunk.entries.iter()) {
+        entries.entry(entry.id).or_default().push(entry.clone());
+    }
+    entries
+        .into_values()
+        .map(collapse_entries)
+        .filter(|entry| !entry.cmd.is_empty())
+        // IDs are UUIDv7, so they keep entries with the same timestamp in the
+        // order that they were stored, after any from history.primary_host.
+        .sorted_by(|a, b| {
+            let secondary = |entry: &Entry| primary_host.is_some_and(|host| entry.host != host);
+            a.ts.cmp(&b.ts)
+                .then(secondary(a).cmp(&secondary(b)))
+                .then(a.id.cmp(&b.id))
+        })
+        .collect()
+}
+
+/// Collapse all the records for a single ID into one entry, which keeps the
+/// details of the oldest record with the command from the newest record that
+/// isn't a note or exit status, unless any of the records is a deletion (an
+/// empty command), the newest path (updates that don't change the path leave
+/// it empty), the newest note and the newest exit status.
 fn collapse_entries(entries: Vec<Entry>) -> Entry {
     if entries.len() == 1 {
         return entries.into_iter().next().unwrap();
     }
+    // a deletion always wins, even if the host that stored the original entry
+    // has a clock that is ahead of ours (and so the original appears to be
+    // newer than the tombstone).
+    let deleted = entries.iter().any(|entry| entry.cmd.is_empty());
+    let note = entries
+        .iter()
+        .sorted()
+        .rev()
+        .find_map(|entry| entry.note.clone())
+        .filter(|note| !note.is_empty());
+    let path = entries
+        .iter()
+        .sorted()
+        .rev()
+        .find(|entry| !entry.path.is_empty() || entry.path_cleared)
+        .map(|entry| entry.path.clone())
+        .unwrap_or_default();
+    // records written by annotate and finish only change the note or the exit
+    // status, the command that they carry is a copy, which would undo an edit
+    // made at the same time on another host.
+    let cmd = entries
+        .iter()
+        .sorted()
+        .rev()
+        .find(|entry| entry.note.is_none() && entry.exit.is_none())
+        .map(|entry| entry.cmd.clone());
+    let finished = entries
+        .iter()
+        .sorted()
+        .rev()
+        .find(|entry| entry.exit.is_some())
+        .map(|entry| (entry.exit, entry.duration_ms));
     let mut entries = entries.into_iter().sorted();
     // we know that we must have at least two entries, so we just unwrap the
     // Options.
     let mut first = entries.next().unwrap();
     let last = entries.next_back().unwrap();
-    first.cmd = last.cmd;
+    first.cmd = match deleted {
+        true => "".to_string(),
+        false => cmd.unwrap_or(last.cmd),
+    };
+    first.path = path;
+    first.path_cleared = false;
+    first.note = note;
+    if let Some((exit, duration_ms)) = finished {
+        first.exit = exit;
+        first.duration_ms = duration_ms;
+    }
     first
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: u128) -> Uuid {
+        Uuid::from_u128(n)
+    }
+
+    /// A record stored by host at the given number of seconds after the epoch.
+    fn record(id: Uuid, host: &str, cmd: &str, path: &str, secs: i64) -> Entry {
+        let mut entry = Entry::existing(id, host, cmd, path, "session");
+        entry.ts = DateTime::from_timestamp(secs, 0).unwrap();
+        entry
+    }
+
+    fn chunk(entries: Vec<Entry>) -> Chunk {
+        let mut chunk = Chunk::with_start(entries.iter().map(|e| e.ts).min().unwrap());
+        for entry in entries {
+            chunk.push(entry);
+        }
+        chunk
+    }
+
+    /// The (ID, command, path) of each merged entry.
+    fn merged(chunks: &[Chunk], primary_host: Option<&str>) -> Vec<(Uuid, String, String)> {
+        merge(chunks, primary_host)
+            .into_iter()
+            .map(|entry| (entry.id, entry.cmd, entry.path))
+            .collect()
+    }
+
+    fn expect(id: Uuid, cmd: &str, path: &str) -> (Uuid, String, String) {
+        (id, cmd.to_string(), path.to_string())
+    }
+
+    #[test]
+    fn merge_cases() {
+        let cases = [
+            (
+                "single entry",
+                vec![chunk(vec![record(id(1), "a", "ls", "/tmp", 10)])],
+                vec![expect(id(1), "ls", "/tmp")],
+            ),
+            (
+                "update after create",
+                vec![chunk(vec![
+                    record(id(1), "a", "ls", "/tmp", 10),
+                    record(id(1), "a", "ls -l", "", 20),
+                ])],
+                vec![expect(id(1), "ls -l", "/tmp")],
+            ),
+            (
+                "update with a new path",
+                vec![chunk(vec![
+                    record(id(1), "a", "ls", "/tmp", 10),
+                    record(id(1), "a", "ls", "/home", 20),
+                ])],
+                vec![expect(id(1), "ls", "/home")],
+            ),
+            (
+                // a deletion always wins, so storing the same ID again doesn't
+                // bring it back, but the same command with a new ID is kept.
+                "delete then re-add",
+                vec![chunk(vec![
+                    record(id(1), "a", "ls", "/tmp", 10),
+                    record(id(1), "a", "", "", 20),
+                    record(id(1), "a", "ls", "/tmp", 30),
+                    record(id(2), "a", "ls", "/tmp", 40),
+                ])],
+                vec![expect(id(2), "ls", "/tmp")],
+            ),
+            (
+                "two hosts editing the same ID",
+                vec![
+                    chunk(vec![
+                        record(id(1), "a", "ls", "/tmp", 10),
+                        record(id(1), "a", "ls -a", "", 30),
+                    ]),
+                    chunk(vec![record(id(1), "b", "ls -b", "", 20)]),
+                ],
+                vec![expect(id(1), "ls -a", "/tmp")],
+            ),
+            (
+                // the tombstone is older than the original, e.g. because the
+                // clock on host a is ahead.
+                "tombstone from another host",
+                vec![
+                    chunk(vec![
+                        record(id(1), "a", "ls", "/tmp", 30),
+                        record(id(2), "a", "pwd", "/tmp", 40),
+                    ]),
+                    chunk(vec![record(id(1), "b", "", "", 20)]),
+                ],
+                vec![expect(id(2), "pwd", "/tmp")],
+            ),
+            (
+                "ordered by time then ID",
+                vec![
+                    chunk(vec![
+                        record(id(3), "a", "three", "", 10),
+                        record(id(1), "a", "one", "", 20),
+                    ]),
+                    chunk(vec![record(id(2), "b", "two", "", 10)]),
+                ],
+                vec![
+                    expect(id(2), "two", ""),
+                    expect(id(3), "three", ""),
+                    expect(id(1), "one", ""),
+                ],
+            ),
+            ("empty", vec![], vec![]),
+        ];
+        for (name, chunks, expected) in cases {
+            assert_eq!(merged(&chunks, None), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn merge_primary_host_first() {
+        let chunks = vec![
+            chunk(vec![record(id(1), "a", "one", "", 10)]),
+            chunk(vec![record(id(2), "b", "two", "", 10)]),
+        ];
+        assert_eq!(
+            merged(&chunks, Some("b")),
+            vec![expect(id(2), "two", ""), expect(id(1), "one", "")]
+        );
+    }
+
+    #[test]
+    fn note_doesnt_undo_edit() {
+        let mut note = record(id(1), "b", "ls", "", 30);
+        note.note = Some("a note".to_string());
+        let chunks = vec![
+            chunk(vec![
+                record(id(1), "a", "ls", "/tmp", 10),
+                record(id(1), "a", "ls -l", "", 20),
+            ]),
+            chunk(vec![note]),
+        ];
+        let entries = merge(&chunks, None);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].cmd, "ls -l");
+        assert_eq!(entries[0].note.as_deref(), Some("a note"));
+    }
+
+    #[test]
+    fn edit_clears_path() {
+        let mut cleared = record(id(1), "a", "ls", "", 20);
+        cleared.path_cleared = true;
+        let chunks = vec![chunk(vec![
+            record(id(1), "a", "ls", "/tmp", 10),
+            cleared,
+            record(id(1), "a", "ls -l", "", 30),
+        ])];
+        let entries = merge(&chunks, None);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].cmd, "ls -l");
+        assert_eq!(entries[0].path, "");
+        assert!(!entries[0].path_cleared);
+    }
+
+    #[test]
+    fn exit_status() {
+        let mut finished = record(id(1), "a", "make", "", 20);
+        finished.exit = Some(2);
+        finished.duration_ms = Some(1500);
+        let chunks = vec![chunk(vec![
+            record(id(1), "a", "make", "/src", 10),
+            finished,
+            record(id(1), "a", "make test", "", 30),
+        ])];
+        let entries = merge(&chunks, None);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].cmd, "make test");
+        assert_eq!(entries[0].path, "/src");
+        assert_eq!(entries[0].exit, Some(2));
+        assert_eq!(entries[0].duration_ms, Some(1500));
+    }
+
+    /// A config that keeps everything in dir.
+    fn test_config(dir: &Path) -> Config {
+        // the key can only be set once per process, the error if it already
+        // has been is ignored.
+        let _ = set_key(vec![7; 32]);
+        Config {
+            state_dir: dir.join("state"),
+            cache_dir: dir.join("cache"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn queued_added_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = test_config(dir.path());
+        let host = cfg.hostname.to_string_lossy().to_string();
+        save_queued(&cfg, vec![Entry::new(&host, "ls", "/tmp", "s")]).unwrap();
+        save_queued(&cfg, vec![Entry::new(&host, "pwd", "/tmp", "s")]).unwrap();
+
+        let history = History::load(&cfg, cfg.sync_path()).unwrap();
+        let cmds: Vec<String> = history.history().into_iter().map(|e| e.cmd).collect();
+        assert_eq!(cmds, vec!["ls", "pwd"]);
+        assert!(!queued_path(&cfg).exists());
+        // and they are in the active chunk for the next load.
+        let history = History::load(&cfg, cfg.sync_path()).unwrap();
+        assert_eq!(history.len(), 2);
+    }
+
+    #[test]
+    fn huge_retention_keeps_everything() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cfg = test_config(dir.path());
+        cfg.history.retention = humantime::parse_duration("300000y").unwrap();
+        let history = History::load(&cfg, cfg.sync_path()).unwrap();
+        assert_eq!(history.retention_cutoff(), Some(DateTime::<Utc>::MIN_UTC));
+    }
+
+    #[test]
+    fn counters_follow_edits_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cfg = test_config(dir.path());
+        cfg.history.frequency_counters = true;
+        let mut history = History::load(&cfg, cfg.sync_path()).unwrap();
+        let first = history.add("ls", "/tmp", "s", None, None, None, None);
+        let second = history.add("ls", "/tmp", "s", None, None, None, None);
+        history.add("pwd", "/tmp", "s", None, None, None, None);
+        let counts = |history: &History| -> Vec<(String, usize)> {
+            let top = history.top_commands(10);
+            top.into_iter().map(|c| (c.cmd, c.count)).collect()
+        };
+        assert_eq!(counts(&history), vec![("ls".into(), 2), ("pwd".into(), 1)]);
+
+        history.update(first, "pwd", "s").unwrap();
+        assert_eq!(counts(&history), vec![("pwd".into(), 2), ("ls".into(), 1)]);
+        history.update(second, "", "s").unwrap();
+        assert_eq!(counts(&history), vec![("pwd".into(), 2)]);
+
+        // and they match counting from scratch.
+        history.counters = None;
+        assert_eq!(counts(&history), vec![("pwd".into(), 2)]);
+    }
+
+    #[test]
+    fn edit_drops_path_without_store_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cfg = test_config(dir.path());
+        cfg.history.store_path = false;
+        let mut history = History::load(&cfg, cfg.sync_path()).unwrap();
+        let id = history.add("ls", "/tmp", "s", None, None, None, None);
+        history.update_with_path(id, "ls -l", "/home", "s").unwrap();
+        let entry = history.history().pop().unwrap();
+        assert_eq!((entry.cmd.as_str(), entry.path.as_str()), ("ls -l", ""));
+    }
+}
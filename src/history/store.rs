@@ -1,26 +1,45 @@
 use std::{
-    collections::HashMap,
+    collections::BTreeSet,
     env,
     fs::{self, File, ReadDir, exists},
     io::{self, Read, Write},
     path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
-use aws_lc_rs::{cipher::AES_256_KEY_LEN, rand};
+use aws_lc_rs::{
+    hmac::{self, HMAC_SHA256},
+    rand,
+};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
 use itertools::Itertools;
 use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{
+    config::{Cipher, Config},
+    error::{Error, Result},
+};
 
-use crate::error::{Error, Result};
+use super::category;
 
-pub use v1::Chunk;
-pub use v1::Entry;
+pub use v9::Chunk;
+pub use v9::Entry;
 
 #[derive(Debug)]
 enum EncryptedChunk {
     V0(v0::EncryptedChunk),
     V1(v1::EncryptedChunk),
+    V2(v2::EncryptedChunk),
+    V3(v3::EncryptedChunk),
+    V4(v4::EncryptedChunk),
+    V5(v5::EncryptedChunk),
+    V6(v6::EncryptedChunk),
+    V7(v7::EncryptedChunk),
+    V8(v8::EncryptedChunk),
+    V9(v9::EncryptedChunk),
 }
 
 impl EncryptedChunk {
@@ -28,6 +47,14 @@ impl EncryptedChunk {
         Ok(match version {
             0 => Some(Self::V0(v0::EncryptedChunk::decode(data)?)),
             1 => Some(Self::V1(v1::EncryptedChunk::decode(data)?)),
+            2 => Some(Self::V2(v2::EncryptedChunk::decode(data)?)),
+            3 => Some(Self::V3(v3::EncryptedChunk::decode(data)?)),
+            4 => Some(Self::V4(v4::EncryptedChunk::decode(data)?)),
+            5 => Some(Self::V5(v5::EncryptedChunk::decode(data)?)),
+            6 => Some(Self::V6(v6::EncryptedChunk::decode(data)?)),
+            7 => Some(Self::V7(v7::EncryptedChunk::decode(data)?)),
+            8 => Some(Self::V8(v8::EncryptedChunk::decode(data)?)),
+            9 => Some(Self::V9(v9::EncryptedChunk::decode(data)?)),
             v => {
                 warn!("Ignoring chunk of unknown version {v}");
                 None
@@ -39,6 +66,14 @@ impl EncryptedChunk {
         match self {
             Self::V0(_) => 0,
             Self::V1(_) => 1,
+            Self::V2(_) => 2,
+            Self::V3(_) => 3,
+            Self::V4(_) => 4,
+            Self::V5(_) => 5,
+            Self::V6(_) => 6,
+            Self::V7(_) => 7,
+            Self::V8(_) => 8,
+            Self::V9(_) => 9,
         }
     }
 
@@ -46,6 +81,14 @@ impl EncryptedChunk {
         match self {
             Self::V0(v0) => v0.start,
             Self::V1(v1) => v1.start,
+            Self::V2(v2) => v2.start,
+            Self::V3(v3) => v3.start,
+            Self::V4(v4) => v4.start,
+            Self::V5(v5) => v5.start,
+            Self::V6(v6) => v6.start,
+            Self::V7(v7) => v7.start,
+            Self::V8(v8) => v8.start,
+            Self::V9(v9) => v9.start,
         }
     }
 
@@ -53,56 +96,308 @@ impl EncryptedChunk {
         match self {
             Self::V0(v0) => v0.encode(),
             Self::V1(v1) => v1.encode(),
+            Self::V2(v2) => v2.encode(),
+            Self::V3(v3) => v3.encode(),
+            Self::V4(v4) => v4.encode(),
+            Self::V5(v5) => v5.encode(),
+            Self::V6(v6) => v6.encode(),
+            Self::V7(v7) => v7.encode(),
+            Self::V8(v8) => v8.encode(),
+            Self::V9(v9) => v9.encode(),
         }
     }
 
-    fn encrypt(chunk: &Chunk, key: &[u8]) -> Result<Self> {
-        Ok(Self::V1(v1::EncryptedChunk::encrypt(chunk, key)?))
+    fn encrypt(chunk: &Chunk, key: &[u8], host: &str, cipher: Cipher) -> Result<Self> {
+        Ok(Self::V9(v9::EncryptedChunk::encrypt(
+            chunk, key, host, cipher,
+        )?))
+    }
+
+    /// Check that the chunk belongs to the expected host. Older chunks don't
+    /// record the host, so they can't be checked.
+    fn check_host(&self, expected: &str) -> Result<()> {
+        match self {
+            Self::V5(v5) if v5.host != expected => Err(Error::Generic(format!(
+                "found chunk for host {:?} in the history for {expected:?}",
+                v5.host
+            ))),
+            Self::V6(v6) if v6.host != expected => Err(Error::Generic(format!(
+                "found chunk for host {:?} in the history for {expected:?}",
+                v6.host
+            ))),
+            Self::V7(v7) if v7.host != expected => Err(Error::Generic(format!(
+                "found chunk for host {:?} in the history for {expected:?}",
+                v7.host
+            ))),
+            Self::V8(v8) if v8.host != expected => Err(Error::Generic(format!(
+                "found chunk for host {:?} in the history for {expected:?}",
+                v8.host
+            ))),
+            Self::V9(v9) if v9.host != expected => Err(Error::Generic(format!(
+                "found chunk for host {:?} in the history for {expected:?}",
+                v9.host
+            ))),
+            _ => Ok(()),
+        }
     }
 
     fn decrypt(self, key: &[u8]) -> Result<Chunk> {
         match self {
             Self::V0(v0) => v0.decrypt(key),
             Self::V1(v1) => v1.decrypt(key),
+            Self::V2(v2) => v2.decrypt(key),
+            Self::V3(v3) => v3.decrypt(key),
+            Self::V4(v4) => v4.decrypt(key),
+            Self::V5(v5) => v5.decrypt(key),
+            Self::V6(v6) => v6.decrypt(key),
+            Self::V7(v7) => v7.decrypt(key),
+            Self::V8(v8) => v8.decrypt(key),
+            Self::V9(v9) => v9.decrypt(key),
         }
     }
 }
 
-pub fn generate_key() -> Result<String> {
-    let mut buf = [0_u8; AES_256_KEY_LEN];
+/// Generate a new key for the given cipher.
+pub fn generate_key(cipher: Cipher) -> Result<String> {
+    let mut buf = vec![0_u8; cipher.key_len()];
     rand::fill(&mut buf)?;
     Ok(BASE64_STANDARD.encode(buf))
 }
 
-pub fn get_key() -> Result<Vec<u8>> {
-    let vellum_key = env::var("VELLUM_KEY")?;
-    let key = BASE64_STANDARD.decode(&vellum_key)?;
-    if key.len() != AES_256_KEY_LEN {
+// the key from the bundle, if the config was loaded from one.
+static BUNDLE_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Set the encryption key to use instead of looking one up, used when the key
+/// comes from a bundle.
+pub fn set_key(key: Vec<u8>) -> Result<()> {
+    BUNDLE_KEY
+        .set(key)
+        .map_err(|_| Error::from_str("encryption key has already been set"))
+}
+
+/// Get the encryption key, from a bundle, $VELLUM_KEY, the file named by
+/// $VELLUM_KEY_FILE, or the system keyring (in that order), checking that it
+/// is the right length for history.cipher.
+pub fn get_key(cfg: &Config) -> Result<Vec<u8>> {
+    let key = find_key()?;
+    let cipher = cfg.history.cipher;
+    if key.len() != cipher.key_len() {
+        return Err(Error::Generic(format!(
+            "the key is the wrong length for history.cipher, it needs to be {} bytes, got {}",
+            cipher.key_len(),
+            key.len()
+        )));
+    }
+    Ok(key)
+}
+
+fn find_key() -> Result<Vec<u8>> {
+    if let Some(key) = BUNDLE_KEY.get() {
+        return Ok(key.clone());
+    }
+    if let Some(key) = env::var_os("VELLUM_KEY") {
+        let key = key
+            .into_string()
+            .map_err(|_| Error::from_str("VELLUM_KEY is not valid unicode"))?;
+        return parse_key(&key);
+    }
+    if let Some(path) = env::var_os("VELLUM_KEY_FILE") {
+        return parse_key(&fs::read_to_string(path)?);
+    }
+    match keyring_entry()?.get_password() {
+        Ok(key) => parse_key(&key),
+        Err(keyring::Error::NoEntry) => Err(Error::from_str(
+            "no key configured, set $VELLUM_KEY or $VELLUM_KEY_FILE, or store one in the keyring with `vellum init key --keyring`",
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Store the given encryption key in the system keyring, where get_key will
+/// find it if there isn't a key in the environment.
+/// An existing key is never replaced, as that would make the history
+/// unreadable.
+pub fn set_keyring_key(key: &str) -> Result<()> {
+    let entry = keyring_entry()?;
+    match entry.get_password() {
+        Ok(_) => Err(Error::from_str(
+            "a key is already stored in the system keyring, refusing to replace it",
+        )),
+        Err(keyring::Error::NoEntry) => Ok(entry.set_password(key)?),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn keyring_entry() -> Result<keyring::Entry> {
+    Ok(keyring::Entry::new("vellum", "key")?)
+}
+
+/// Decode a key, which has to be the right length for one of the ciphers
+/// (get_key checks that it is the right length for the configured cipher).
+pub fn parse_key(key: &str) -> Result<Vec<u8>> {
+    let key = BASE64_STANDARD.decode(key.trim())?;
+    let lengths: BTreeSet<usize> = Cipher::value_variants()
+        .iter()
+        .map(Cipher::key_len)
+        .collect();
+    if !lengths.contains(&key.len()) {
         return Err(Error::Generic(format!(
-            "key should be {AES_256_KEY_LEN} bytes, got {}",
+            "key should be {} bytes, got {}",
+            lengths.iter().join(" or "),
             key.len()
         )));
     }
     Ok(key)
 }
 
+/// Derive a separate HMAC key for a purpose, rather than using the encryption
+/// key for more than one thing.
+fn derive_key(key: &[u8], purpose: &[u8]) -> hmac::Key {
+    let tag = hmac::sign(&hmac::Key::new(HMAC_SHA256, key), purpose);
+    hmac::Key::new(HMAC_SHA256, tag.as_ref())
+}
+
+/// Is the file named after the day (or month, for archives) that it holds?
+fn is_dated(name: &str) -> bool {
+    NaiveDate::parse_from_str(name, "%Y-%m-%d").is_ok()
+        || NaiveDate::parse_from_str(&format!("{name}-01"), "%Y-%m-%d").is_ok()
+}
+
+/// Read and decrypt all the chunks from the history files found in the given
+/// directory (and any subdirectories), using the given key instead of ours.
+pub(super) fn decrypt_dir<P: AsRef<Path>>(
+    path: P,
+    key: &[u8],
+    max_chunk_size: u64,
+) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.metadata()?.is_dir() {
+            chunks.append(&mut decrypt_dir(entry.path(), key, max_chunk_size)?);
+            continue;
+        }
+        chunks.append(&mut decrypt_file(entry.path(), key, max_chunk_size)?);
+    }
+    Ok(chunks)
+}
+
+/// Read and decrypt all the chunks from a single history file.
+pub(super) fn decrypt_file<P: AsRef<Path>>(
+    path: P,
+    key: &[u8],
+    max_chunk_size: u64,
+) -> Result<Vec<Chunk>> {
+    let path = path.as_ref();
+    HistoryFile::open(path, max_chunk_size)?
+        .map(|chunk| {
+            chunk?.decrypt(key).map_err(|e| {
+                Error::Generic(format!(
+                    "failed to decrypt {path:?} (is the key correct?): {e}"
+                ))
+            })
+        })
+        .collect()
+}
+
+/// List all the files in the given directory (and any subdirectories).
+pub(super) fn list_files<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.metadata()?.is_dir() {
+            files.append(&mut list_files(entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// A chunk that couldn't be read from a history file.
+#[derive(Serialize, Debug)]
+pub struct BadChunk {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub error: String,
+}
+
 #[derive(Debug)]
 pub(super) struct Store {
     key: Vec<u8>,
+    cipher: Cipher,
     state: PathBuf,
+    max_chunk_size: u64,
+    // used to name history files after a keyed hash of the day, instead of
+    // the day itself, if history.opaque_filenames is set.
+    name_key: Option<hmac::Key>,
+    // used to replace commands with a keyed hash of the program that they
+    // run, if history.hash_commands is set.
+    cmd_key: Option<hmac::Key>,
 }
 
 impl Store {
-    pub(super) fn new<S: AsRef<Path>>(state: S) -> Result<Self> {
-        let key = get_key()?;
-        let state_dir = state.as_ref();
-        fs::create_dir_all(state_dir)?;
-        let state = Path::new(state_dir).join("history.chunk");
-        Ok(Self { key, state })
+    pub(super) fn new(cfg: &Config) -> Result<Self> {
+        let key = get_key(cfg)?;
+        fs::create_dir_all(&cfg.state_dir)?;
+        let state = cfg.state_dir.join("history.chunk");
+        let name_key = cfg
+            .history
+            .opaque_filenames
+            .then(|| derive_key(&key, b"vellum history file names"));
+        let cmd_key = cfg
+            .history
+            .hash_commands
+            .then(|| derive_key(&key, b"vellum command hashes"));
+        Ok(Self {
+            key,
+            cipher: cfg.history.cipher,
+            state,
+            max_chunk_size: cfg.history.max_chunk_size,
+            name_key,
+            cmd_key,
+        })
+    }
+
+    /// The hash to store instead of the command, if history.hash_commands is
+    /// set. Only the program is hashed, so that the same program always gets
+    /// the same hash. The hash starts with a '#', so that it is a comment if
+    /// it ends up being run by a shell.
+    pub(super) fn hash_command(&self, cmd: &str) -> Option<String> {
+        let cmd_key = self.cmd_key.as_ref()?;
+        let program = category::program(cmd).unwrap_or(cmd.trim());
+        let hash: String = hmac::sign(cmd_key, program.as_bytes())
+            .as_ref()
+            .iter()
+            .take(8)
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Some(format!("#{hash}"))
+    }
+
+    /// The name of the history file for the given day.
+    fn file_name(&self, day: &str) -> String {
+        let Some(name_key) = &self.name_key else {
+            return day.to_string();
+        };
+        // half of the hash is plenty to keep the names unique
+        hmac::sign(name_key, day.as_bytes())
+            .as_ref()
+            .iter()
+            .take(16)
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// The previous version of the active chunk file, kept in case the current
+    /// one can't be read.
+    fn backup_path(&self) -> PathBuf {
+        self.state.with_extension("chunk.bak")
     }
 
     pub(super) fn read_state(&self) -> Result<Vec<Chunk>> {
-        if !exists(&self.state)? {
+        let backup = self.backup_path();
+        if !exists(&self.state)? && !exists(&backup)? {
             debug!(
                 "active chunk file {:?} not found, skipping active chunks load",
                 self.state
@@ -110,11 +405,25 @@ impl Store {
             return Ok(Vec::new());
         }
 
-        let path = self.state.clone();
+        let err = match self.read_state_file(&self.state) {
+            Ok(chunks) => return Ok(chunks),
+            Err(e) => e,
+        };
+        if !exists(&backup)? {
+            return Err(err);
+        }
+        warn!("Failed to read active chunk file {:?}: {err}", self.state);
+        let chunks = self.read_state_file(&backup)?;
+        warn!(
+            "Recovered active chunks from backup {backup:?}, the most recent command may be lost"
+        );
+        Ok(chunks)
+    }
 
+    fn read_state_file(&self, path: &Path) -> Result<Vec<Chunk>> {
         debug!("load active chunks from {path:?}");
 
-        let mut f = HistoryFile::open(path)?;
+        let mut f = HistoryFile::open(path, self.max_chunk_size)?;
 
         let chunk = match f.read()? {
             Some(e) => e.decrypt(&self.key)?,
@@ -144,19 +453,172 @@ impl Store {
         Ok(chunks)
     }
 
-    pub(super) fn write_state(&self, chunk: Option<&Chunk>) -> Result<()> {
-        let path = self.state.clone();
-        debug!("Write state to {path:?}");
-        let mut f = HistoryFile::create(path, false)?;
-
-        if let Some(chunk) = chunk {
-            f.write(&EncryptedChunk::encrypt(chunk, &self.key)?)?;
+    /// Replace the active chunk file, keeping the previous version as a backup.
+    /// The new file is written alongside and renamed into place, so there is
+    /// always at least one complete copy on disk.
+    pub(super) fn write_state(&self, chunks: &[&Chunk], host: &str) -> Result<()> {
+        let tmp = self.state.with_extension("chunk.tmp");
+        debug!("Write state to {tmp:?}");
+        let mut f = HistoryFile::create(&tmp, false)?;
+
+        for chunk in chunks {
+            f.write(&EncryptedChunk::encrypt(
+                chunk,
+                &self.key,
+                host,
+                self.cipher,
+            )?)?;
         }
 
         f.flush()?;
+        drop(f);
+
+        if exists(&self.state)? {
+            fs::rename(&self.state, self.backup_path())?;
+        }
+        fs::rename(tmp, &self.state)?;
+        Ok(())
+    }
+
+    /// Encrypt data kept alongside the history with the history key, the
+    /// same purpose has to be given to decrypt_data.
+    pub(super) fn encrypt_data(&self, data: Vec<u8>, purpose: &str) -> Result<Vec<u8>> {
+        v9::EncryptedData::encrypt(data, &self.key, purpose, self.cipher)?.encode()
+    }
+
+    pub(super) fn decrypt_data(&self, data: &[u8], purpose: &str) -> Result<Vec<u8>> {
+        v9::EncryptedData::decode(data)?.decrypt(&self.key, purpose)
+    }
+
+    /// Encrypt a throwaway chunk, write it to a file next to the active chunk
+    /// file and read it back, to check that the key and state directory work.
+    pub(super) fn self_test(&self, host: &str) -> Result<()> {
+        let path = self.state.with_extension("chunk.selftest");
+        debug!("run self-test using {path:?}");
+
+        let mut chunk = Chunk::new();
+        chunk.push(Entry::new(host, "vellum self-test", "", ""));
+        let encrypted = EncryptedChunk::encrypt(&chunk, &self.key, host, self.cipher)
+            .map_err(|e| Error::Generic(format!("failed to encrypt test chunk: {e}")))?;
+
+        let result = self.self_test_file(&path, &encrypted, &chunk, host);
+        if exists(&path).unwrap_or(false)
+            && let Err(e) = fs::remove_file(&path)
+        {
+            warn!("Failed to remove self-test file {path:?}: {e}");
+        }
+        result
+    }
+
+    fn self_test_file(
+        &self,
+        path: &Path,
+        encrypted: &EncryptedChunk,
+        chunk: &Chunk,
+        host: &str,
+    ) -> Result<()> {
+        let write = || -> Result<()> {
+            let mut f = HistoryFile::create(path, false)?;
+            f.write(encrypted)?;
+            f.flush()
+        };
+        write().map_err(|e| Error::Generic(format!("failed to write {path:?}: {e}")))?;
+
+        let read = || -> Result<Chunk> {
+            let Some(encrypted) = HistoryFile::open(path, self.max_chunk_size)?.read()? else {
+                return Err(Error::from_str("no chunk found"));
+            };
+            encrypted.check_host(host)?;
+            encrypted.decrypt(&self.key)
+        };
+        let read = read().map_err(|e| Error::Generic(format!("failed to read {path:?}: {e}")))?;
+
+        if read.start != chunk.start || read.entries != chunk.entries {
+            return Err(Error::Generic(format!(
+                "chunk read back from {path:?} doesn't match the one written"
+            )));
+        }
         Ok(())
     }
 
+    /// Decrypt all the chunks in the given history file data.
+    pub(super) fn decode_chunks(&self, data: &[u8]) -> Result<Vec<Chunk>> {
+        HistoryFile::from_reader(data, self.max_chunk_size)
+            .map(|chunk| chunk.and_then(|c| c.decrypt(&self.key)))
+            .collect()
+    }
+
+    pub(super) fn state_path(&self) -> &Path {
+        &self.state
+    }
+
+    /// Check that every chunk in a history file can be decrypted, returning
+    /// the number of chunks found and the ones that are bad. If repair is set,
+    /// then the file is rewritten without the bad chunks (a bad chunk header
+    /// means the following chunks can't be found, so they are lost too).
+    pub(super) fn verify_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        host: &str,
+        repair: bool,
+    ) -> Result<(usize, Vec<BadChunk>)> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        let mut f = HistoryFile::from_reader(io::Cursor::new(&data), self.max_chunk_size);
+        let mut chunks = 0;
+        let mut bad = Vec::new();
+        let mut good = Vec::new();
+        let bad_chunk = |offset, error| BadChunk {
+            path: path.to_owned(),
+            offset,
+            error,
+        };
+
+        loop {
+            let offset = f.f.position();
+            let chunk = match f.read() {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) if offset < data.len() as u64 => {
+                    bad.push(bad_chunk(offset, "truncated chunk header".to_string()));
+                    break;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    chunks += 1;
+                    // if only the header was read, then we don't know where
+                    // the next chunk starts.
+                    if f.f.position() <= offset + 8 {
+                        bad.push(bad_chunk(offset, format!("{e} (rest of file skipped)")));
+                        break;
+                    }
+                    bad.push(bad_chunk(offset, e.to_string()));
+                    continue;
+                }
+            };
+            chunks += 1;
+            let end = f.f.position();
+            match chunk
+                .check_host(host)
+                .and_then(|_| chunk.decrypt(&self.key))
+            {
+                Ok(_) => good.extend_from_slice(&data[offset as usize..end as usize]),
+                Err(e) => bad.push(bad_chunk(offset, e.to_string())),
+            }
+        }
+
+        if repair && !bad.is_empty() {
+            debug!("rewrite {path:?} without {} bad chunks", bad.len());
+            // as with write_state, write a new file and move it into place,
+            // so that a failed write doesn't lose the good chunks as well.
+            let mut tmp = path.as_os_str().to_owned();
+            tmp.push(".tmp");
+            fs::write(&tmp, good)?;
+            fs::rename(&tmp, path)?;
+        }
+
+        Ok((chunks, bad))
+    }
+
     pub(super) fn get_hosts<P: AsRef<Path>>(
         &self,
         path: P,
@@ -169,31 +631,59 @@ impl Store {
     pub(super) fn read_chunks<P: AsRef<Path>>(
         &self,
         path: P,
+        host: &str,
+        last_read: DateTime<Utc>,
+        strict: bool,
+    ) -> Result<Vec<Chunk>> {
+        self.read_chunk_range(path, host, last_read, None, strict)
+    }
+
+    /// Like read_chunks, but only reads chunks that start at or before until
+    /// (if it is set).
+    pub(super) fn read_chunk_range<P: AsRef<Path>>(
+        &self,
+        path: P,
+        host: &str,
         last_read: DateTime<Utc>,
+        until: Option<DateTime<Utc>>,
+        strict: bool,
     ) -> Result<Vec<Chunk>> {
         let mut chunks = Vec::new();
         let last_read_day = format!("{}", last_read.format("%Y-%m-%d"));
+        let until_day = until.map(|until| format!("{}", until.format("%Y-%m-%d")));
 
         for entry in fs::read_dir(&path)? {
             let entry = entry?;
             let day = entry.file_name();
-            if day.to_string_lossy().as_ref() < last_read_day.as_str() {
-                // skip any files that have already been read
-                continue;
+            let day = day.to_string_lossy();
+            // files with opaque names could hold chunks from any time, so they
+            // always have to be read.
+            if is_dated(&day) {
+                // monthly archive files are named after the month, so compare
+                // them against the month that was last read.
+                let last_read_prefix = &last_read_day[..day.len().min(last_read_day.len())];
+                if day.as_ref() < last_read_prefix {
+                    // skip any files that have already been read
+                    continue;
+                }
+                if let Some(until_day) = &until_day
+                    && day.as_ref() > &until_day[..day.len().min(until_day.len())]
+                {
+                    // skip any files that are after the range
+                    continue;
+                }
             }
 
             // read chunks from the file, ignoring any that we have already
             // read.
-            let mut new_chunks = HistoryFile::open(entry.path())?
-                .filter(|chunk| match chunk {
-                    Ok(c) => c.start() > last_read,
-                    Err(_) => true,
-                })
-                .map(|chunk| match chunk {
-                    Ok(c) => c.decrypt(&self.key),
-                    Err(e) => Err(e),
-                })
-                .collect::<Result<Vec<Chunk>>>()?;
+            let mut new_chunks = match self.read_file(entry.path(), host, last_read, until) {
+                Ok(chunks) => chunks,
+                Err(e) if !strict => {
+                    warn!("Skipping unreadable history file {:?}: {e}", entry.path());
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
 
             if !new_chunks.is_empty() {
                 // we only need to do anything if we read some new chunks
@@ -204,13 +694,42 @@ impl Store {
         Ok(chunks)
     }
 
+    /// Read every chunk from a single history file for the given host.
+    pub(super) fn read_all_chunks<P: AsRef<Path>>(
+        &self,
+        path: P,
+        host: &str,
+    ) -> Result<Vec<Chunk>> {
+        self.read_file(path, host, DateTime::<Utc>::MIN_UTC, None)
+    }
+
+    fn read_file<P: AsRef<Path>>(
+        &self,
+        path: P,
+        host: &str,
+        last_read: DateTime<Utc>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Chunk>> {
+        HistoryFile::open(path, self.max_chunk_size)?
+            .filter(|chunk| match chunk {
+                Ok(c) => c.start() > last_read && until.is_none_or(|until| c.start() <= until),
+                Err(_) => true,
+            })
+            .map(|chunk| {
+                let chunk = chunk?;
+                chunk.check_host(host)?;
+                chunk.decrypt(&self.key)
+            })
+            .collect()
+    }
+
     pub(super) fn write_chunks<P: AsRef<Path>>(
         &self,
         path: P,
         host: &str,
         chunks: &[Chunk],
         last_write: DateTime<Utc>,
-    ) -> Result<()> {
+    ) -> Result<usize> {
         debug!("We have {} total chunks", chunks.len());
 
         let mut entries = 0;
@@ -226,28 +745,90 @@ impl Store {
             .into_iter()
         {
             debug!("write chunks for {day}");
-            let mut f = HistoryFile::create(Path::new(&dir).join(day), true)?;
+            let mut f = HistoryFile::create(Path::new(&dir).join(self.file_name(&day)), true)?;
             for chunk in chunks {
                 entries += chunk.entries.len();
-                f.write(&EncryptedChunk::encrypt(chunk, &self.key)?)?;
+                f.write(&EncryptedChunk::encrypt(
+                    chunk,
+                    &self.key,
+                    host,
+                    self.cipher,
+                )?)?;
             }
             f.flush()?;
         }
 
         debug!("Wrote total of {entries} new entries");
 
-        Ok(())
+        Ok(entries)
+    }
+
+    /// Combine the daily files in a host directory from before the given day
+    /// into one archive file per month, returning the number of daily files
+    /// that were packed. The chunks are copied as they are, so nothing needs
+    /// to be decrypted.
+    pub(super) fn pack_files<P: AsRef<Path>>(&self, dir: P, before: NaiveDate) -> Result<usize> {
+        let dir = dir.as_ref();
+        let mut days = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if let Ok(day) = NaiveDate::parse_from_str(&name, "%Y-%m-%d")
+                && day < before
+            {
+                days.push((day, name));
+            }
+        }
+        days.sort();
+
+        let mut packed = 0;
+        for (month, days) in days
+            .iter()
+            .chunk_by(|(day, _)| format!("{}", day.format("%Y-%m")))
+            .into_iter()
+        {
+            let days: Vec<_> = days.map(|(_, name)| dir.join(name)).collect();
+            debug!("pack {} daily files into {month} in {dir:?}", days.len());
+            let mut archive = File::options()
+                .append(true)
+                .create(true)
+                .open(dir.join(&month))?;
+            for day in days.iter() {
+                archive.write_all(&fs::read(day)?)?;
+            }
+            archive.sync_all()?;
+            // only remove the daily files once the archive is complete, so
+            // that a failure can't lose anything (though it might duplicate
+            // chunks, which doesn't change the history).
+            for day in days {
+                fs::remove_file(day)?;
+                packed += 1;
+            }
+        }
+
+        Ok(packed)
     }
 
-    pub(super) fn rewrite_all_chunks<P: AsRef<Path>>(
+    /// Replace all the files for the given host with the given chunks.
+    pub(super) fn rewrite_host_chunks<P: AsRef<Path>>(
         &self,
         path: P,
-        history: &HashMap<String, Vec<Chunk>>,
+        host: &str,
+        chunks: &[Chunk],
     ) -> Result<()> {
-        fs::remove_dir_all(path.as_ref())?;
+        self.remove_host(path.as_ref(), host)?;
         // since we have removed the files, use the epoch as the last_write time
-        for (host, chunks) in history.iter() {
-            self.write_chunks(path.as_ref(), host, chunks, DateTime::UNIX_EPOCH)?;
+        self.write_chunks(path, host, chunks, DateTime::UNIX_EPOCH)?;
+        Ok(())
+    }
+
+    /// Remove all the files for the given host.
+    pub(super) fn remove_host<P: AsRef<Path>>(&self, path: P, host: &str) -> Result<()> {
+        for dir in ["hosts", "hosts.v1"] {
+            let dir = path.as_ref().join(dir).join(host);
+            if exists(&dir)? {
+                debug!("remove host directory {dir:?}");
+                fs::remove_dir_all(dir)?;
+            }
         }
         Ok(())
     }
@@ -293,16 +874,28 @@ impl Iterator for HostIterator {
     }
 }
 
-struct HistoryFile {
-    f: File,
+struct HistoryFile<F = File> {
+    f: F,
     complete: bool,
+    max_size: u64,
+}
+
+impl<R: Read> HistoryFile<R> {
+    fn from_reader(r: R, max_size: u64) -> Self {
+        Self {
+            f: r,
+            complete: false,
+            max_size,
+        }
+    }
 }
 
 impl HistoryFile {
-    fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+    fn open<P: AsRef<Path>>(path: P, max_size: u64) -> Result<Self> {
         Ok(Self {
             f: File::open(path)?,
             complete: false,
+            max_size,
         })
     }
 
@@ -315,6 +908,8 @@ impl HistoryFile {
                 .create(true)
                 .open(path)?,
             complete: true,
+            // we only write to created files, so there is nothing to limit
+            max_size: u64::MAX,
         })
     }
 
@@ -330,7 +925,9 @@ impl HistoryFile {
     fn flush(&mut self) -> Result<()> {
         Ok(self.f.flush()?)
     }
+}
 
+impl<R: Read> HistoryFile<R> {
     fn read(&mut self) -> Result<Option<EncryptedChunk>> {
         let mut buf = [0_u8; 8];
         let mut read = 0;
@@ -355,8 +952,23 @@ impl HistoryFile {
         let len = header & 0x00ffffffffffffff;
         let version = ((header & 0xff00000000000000) >> 56) as u8;
 
-        let mut data = vec![0u8; len as usize];
-        self.f.read_exact(&mut data)?;
+        if len > self.max_size {
+            return Err(Error::Generic(format!(
+                "chunk of {len} bytes exceeds the maximum chunk size of {} bytes",
+                self.max_size
+            )));
+        }
+
+        // don't trust the length enough to allocate it up front, a corrupt
+        // header could ask for an impossible amount of memory.
+        let mut data = Vec::new();
+        (&mut self.f).take(len).read_to_end(&mut data)?;
+        if data.len() as u64 != len {
+            return Err(Error::Generic(format!(
+                "truncated chunk: expected {len} bytes, got {}",
+                data.len()
+            )));
+        }
 
         match EncryptedChunk::decode(version, &data)? {
             Some(chunk) => Ok(Some(chunk)),
@@ -365,7 +977,7 @@ impl HistoryFile {
     }
 }
 
-impl Iterator for HistoryFile {
+impl<R: Read> Iterator for HistoryFile<R> {
     type Item = Result<EncryptedChunk>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -415,6 +1027,15 @@ mod v0 {
                 cmd: self.cmd,
                 path: "".to_string(),
                 session: self.session,
+                seq: None,
+                env_hash: None,
+                uid: None,
+                category: None,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
             })
         }
     }
@@ -456,13 +1077,14 @@ mod v0 {
 mod v1 {
     use chrono::{DateTime, Utc};
     use serde::{Deserialize, Serialize};
-    use std::cmp::Ordering;
     use uuid::Uuid;
 
     use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
 
     use crate::error::Result;
 
+    use super::Chunk;
+
     #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
     pub struct Entry {
         pub id: Uuid,
@@ -474,82 +1096,24 @@ mod v1 {
     }
 
     impl Entry {
-        pub fn new<H: Into<String>, C: Into<String>, P: Into<String>, S: Into<String>>(
-            host: H,
-            cmd: C,
-            path: P,
-            session: S,
-        ) -> Self {
-            Self::existing(Uuid::now_v7(), host, cmd, path, session)
-        }
-
-        pub fn existing<
-            I: Into<Uuid>,
-            H: Into<String>,
-            C: Into<String>,
-            P: Into<String>,
-            S: Into<String>,
-        >(
-            id: I,
-            host: H,
-            cmd: C,
-            path: P,
-            session: S,
-        ) -> Self {
-            Self {
-                id: id.into(),
-                ts: Utc::now(),
-                host: host.into(),
-                cmd: cmd.into(),
-                path: path.into(),
-                session: session.into(),
-            }
-        }
-    }
-
-    impl Ord for Entry {
-        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-            self.ts
-                .cmp(&other.ts)
-                .then(self.host.cmp(&other.host))
-                .then(self.cmd.cmp(&other.cmd))
-                .then(self.path.cmp(&other.path))
-        }
-    }
-
-    impl PartialOrd for Entry {
-        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-            Some(self.cmp(other))
-        }
-    }
-
-    #[derive(Debug)]
-    pub struct Chunk {
-        pub start: DateTime<Utc>,
-        pub entries: Vec<Entry>,
-    }
-
-    impl Chunk {
-        pub fn new() -> Self {
-            Self {
-                start: Utc::now(),
-                entries: Vec::new(),
-            }
-        }
-
-        pub fn with_start(start: DateTime<Utc>) -> Self {
-            Self {
-                start,
-                entries: Vec::new(),
-            }
-        }
-
-        pub fn push(&mut self, entry: Entry) {
-            self.entries.push(entry);
-        }
-
-        pub fn len(&self) -> usize {
-            self.entries.len()
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: None,
+                env_hash: None,
+                uid: None,
+                category: None,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
         }
     }
 
@@ -571,25 +1135,992 @@ mod v1 {
             Ok(rmp_serde::to_vec(&self)?)
         }
 
-        pub(super) fn encrypt(chunk: &Chunk, key: &[u8]) -> Result<Self> {
-            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
-            let mut data = rmp_serde::to_vec(&chunk.entries)?;
-            let nonce = key.seal_in_place_append_tag(Aad::empty(), &mut data)?;
-            Ok(Self {
-                start: chunk.start,
-                nonce: nonce.as_ref().into(),
-                data,
-            })
-        }
-
         pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
             let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
             let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
             let data = key.open_in_place(nonce, Aad::empty(), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
             Ok(Chunk {
                 start: self.start,
-                entries: rmp_serde::from_slice(data)?,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+}
+
+mod v2 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
+
+    use crate::error::Result;
+
+    use super::Chunk;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Entry {
+        pub id: Uuid,
+        pub ts: DateTime<Utc>,
+        pub host: String,
+        pub cmd: String,
+        pub path: String,
+        pub session: String,
+        pub seq: Option<u64>,
+    }
+
+    impl Entry {
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: self.seq,
+                env_hash: None,
+                uid: None,
+                category: None,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let data = key.open_in_place(nonce, Aad::empty(), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+}
+
+mod v3 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
+
+    use crate::error::Result;
+
+    use super::Chunk;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Entry {
+        pub id: Uuid,
+        pub ts: DateTime<Utc>,
+        pub host: String,
+        pub cmd: String,
+        pub path: String,
+        pub session: String,
+        pub seq: Option<u64>,
+        pub env_hash: Option<String>,
+    }
+
+    impl Entry {
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: self.seq,
+                env_hash: self.env_hash,
+                uid: None,
+                category: None,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let data = key.open_in_place(nonce, Aad::empty(), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+}
+
+mod v4 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
+
+    use crate::error::Result;
+
+    use super::Chunk;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Entry {
+        pub id: Uuid,
+        pub ts: DateTime<Utc>,
+        pub host: String,
+        pub cmd: String,
+        pub path: String,
+        pub session: String,
+        pub seq: Option<u64>,
+        pub env_hash: Option<String>,
+        pub uid: Option<u32>,
+    }
+
+    impl Entry {
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: self.seq,
+                env_hash: self.env_hash,
+                uid: self.uid,
+                category: None,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let data = key.open_in_place(nonce, Aad::empty(), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+}
+
+mod v5 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
+
+    use crate::error::Result;
+
+    use super::Chunk;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Entry {
+        pub id: Uuid,
+        pub ts: DateTime<Utc>,
+        pub host: String,
+        pub cmd: String,
+        pub path: String,
+        pub session: String,
+        pub seq: Option<u64>,
+        pub env_hash: Option<String>,
+        pub uid: Option<u32>,
+    }
+
+    impl Entry {
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: self.seq,
+                env_hash: self.env_hash,
+                uid: self.uid,
+                category: None,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        pub(super) host: String,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let aad = aad(&self.host, &self.start);
+            let data = key.open_in_place(nonce, Aad::from(&aad), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+
+    /// The associated data binds the encrypted entries to the host and start
+    /// time stored alongside them, so that neither can be changed without the
+    /// chunk failing to decrypt.
+    fn aad(host: &str, start: &DateTime<Utc>) -> Vec<u8> {
+        format!("{host}\0{}", start.to_rfc3339()).into_bytes()
+    }
+}
+
+mod v6 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
+
+    use crate::error::Result;
+
+    use super::Chunk;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Entry {
+        pub id: Uuid,
+        pub ts: DateTime<Utc>,
+        pub host: String,
+        pub cmd: String,
+        pub path: String,
+        pub session: String,
+        pub seq: Option<u64>,
+        pub env_hash: Option<String>,
+        pub uid: Option<u32>,
+        pub category: Option<String>,
+    }
+
+    impl Entry {
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: self.seq,
+                env_hash: self.env_hash,
+                uid: self.uid,
+                category: self.category,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        pub(super) host: String,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let aad = aad(&self.host, &self.start);
+            let data = key.open_in_place(nonce, Aad::from(&aad), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+
+    /// The associated data binds the encrypted entries to the host and start
+    /// time stored alongside them, so that neither can be changed without the
+    /// chunk failing to decrypt.
+    fn aad(host: &str, start: &DateTime<Utc>) -> Vec<u8> {
+        format!("{host}\0{}", start.to_rfc3339()).into_bytes()
+    }
+}
+
+mod v7 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
+
+    use crate::error::Result;
+
+    use super::Chunk;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Entry {
+        pub id: Uuid,
+        pub ts: DateTime<Utc>,
+        pub host: String,
+        pub cmd: String,
+        pub path: String,
+        pub session: String,
+        pub seq: Option<u64>,
+        pub env_hash: Option<String>,
+        pub uid: Option<u32>,
+        pub category: Option<String>,
+        pub shell: Option<String>,
+    }
+
+    impl Entry {
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: self.seq,
+                env_hash: self.env_hash,
+                uid: self.uid,
+                category: self.category,
+                shell: self.shell,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        pub(super) host: String,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let aad = aad(&self.host, &self.start);
+            let data = key.open_in_place(nonce, Aad::from(&aad), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+
+    /// The associated data binds the encrypted entries to the host and start
+    /// time stored alongside them, so that neither can be changed without the
+    /// chunk failing to decrypt.
+    fn aad(host: &str, start: &DateTime<Utc>) -> Vec<u8> {
+        format!("{host}\0{}", start.to_rfc3339()).into_bytes()
+    }
+}
+
+mod v8 {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use aws_lc_rs::aead::{AES_256_GCM, Aad, Nonce, RandomizedNonceKey};
+
+    use crate::error::Result;
+
+    use super::Chunk;
+
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+    pub struct Entry {
+        pub id: Uuid,
+        pub ts: DateTime<Utc>,
+        pub host: String,
+        pub cmd: String,
+        pub path: String,
+        pub session: String,
+        pub seq: Option<u64>,
+        pub env_hash: Option<String>,
+        pub uid: Option<u32>,
+        pub category: Option<String>,
+        pub shell: Option<String>,
+        pub note: Option<String>,
+    }
+
+    impl Entry {
+        fn convert(self) -> Result<super::Entry> {
+            Ok(super::Entry {
+                id: self.id,
+                ts: self.ts,
+                host: self.host,
+                cmd: self.cmd,
+                path: self.path,
+                session: self.session,
+                seq: self.seq,
+                env_hash: self.env_hash,
+                uid: self.uid,
+                category: self.category,
+                shell: self.shell,
+                note: self.note,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            })
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        pub(super) host: String,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let key = RandomizedNonceKey::new(&AES_256_GCM, key)?;
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let aad = aad(&self.host, &self.start);
+            let data = key.open_in_place(nonce, Aad::from(&aad), &mut self.data)?;
+            let entries: Vec<Entry> = rmp_serde::from_slice(data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: entries
+                    .into_iter()
+                    .map(|e| e.convert())
+                    .collect::<Result<_>>()?,
+            })
+        }
+    }
+
+    /// The associated data binds the encrypted entries to the host and start
+    /// time stored alongside them, so that neither can be changed without the
+    /// chunk failing to decrypt.
+    fn aad(host: &str, start: &DateTime<Utc>) -> Vec<u8> {
+        format!("{host}\0{}", start.to_rfc3339()).into_bytes()
+    }
+}
+
+mod v9 {
+    use chrono::{DateTime, Utc};
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
+    use std::cmp::Ordering;
+    use uuid::Uuid;
+
+    use aws_lc_rs::{
+        aead::{
+            AES_128_GCM, AES_256_GCM, Aad, Algorithm, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN,
+            Nonce, UnboundKey,
+        },
+        rand,
+    };
+
+    use crate::{
+        config::Cipher,
+        error::{Error, Result},
+    };
+
+    /// A single command from the history.
+    #[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize, JsonSchema)]
+    pub struct Entry {
+        /// Unique ID of the command, shared by any later edits of it
+        pub id: Uuid,
+        /// When the command was stored
+        pub ts: DateTime<Utc>,
+        /// The host that stored the command
+        pub host: String,
+        /// The command itself
+        pub cmd: String,
+        /// The directory the command was run in (empty if not recorded)
+        pub path: String,
+        /// The shell session the command was run in
+        pub session: String,
+        /// The position of the command in its session
+        pub seq: Option<u64>,
+        /// Hash of the history.env_vars environment variables
+        pub env_hash: Option<String>,
+        /// The user ID that ran the command
+        pub uid: Option<u32>,
+        /// The category of the command (e.g. git)
+        pub category: Option<String>,
+        /// The shell that the command was run from (e.g. bash)
+        pub shell: Option<String>,
+        /// A note attached to the command with vellum annotate
+        pub note: Option<String>,
+        /// The exit status of the command, if it was recorded (e.g. by vellum
+        /// run)
+        pub exit: Option<i32>,
+        /// How long the command took to run in milliseconds, if it was
+        /// recorded
+        pub duration_ms: Option<u64>,
+        /// Set on an edit that removes the path, since the empty path of any
+        /// other edit leaves the path unchanged
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        pub path_cleared: bool,
+    }
+
+    impl Entry {
+        pub fn new<H: Into<String>, C: Into<String>, P: Into<String>, S: Into<String>>(
+            host: H,
+            cmd: C,
+            path: P,
+            session: S,
+        ) -> Self {
+            Self::existing(Uuid::now_v7(), host, cmd, path, session)
+        }
+
+        pub fn existing<
+            I: Into<Uuid>,
+            H: Into<String>,
+            C: Into<String>,
+            P: Into<String>,
+            S: Into<String>,
+        >(
+            id: I,
+            host: H,
+            cmd: C,
+            path: P,
+            session: S,
+        ) -> Self {
+            Self {
+                id: id.into(),
+                ts: Utc::now(),
+                host: host.into(),
+                cmd: cmd.into(),
+                path: path.into(),
+                session: session.into(),
+                seq: None,
+                env_hash: None,
+                uid: None,
+                category: None,
+                shell: None,
+                note: None,
+                exit: None,
+                duration_ms: None,
+                path_cleared: false,
+            }
+        }
+    }
+
+    impl Ord for Entry {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.ts
+                .cmp(&other.ts)
+                .then(self.host.cmp(&other.host))
+                .then(self.cmd.cmp(&other.cmd))
+                .then(self.path.cmp(&other.path))
+        }
+    }
+
+    impl PartialOrd for Entry {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct Chunk {
+        pub start: DateTime<Utc>,
+        pub entries: Vec<Entry>,
+    }
+
+    impl Chunk {
+        pub fn new() -> Self {
+            Self {
+                start: Utc::now(),
+                entries: Vec::new(),
+            }
+        }
+
+        pub fn with_start(start: DateTime<Utc>) -> Self {
+            Self {
+                start,
+                entries: Vec::new(),
+            }
+        }
+
+        pub fn push(&mut self, entry: Entry) {
+            self.entries.push(entry);
+        }
+
+        pub fn len(&self) -> usize {
+            self.entries.len()
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedChunk {
+        pub(super) start: DateTime<Utc>,
+        pub(super) host: String,
+        // which cipher the chunk was encrypted with, see cipher_id.
+        cipher: u8,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedChunk {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        // RandomizedNonceKey doesn't support ChaCha20-Poly1305, so we generate
+        // the random nonce ourselves.
+        pub(super) fn encrypt(
+            chunk: &Chunk,
+            key: &[u8],
+            host: &str,
+            cipher: Cipher,
+        ) -> Result<Self> {
+            let key = LessSafeKey::new(UnboundKey::new(algorithm(cipher), key)?);
+            let mut nonce = [0_u8; NONCE_LEN];
+            rand::fill(&mut nonce)?;
+            let mut data = rmp_serde::to_vec(&chunk.entries)?;
+            let aad = aad(host, &chunk.start);
+            key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(&aad),
+                &mut data,
+            )?;
+            Ok(Self {
+                start: chunk.start,
+                host: host.to_string(),
+                cipher: cipher_id(cipher),
+                nonce: nonce.into(),
+                data,
+            })
+        }
+
+        pub(super) fn decrypt(mut self, key: &[u8]) -> Result<Chunk> {
+            let cipher = cipher_from_id(self.cipher)?;
+            let key = LessSafeKey::new(UnboundKey::new(algorithm(cipher), key)?);
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let aad = aad(&self.host, &self.start);
+            let data = key.open_in_place(nonce, Aad::from(&aad), &mut self.data)?;
+            Ok(Chunk {
+                start: self.start,
+                entries: rmp_serde::from_slice(data)?,
+            })
+        }
+    }
+
+    /// Data kept alongside the history (e.g. the command counters), encrypted
+    /// in the same way as a chunk. The purpose is used as the associated data,
+    /// so that one kind of data can't be passed off as another.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub(super) struct EncryptedData {
+        cipher: u8,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    impl EncryptedData {
+        pub(super) fn decode(data: &[u8]) -> Result<Self> {
+            Ok(rmp_serde::from_slice(data)?)
+        }
+
+        pub(super) fn encode(&self) -> Result<Vec<u8>> {
+            Ok(rmp_serde::to_vec(&self)?)
+        }
+
+        pub(super) fn encrypt(
+            mut data: Vec<u8>,
+            key: &[u8],
+            purpose: &str,
+            cipher: Cipher,
+        ) -> Result<Self> {
+            let key = LessSafeKey::new(UnboundKey::new(algorithm(cipher), key)?);
+            let mut nonce = [0_u8; NONCE_LEN];
+            rand::fill(&mut nonce)?;
+            key.seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(purpose.as_bytes()),
+                &mut data,
+            )?;
+            Ok(Self {
+                cipher: cipher_id(cipher),
+                nonce: nonce.into(),
+                data,
             })
         }
+
+        pub(super) fn decrypt(mut self, key: &[u8], purpose: &str) -> Result<Vec<u8>> {
+            let cipher = cipher_from_id(self.cipher)?;
+            let key = LessSafeKey::new(UnboundKey::new(algorithm(cipher), key)?);
+            let nonce = Nonce::try_assume_unique_for_key(&self.nonce)?;
+            let len = key
+                .open_in_place(nonce, Aad::from(purpose.as_bytes()), &mut self.data)?
+                .len();
+            self.data.truncate(len);
+            Ok(self.data)
+        }
+    }
+
+    /// The ID recorded in the chunk for each cipher, these must never change.
+    fn cipher_id(cipher: Cipher) -> u8 {
+        match cipher {
+            Cipher::Aes256Gcm => 0,
+            Cipher::Aes128Gcm => 1,
+            Cipher::Chacha20Poly1305 => 2,
+        }
+    }
+
+    fn cipher_from_id(id: u8) -> Result<Cipher> {
+        match id {
+            0 => Ok(Cipher::Aes256Gcm),
+            1 => Ok(Cipher::Aes128Gcm),
+            2 => Ok(Cipher::Chacha20Poly1305),
+            id => Err(Error::Generic(format!("chunk uses unknown cipher {id}"))),
+        }
+    }
+
+    fn algorithm(cipher: Cipher) -> &'static Algorithm {
+        match cipher {
+            Cipher::Aes256Gcm => &AES_256_GCM,
+            Cipher::Aes128Gcm => &AES_128_GCM,
+            Cipher::Chacha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+
+    /// The associated data binds the encrypted entries to the host and start
+    /// time stored alongside them, so that neither can be changed without the
+    /// chunk failing to decrypt.
+    fn aad(host: &str, start: &DateTime<Utc>) -> Vec<u8> {
+        format!("{host}\0{}", start.to_rfc3339()).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(version: u8, len: u64) -> [u8; 8] {
+        (len | ((version as u64) << 56)).to_be_bytes()
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let data = header(8, 0x00ff_ffff_ffff_ffff);
+        let mut f = HistoryFile::from_reader(&data[..], 1024);
+        let err = f.read().unwrap_err();
+        assert!(err.to_string().contains("exceeds the maximum chunk size"));
+    }
+
+    #[test]
+    fn truncated_chunk() {
+        let mut data = header(8, 100).to_vec();
+        data.extend([0_u8; 10]);
+        let mut f = HistoryFile::from_reader(&data[..], 1024);
+        let err = f.read().unwrap_err();
+        assert!(err.to_string().contains("truncated chunk"));
+    }
+
+    #[test]
+    fn replaced_bytes_round_trip() {
+        // commands that weren't valid UTF-8 are stored with the bad bytes
+        // replaced, which mustn't stop the rest of the chunk being read.
+        let key = vec![7_u8; Cipher::Aes256Gcm.key_len()];
+        let mut chunk = Chunk::new();
+        chunk.push(Entry::new("host", "ls", "/tmp", "s"));
+        chunk.push(Entry::new(
+            "host",
+            String::from_utf8_lossy(b"cat \xff"),
+            String::from_utf8_lossy(b"/tmp/\xfe"),
+            "s",
+        ));
+        let encrypted = EncryptedChunk::encrypt(&chunk, &key, "host", Cipher::Aes256Gcm).unwrap();
+        let data = encrypted.encode().unwrap();
+        let decrypted = EncryptedChunk::decode(encrypted.version(), &data)
+            .unwrap()
+            .unwrap()
+            .decrypt(&key)
+            .unwrap();
+        assert_eq!(decrypted.entries, chunk.entries);
+    }
+
+    #[test]
+    fn empty_file() {
+        let mut f = HistoryFile::from_reader(&[][..], 1024);
+        assert!(f.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn new_chunks_are_v9() {
+        // the cipher, exit status and duration are only stored by version 9,
+        // so older binaries skip these chunks rather than misreading them.
+        let key = vec![7_u8; Cipher::Chacha20Poly1305.key_len()];
+        let mut chunk = Chunk::new();
+        let mut entry = Entry::new("host", "make", "/tmp", "s");
+        entry.exit = Some(2);
+        entry.duration_ms = Some(1500);
+        chunk.push(entry);
+        let encrypted =
+            EncryptedChunk::encrypt(&chunk, &key, "host", Cipher::Chacha20Poly1305).unwrap();
+        assert_eq!(encrypted.version(), 9);
+        let data = encrypted.encode().unwrap();
+        let decrypted = EncryptedChunk::decode(9, &data)
+            .unwrap()
+            .unwrap()
+            .decrypt(&key)
+            .unwrap();
+        assert_eq!(decrypted.entries, chunk.entries);
+        assert!(EncryptedChunk::decode(10, &data).unwrap().is_none());
     }
 }
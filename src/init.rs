@@ -1,20 +1,26 @@
 use std::{
-    fs::{self, File},
-    io::{Write, stdout},
-    path::Path,
+    env,
+    fs::{self, File, exists},
+    io::{Write, stdin, stdout},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
 };
 
+use base64::{Engine, prelude::BASE64_STANDARD};
 use chrono::Utc;
 use clap::{Command, ValueHint};
 use clap_mangen::Man;
 use flate2::{Compression, write::GzEncoder};
 use log::{debug, info};
 use uuid::Uuid;
+use xdg::BaseDirectories;
 
 use crate::{
-    assets,
+    assets, bundle, client,
+    config::{Cipher, Config},
     error::{Error, Result},
-    history::generate_key,
+    history::{generate_key, get_key, parse_key, set_keyring_key},
+    mnemonic,
 };
 
 #[derive(clap::Args, Debug)]
@@ -32,7 +38,42 @@ enum Commands {
     Zsh,
 
     /// Output an encryption key, suitable for use as $VELLUM_KEY
-    Key,
+    Key {
+        /// Store the new key in the system keyring, as well as showing it
+        #[arg(long)]
+        keyring: bool,
+
+        /// The cipher that the key is for (set history.cipher to match)
+        #[arg(long, value_enum, default_value_t = Cipher::default())]
+        cipher: Cipher,
+
+        /// Also show the key as a mnemonic (a list of words, written to
+        /// stderr), which can be used with --from-mnemonic to recover the key
+        #[arg(long)]
+        mnemonic: bool,
+
+        /// Recover the key from the mnemonic shown by --mnemonic, instead of
+        /// generating a new one. The words are read from stdin (or prompted
+        /// for)
+        #[arg(long, conflicts_with_all = ["cipher", "mnemonic"])]
+        from_mnemonic: bool,
+    },
+
+    /// Write a bundle containing the config file and encryption key, for use
+    /// as $VELLUM_BUNDLE
+    ///
+    /// The bundle is encrypted with the passphrase from
+    /// $VELLUM_BUNDLE_PASSPHRASE, which also needs to be set when using it.
+    Bundle {
+        /// The config file to include in the bundle, defaults to the user
+        /// config file (or $VELLUM_CONFIG if set)
+        #[arg(long, value_name = "CONFIG", value_hint = ValueHint::FilePath)]
+        from: Option<PathBuf>,
+
+        /// The bundle file to create
+        #[clap(value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+    },
 
     /// Output a session id, suitable for use as $VELLUM_SESSION
     Session,
@@ -40,6 +81,16 @@ enum Commands {
     /// Output a timestamp, suitable for use as $VELLUM_SESSION_START
     Timestamp,
 
+    /// Interactively setup vellum for the first time
+    ///
+    /// This generates an encryption key, writes a config file, and shows the
+    /// shell integration that needs to be added to your shell startup file.
+    Setup {
+        /// Don't prompt, just use the default answer for every question
+        #[arg(long)]
+        defaults: bool,
+    },
+
     /// Output the man page for vellum
     Man {
         /// Destination directory to write man pages to
@@ -52,9 +103,16 @@ pub fn init(args: Args, cmd: Command) -> Result<()> {
     match args.command {
         Commands::Bash => show_bash(),
         Commands::Zsh => show_zsh(),
-        Commands::Key => show_key(),
+        Commands::Key {
+            keyring,
+            cipher,
+            mnemonic,
+            from_mnemonic,
+        } => show_key(keyring, cipher, mnemonic, from_mnemonic),
+        Commands::Bundle { from, output } => write_bundle(from, output),
         Commands::Session => show_session(),
         Commands::Timestamp => show_timestamp(),
+        Commands::Setup { defaults } => setup(defaults),
         Commands::Man { dest } => show_manpage(dest, cmd),
     }
 }
@@ -75,9 +133,60 @@ fn show_zsh() -> Result<()> {
     Ok(())
 }
 
-fn show_key() -> Result<()> {
-    debug!("show key ...");
-    print!("{}", generate_key()?);
+fn show_key(keyring: bool, cipher: Cipher, show_mnemonic: bool, from_mnemonic: bool) -> Result<()> {
+    debug!("show key (keyring={keyring}, cipher={cipher:?}, mnemonic={show_mnemonic}) ...");
+    let key = match from_mnemonic {
+        true => {
+            let words = client::read_secret("Mnemonic words")?;
+            let key = BASE64_STANDARD.encode(mnemonic::decode(&words)?);
+            // make sure that the key is usable with one of the ciphers.
+            parse_key(&key)?;
+            key
+        }
+        false => generate_key(cipher)?,
+    };
+    if show_mnemonic {
+        let words = mnemonic::encode(&BASE64_STANDARD.decode(&key)?)?;
+        eprintln!(
+            "Write down these words, the key can be recovered from them with `vellum init key --from-mnemonic`:\n\n{words}\n"
+        );
+    }
+    if keyring {
+        set_keyring_key(&key)?;
+        // the key is only in the keyring, so show it once so that a copy can
+        // be kept (it is needed to read the history on other hosts).
+        println!(
+            "Stored new encryption key in the system keyring, keep a copy of it somewhere safe:\n\n{key}"
+        );
+    } else {
+        print!("{key}");
+    }
+    Ok(())
+}
+
+fn write_bundle(from: Option<PathBuf>, output: PathBuf) -> Result<()> {
+    debug!("write bundle {output:?} (from={from:?}) ...");
+    let passphrase = bundle::passphrase()?;
+    let from = match from {
+        Some(path) => Some(path),
+        None => match env::var_os("VELLUM_CONFIG") {
+            Some(path) => Some(PathBuf::from(path)),
+            None => BaseDirectories::with_prefix("vellum")?.find_config_file("config.toml"),
+        },
+    };
+    let config = match &from {
+        Some(path) => fs::read_to_string(path)?,
+        None => String::new(),
+    };
+    // make sure that the config is valid now, rather than when the bundle is
+    // used.
+    let cfg: Config = toml::from_str(&config)?;
+    let key = BASE64_STANDARD.encode(get_key(&cfg)?);
+    bundle::write(&output, &passphrase, &bundle::Contents { config, key })?;
+    match from {
+        Some(from) => println!("Wrote config from {from:?} and encryption key to {output:?}"),
+        None => println!("Wrote default config and encryption key to {output:?}"),
+    }
     Ok(())
 }
 
@@ -93,6 +202,141 @@ fn show_timestamp() -> Result<()> {
     Ok(())
 }
 
+fn setup(defaults: bool) -> Result<()> {
+    debug!("setup (defaults={defaults}) ...");
+    let prompt = Prompt { defaults };
+    let dirs = BaseDirectories::with_prefix("vellum")?;
+
+    // encryption key
+    let key = generate_key(Cipher::default())?;
+    let key_file = dirs.get_config_home().join("key");
+    let key_path = key_file.to_string_lossy();
+    let read_key = shlex::try_quote(&key_path)
+        .map(|path| format!("\"$(cat {path})\""))
+        .map_err(|e| Error::Generic(format!("can't quote the path {key_path:?}: {e}")))?;
+    let key_var = if exists(&key_file)? {
+        println!("Key file {key_file:?} already exists, not generating a new key.");
+        read_key
+    } else if prompt.confirm(&format!("Write new encryption key to {key_file:?}?"), true)? {
+        write_private(&key_file, &key)?;
+        println!("Wrote encryption key to {key_file:?}, keep a copy somewhere safe!");
+        read_key
+    } else {
+        println!("Generated encryption key, keep a copy somewhere safe!");
+        format!("\"{key}\"")
+    };
+
+    // config file
+    let config_file = match env::var_os("VELLUM_CONFIG") {
+        Some(path) => PathBuf::from(path),
+        None => dirs.get_config_home().join("config.toml"),
+    };
+    if exists(&config_file)?
+        && !prompt.confirm(&format!("Overwrite existing {config_file:?}?"), false)?
+    {
+        println!("Keeping existing config file {config_file:?}");
+    } else {
+        let url = prompt.ask("Git sync URL (leave empty to only sync locally)", "")?;
+        let mut sync = toml::Table::new();
+        if url.is_empty() {
+            sync.insert("enabled".into(), false.into());
+        } else {
+            sync.insert("url".into(), url.into());
+            let ssh_key = prompt.ask("SSH private key file (leave empty for none)", "")?;
+            if !ssh_key.is_empty() {
+                sync.insert("ssh_key".into(), ssh_key.into());
+            }
+        }
+        let mut config = toml::Table::new();
+        config.insert("sync".into(), sync.into());
+        if let Some(parent) = config_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&config_file, toml::to_string_pretty(&config)?)?;
+        println!("Wrote config file {config_file:?}");
+    }
+
+    // shell integration
+    let shell = env::var("SHELL").unwrap_or_default();
+    let shell = Path::new(&shell)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let shell = match shell.as_str() {
+        "bash" | "zsh" => shell,
+        _ => {
+            println!("Unable to detect a supported shell from $SHELL, assuming bash.");
+            "bash".to_string()
+        }
+    };
+
+    println!();
+    println!("Add the following to your ~/.{shell}rc:");
+    println!();
+    println!("export VELLUM_KEY={key_var}");
+    if shell == "bash" {
+        println!("source /path/to/bash-preexec.sh");
+    }
+    println!("eval \"$(fzf --{shell})\"");
+    println!("eval \"$(vellum init {shell})\"");
+    println!("eval \"$(vellum complete {shell})\"");
+
+    Ok(())
+}
+
+/// Write a file that only the current user can read.
+fn write_private<P: AsRef<Path>>(path: P, contents: &str) -> Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut f = File::options()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(f, "{contents}")?;
+    Ok(())
+}
+
+struct Prompt {
+    defaults: bool,
+}
+
+impl Prompt {
+    fn ask(&self, question: &str, default: &str) -> Result<String> {
+        if self.defaults {
+            return Ok(default.to_string());
+        }
+        match default {
+            "" => print!("{question}: "),
+            default => print!("{question} [{default}]: "),
+        }
+        stdout().flush()?;
+        let mut buf = String::new();
+        stdin().read_line(&mut buf)?;
+        match buf.trim() {
+            "" => Ok(default.to_string()),
+            answer => Ok(answer.to_string()),
+        }
+    }
+
+    fn confirm(&self, question: &str, default: bool) -> Result<bool> {
+        let hint = match default {
+            true => "Y/n",
+            false => "y/N",
+        };
+        loop {
+            let answer = self.ask(&format!("{question} ({hint})"), "")?;
+            match answer.to_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                _ => println!("Please enter \"yes\" or \"no\""),
+            }
+        }
+    }
+}
+
 fn show_manpage(dest: String, cmd: Command) -> Result<()> {
     fs::create_dir_all(&dest)?;
     let dest = Path::new(&dest);
@@ -1,20 +1,34 @@
-use std::{env, fs, io, path::Path, process::exit};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::Path,
+    process::{exit, id},
+    time::Duration,
+};
 
-use clap::{CommandFactory, Parser, Subcommand, ValueHint};
-use env_logger::{Env, Target};
-use log::error;
+use chrono::{SecondsFormat, Utc};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueHint};
+use env_logger::{Builder, Env, Target};
+use log::{LevelFilter, error};
+use serde_json::json;
 
 mod api;
 mod assets;
+mod bundle;
 mod client;
 mod complete;
 mod config;
 mod error;
 mod history;
 mod init;
+mod mnemonic;
 mod process;
+mod selftest;
 mod server;
 mod sync;
+mod systemd;
 
 const CLAP_STYLING: clap::builder::styling::Styles = clap::builder::styling::Styles::styled()
     .header(clap_cargo::style::HEADER)
@@ -25,6 +39,10 @@ const CLAP_STYLING: clap::builder::styling::Styles = clap::builder::styling::Sty
     .valid(clap_cargo::style::VALID)
     .invalid(clap_cargo::style::INVALID);
 
+/// How long stop waits by default for the sync on exit, on top of
+/// sync.lock_timeout.
+const STOP_SYNC_TIMEOUT: Duration = Duration::from_secs(60);
+
 const LONG_ABOUT: &str = r#"vellum syncs shell command history between hosts using a git repository as a
  central synchronisation point."#;
 
@@ -36,21 +54,99 @@ struct Cli {
     #[arg(short, long, value_name = "FILE")]
     config: Option<String>,
 
+    /// Only log errors (use twice to disable logging completely), overrides
+    /// $VELLUM_LOG
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// Log more detail (use twice for even more), overrides $VELLUM_LOG
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// How to report an error that causes vellum to exit
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    error_format: ErrorFormat,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum ErrorFormat {
+    /// Log the error message
+    Text,
+    /// Output a JSON object with the error kind, message and exit code to
+    /// stderr
+    Json,
+}
+
+impl ErrorFormat {
+    /// Report the error and exit.
+    fn fail(&self, prefix: &str, e: error::Error) -> ! {
+        let code = 1;
+        match self {
+            Self::Text => error!("{prefix}{e}"),
+            Self::Json => eprintln!(
+                "{}",
+                json!({
+                    "kind": e.kind(),
+                    "message": format!("{prefix}{e}"),
+                    "exit_code": code,
+                })
+            ),
+        }
+        exit(code);
+    }
+}
+
+impl Cli {
+    /// The log level requested on the command line, if any.
+    fn log_level(&self) -> Option<LevelFilter> {
+        match (self.quiet, self.verbose) {
+            (0, 0) => None,
+            (1, _) => Some(LevelFilter::Error),
+            (2.., _) => Some(LevelFilter::Off),
+            (_, 1) => Some(LevelFilter::Debug),
+            (_, 2..) => Some(LevelFilter::Trace),
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Store a shell command in the history
     Store {
-        /// the shell command to be stored
-        shell_command: String,
+        /// Output the ID of the stored entry
+        #[arg(long)]
+        print_id: bool,
+
+        /// The directory the command was run in (defaults to the current
+        /// directory)
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        path: Option<OsString>,
+
+        /// Read commands from stdin, one per line, and store each of them in
+        /// the current session
+        #[arg(long, conflicts_with = "shell_command")]
+        stdin: bool,
+
+        /// the shell command to be stored (any bytes that aren't valid UTF-8
+        /// are replaced, with a warning)
+        #[arg(required_unless_present = "stdin")]
+        shell_command: Option<OsString>,
     },
 
+    /// Store a command in the history and then run it, recording its exit
+    /// status and how long it took, and exiting with its status
+    Run(client::RunArgs),
+
     /// List all the stored commands
     History(client::HistoryArgs),
 
+    /// Interactively browse and search the history, printing the selected
+    /// command
+    Browse(client::BrowseArgs),
+
     /// Save the current history, by outputting it as JSON
     Save(client::SaveArgs),
 
@@ -66,24 +162,65 @@ enum Commands {
     /// Edit stored history
     Edit(client::EditArgs),
 
+    /// List the sessions seen in the history, with when they were active and
+    /// how many commands they stored
+    Sessions(client::SessionsArgs),
+
+    /// List the directories that commands were run in, ranked by how often
+    /// and how recently they were used
+    Paths(client::PathsArgs),
+
+    /// Print the commands stored by a session as a shell script
+    ReplaySession(client::ReplaySessionArgs),
+
     /// Mark specified history entries as deleted
     ///
+    /// Entries stored by any host can be deleted, including entries that have
+    /// not been synced to this host yet (they will be hidden once they are).
+    ///
     /// NOTE: When entries are deleted they are only marked as deleted. This
     /// means that they are no longer visible from a client, but the deleted
     /// entry is still stored on disk / in git (in encrypted form). If you want
     /// to completely erase the entry you will also need to run `vellum rebuild`
-    /// to rebuild the on-disk data.
+    /// to rebuild the on-disk data (this rewrites the data for all hosts, so
+    /// can be run from any host).
     Delete {
         /// IDs of entries to be marked as deleted
         #[arg(required = true, value_hint = ValueHint::Other)]
         ids: Vec<String>,
     },
 
+    /// Attach a note to a history entry (e.g. "this broke prod, don't rerun"),
+    /// which can be shown with history --with-notes. Annotating an entry
+    /// again replaces its note, and an empty note removes it.
+    Annotate {
+        /// ID of the entry to annotate
+        #[arg(value_hint = ValueHint::Other)]
+        id: String,
+
+        /// The text of the note
+        #[arg(value_hint = ValueHint::Other)]
+        note: String,
+    },
+
     /// Import command history from stdin or a file
     Import(client::ImportArgs),
 
+    /// Export command history for use by other tools
+    Export(client::ExportArgs),
+
     /// Display the vellum configuration
-    Config,
+    Config {
+        /// Display the configuration, and the paths derived from it, as JSON
+        #[arg(short, long)]
+        json: bool,
+
+        /// Display the configuration that the running server is using, which
+        /// can differ from the config file if it has changed since the server
+        /// started (any WebDAV password or token is redacted)
+        #[arg(long)]
+        server: bool,
+    },
 
     /// Commands to setup/initialise vellum
     Init(init::Args),
@@ -91,11 +228,24 @@ enum Commands {
     /// Generate shell completion file
     Complete(complete::Args),
 
+    /// Output completion candidates, used by the shell completion
+    #[command(name = complete::VALUES_COMMAND, hide = true)]
+    CompleteValues {
+        #[arg(value_enum)]
+        values: complete::Values,
+    },
+
     /// Ping the server
     Ping {
         /// Wait for the ping to succeed
         #[arg(short, long)]
         wait: bool,
+
+        /// Send COUNT pings and report the round trip times, followed by the
+        /// time taken by a history request (pings don't need the history lock,
+        /// so a slow history request with fast pings points to lock contention)
+        #[arg(short, long)]
+        count: Option<u32>,
     },
 
     /// Request the server sync the history immediately
@@ -103,10 +253,72 @@ enum Commands {
         /// Force a sync, even if things seem to be up-to-date
         #[arg(short, long)]
         force: bool,
+
+        /// Fetch upstream changes and show what a sync would change, without
+        /// actually syncing
+        #[arg(short, long, conflicts_with = "force")]
+        preview: bool,
+
+        /// Push any local changes, and then replace the sync checkout with a
+        /// fresh clone of sync.url
+        #[arg(long, conflicts_with_all = ["force", "preview"])]
+        reclone: bool,
+
+        /// Only fetch and read in the changes from other hosts, without
+        /// pushing local changes (they are pushed by the next full sync)
+        #[arg(long, conflicts_with_all = ["force", "preview", "reclone"])]
+        only_pull: bool,
+
+        /// Only push local changes, without fetching the changes from other
+        /// hosts first (unless the push is rejected because upstream has
+        /// changed)
+        #[arg(long, conflicts_with_all = ["only_pull", "preview", "reclone"])]
+        only_push: bool,
     },
 
     /// Request the server rebuild the sync data
-    Rebuild,
+    ///
+    /// This rewrites the history in the sync repo for all hosts, so has to be
+    /// confirmed, either interactively or with --yes.
+    Rebuild {
+        /// Don't ask for confirmation before rebuilding
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Change the directory recorded for commands, e.g. after moving a project
+    ///
+    /// This is done by rebuilding the sync repo, so has to be confirmed,
+    /// either interactively or with --yes.
+    RewritePath(client::RewritePathArgs),
+
+    /// Analyse the sync data, to see if a rebuild is worthwhile
+    Gc(client::GcArgs),
+
+    /// Show how much space each host's history is using in the sync repo
+    Du(client::DuArgs),
+
+    /// Show the last command in a single fast call, for use in shell prompts
+    Prompt(client::PromptArgs),
+
+    /// Show the most frequently run commands
+    Stats(client::StatsArgs),
+
+    /// Check that all the history files can be decrypted
+    Verify(client::VerifyArgs),
+
+    /// Show the sync status of the server
+    Status(client::StatusArgs),
+
+    /// Print sync events from the server as they happen (e.g. for a status
+    /// bar)
+    Events(client::EventsArgs),
+
+    /// Show the identity (hostname, session, etc.) that vellum is using
+    Whoami(client::WhoamiArgs),
+
+    /// Show the server log, optionally following it as it is written
+    Logs(client::LogsArgs),
 
     /// Run the background history management server
     Server(server::Args),
@@ -116,8 +328,35 @@ enum Commands {
         /// Don't sync the history before exiting
         #[arg(short, long)]
         no_sync: bool,
+
+        /// How long to wait for the server to exit (0 waits forever). The
+        /// default is long enough for the sync on exit to wait for a rebuild on
+        /// another host, which is sync.lock_timeout plus a minute.
+        #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION", value_hint = ValueHint::Other)]
+        timeout: Option<Duration>,
+
+        /// Kill the server if it doesn't exit within the timeout
+        #[arg(short, long)]
+        force: bool,
     },
 
+    /// Decrypt a history file and output the entries it contains as JSON
+    #[command(hide = true)]
+    DumpChunk {
+        /// The history file to decrypt
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: String,
+    },
+
+    /// Run end-to-end tests of vellum's internals against temporary state
+    #[command(hide = true)]
+    Selftest(selftest::Args),
+
+    /// Benchmark storing commands using a temporary server
+    #[cfg(feature = "bench")]
+    #[command(hide = true)]
+    Bench(client::BenchArgs),
+
     /// Show version information of client and server
     Version {
         /// Display the version information as JSON
@@ -141,46 +380,133 @@ fn log_target() -> Target {
     Target::Stderr
 }
 
+fn json_log_format(builder: &mut Builder) {
+    let host = hostname::get()
+        .map(|h| h.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let pid = id();
+    builder.format(move |buf, record| {
+        let line = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+            "host": host,
+            "pid": pid,
+        });
+        writeln!(buf, "{line}")
+    });
+}
+
 fn main() {
-    env_logger::Builder::from_env(
+    let cli = Cli::parse();
+
+    let mut builder = Builder::from_env(
         Env::new()
             .filter_or("VELLUM_LOG", "info")
             .write_style("VELLUM_LOG_STYLE"),
-    )
-    .target(log_target())
-    .init();
-
-    let cli = Cli::parse();
+    );
+    if let Some(level) = cli.log_level() {
+        builder.filter_level(level);
+    }
+    builder.target(log_target());
+    // an unknown format is reported once the logger is set up, using the
+    // text format.
+    let log_format = match env::var("VELLUM_LOG_FORMAT") {
+        Ok(format) if format == "json" => {
+            json_log_format(&mut builder);
+            Ok(())
+        }
+        Ok(format) if format != "text" => Err(error::Error::Generic(format!(
+            "Unknown log format {format:?} in $VELLUM_LOG_FORMAT (expected \"text\" or \"json\")"
+        ))),
+        _ => Ok(()),
+    };
+    builder.init();
 
+    let error_format = cli.error_format;
+    if let Err(e) = log_format {
+        error_format.fail("", e);
+    }
     let config = match config::Config::load(cli.config.as_ref()) {
         Ok(c) => c,
-        Err(e) => {
-            error!("Failed to load config: {e}");
-            exit(1);
-        }
+        Err(e) => error_format.fail("Failed to load config: ", e),
     };
 
     if let Err(e) = match cli.command {
-        Commands::Store { shell_command } => client::store(&config, shell_command),
+        Commands::Store {
+            shell_command,
+            print_id,
+            path,
+            stdin,
+        } => client::store(&config, shell_command, path, print_id, stdin),
+        Commands::Run(args) => client::run(&config, args),
         Commands::History(args) => client::history(&config, args),
+        Commands::Browse(args) => client::browse(&config, args),
         Commands::Save(args) => client::save(&config, args),
         Commands::Load(args) => client::load(&config, args),
         Commands::Get(args) => client::get(&config, args),
         Commands::Move(args) => client::do_move(&config, args),
         Commands::Edit(args) => client::edit(&config, args),
+        Commands::Sessions(args) => client::sessions(&config, args),
+        Commands::Events(args) => client::events(&config, args),
+        Commands::Paths(args) => client::paths(&config, args),
+        Commands::ReplaySession(args) => client::replay_session(&config, args),
         Commands::Delete { ids } => client::delete(&config, ids),
+        Commands::Annotate { id, note } => client::annotate(&config, id, note),
         Commands::Import(args) => client::import(&config, args),
-        Commands::Config => config.show(),
+        Commands::Export(args) => client::export(&config, args),
+        Commands::Config { json, server } => match server {
+            true => client::server_config(&config, json),
+            false => config.show(json),
+        },
         Commands::Init(args) => init::init(args, Cli::command()),
         Commands::Complete(args) => complete::complete(args, Cli::command()),
-        Commands::Ping { wait } => client::ping(&config, wait),
-        Commands::Sync { force } => client::sync(&config, force),
-        Commands::Rebuild => client::rebuild(&config),
+        Commands::CompleteValues { values } => complete::values(&config, values),
+        Commands::Ping { wait, count } => client::ping(&config, wait, count),
+        Commands::Sync {
+            force,
+            preview,
+            reclone,
+            only_pull,
+            only_push,
+        } => match (preview, reclone) {
+            (true, _) => client::sync_preview(&config),
+            (_, true) => client::sync_reclone(&config),
+            _ => client::sync(
+                &config,
+                api::SyncOptions {
+                    force,
+                    only_pull,
+                    only_push,
+                },
+            ),
+        },
+        Commands::Rebuild { yes } => client::rebuild(&config, yes),
+        Commands::RewritePath(args) => client::rewrite_path(&config, args),
+        Commands::Gc(args) => client::gc(&config, args),
+        Commands::Du(args) => client::du(&config, args),
+        Commands::Prompt(args) => client::prompt(&config, args),
+        Commands::Stats(args) => client::stats(&config, args),
+        Commands::Verify(args) => client::verify(&config, args),
+        Commands::Status(args) => client::status(&config, args),
+        Commands::Whoami(args) => client::whoami(&config, args),
+        Commands::Logs(args) => client::logs(&config, args),
         Commands::Server(args) => server::run(&config, args),
-        Commands::Stop { no_sync } => client::stop_server(&config, no_sync),
+        Commands::Stop {
+            no_sync,
+            timeout,
+            force,
+        } => {
+            let timeout = timeout.unwrap_or(config.sync.lock_timeout + STOP_SYNC_TIMEOUT);
+            client::stop_server(&config, no_sync, timeout, force)
+        }
         Commands::Version { json } => client::version(&config, json),
+        Commands::DumpChunk { file } => client::dump_chunk(&config, file),
+        Commands::Selftest(args) => selftest::selftest(&config, args),
+        #[cfg(feature = "bench")]
+        Commands::Bench(args) => client::bench(&config, args),
     } {
-        error!("{e}");
-        exit(1);
+        error_format.fail("", e);
     }
 }
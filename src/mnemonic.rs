@@ -0,0 +1,142 @@
+use aws_lc_rs::digest::{SHA256, digest};
+
+use crate::{
+    assets,
+    error::{Error, Result},
+};
+
+const BITS_PER_WORD: usize = 11;
+
+fn wordlist() -> Result<Vec<&'static str>> {
+    let words: Vec<&str> = assets::get_file("bip39-english.txt")
+        .and_then(|file| file.contents_utf8())
+        .ok_or_else(|| Error::from_str("mnemonic wordlist missing"))?
+        .lines()
+        .collect();
+    if words.len() != 1 << BITS_PER_WORD {
+        return Err(Error::from_str("mnemonic wordlist is the wrong length"));
+    }
+    Ok(words)
+}
+
+/// Get the bit at index i of data, most significant bit first.
+fn bit(data: &[u8], i: usize) -> bool {
+    data[i / 8] & (0x80 >> (i % 8)) != 0
+}
+
+/// Encode the key as a BIP39 mnemonic (words from the BIP39 English
+/// wordlist), so that it can be written down and typed back in. The mnemonic
+/// is the key itself, along with a checksum to catch typos. The key has to be
+/// between 16 and 32 bytes, and a multiple of 4 bytes.
+pub fn encode(key: &[u8]) -> Result<String> {
+    if !(16..=32).contains(&key.len()) || !key.len().is_multiple_of(4) {
+        return Err(Error::Generic(format!(
+            "can't make a mnemonic for a {} byte key",
+            key.len()
+        )));
+    }
+    let words = wordlist()?;
+    // the checksum is the first bit of the hash of the key for every 4 bytes
+    // of key.
+    let hash = digest(&SHA256, key);
+    let checksum_bits = key.len() / 4;
+    let total = key.len() * 8 + checksum_bits;
+    let mut mnemonic = Vec::with_capacity(total / BITS_PER_WORD);
+    for start in (0..total).step_by(BITS_PER_WORD) {
+        let mut index = 0;
+        for i in start..start + BITS_PER_WORD {
+            let set = match i < key.len() * 8 {
+                true => bit(key, i),
+                false => bit(hash.as_ref(), i - key.len() * 8),
+            };
+            index = (index << 1) | usize::from(set);
+        }
+        mnemonic.push(words[index]);
+    }
+    Ok(mnemonic.join(" "))
+}
+
+/// Decode a mnemonic made by encode back into the key, checking the checksum.
+pub fn decode(mnemonic: &str) -> Result<Vec<u8>> {
+    let words = wordlist()?;
+    let mut bits = Vec::new();
+    for word in mnemonic.split_whitespace() {
+        let word = word.to_lowercase();
+        let index = words
+            .binary_search(&word.as_str())
+            .map_err(|_| Error::Generic(format!("{word:?} is not a mnemonic word")))?;
+        bits.extend((0..BITS_PER_WORD).rev().map(|i| index & (1 << i) != 0));
+    }
+    if ![12, 15, 18, 21, 24].contains(&(bits.len() / BITS_PER_WORD)) {
+        return Err(Error::Generic(format!(
+            "a mnemonic should have 12, 15, 18, 21 or 24 words, got {}",
+            bits.len() / BITS_PER_WORD
+        )));
+    }
+    let key_bits = bits.len() * 32 / 33;
+    let key: Vec<u8> = bits[..key_bits]
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |acc, &set| (acc << 1) | u8::from(set)))
+        .collect();
+    let hash = digest(&SHA256, &key);
+    if bits[key_bits..]
+        .iter()
+        .enumerate()
+        .any(|(i, &set)| set != bit(hash.as_ref(), i))
+    {
+        return Err(Error::from_str(
+            "mnemonic checksum doesn't match, check the words for typos",
+        ));
+    }
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vectors from the BIP39 reference implementation.
+    const VECTORS: &[(&[u8], &str)] = &[
+        (
+            &[0x00; 16],
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        ),
+        (
+            &[0x7f; 16],
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+        ),
+        (
+            &[0x80; 16],
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+        ),
+        (
+            &[0xff; 16],
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+        ),
+        (
+            &[0x00; 32],
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon art",
+        ),
+        (
+            &[0x7f; 32],
+            "legal winner thank year wave sausage worth useful legal winner thank year wave sausage worth useful legal winner thank year wave sausage worth title",
+        ),
+        (
+            &[0xff; 32],
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+        ),
+    ];
+
+    #[test]
+    fn bip39_vectors() {
+        for (key, words) in VECTORS {
+            assert_eq!(encode(key).unwrap(), *words);
+            assert_eq!(decode(words).unwrap(), *key);
+        }
+    }
+
+    #[test]
+    fn bad_checksum() {
+        assert!(decode("zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo").is_err());
+    }
+}
@@ -1,44 +1,141 @@
 use std::{
     fs::{exists, read_to_string},
-    path::Path,
+    io::Write,
+    path::{Path, PathBuf},
     process,
+    thread::sleep,
+    time::{Duration, Instant},
 };
 
-use log::debug;
-use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+use log::{debug, warn};
+use sysinfo::{Pid, Process, ProcessRefreshKind, ProcessesToUpdate, Signal, System};
 
 use crate::{config::Config, error::Result};
 
+const EXIT_POLL: Duration = Duration::from_millis(100);
+
+/// The contents of the server pid file.
+///
+/// As well as the pid, we record the socket that the server is listening on,
+/// and when the process was started. This allows us to detect when the pid has
+/// been reused by some other process, or the file belongs to a different
+/// server.
+#[derive(Debug, Default)]
+struct PidFile {
+    pid: u32,
+    socket: Option<PathBuf>,
+    started: Option<u64>,
+}
+
+impl PidFile {
+    fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let buf = read_to_string(path)?;
+        let mut lines = buf.lines();
+        let pid = match lines.next() {
+            Some(pid) if !pid.is_empty() => pid.parse()?,
+            _ => return Ok(Self::default()),
+        };
+        // older servers only wrote the pid, so the rest is optional.
+        let socket = lines.next().map(PathBuf::from);
+        let started = match lines.next() {
+            Some(started) => Some(started.parse()?),
+            None => None,
+        };
+        Ok(Self {
+            pid,
+            socket,
+            started,
+        })
+    }
+
+    /// Check that the given process is the server described by this pid file,
+    /// and that it is the server for the given config.
+    fn matches(&self, cfg: &Config, p: &Process) -> bool {
+        if !p.name().to_string_lossy().contains("vellum") {
+            debug!("process {} is not vellum", self.pid);
+            return false;
+        }
+        if let Some(started) = self.started
+            && started != p.start_time()
+        {
+            warn!(
+                "Server pid {} has been reused by another process, ignoring stale pid file",
+                self.pid
+            );
+            return false;
+        }
+        let socket = cfg.socket_path();
+        if let Some(expected) = self.socket.as_ref()
+            && expected != &socket
+        {
+            warn!(
+                "Server pid {} is listening on {expected:?}, not {socket:?}, ignoring pid file",
+                self.pid
+            );
+            return false;
+        }
+        true
+    }
+}
+
+/// Write the details of the current process to the given pid file.
+pub fn write_pid_file<W: Write>(w: &mut W, cfg: &Config) -> Result<()> {
+    let pid = process::id();
+    let started = find_process(pid, |p| p.start_time()).unwrap_or_default();
+    writeln!(w, "{pid}")?;
+    writeln!(w, "{}", cfg.socket_path().display())?;
+    writeln!(w, "{started}")?;
+    Ok(w.flush()?)
+}
+
 pub fn server_is_running(cfg: &Config) -> Result<bool> {
-    let pid_file = Path::new(&cfg.state_dir).join("server.pid");
+    let pid_file = cfg.pid_path();
     debug!("Check server pid file: {pid_file:?}");
     if !exists(&pid_file)? {
         return Ok(false);
     }
-    let raw_pid = read_pid_file(pid_file)?;
-    debug!("Got server pid: {raw_pid}");
-    if raw_pid == 0 || raw_pid == process::id() {
+    let info = PidFile::read(pid_file)?;
+    debug!("Got server pid file: {info:?}");
+    if info.pid == 0 || info.pid == process::id() {
         // not running, or re-execing in the same process
         return Ok(false);
     }
-    let pid = Pid::from_u32(raw_pid);
 
-    let mut s = System::new();
-    s.refresh_processes_specifics(
-        ProcessesToUpdate::Some(&[pid]),
-        true,
-        ProcessRefreshKind::nothing(),
-    );
+    Ok(find_process(info.pid, |p| info.matches(cfg, p)).unwrap_or(false))
+}
 
-    match s.process(pid) {
-        Some(p) => Ok(p.name().to_string_lossy().contains("vellum")),
-        None => Ok(false),
+/// Wait for the server to exit, giving up after the timeout (if there is one).
+/// Returns true if the server has exited.
+pub fn wait_for_server_exit(cfg: &Config, timeout: Option<Duration>) -> Result<bool> {
+    let start = Instant::now();
+    while server_is_running(cfg)? {
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            return Ok(false);
+        }
+        sleep(EXIT_POLL);
+    }
+    Ok(true)
+}
+
+/// Send a signal to the server process, returns false if the server isn't
+/// running or the signal couldn't be sent.
+pub fn signal_server(cfg: &Config, signal: Signal) -> Result<bool> {
+    let pid_file = cfg.pid_path();
+    if !exists(&pid_file)? {
+        return Ok(false);
+    }
+    let info = PidFile::read(pid_file)?;
+    if info.pid == 0 || info.pid == process::id() {
+        return Ok(false);
     }
+    Ok(find_process(info.pid, |p| {
+        info.matches(cfg, p) && p.kill_with(signal).unwrap_or(false)
+    })
+    .unwrap_or(false))
 }
 
-pub fn wait_for_server_exit(cfg: &Config) -> Result<()> {
-    let pid_file = Path::new(&cfg.state_dir).join("server.pid");
-    let pid = Pid::from_u32(read_pid_file(pid_file)?);
+fn find_process<T, F: FnOnce(&Process) -> T>(pid: u32, f: F) -> Option<T> {
+    let pid = Pid::from_u32(pid);
 
     let mut s = System::new();
     s.refresh_processes_specifics(
@@ -47,18 +144,5 @@ pub fn wait_for_server_exit(cfg: &Config) -> Result<()> {
         ProcessRefreshKind::nothing(),
     );
 
-    if let Some(p) = s.process(pid) {
-        p.wait();
-    };
-
-    Ok(())
-}
-
-fn read_pid_file<P: AsRef<Path>>(path: P) -> Result<u32> {
-    let buf = read_to_string(path)?;
-    if buf.is_empty() {
-        return Ok(0);
-    }
-    let pid: u32 = buf.parse()?;
-    Ok(pid)
+    s.process(pid).map(f)
 }
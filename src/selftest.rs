@@ -0,0 +1,206 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use git2::Repository;
+use log::info;
+use tempfile::TempDir;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+    history::History,
+    sync::{Syncer, get_syncer},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Commands {
+    /// Sync two temporary hosts through a temporary git repo, and check that
+    /// they end up with the same history
+    Sync {
+        /// Number of commands to store on each host in each round
+        #[arg(short, long, default_value_t = 10)]
+        count: usize,
+
+        /// Keep the temporary directory, so that it can be looked at
+        /// afterwards
+        #[arg(long)]
+        keep: bool,
+    },
+}
+
+pub fn selftest(cfg: &Config, args: Args) -> Result<()> {
+    match args.command {
+        Commands::Sync { count, keep } => sync(cfg, count, keep),
+    }
+}
+
+/// A host with its own state directory, with the history and syncer driven
+/// in-process, the same way that the server drives them.
+struct Host {
+    cfg: Config,
+    name: String,
+    history: History,
+    syncer: Box<dyn Syncer>,
+}
+
+impl Host {
+    fn new(dir: &Path, name: &str, url: &Path) -> Result<Self> {
+        let mut cfg = Config {
+            state_dir: dir.join(name).join("state"),
+            cache_dir: dir.join(name).join("cache"),
+            hostname: PathBuf::from(name),
+            ..Default::default()
+        };
+        cfg.sync.enabled = true;
+        cfg.sync.url = url.to_string_lossy().to_string();
+        cfg.sync.branch = "vellum-sync".to_string();
+        // don't wait long for a lock, since the test takes one on purpose
+        cfg.sync.lock_timeout = Duration::from_secs(1);
+        cfg.sync.rebuild_drain = Duration::ZERO;
+        let (syncer, path) = get_syncer(&cfg)?;
+        let history = History::load(&cfg, path)?;
+        Ok(Self {
+            cfg,
+            name: name.to_string(),
+            history,
+            syncer,
+        })
+    }
+
+    fn store(&mut self, round: usize, count: usize) {
+        for i in 0..count {
+            self.history.add(
+                format!("echo {} round {round} command {i}", self.name),
+                "/selftest",
+                format!("{}-session", self.name),
+                None,
+                None,
+                None,
+                None,
+            );
+        }
+    }
+
+    /// Write out and push our changes, after pulling in everyone else's.
+    fn sync(&mut self) -> Result<()> {
+        let path = self.syncer.refresh()?;
+        let written = self.history.sync(path)?;
+        self.syncer.push_changes(&self.name, written, false)
+    }
+
+    /// Reload the history from disk, like a server restart.
+    fn restart(&mut self) -> Result<()> {
+        self.history = History::load(&self.cfg, self.syncer.refresh()?)?;
+        Ok(())
+    }
+}
+
+/// Run the steps of the sync test, printing each one as it passes.
+fn sync(cfg: &Config, count: usize, keep: bool) -> Result<()> {
+    std::fs::create_dir_all(&cfg.cache_dir)?;
+    let dir = TempDir::with_prefix_in("selftest-", &cfg.cache_dir)?;
+    info!("Running sync selftest in {:?}", dir.path());
+
+    let url = dir.path().join("remote.git");
+    Repository::init_bare(&url)?;
+    let mut a = Host::new(dir.path(), "alpha", &url)?;
+    let mut b = Host::new(dir.path(), "beta", &url)?;
+
+    let result = run_sync(&mut a, &mut b, count);
+
+    if keep {
+        let path = dir.into_path();
+        println!("Kept {path:?}");
+    }
+    result
+}
+
+fn run_sync(a: &mut Host, b: &mut Host, count: usize) -> Result<()> {
+    step("store and sync on each host in turn", || {
+        a.store(1, count);
+        b.store(1, count);
+        a.sync()?;
+        b.sync()?;
+        a.sync()?;
+        converged(a, b, 2 * count)
+    })?;
+
+    step("store on both hosts before either syncs", || {
+        a.store(2, count);
+        b.store(2, count);
+        b.sync()?;
+        a.sync()?;
+        b.sync()?;
+        converged(a, b, 4 * count)
+    })?;
+
+    step("sync waits for a locked repo", || {
+        let guard = a.syncer.lock()?;
+        b.store(3, count);
+        match b.sync() {
+            Ok(()) => return Err(Error::from_str("sync succeeded while the repo was locked")),
+            Err(e) => info!("sync failed while the repo was locked: {e}"),
+        }
+        guard.unlock()?;
+        drop(guard);
+        b.sync()?;
+        a.sync()?;
+        converged(a, b, 5 * count)
+    })?;
+
+    step("hosts restart from their state", || {
+        a.restart()?;
+        b.restart()?;
+        converged(a, b, 5 * count)
+    })?;
+
+    println!("All sync selftests passed");
+    Ok(())
+}
+
+fn step<F: FnOnce() -> Result<()>>(name: &str, f: F) -> Result<()> {
+    match f() {
+        Ok(()) => {
+            println!("{name} ... ok");
+            Ok(())
+        }
+        Err(e) => {
+            println!("{name} ... FAILED");
+            Err(Error::Generic(format!("selftest {name:?} failed: {e}")))
+        }
+    }
+}
+
+/// Check that both hosts have the same merged history, with the expected
+/// number of entries.
+fn converged(a: &Host, b: &Host, expected: usize) -> Result<()> {
+    let a_history = a.history.history();
+    let b_history = b.history.history();
+    for (name, history) in [(&a.name, &a_history), (&b.name, &b_history)] {
+        if history.len() != expected {
+            return Err(Error::Generic(format!(
+                "{name} has {} entries, expected {expected}",
+                history.len()
+            )));
+        }
+    }
+    if let Some((x, y)) = a_history
+        .iter()
+        .zip(b_history.iter())
+        .find(|(x, y)| x.id != y.id || x.cmd != y.cmd)
+    {
+        return Err(Error::Generic(format!(
+            "histories differ: {} has {:?} ({}) where {} has {:?} ({})",
+            a.name, x.cmd, x.id, b.name, y.cmd, y.id
+        )));
+    }
+    Ok(())
+}
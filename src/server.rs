@@ -1,39 +1,86 @@
 use std::{
     env::{self, current_exe},
     fs::{self, File},
-    io::Write,
-    os::unix::process::CommandExt,
+    io::{ErrorKind, Write},
+    mem::take,
+    os::unix::{
+        fs::{MetadataExt, OpenOptionsExt},
+        process::CommandExt,
+    },
     path::Path,
-    process::{self, Command, exit},
+    process::{self, Child, Command, Stdio, exit},
     sync::{
-        Arc, Mutex,
-        atomic::AtomicBool,
+        Arc, Mutex, MutexGuard, TryLockError,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{SyncSender, sync_channel},
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use chrono::{DateTime, DurationRound, SecondsFormat, TimeDelta, Utc};
 use clap::{self, crate_version};
 use fd_lock::RwLock;
-use fork::{Fork, daemon};
+use fork::{Fork, chdir, close_fd, fork, setsid};
 use humantime::format_duration;
 use log::{debug, error, info, warn};
 use rand::random_range;
-use signal_hook::{consts::TERM_SIGNALS, flag, iterator::Signals};
+use regex::RegexSet;
+use rustix::process::{Pid, Signal, geteuid, kill_process_group};
+use signal_hook::{
+    consts::{SIGUSR1, SIGUSR2, TERM_SIGNALS},
+    flag,
+    iterator::Signals,
+};
+use tempfile::TempDir;
 use uuid::Uuid;
 
 use crate::{
-    api::{Connection, Listener, Message, ping},
+    api::{Connection, Listener, Message, Preview, Status, SyncOptions, ping},
     client,
-    config::Config,
+    config::{Config, ServerMode},
     error::{Error, Result},
-    history::{self, Entry, History},
-    process::server_is_running,
-    sync::{Syncer, get_syncer},
+    history::{self, Analysis, Entry, History, PathRewrite, RebuildSummary},
+    process::{server_is_running, write_pid_file},
+    sync::{LockedSyncer, Syncer, get_syncer},
+    systemd,
 };
 
+/// The most commands that will be queued up whilst a rebuild is running.
+const MAX_QUEUED_STORES: usize = 1000;
+
+/// The shortest time between purges of the history that is past
+/// history.retention, since each purge rewrites the sync repo.
+const PURGE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long to wait for a background server to lock the pid file.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long sending a sync event to a subscriber can take, before the
+/// subscriber is dropped.
+const SUBSCRIBER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long server --wait waits for the server to respond, without any
+/// progress being made on cloning the sync repo.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often server --wait checks for progress whilst waiting.
+const READY_POLL: Duration = Duration::from_millis(500);
+
+/// How long sync.precondition_command can run for before it is killed, and
+/// the background sync skipped.
+const PRECONDITION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often to check if the precondition command has exited.
+const PRECONDITION_POLL: Duration = Duration::from_millis(50);
+
+/// How long to wait for the history or syncer locks when shutting down, before
+/// giving up on them rather than blocking the exit.
+const SHUTDOWN_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often to retry a lock whilst waiting for it on shutdown.
+const SHUTDOWN_LOCK_POLL: Duration = Duration::from_millis(10);
+
 #[derive(clap::Args, Debug, Default)]
 pub struct Args {
     /// Run the server in the foreground
@@ -61,14 +108,16 @@ pub struct Args {
 pub fn run(config: &Config, args: Args) -> Result<()> {
     // make sure that we have a crypt key before trying to run a server,
     // otherwise things aren't going to go very well ...
-    if let Err(e) = history::get_key() {
-        error!("Unable to get crypt key from $VELLUM_KEY, refusing to start server:");
+    if let Err(e) = history::get_key(config) {
+        error!("Unable to get crypt key, refusing to start server:");
         error!("  {e}");
         exit(1);
     }
 
+    check_ownership(config)?;
+
     if args.restart {
-        client::stop_server(config, false)?;
+        client::stop_server(config, false, Duration::ZERO, false)?;
     }
 
     if !args.force && server_is_running(config)? {
@@ -86,16 +135,125 @@ pub fn run(config: &Config, args: Args) -> Result<()> {
         debug!("start the server");
         ensure_running(config, true)?;
         debug!("wait for server to respond ...");
-        ping(config, Some(Duration::from_secs(30)))?;
-        return Ok(());
-    } else if let Fork::Child = daemon(false, false)? {
-        background(config, args.force);
-        exit(0);
+        wait_for_ready(config)?;
+        Ok(())
+    } else if let Fork::Parent(_) = fork()? {
+        // don't exit until the server has the pid file, so that anyone
+        // waiting for us will find the new server instead of starting another.
+        wait_for_start(config)
     } else {
+        setsid()?;
+        chdir()?;
+        close_fd()?;
+        if let Fork::Child = fork()? {
+            background(config, args.force);
+        }
         exit(0);
     }
 }
 
+/// Check that the directories that the server writes to (and the files in
+/// them) belong to us, so that if they were created by running vellum as
+/// another user (e.g. with sudo) we can say how to fix it, rather than failing
+/// later with a permission error.
+fn check_ownership(config: &Config) -> Result<()> {
+    let uid = geteuid().as_raw();
+    if uid == 0 {
+        // root can write to anything.
+        return Ok(());
+    }
+    for dir in [&config.state_dir, &config.runtime_dir(), &config.cache_dir] {
+        let meta = match fs::symlink_metadata(dir) {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        check_owner(dir, dir, meta.uid(), uid)?;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            check_owner(&entry.path(), dir, entry.metadata()?.uid(), uid)?;
+        }
+    }
+    Ok(())
+}
+
+fn check_owner(path: &Path, dir: &Path, owner: u32, uid: u32) -> Result<()> {
+    if owner == uid {
+        return Ok(());
+    }
+    Err(Error::Generic(format!(
+        "{path:?} is owned by uid {owner}, not the current user (uid {uid}), probably from running \
+         vellum as another user (e.g. with sudo). To fix it, run: sudo chown -R {uid} {dir:?}"
+    )))
+}
+
+fn wait_for_start(config: &Config) -> Result<()> {
+    let start = Instant::now();
+    while start.elapsed() < START_TIMEOUT {
+        // the pid file might be part written, so treat errors as not running
+        // yet.
+        if server_is_running(config).unwrap_or(false) {
+            debug!("server started after {:?}", start.elapsed());
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Err(Error::Generic(format!(
+        "server did not start within {}, check {:?} for errors",
+        format_duration(START_TIMEOUT),
+        config.log_path()
+    )))
+}
+
+/// Wait for a newly started server to respond, and then for the first sync to
+/// clone the sync repo if it needs cloning. The clone can take a long time, so
+/// whilst it is making progress we show the progress and keep waiting.
+fn wait_for_ready(config: &Config) -> Result<()> {
+    let progress_path = config.clone_progress_path();
+    let mut last_progress = String::new();
+    let mut waiting = Instant::now();
+    let mut responding = false;
+    loop {
+        if responding {
+            thread::sleep(READY_POLL);
+        } else {
+            match ping(config, Some(READY_POLL)) {
+                Ok(_) => responding = true,
+                // a server that is running a different version won't change,
+                // so there is no point in waiting for it.
+                Err(e @ Error::ApiVersion(_)) => return Err(e),
+                Err(e) if waiting.elapsed() >= READY_TIMEOUT => {
+                    debug!("last ping error: {e}");
+                    return Err(Error::Generic(format!(
+                        "server didn't respond within {}, check {:?} for errors",
+                        format_duration(READY_TIMEOUT),
+                        config.log_path()
+                    )));
+                }
+                Err(_) => (),
+            }
+        }
+        match fs::read_to_string(&progress_path) {
+            Ok(progress) if progress != last_progress => {
+                info!("{progress}");
+                last_progress = progress;
+                waiting = Instant::now();
+            }
+            Ok(_) if responding && waiting.elapsed() >= READY_TIMEOUT => {
+                warn!(
+                    "The sync repo is still being cloned, check {:?} for progress",
+                    config.log_path()
+                );
+                return Ok(());
+            }
+            Ok(_) => (),
+            // there is no clone in progress
+            Err(_) if responding => return Ok(()),
+            Err(_) => (),
+        }
+    }
+}
+
 fn start(config: &Config) -> Result<()> {
     let pid = process::id();
     debug!("server: config={config:?} pid={pid}");
@@ -106,7 +264,8 @@ fn start(config: &Config) -> Result<()> {
     // truncate after opening to ensure that writing a smaller value than was
     // previously written works correctly.
     debug!("create pid file");
-    let pid_file = Path::new(&config.state_dir).join("server.pid");
+    fs::create_dir_all(config.runtime_dir())?;
+    let pid_file = config.pid_path();
     let mut f = RwLock::new(
         File::options()
             .create(true)
@@ -116,15 +275,14 @@ fn start(config: &Config) -> Result<()> {
     );
     let mut pid_lock = f.try_write()?;
     pid_lock.set_len(0)?;
-    write!(pid_lock, "{}", pid)?;
-    pid_lock.flush()?;
+    write_pid_file(&mut *pid_lock, config)?;
 
     info!("Starting vellum server v{} (pid: {pid})", crate_version!());
 
     // clean up an old socket file if there is one. We should only get here if
     // we got the pid lock.
     debug!("check for old server socket");
-    let server_sock = Path::new(&config.state_dir).join("server.sock");
+    let server_sock = config.socket_path();
     if fs::exists(&server_sock)? {
         debug!("remove old server socket");
         fs::remove_file(&server_sock)?;
@@ -142,10 +300,10 @@ fn start(config: &Config) -> Result<()> {
 }
 
 fn background(config: &Config, force: bool) {
-    let log_file = Path::new(&config.state_dir).join("server.log");
+    let log_file = config.log_path();
     let exe = current_exe().expect("failed to get executable path");
     let mut cmd = Command::new(exe);
-    if let Some(cfg) = config.path.as_ref() {
+    if let Some(cfg) = config.config_arg() {
         cmd.arg("--config").arg(cfg);
     };
     if let Ok(value) = env::var("VELLUM_SERVER_LOG") {
@@ -167,14 +325,18 @@ fn ensure_running(cfg: &Config, force: bool) -> Result<()> {
     debug!("start server in background");
     let exe = current_exe()?;
     let mut cmd = Command::new(exe);
-    if let Some(cfg_path) = cfg.path.as_ref() {
+    if let Some(cfg_path) = cfg.config_arg() {
         cmd.arg("--config").arg(cfg_path);
     };
     cmd.arg("server");
     if force {
         cmd.arg("--force");
     }
-    cmd.spawn()?;
+    // this only returns once the server is running (or has failed to start).
+    let status = cmd.status()?;
+    if !status.success() {
+        return Err(Error::Generic(format!("failed to start server ({status})")));
+    }
 
     Ok(())
 }
@@ -187,6 +349,54 @@ pub fn ensure_ready(cfg: &Config) -> Result<Connection> {
     Ok(conn)
 }
 
+/// Lock the mutex, giving up if it is still held by someone else after the
+/// timeout. A poisoned lock is still returned, since this is used to save what
+/// we can on the way out.
+fn lock_within<T>(mutex: &Mutex<T>, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match mutex.try_lock() {
+            Ok(guard) => return Some(guard),
+            Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return None,
+            Err(TryLockError::WouldBlock) => thread::sleep(SHUTDOWN_LOCK_POLL),
+        }
+    }
+}
+
+/// Kill a child that was started in its own process group, along with anything
+/// it started, and reap it.
+fn kill_group(child: &mut Child) {
+    if let Err(e) = kill_process_group(Pid::from_child(child), Signal::KILL) {
+        debug!("failed to kill process group {}: {e}", child.id());
+    }
+    let _ = child.wait();
+}
+
+/// The total size of the files in a directory, and all the directories below
+/// it.
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += match metadata.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => metadata.len(),
+        };
+    }
+    Ok(size)
+}
+
+/// The commands that are stored whilst the history is being rebuilt. More than
+/// one rebuild can be waiting for the sync lock at once, so the commands are
+/// only added to the history once the last of them has finished.
+#[derive(Debug, Default)]
+struct StoreQueue {
+    rebuilds: usize,
+    entries: Vec<Entry>,
+}
+
 #[derive(Debug, Clone)]
 struct Server {
     cfg: Config,
@@ -195,6 +405,20 @@ struct Server {
     syncer: Arc<Mutex<Box<dyn Syncer>>>,
     history: Arc<Mutex<History>>,
     last_sync: Arc<Mutex<DateTime<Utc>>>,
+    // number of startup steps (listening, initial sync) that are complete
+    started: Arc<AtomicUsize>,
+    // a sync is running
+    syncing: Arc<AtomicBool>,
+    // commands stored whilst a rebuild is running, which are added to the
+    // history once it is complete.
+    queued: Arc<Mutex<StoreQueue>>,
+    // clients that have asked to be sent sync events
+    subscribers: Arc<Mutex<Vec<Connection>>>,
+    // when expired history was last purged
+    last_purge: Arc<Mutex<Option<Instant>>>,
+    // history.ignore and history.always
+    ignore: RegexSet,
+    always: RegexSet,
 }
 
 impl Server {
@@ -202,23 +426,51 @@ impl Server {
         let pid = process::id();
         debug!("server: config={cfg:?} pid={pid}");
 
+        if cfg.server.self_test {
+            debug!("run startup self-test");
+            history::self_test(cfg)
+                .map_err(|e| Error::Generic(format!("startup self-test failed: {e}")))?;
+            info!("Startup self-test passed");
+        }
+
+        let ignore = RegexSet::new(&cfg.history.ignore)
+            .map_err(|e| Error::Generic(format!("invalid history.ignore pattern: {e}")))?;
+        let always = RegexSet::new(&cfg.history.always)
+            .map_err(|e| Error::Generic(format!("invalid history.always pattern: {e}")))?;
+
+        if cfg.history.hash_commands && cfg.history.plaintext_log.is_some() {
+            warn!(
+                "history.plaintext_log records the full commands, even though history.hash_commands is set"
+            );
+        }
+
+        let s = Self::open(cfg, ignore, always)?;
+        s.start_background_sync();
+        s.start_background_flush();
+
+        Ok(s)
+    }
+
+    /// Load the history and set up the syncer, without starting any of the
+    /// background threads.
+    fn open(cfg: &Config, ignore: RegexSet, always: RegexSet) -> Result<Self> {
         let host = cfg.hostname.to_string_lossy().to_string();
         let (syncer, path) = get_syncer(cfg)?;
 
-        let s = Self {
+        Ok(Self {
             cfg: cfg.clone(),
-            history: Arc::new(Mutex::new(History::load(
-                host.clone(),
-                cfg.state_dir.clone(),
-                path,
-            )?)),
+            history: Arc::new(Mutex::new(History::load(cfg, path)?)),
             host,
             syncer: Arc::new(Mutex::new(syncer)),
             last_sync: Arc::new(Mutex::new(Utc::now())),
-        };
-        s.start_background_sync();
-
-        Ok(s)
+            started: Arc::new(AtomicUsize::new(0)),
+            syncing: Arc::new(AtomicBool::new(false)),
+            queued: Arc::new(Mutex::new(StoreQueue::default())),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            last_purge: Arc::new(Mutex::new(None)),
+            ignore,
+            always,
+        })
     }
 
     fn start_background_sync(&self) {
@@ -235,18 +487,68 @@ impl Server {
         thread::spawn(move || s.sync_watchdog());
     }
 
+    fn start_background_flush(&self) {
+        let interval = self.cfg.history.flush_interval;
+        if interval.is_zero() {
+            // the active chunk is written every time it changes
+            return;
+        }
+        debug!("starting background flush with {interval:?} interval");
+        let s = self.clone();
+        thread::spawn(move || {
+            loop {
+                thread::sleep(interval);
+                s.flush();
+            }
+        });
+    }
+
+    fn start_systemd_watchdog(&self) {
+        if let Some(interval) = systemd::watchdog_interval() {
+            let s = self.clone();
+            thread::spawn(move || s.systemd_watchdog(interval));
+        }
+    }
+
+    /// Record that a startup step has completed, once both the listener is
+    /// ready and the initial sync has completed we tell systemd that we are
+    /// ready.
+    fn startup_step_complete(&self) {
+        if self.started.fetch_add(1, Ordering::SeqCst) == 1 {
+            info!("Server startup complete");
+            systemd::ready();
+        }
+    }
+
     fn initial_sync(&self) {
         debug!("perform initial sync");
-        if let Err(e) = self.sync(false) {
+        if let Err(e) = self.sync(SyncOptions::default()) {
             error!("Failed to run initial sync: {e}");
         }
+        self.initial_sync_complete();
+    }
+
+    /// Record that the initial sync has completed. If it didn't clone the sync
+    /// repo (e.g. because it was skipped by sync.precondition, or failed
+    /// before getting that far) then the repo won't be cloned until a later
+    /// sync, so clients shouldn't keep waiting for the clone.
+    fn initial_sync_complete(&self) {
+        if let Err(e) = fs::remove_file(self.cfg.clone_progress_path())
+            && e.kind() != ErrorKind::NotFound
+        {
+            debug!("failed to remove clone progress: {e}");
+        }
+        self.startup_step_complete();
     }
 
     fn background_sync(&self) {
         debug!("perform initial sync");
-        if let Err(e) = self.sync(false) {
+        if !self.sync_precondition() {
+            self.flush();
+        } else if let Err(e) = self.sync(SyncOptions::default()) {
             error!("Failed to run initial sync: {e}");
         }
+        self.initial_sync_complete();
         // It doesn't matter if the sync was successful or not, all the
         // watchdog cares about is that the sync didn't get stuck, so we
         // always update the time, regardless of result.
@@ -292,7 +594,9 @@ impl Server {
             };
             debug!("wait is: {}", format_duration(wait));
             thread::sleep(wait);
-            if let Err(e) = self.sync(false) {
+            if !self.sync_precondition() {
+                self.flush();
+            } else if let Err(e) = self.sync(SyncOptions::default()) {
                 error!("Failed to run background sync: {e}");
             }
             // It doesn't matter if the sync was successful or not, all the
@@ -336,10 +640,34 @@ impl Server {
         }
     }
 
+    fn systemd_watchdog(&self, interval: Duration) {
+        // systemd recommends pinging at half the watchdog interval
+        let interval = interval / 2;
+        debug!("starting systemd watchdog with {interval:?} interval");
+        loop {
+            thread::sleep(interval);
+            if !self.cfg.sync.interval.is_zero() {
+                // stop pinging if the background sync has got stuck, so that
+                // systemd will restart us.
+                let time_since_sync = {
+                    let last_sync = self.last_sync.lock().unwrap();
+                    (Utc::now() - *last_sync).to_std().unwrap_or_default()
+                };
+                if time_since_sync > self.cfg.sync.watchdog_timeout {
+                    warn!("Background sync appears stuck, not pinging systemd watchdog");
+                    continue;
+                }
+            }
+            systemd::watchdog();
+        }
+    }
+
     fn serve(&self) -> Result<()> {
         self.setup_signals()?;
 
         let listener = Listener::new(&self.cfg)?;
+        self.start_systemd_watchdog();
+        self.startup_step_complete();
         for conn in listener.incoming() {
             match conn {
                 Ok(conn) => {
@@ -367,14 +695,27 @@ impl Server {
         thread::spawn(move || {
             if let Some(signal) = signals.forever().next() {
                 info!("Received signal: {signal}");
-                // run a sync before exiting, so that we don't loose any state.
-                if let Err(e) = server.sync_local(false) {
-                    error!("Failed to sync: {e}");
-                }
+                server.shutdown();
                 info!("Exiting ...");
                 exit(0);
             }
         });
+        // SIGUSR1 triggers a sync, and SIGUSR2 forces the local state to be
+        // written out and pushed, without exiting.
+        let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+        let server = self.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                info!("Received signal: {signal}");
+                let result = match signal {
+                    SIGUSR1 => server.sync(SyncOptions::default()),
+                    _ => server.sync_local(true),
+                };
+                if let Err(e) = result {
+                    error!("Failed to sync: {e}");
+                }
+            }
+        });
         Ok(())
     }
 
@@ -399,11 +740,25 @@ impl Server {
 
     fn handle_request(&self, req: Message, conn: &mut Connection) {
         match req {
-            Message::Store { cmd, path, session } => {
+            Message::Store {
+                cmd,
+                path,
+                session,
+                env_hash,
+                uid,
+                shell,
+                id,
+            } => {
                 debug!("Received request from session {session} to store command: {cmd}");
-                self.store(cmd, path, session);
-                if let Err(e) = conn.ack() {
-                    error!("Failed to send ack: {e}");
+                if let Err(e) = match self.store(cmd, path, session, env_hash, uid, shell, id) {
+                    Ok(Some(id)) => conn.send_stored(id),
+                    Ok(None) => conn.ack(),
+                    Err(e) => {
+                        error!("Failed to store command: {e}");
+                        conn.error(format!("{e}"))
+                    }
+                } {
+                    error!("Failed to send stored: {e}");
                 };
             }
             Message::HistoryRequest => {
@@ -413,8 +768,48 @@ impl Server {
                     error!("Failed to send history: {e}");
                 };
             }
+            Message::HistoryIfChanged(version) => {
+                debug!("Received history request if changed from {version:?}");
+                let (current, history) = self.history_if_changed(version);
+                if let Err(e) = conn.send_versioned_history(current, history) {
+                    error!("Failed to send history: {e}");
+                };
+            }
+            Message::EntryHistory(id) => {
+                debug!("Received history request for {id}");
+                let history = self.entry_history(id);
+                if let Err(e) = conn.send_history(history) {
+                    error!("Failed to send history: {e}");
+                };
+            }
+            Message::HistoryBetween(after, before) => {
+                debug!("Received history request for after={after:?} before={before:?}");
+                if let Err(e) = match self.history_between(after, before) {
+                    Ok(history) => conn.send_history(history),
+                    Err(e) => {
+                        error!("Failed to load history: {e}");
+                        conn.error(format!("failed to load history: {e}"))
+                    }
+                } {
+                    error!("Failed to send history: {e}");
+                }
+            }
+            Message::HistoryAt(at) => {
+                debug!("Received history request at {at}");
+                if let Err(e) = match self.history_at(&at) {
+                    Ok(history) => conn.send_history(history),
+                    Err(e) => {
+                        error!("Failed to load history at {at}: {e}");
+                        conn.error(format!("failed to load history at {at}: {e}"))
+                    }
+                } {
+                    error!("Failed to send history: {e}");
+                }
+            }
             Message::Exit(no_sync) => {
                 info!("Received request to exit");
+                self.flush();
+                self.history.lock().unwrap().save_counters();
                 if let Err(e) = conn.ack() {
                     error!("Failed to send ack: {e}");
                 };
@@ -431,9 +826,9 @@ impl Server {
                 info!("Exiting ...");
                 exit(0);
             }
-            Message::Sync(force) => {
-                info!("Received request to sync");
-                if let Err(e) = self.sync(force) {
+            Message::Sync(opts) => {
+                info!("Received request to sync ({opts:?})");
+                if let Err(e) = self.sync(opts) {
                     error!("Failed to sync: {e}");
                     if let Err(e) = conn.error(format!("failed to sync: {e}")) {
                         error!("Failed to send error: {e}");
@@ -442,6 +837,29 @@ impl Server {
                     error!("Failed to send ack: {e}");
                 };
             }
+            Message::Reclone => {
+                info!("Received request to reclone the sync repo");
+                if let Err(e) = self.reclone() {
+                    error!("Failed to reclone: {e}");
+                    if let Err(e) = conn.error(format!("failed to reclone: {e}")) {
+                        error!("Failed to send error: {e}");
+                    }
+                } else if let Err(e) = conn.ack() {
+                    error!("Failed to send ack: {e}");
+                };
+            }
+            Message::Pack(older_than) => {
+                info!("Received request to pack history files");
+                if let Err(e) = match self.pack(older_than) {
+                    Ok(count) => conn.send_packed(count),
+                    Err(e) => {
+                        error!("Failed to pack history files: {e}");
+                        conn.error(format!("failed to pack history files: {e}"))
+                    }
+                } {
+                    error!("Failed to send packed: {e}");
+                }
+            }
             Message::Ping => {
                 debug!("Received ping request");
                 if let Err(e) = conn.pong() {
@@ -460,24 +878,95 @@ impl Server {
                     error!("Failed to send ack: {e}");
                 };
             }
-            Message::Rebuild => {
-                debug!("Received request to rebuild data store");
-                let s = self.clone();
-                let (sender, receiver) = sync_channel(0);
-                let worker = thread::spawn(move || s.rebuild(sender));
-                for status in receiver {
-                    if let Err(e) = conn.rebuild_status(status) {
-                        error!("Failed to send status: {e}");
+            Message::UpdateWithPath {
+                id,
+                cmd,
+                path,
+                session,
+            } => {
+                debug!(
+                    "Received request from session {session} to update command {id}: {cmd} (in {path})"
+                );
+                match self.update_with_path(id, cmd, path, session) {
+                    Ok(()) => {
+                        if let Err(e) = conn.ack() {
+                            error!("Failed to send ack: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to update {id}: {e}");
+                        if let Err(e) = conn.error(format!("{e}")) {
+                            error!("Failed to send error: {e}");
+                        }
                     }
                 }
-                let result = match worker.join() {
-                    Ok(r) => r,
-                    Err(e) => Err(Error::Generic(format!("rebuild thread paniced: {e:?}"))),
-                };
+            }
+            Message::Annotate { id, note, session } => {
+                debug!("Received request from session {session} to annotate {id}");
+                match self.annotate(id, note, session) {
+                    Ok(()) => {
+                        if let Err(e) = conn.ack() {
+                            error!("Failed to send ack: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to annotate {id}: {e}");
+                        if let Err(e) = conn.error(format!("{e}")) {
+                            error!("Failed to send error: {e}");
+                        }
+                    }
+                }
+            }
+            Message::Finished {
+                id,
+                exit,
+                duration_ms,
+                session,
+            } => {
+                debug!("Received request from session {session} to record the exit status of {id}");
+                match self.finished(id, exit, duration_ms, session) {
+                    Ok(()) => {
+                        if let Err(e) = conn.ack() {
+                            error!("Failed to send ack: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to record the exit status of {id}: {e}");
+                        if let Err(e) = conn.error(format!("{e}")) {
+                            error!("Failed to send error: {e}");
+                        }
+                    }
+                }
+            }
+            Message::Rebuild(false) => {
+                debug!("Received unconfirmed request to rebuild data store");
+                let result = Err(Error::from_str(
+                    "rebuild rewrites the sync repo for all hosts, and must be confirmed (e.g. with vellum rebuild --yes)",
+                ));
+                if let Err(e) = conn.rebuild_complete(result) {
+                    error!("Failed to send complete: {e}");
+                }
+            }
+            Message::Rebuild(true) => {
+                debug!("Received request to rebuild data store");
+                self.run_rebuild(conn, None);
+            }
+            Message::RewritePath(_, false) => {
+                debug!("Received unconfirmed request to rewrite paths");
+                let result = Err(Error::from_str(
+                    "rewriting paths rebuilds the sync repo for all hosts, and must be confirmed (e.g. with vellum rewrite-path --yes)",
+                ));
                 if let Err(e) = conn.rebuild_complete(result) {
                     error!("Failed to send complete: {e}");
                 }
             }
+            Message::RewritePath(rewrite, true) => {
+                info!(
+                    "Received request to rewrite paths from {:?} to {:?}",
+                    rewrite.from, rewrite.to
+                );
+                self.run_rebuild(conn, Some(rewrite));
+            }
             Message::VersionRequest => {
                 debug!("Received version request");
                 if let Err(e) = conn.send_version(crate_version!()) {
@@ -493,6 +982,75 @@ impl Server {
                     error!("Failed to send load response: {e}");
                 }
             }
+            Message::StatusRequest => {
+                debug!("Received status request");
+                if let Err(e) = match self.status() {
+                    Ok(status) => conn.send_status(status),
+                    Err(e) => conn.error(format!("{e}")),
+                } {
+                    error!("Failed to send status: {e}");
+                }
+            }
+            Message::PreviewRequest => {
+                info!("Received request to preview sync");
+                if let Err(e) = match self.preview() {
+                    Ok(preview) => conn.send_preview(preview),
+                    Err(e) => conn.error(format!("{e}")),
+                } {
+                    error!("Failed to send preview: {e}");
+                }
+            }
+            Message::AnalyzeRequest => {
+                info!("Received request to analyze sync repo");
+                if let Err(e) = match self.analyze() {
+                    Ok(analysis) => conn.send_analysis(analysis),
+                    Err(e) => conn.error(format!("{e}")),
+                } {
+                    error!("Failed to send analysis: {e}");
+                }
+            }
+            Message::HostsRequest => {
+                debug!("Received hosts request");
+                if let Err(e) = conn.send_hosts(self.hosts()) {
+                    error!("Failed to send hosts: {e}");
+                }
+            }
+            Message::ConfigRequest => {
+                debug!("Received config request");
+                if let Err(e) = conn.send_config(&self.cfg) {
+                    error!("Failed to send config: {e}");
+                }
+            }
+            Message::TopCommandsRequest(n) => {
+                debug!("Received top commands request for {n}");
+                let counts = self.history.lock().unwrap().top_commands(n);
+                if let Err(e) = conn.send_top_commands(counts) {
+                    error!("Failed to send top commands: {e}");
+                }
+            }
+            Message::LastRequest(session) => {
+                debug!("Received last entry request");
+                let last = self.history.lock().unwrap().last(session.as_deref());
+                if let Err(e) = conn.send_last(last) {
+                    error!("Failed to send last entry: {e}");
+                }
+            }
+            Message::DeletedRequest => {
+                debug!("Received deleted entries request");
+                let history = self.history.lock().unwrap().deleted();
+                if let Err(e) = conn.send_history(history) {
+                    error!("Failed to send history: {e}");
+                };
+            }
+            Message::Subscribe => {
+                debug!("Received subscribe request");
+                if let Err(e) = self.subscribe(conn) {
+                    error!("Failed to subscribe: {e}");
+                    if let Err(e) = conn.error(format!("failed to subscribe: {e}")) {
+                        error!("Failed to send error: {e}");
+                    }
+                }
+            }
             r => {
                 error!("received unknown request: {r:?}");
                 if let Err(e) = conn.error(format!("unknown request: {r:?}")) {
@@ -502,9 +1060,192 @@ impl Server {
         }
     }
 
-    fn store(&self, cmd: String, path: String, session: String) {
+    #[allow(clippy::too_many_arguments)]
+    fn store(
+        &self,
+        cmd: String,
+        path: String,
+        session: String,
+        env_hash: Option<String>,
+        uid: Option<u32>,
+        shell: Option<String>,
+        id: Option<Uuid>,
+    ) -> Result<Option<Uuid>> {
+        self.check_writable()?;
+        // an allowlisted command is kept, even if it also matches an ignore
+        // pattern.
+        if !self.always.is_match(&cmd) && self.ignore.is_match(&cmd) {
+            debug!("ignoring command that matches history.ignore");
+            return Ok(None);
+        }
+        // the history is locked for a long time during a rebuild, so queue the
+        // command rather than making the shell wait.
+        let mut queued = self.queued.lock().unwrap();
+        if queued.rebuilds > 0 {
+            let queue = &mut queued.entries;
+            if queue.len() >= MAX_QUEUED_STORES {
+                return Err(Error::from_str(
+                    "rebuild in progress and too many commands queued, command dropped",
+                ));
+            }
+            if let Some(id) = id
+                && queue.iter().any(|entry| entry.id == id)
+            {
+                debug!("command {id} is already queued");
+                return Ok(Some(id));
+            }
+            self.log_command(&cmd, &path);
+            let mut entry = match id {
+                Some(id) => Entry::existing(id, &self.host, cmd, path, session),
+                None => Entry::new(&self.host, cmd, path, session),
+            };
+            entry.env_hash = env_hash;
+            entry.uid = uid;
+            entry.shell = shell;
+            let id = entry.id;
+            queue.push(entry);
+            return Ok(Some(id));
+        }
+        drop(queued);
         let mut history = self.history.lock().unwrap();
-        history.add(cmd, path, session);
+        if let Some(id) = id
+            && history.contains(id)
+        {
+            debug!("command {id} has already been stored");
+            return Ok(Some(id));
+        }
+        self.log_command(&cmd, &path);
+        Ok(Some(
+            history.add(cmd, path, session, env_hash, uid, shell, id),
+        ))
+    }
+
+    /// Write a command that has been accepted for storing to
+    /// history.plaintext_log, if it is set.
+    fn log_command(&self, cmd: &str, path: &str) {
+        if let Some(log) = &self.cfg.history.plaintext_log
+            && let Err(e) = self.append_plaintext_log(log, cmd, path)
+        {
+            warn!("Failed to write to {log:?}: {e}");
+        }
+    }
+
+    /// Append the command to history.plaintext_log, with any tabs or newlines
+    /// in it escaped so that each command is a single line.
+    fn append_plaintext_log(&self, log: &Path, cmd: &str, path: &str) -> Result<()> {
+        let path = match self.cfg.history.store_path {
+            true => path,
+            false => "",
+        };
+        let escape = |s: &str| {
+            s.replace('\\', "\\\\")
+                .replace('\t', "\\t")
+                .replace('\n', "\\n")
+                .replace('\r', "\\r")
+        };
+        let mut f = File::options()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(log)?;
+        writeln!(
+            f,
+            "{}\t{}\t{}\t{}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.host,
+            escape(path),
+            escape(cmd)
+        )?;
+        Ok(())
+    }
+
+    /// Run a rebuild (optionally rewriting paths first) in a background
+    /// thread, sending the progress to the client.
+    fn run_rebuild(&self, conn: &mut Connection, rewrite: Option<PathRewrite>) {
+        let result = self.rebuild_with_status(rewrite, |status| {
+            if let Err(e) = conn.rebuild_status(status) {
+                error!("Failed to send status: {e}");
+            }
+        });
+        if let Err(e) = conn.rebuild_complete(result) {
+            error!("Failed to send complete: {e}");
+        }
+    }
+
+    /// Run a rebuild in a background thread, passing each progress update to
+    /// the status function. Commands stored during the rebuild are queued,
+    /// and added once it is complete.
+    fn rebuild_with_status<F: FnMut(String)>(
+        &self,
+        rewrite: Option<PathRewrite>,
+        status: F,
+    ) -> Result<RebuildSummary> {
+        self.queue_stores();
+        let s = self.clone();
+        let (sender, receiver) = sync_channel(0);
+        let worker = thread::spawn(move || s.rebuild(sender, rewrite));
+        receiver.into_iter().for_each(status);
+        let result = match worker.join() {
+            Ok(r) => r,
+            Err(e) => Err(Error::Generic(format!("rebuild thread paniced: {e:?}"))),
+        };
+        self.add_queued_stores();
+        result
+    }
+
+    /// Purge the history that is past history.retention from the sync repo,
+    /// if there is any and it hasn't been done in the last PURGE_INTERVAL.
+    fn purge_expired(&self) {
+        let retention = self.cfg.history.retention;
+        if retention.is_zero() || self.replica() {
+            return;
+        }
+        {
+            let mut last_purge = self.last_purge.lock().unwrap();
+            if last_purge.is_some_and(|last| last.elapsed() < PURGE_INTERVAL) {
+                return;
+            }
+            *last_purge = Some(Instant::now());
+        }
+        if !self.history.lock().unwrap().has_expired() {
+            debug!("no history older than {}", format_duration(retention));
+            return;
+        }
+        info!(
+            "Purging history older than {} from the sync repo",
+            format_duration(retention)
+        );
+        match self.rebuild_with_status(None, |status| debug!("purge: {status}")) {
+            Ok(summary) => info!(
+                "Purge complete, kept {} of {} records",
+                summary.entries_after, summary.records_before
+            ),
+            Err(e) => error!("Failed to purge expired history: {e}"),
+        }
+    }
+
+    fn queue_stores(&self) {
+        self.queued.lock().unwrap().rebuilds += 1;
+    }
+
+    fn add_queued_stores(&self) {
+        // lock the history first, so that any commands stored while we are
+        // doing this still go into the queue and stay in order.
+        let mut history = self.history.lock().unwrap();
+        let mut queue = self.queued.lock().unwrap();
+        queue.rebuilds -= 1;
+        if queue.rebuilds > 0 {
+            debug!("another rebuild is running, keeping the queued commands");
+            return;
+        }
+        let queued = take(&mut queue.entries);
+        drop(queue);
+        if !queued.is_empty() {
+            info!("Adding {} commands stored during the rebuild", queued.len());
+        }
+        for entry in queued {
+            history.add_entry(entry);
+        }
     }
 
     fn history(&self) -> Vec<Entry> {
@@ -512,40 +1253,428 @@ impl Server {
         history.history()
     }
 
+    fn history_if_changed(&self, version: Option<u64>) -> (u64, Option<Vec<Entry>>) {
+        let history = self.history.lock().unwrap();
+        let current = history.version();
+        match version == Some(current) {
+            true => (current, None),
+            false => (current, Some(history.history())),
+        }
+    }
+
+    fn entry_history(&self, id: Uuid) -> Vec<Entry> {
+        let history = self.history.lock().unwrap();
+        history.entry_history(id)
+    }
+
+    fn history_between(
+        &self,
+        after: Option<DateTime<Utc>>,
+        before: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Entry>> {
+        // hold the syncer lock so that the files don't change underneath us if
+        // older history has to be read.
+        let _syncer = self.syncer.lock().unwrap();
+        let history = self.history.lock().unwrap();
+        history.history_between(self.cfg.sync_path(), after, before)
+    }
+
+    /// Read the history as it was at an earlier point in the sync repo, from
+    /// a temporary copy of the history files so that the live history isn't
+    /// touched.
+    fn history_at(&self, at: &str) -> Result<Vec<Entry>> {
+        fs::create_dir_all(&self.cfg.cache_dir)?;
+        let dir = TempDir::with_prefix_in("snapshot-", &self.cfg.cache_dir)?;
+        {
+            let syncer = self.syncer.lock().unwrap();
+            syncer.snapshot(at, dir.path())?;
+        }
+        History::read_snapshot(&self.cfg, dir.path())
+    }
+
+    fn hosts(&self) -> Vec<String> {
+        let history = self.history.lock().unwrap();
+        history.hosts()
+    }
+
+    fn status(&self) -> Result<Status> {
+        let last_sync = *self.last_sync.lock().unwrap();
+        // the syncer is locked for the whole sync, so don't wait for it just
+        // to count the pending changes.
+        if self.syncing.load(Ordering::SeqCst) {
+            return Ok(Status {
+                pending: 0,
+                last_sync,
+                syncing: true,
+            });
+        }
+        let pending = {
+            let syncer = self.syncer.lock().unwrap();
+            syncer.pending()?
+        };
+        Ok(Status {
+            pending,
+            last_sync,
+            syncing: false,
+        })
+    }
+
+    fn preview(&self) -> Result<Preview> {
+        let syncer = self.syncer.lock().unwrap();
+        let files = syncer.incoming()?;
+        let pending = syncer.pending()?;
+        let incoming = {
+            let history = self.history.lock().unwrap();
+            history.preview(&files)?
+        };
+        Ok(Preview { incoming, pending })
+    }
+
+    fn flush(&self) {
+        let mut history = self.history.lock().unwrap();
+        history.flush();
+    }
+
+    fn analyze(&self) -> Result<Analysis> {
+        // hold the syncer lock so that the files don't change underneath us,
+        // but don't refresh since we only want to look at what we have.
+        let _syncer = self.syncer.lock().unwrap();
+        let mut analysis = history::analyze(&self.cfg, self.cfg.sync_path())?;
+        analysis.repo_bytes = dir_size(&self.cfg.sync_repo_path())?;
+        Ok(analysis)
+    }
+
+    /// Save the local state before exiting. The active chunk is flushed first,
+    /// so that it is on disk even if the sync fails. Neither step waits for
+    /// more than SHUTDOWN_LOCK_TIMEOUT, so a stuck sync or rebuild can't stop
+    /// the flush, or block the exit.
+    fn shutdown(&self) {
+        if self.queued.lock().unwrap().rebuilds > 0 {
+            info!("Waiting for the rebuild to finish ...");
+        }
+        match lock_within(&self.history, SHUTDOWN_LOCK_TIMEOUT) {
+            Some(mut history) => {
+                // the rebuild may have finished without its queued commands
+                // being added yet.
+                for entry in self.queued.lock().unwrap().entries.drain(..) {
+                    history.add_entry(entry);
+                }
+                history.flush();
+                history.save_counters();
+            }
+            None => {
+                error!("History is busy, unable to flush the active chunk");
+                // the commands stored during a rebuild are only queued in
+                // memory, so they are saved for the next start instead.
+                let queued = take(&mut self.queued.lock().unwrap().entries);
+                if !queued.is_empty() {
+                    let count = queued.len();
+                    match history::save_queued(&self.cfg, queued) {
+                        Ok(()) => info!("Saved {count} commands stored during the rebuild"),
+                        Err(e) => error!("Failed to save the queued commands: {e}"),
+                    }
+                }
+            }
+        }
+        let Some(syncer) = lock_within(&self.syncer, SHUTDOWN_LOCK_TIMEOUT) else {
+            warn!("Sync is busy, skipping the sync on exit");
+            return;
+        };
+        // run a sync before exiting, so that we don't loose any state.
+        if let Err(e) = self.sync_local_with(syncer.as_ref(), false) {
+            error!("Failed to sync: {e}");
+        }
+    }
+
     fn sync_local(&self, force: bool) -> Result<()> {
         let syncer = self.syncer.lock().unwrap();
+        self.sync_local_with(syncer.as_ref(), force)
+    }
+
+    fn sync_local_with(&self, syncer: &dyn Syncer, force: bool) -> Result<()> {
+        if self.replica() {
+            debug!("replica has no local changes to sync");
+            return Ok(());
+        }
         let path = syncer.refresh()?;
-        {
+        let written = {
             // we want to lock the history for the shortest time that we can
             let mut history = self.history.lock().unwrap();
-            history.save(path)?;
+            history.save(path)?
+        };
+        syncer.push_changes(&self.host, written, force)
+    }
+
+    /// Run sync.precondition_command (if set), to decide if a background sync
+    /// should go ahead. A command that doesn't exit within
+    /// PRECONDITION_TIMEOUT is killed, so that it can't stop syncing forever.
+    fn sync_precondition(&self) -> bool {
+        let cmd = &self.cfg.sync.precondition_command;
+        if !self.cfg.sync.enabled || cmd.is_empty() {
+            return true;
+        }
+        debug!("run sync precondition: {cmd}");
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .process_group(0)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Skipping background sync, failed to run precondition command: {e}");
+                return false;
+            }
+        };
+        let deadline = Instant::now() + PRECONDITION_TIMEOUT;
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) if status.success() => return true,
+                Ok(Some(status)) => {
+                    info!("Skipping background sync, precondition command failed ({status})");
+                    return false;
+                }
+                Ok(None) if Instant::now() >= deadline => {
+                    warn!(
+                        "Skipping background sync, precondition command didn't finish within {}",
+                        format_duration(PRECONDITION_TIMEOUT)
+                    );
+                    kill_group(&mut child);
+                    return false;
+                }
+                Ok(None) => thread::sleep(PRECONDITION_POLL),
+                Err(e) => {
+                    warn!("Skipping background sync, failed to wait for precondition command: {e}");
+                    kill_group(&mut child);
+                    return false;
+                }
+            }
+        }
+    }
+
+    /// Run a sync, letting any subscribers know when it starts and how it
+    /// went.
+    fn sync(&self, opts: SyncOptions) -> Result<()> {
+        self.broadcast(&Message::SyncStarted);
+        let before = self.history.lock().unwrap().len();
+        self.syncing.store(true, Ordering::SeqCst);
+        let result = self.run_sync(opts);
+        self.syncing.store(false, Ordering::SeqCst);
+        match result {
+            Ok(pushed) => {
+                let added = self.history.lock().unwrap().len().saturating_sub(before);
+                self.broadcast(&Message::SyncCompleted { added, pushed });
+                self.purge_expired();
+                Ok(())
+            }
+            Err(e) => {
+                self.broadcast(&Message::SyncFailed(e.to_string()));
+                Err(e)
+            }
         }
-        syncer.push_changes(&self.host, force)
     }
 
-    fn sync(&self, force: bool) -> Result<()> {
+    /// Run a sync, returning the number of our entries that were pushed.
+    fn run_sync(&self, mut opts: SyncOptions) -> Result<usize> {
+        if self.replica() {
+            // a replica only ever pulls, since it has nothing of its own.
+            if opts.only_push {
+                self.check_writable()?;
+            }
+            opts.only_pull = true;
+        }
         let syncer = self.syncer.lock().unwrap();
+        if opts.only_push {
+            // write out and push our changes, without fetching anything first.
+            let (written, pushed) = self.push_batches(syncer.as_ref(), &self.cfg.sync_path())?;
+            syncer.push_changes(&self.host, written, opts.force)?;
+            return Ok(pushed + written);
+        }
         let path = syncer.refresh()?;
-        {
+        if opts.only_pull {
+            // read in the upstream changes, our changes stay in the active
+            // chunk until the next full sync.
+            let mut history = self.history.lock().unwrap();
+            history.pull(path)?;
+            return Ok(0);
+        }
+        let (mut written, pushed) = self.push_batches(syncer.as_ref(), &path)?;
+        written += {
             // we want to lock the history for the shortest time that we can
             let mut history = self.history.lock().unwrap();
-            history.sync(path)?;
+            history.sync(path)?
+        };
+        syncer.push_changes(&self.host, written, opts.force)?;
+        Ok(pushed + written)
+    }
+
+    /// Send an event to all the subscribed clients, dropping any that have
+    /// gone away.
+    fn broadcast(&self, msg: &Message) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain_mut(|conn| match conn.send(msg) {
+            Ok(()) => true,
+            Err(e) => {
+                debug!("dropping subscriber: {e}");
+                false
+            }
+        });
+    }
+
+    /// If there are more than sync.max_entries_per_push new entries, then
+    /// write, commit and push them in batches until there is only one batch
+    /// left. The last batch (everything, if there's no limit) is written, but
+    /// left for the caller to push. Returns the number of entries in the last
+    /// batch, and the number that were pushed in the earlier batches.
+    fn push_batches(&self, syncer: &dyn Syncer, path: &Path) -> Result<(usize, usize)> {
+        let max = self.cfg.sync.max_entries_per_push;
+        let mut pushed = 0;
+        loop {
+            let (written, more) = {
+                let mut history = self.history.lock().unwrap();
+                history.save_batch(path, max)?
+            };
+            if !more {
+                return Ok((written, pushed));
+            }
+            info!("Pushing a batch of {written} entries");
+            syncer.push_changes(&self.host, written, false)?;
+            pushed += written;
+        }
+    }
+
+    /// Pack the daily history files from more than older_than ago into monthly
+    /// archives. This is done with the sync repo locked, so that no other host
+    /// is changing the files at the same time.
+    fn pack(&self, older_than: Duration) -> Result<usize> {
+        let older_than = TimeDelta::from_std(older_than)
+            .map_err(|_| Error::from_str("pack age is too large"))?;
+        let before = (Utc::now() - older_than).date_naive();
+        self.check_writable()?;
+
+        let syncer = self.syncer.lock().unwrap();
+        syncer.refresh()?;
+        let mut history = self.history.lock().unwrap();
+
+        let sync_lock = syncer.lock()?;
+        let result = sync_lock.refresh().and_then(|path| {
+            history.sync(&path)?;
+            let packed = history.pack(&path, before)?;
+            if packed > 0 {
+                sync_lock
+                    .commit_changes(&format!("pack {packed} history files from {}", self.host))?;
+            }
+            Ok(packed)
+        });
+        // always try to unlock, even if the pack failed.
+        sync_lock.unlock()?;
+        result
+    }
+
+    /// Replace the sync checkout with a fresh clone. Our changes are pushed
+    /// first, so that nothing that only exists in the old checkout is lost.
+    fn reclone(&self) -> Result<()> {
+        if !self.cfg.sync.enabled {
+            return Err(Error::from_str("git sync is not enabled"));
+        }
+        if self.cfg.sync.worktree {
+            return Err(Error::from_str(
+                "can't reclone when sync.worktree is set, remove the worktree instead",
+            ));
+        }
+
+        let mut syncer = self.syncer.lock().unwrap();
+        if !self.replica() {
+            let path = syncer.refresh()?;
+            let written = {
+                let mut history = self.history.lock().unwrap();
+                history.sync(path)?
+            };
+            syncer
+                .push_changes(&self.host, written, false)
+                .map_err(|e| {
+                    Error::Generic(format!("failed to push changes before recloning: {e}"))
+                })?;
+        }
+
+        let path = self.cfg.sync_repo_path();
+        info!("Removing sync checkout {path:?}");
+        fs::remove_dir_all(&path)?;
+        let (new, _) = get_syncer(&self.cfg)?;
+        *syncer = new;
+        Ok(())
+    }
+
+    /// Add the connection to the subscribers that are sent sync events. The
+    /// events are sent by the sync thread, on a clone of the connection. The
+    /// ack is sent before the connection is added, so that it always comes
+    /// before the first event.
+    fn subscribe(&self, conn: &mut Connection) -> Result<()> {
+        let mut subscriber = conn.try_clone()?;
+        // don't let a client that isn't reading its events hold up a sync.
+        // this is shared with conn, so only the write timeout is changed, the
+        // read timeout is still used for the client's requests.
+        subscriber.set_write_timeout(SUBSCRIBER_TIMEOUT)?;
+        conn.ack()?;
+        self.subscribers.lock().unwrap().push(subscriber);
+        Ok(())
+    }
+
+    fn replica(&self) -> bool {
+        self.cfg.server.mode == ServerMode::Replica
+    }
+
+    /// Fail if the server isn't allowed to change the history, because it is
+    /// a replica.
+    fn check_writable(&self) -> Result<()> {
+        match self.replica() {
+            true => Err(Error::from_str(
+                "this host is a replica (server.mode), so its history can't be changed",
+            )),
+            false => Ok(()),
         }
-        syncer.push_changes(&self.host, force)
     }
 
     fn update(&self, id: Uuid, cmd: String, session: String) -> Result<()> {
+        self.check_writable()?;
         let mut history = self.history.lock().unwrap();
         history.update(id, cmd, session)
     }
 
+    fn update_with_path(&self, id: Uuid, cmd: String, path: String, session: String) -> Result<()> {
+        self.check_writable()?;
+        let mut history = self.history.lock().unwrap();
+        history.update_with_path(id, cmd, path, session)
+    }
+
+    fn annotate(&self, id: Uuid, note: String, session: String) -> Result<()> {
+        self.check_writable()?;
+        let mut history = self.history.lock().unwrap();
+        history.annotate(id, note, session)
+    }
+
+    fn finished(&self, id: Uuid, exit: i32, duration_ms: u64, session: String) -> Result<()> {
+        self.check_writable()?;
+        let mut history = self.history.lock().unwrap();
+        history.finished(id, exit, duration_ms, session)
+    }
+
     fn load(&self, entries: Vec<Entry>, all_hosts: bool) -> Result<usize> {
+        self.check_writable()?;
         let mut history = self.history.lock().unwrap();
         history.load_entries(entries, all_hosts)
     }
 
-    fn rebuild(&self, sender: SyncSender<String>) -> Result<()> {
+    fn rebuild(
+        &self,
+        sender: SyncSender<String>,
+        rewrite: Option<PathRewrite>,
+    ) -> Result<RebuildSummary> {
         debug!("rebuild background thread started");
+        self.check_writable()?;
 
         sender.send("Refreshing git state ...".to_string())?;
         let syncer = self.syncer.lock().unwrap();
@@ -555,20 +1684,16 @@ impl Server {
         sender.send("Locking git repo ...".to_string())?;
         let sync_lock = syncer.lock()?;
 
-        sender.send("Waiting 5s to allow in progress syncs to complete ...".to_string())?;
-        thread::sleep(Duration::from_secs(5));
-
-        sender.send("Refreshing git state again ...".to_string())?;
-        let path = sync_lock.refresh()?;
-
-        sender.send("Update the history state ...".to_string())?;
-        history.sync(&path)?;
-
-        sender.send("Rewrite the history files ...".to_string())?;
-        history.rewrite_all_files(&path)?;
-
-        sender.send("Flatten git history and push the rewritten files ...".to_string())?;
-        sync_lock.push_changes(&self.host)?;
+        let summary = match self.rebuild_locked(&sender, &*sync_lock, &mut history, rewrite) {
+            Ok(summary) => summary,
+            Err(e) => {
+                // nothing has been pushed, so the lock can be released
+                if let Err(e) = sync_lock.unlock() {
+                    warn!("Failed to unlock the sync repo after the rebuild failed: {e}");
+                }
+                return Err(e);
+            }
+        };
 
         sender.send("Unlocking git repo ...".to_string())?;
         sync_lock.unlock()?;
@@ -583,6 +1708,115 @@ impl Server {
         drop(syncer);
 
         debug!("rebuild background thread complete");
-        Ok(())
+        Ok(summary)
+    }
+
+    /// The part of a rebuild that is done while holding the sync lock, up to
+    /// pushing the rewritten files.
+    fn rebuild_locked(
+        &self,
+        sender: &SyncSender<String>,
+        sync_lock: &dyn LockedSyncer,
+        history: &mut History,
+        rewrite: Option<PathRewrite>,
+    ) -> Result<RebuildSummary> {
+        let drain = self.cfg.sync.rebuild_drain;
+        sender.send(format!(
+            "Waiting up to {} for in progress syncs to complete ...",
+            format_duration(drain)
+        ))?;
+        sync_lock.drain(drain)?;
+        sync_lock.keep_alive()?;
+
+        sender.send("Refreshing git state again ...".to_string())?;
+        let path = sync_lock.refresh()?;
+
+        sender.send("Update the history state ...".to_string())?;
+        history.sync(&path)?;
+        sync_lock.keep_alive()?;
+
+        let mut paths_rewritten = 0;
+        if let Some(rewrite) = rewrite {
+            sender.send("Rewrite the matching paths ...".to_string())?;
+            paths_rewritten = history.rewrite_paths(&path, &rewrite)?;
+            sync_lock.keep_alive()?;
+        }
+
+        sender.send("Rewrite the history files ...".to_string())?;
+        let mut summary = history.rewrite_all_files(&path, |status| {
+            sync_lock.keep_alive()?;
+            Ok(sender.send(status)?)
+        })?;
+        summary.paths_rewritten = paths_rewritten;
+
+        sender.send("Flatten git history and push the rewritten files ...".to_string())?;
+        summary.commit = sync_lock.push_changes(&self.host)?;
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_push_writes_history() {
+        // the key can only be set once per process, the error if it already
+        // has been is ignored.
+        let key = vec![7; 32];
+        let _ = history::set_key(key.clone());
+        let dir = tempfile::tempdir().unwrap();
+        let mut cfg = Config {
+            state_dir: dir.path().join("state"),
+            cache_dir: dir.path().join("cache"),
+            ..Default::default()
+        };
+        cfg.sync.enabled = false;
+        // sync.max_entries_per_push is 0 by default, so everything is written
+        // in one go.
+        let server = Server::open(&cfg, RegexSet::empty(), RegexSet::empty()).unwrap();
+        server
+            .store(
+                "ls".to_string(),
+                "/tmp".to_string(),
+                "session".to_string(),
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        server
+            .sync(SyncOptions {
+                only_push: true,
+                ..Default::default()
+            })
+            .unwrap();
+        let dir = cfg.sync_path().join("hosts.v1").join(&server.host);
+        let written = history::read_foreign_history(&cfg, dir, &key).unwrap();
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].cmd, "ls");
+    }
+
+    #[test]
+    fn plaintext_log_escapes_control_characters() {
+        let _ = history::set_key(vec![7; 32]);
+        let dir = tempfile::tempdir().unwrap();
+        let mut cfg = Config {
+            state_dir: dir.path().join("state"),
+            cache_dir: dir.path().join("cache"),
+            ..Default::default()
+        };
+        cfg.sync.enabled = false;
+        let server = Server::open(&cfg, RegexSet::empty(), RegexSet::empty()).unwrap();
+        let log = dir.path().join("plain.log");
+        server
+            .append_plaintext_log(&log, "printf 'a\r\nb\tc\\n'", "/tmp")
+            .unwrap();
+        let data = fs::read_to_string(&log).unwrap();
+        assert_eq!(data.lines().count(), 1);
+        assert!(!data.contains('\r'));
+        assert!(data.ends_with("\t/tmp\tprintf 'a\\r\\nb\\tc\\\\n'\n"));
     }
 }
@@ -1,17 +1,22 @@
 use std::{
+    cell::{Cell, OnceCell},
     fmt, fs,
     path::{Path, PathBuf},
     result, thread,
     time::{Duration, Instant},
 };
 
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use git2::{
-    Commit, Cred, CredentialType, ErrorClass, ErrorCode, FetchOptions, FetchPrune, Index,
-    IndexAddOption, Oid, PushOptions, Rebase, RebaseOptions, RemoteCallbacks, Repository,
-    build::RepoBuilder,
+    BranchType, Commit, Cred, CredentialType, Direction, ErrorClass, ErrorCode, FetchOptions,
+    FetchPrune, Index, IndexAddOption, ObjectType, Oid, PushOptions, Rebase, RebaseOptions,
+    Reference, RemoteCallbacks, Repository, Sort, Tree, TreeWalkMode, TreeWalkResult,
+    WorktreeAddOptions, WorktreePruneOptions,
+    build::{CheckoutBuilder, RepoBuilder},
 };
 use humantime::format_duration;
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
+use rand::random_range;
 
 use crate::{
     config::Config,
@@ -20,9 +25,29 @@ use crate::{
 
 use super::{LockedSyncer, Syncer};
 
+// the lock tag used when sync.namespace isn't set.
 const LOCK_REF: &str = "refs/tags/lock";
 
-const MAX_LOCK_WAIT: Duration = Duration::from_secs(300);
+// a held lock is refreshed once this fraction of sync.lock_stale_after has
+// passed, so that other hosts don't remove it as stale.
+const LOCK_REFRESH_DIVISOR: u32 = 4;
+
+// the name of the worktree used when sync.worktree is set.
+const WORKTREE_NAME: &str = "vellum";
+
+// the longest we wait between checks to see if the repo has been unlocked.
+const MAX_LOCK_POLL: Duration = Duration::from_secs(30);
+
+// how often we check the upstream branch when waiting for in progress syncs
+// to finish before a rebuild.
+const DRAIN_POLL: Duration = Duration::from_secs(1);
+
+// the longest we wait before retrying a push that was rejected, the actual
+// wait is random so that hosts that are racing each other don't stay in step.
+const MAX_PUSH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// how often the progress of the initial clone is reported.
+const CLONE_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Git {
     path: PathBuf,
@@ -31,13 +56,10 @@ pub struct Git {
 }
 
 impl Git {
-    pub(super) fn path(&self) -> PathBuf {
-        self.path.clone()
-    }
-
     fn existing(cfg: &Config) -> Result<Self> {
-        let path = cfg.sync_path();
+        let path = cfg.sync_repo_path();
         let repo = Repository::open(&path)?;
+        Self::update_remote(cfg, &repo)?;
         fs::create_dir_all(&path)?;
         Ok(Self {
             path,
@@ -46,11 +68,51 @@ impl Git {
         })
     }
 
+    /// Make sure that the origin remote matches the sync URL, since it may have
+    /// been changed since the repo was cloned.
+    fn update_remote(cfg: &Config, repo: &Repository) -> Result<()> {
+        let url = cfg.sync_url();
+        if url.is_empty() {
+            return Ok(());
+        }
+        let remote = repo.find_remote("origin")?;
+        if remote.url() != Some(url) {
+            info!(
+                "sync.url has changed, updating origin from {:?} to {url:?}",
+                remote.url().unwrap_or_default()
+            );
+            repo.remote_set_url("origin", url)?;
+        }
+        Ok(())
+    }
+
     fn clone(cfg: &Config) -> Result<Self> {
         let cm = CredsManager::new(cfg)?;
 
+        // report the progress, both in the log and in the progress file (for
+        // clients that are waiting for the server to start), since a clone of
+        // a large repo can take a long time.
+        let progress_path = cfg.clone_progress_path();
+        let mut last_progress = Instant::now();
         let mut cbs = RemoteCallbacks::new();
-        cbs.credentials(|url, username, types| cm.lookup(url, username, types));
+        cbs.credentials(|url, username, types| cm.lookup(url, username, types))
+            .transfer_progress(|stats| {
+                if last_progress.elapsed() < CLONE_PROGRESS_INTERVAL {
+                    return true;
+                }
+                last_progress = Instant::now();
+                let progress = format!(
+                    "Cloning sync repo: {}/{} objects, {} KiB received",
+                    stats.received_objects(),
+                    stats.total_objects(),
+                    stats.received_bytes() / 1024
+                );
+                info!("{progress}");
+                if let Err(e) = fs::write(&progress_path, &progress) {
+                    debug!("failed to write clone progress: {e}");
+                }
+                true
+            });
 
         let mut opts = FetchOptions::new();
         opts.remote_callbacks(cbs);
@@ -58,8 +120,16 @@ impl Git {
         let mut builder = RepoBuilder::new();
         builder.fetch_options(opts);
 
-        let path = cfg.sync_path();
-        let repo = builder.clone(&cfg.sync.url, &path)?;
+        let path = cfg.sync_repo_path();
+        info!("Cloning sync repo into {path:?}");
+        if let Err(e) = fs::write(&progress_path, "Cloning sync repo") {
+            debug!("failed to write clone progress: {e}");
+        }
+        let result = builder.clone(cfg.sync_url(), &path);
+        if fs::exists(&progress_path)? {
+            fs::remove_file(&progress_path)?;
+        }
+        let repo = result?;
         Ok(Self {
             path,
             cfg: cfg.clone(),
@@ -67,12 +137,201 @@ impl Git {
         })
     }
 
-    pub fn new(cfg: &Config) -> Result<Self> {
-        if fs::exists(cfg.sync_path())? {
-            Self::existing(cfg)
+    fn new(cfg: &Config) -> Result<Self> {
+        let git = Self::open(cfg)?;
+        git.init_empty()?;
+        Ok(git)
+    }
+
+    /// Open the sync repo, cloning it if needed, and switch to the sync
+    /// branch, but don't set up a brand-new repo (which needs the remote).
+    fn open(cfg: &Config) -> Result<Self> {
+        let git = if fs::exists(cfg.sync_repo_path())? {
+            Self::existing(cfg)?
         } else {
-            Self::clone(cfg)
+            Self::clone(cfg)?
+        };
+        if !cfg.sync.namespace.is_empty() && !Reference::is_valid_name(&lock_ref(cfg)) {
+            return Err(Error::Generic(format!(
+                "sync.namespace {:?} can't be used in a git tag name",
+                cfg.sync.namespace
+            )));
+        }
+        let git = match cfg.sync.worktree {
+            true => git.worktree()?,
+            false => git,
+        };
+        git.checkout_branch()?;
+        Ok(git)
+    }
+
+    /// Switch to a private worktree of the checkout, creating it if needed, so
+    /// that we don't touch the checkout's working tree or current branch.
+    fn worktree(self) -> Result<Self> {
+        let path = self.cfg.sync_path();
+        if fs::exists(&path)? {
+            debug!("use existing sync worktree {path:?}");
+            return Ok(Self {
+                repo: Repository::open(&path)?,
+                path,
+                cfg: self.cfg,
+            });
+        }
+
+        let branch = &self.cfg.sync.branch;
+        if branch.is_empty() {
+            return Err(Error::from_str(
+                "sync.worktree requires sync.branch to be set",
+            ));
+        }
+        if self.head_ref_name()? == format!("refs/heads/{branch}") {
+            return Err(Error::Generic(format!(
+                "sync branch {branch} is checked out in {:?}, it can't also be used by the sync worktree",
+                self.path
+            )));
+        }
+
+        let local = match self.repo.find_branch(branch, BranchType::Local) {
+            Ok(local) => local,
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                let start = match self
+                    .repo
+                    .find_branch(&format!("origin/{branch}"), BranchType::Remote)
+                {
+                    Ok(remote) => remote.get().peel_to_commit()?,
+                    Err(e) if e.code() == ErrorCode::NotFound => self
+                        .tip()?
+                        .ok_or_else(|| Error::from_str("sync repo has no commits to start from"))?,
+                    Err(e) => return Err(e.into()),
+                };
+                self.repo.branch(branch, &start, false)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // as in checkout_branch, the upstream may not exist until we push.
+        let mut config = self.repo.config()?;
+        config.set_str(&format!("branch.{branch}.remote"), "origin")?;
+        config.set_str(
+            &format!("branch.{branch}.merge"),
+            &format!("refs/heads/{branch}"),
+        )?;
+
+        // clean up after a worktree that has been removed.
+        if let Ok(old) = self.repo.find_worktree(WORKTREE_NAME) {
+            debug!("prune old sync worktree {:?}", old.path());
+            old.prune(Some(WorktreePruneOptions::new().valid(true)))?;
+        }
+
+        debug!("create sync worktree {path:?} for branch {branch}");
+        let mut opts = WorktreeAddOptions::new();
+        opts.reference(Some(local.get()));
+        let worktree = self.repo.worktree(WORKTREE_NAME, &path, Some(&opts))?;
+        Ok(Self {
+            repo: Repository::open_from_worktree(&worktree)?,
+            path,
+            cfg: self.cfg,
+        })
+    }
+
+    /// A brand-new sync repo has no commits, and so no upstream branch to sync
+    /// with. So we create an initial empty commit on the current branch and
+    /// push it, unless the remote already has the branch, in which case we
+    /// just start from there.
+    fn init_empty(&self) -> Result<()> {
+        if self.tip()?.is_some() {
+            return Ok(());
+        }
+
+        let ref_name = self.head_ref_name()?;
+        let branch = ref_name
+            .strip_prefix("refs/heads/")
+            .ok_or_else(|| Error::Generic(format!("HEAD is not a branch: {ref_name}")))?;
+
+        debug!("sync repo has no commits, initialise branch {branch}");
+
+        let mut config = self.repo.config()?;
+        config.set_str(&format!("branch.{branch}.remote"), "origin")?;
+        config.set_str(&format!("branch.{branch}.merge"), &ref_name)?;
+
+        self.fetch()?;
+        if let Some(upstream) = self.get_head_upstream_target()? {
+            debug!("upstream already has {branch}, starting from {upstream}");
+            self.repo
+                .reference(&ref_name, upstream, false, "start from upstream")?;
+            self.repo
+                .checkout_head(Some(CheckoutBuilder::new().force()))?;
+            return Ok(());
+        }
+
+        let tree = self
+            .repo
+            .find_tree(Index::new()?.write_tree_to(&self.repo)?)?;
+        let author = self.repo.signature()?;
+        let commit = self.repo.commit(
+            Some("HEAD"),
+            &author,
+            &author,
+            "initialise vellum sync repo",
+            &tree,
+            &[],
+        )?;
+        debug!("Created initial commit {commit:?}");
+
+        self.push()
+    }
+
+    fn checkout_branch(&self) -> Result<()> {
+        let branch = &self.cfg.sync.branch;
+        if branch.is_empty() {
+            // no branch configured, so just use whatever was cloned
+            return Ok(());
+        }
+
+        let ref_name = format!("refs/heads/{branch}");
+        if self.head_ref_name()? == ref_name {
+            return Ok(());
         }
+
+        debug!("switch sync repo to branch {branch}");
+
+        let exists = match self.repo.find_branch(branch, BranchType::Local) {
+            Ok(_) => true,
+            Err(e) if e.code() == ErrorCode::NotFound => {
+                // prefer the upstream version of the branch if there is one,
+                // otherwise start the new branch from the current HEAD.
+                let start = match self
+                    .repo
+                    .find_branch(&format!("origin/{branch}"), BranchType::Remote)
+                {
+                    Ok(remote) => Some(remote.get().peel_to_commit()?),
+                    Err(e) if e.code() == ErrorCode::NotFound => self.tip()?,
+                    Err(e) => return Err(e.into()),
+                };
+                match start {
+                    Some(commit) => {
+                        self.repo.branch(branch, &commit, false)?;
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // set the upstream directly in the config, since the remote branch may
+        // not exist yet (it will be created by the first push).
+        let mut config = self.repo.config()?;
+        config.set_str(&format!("branch.{branch}.remote"), "origin")?;
+        config.set_str(&format!("branch.{branch}.merge"), &ref_name)?;
+
+        self.repo.set_head(&ref_name)?;
+        if exists {
+            self.repo
+                .checkout_head(Some(CheckoutBuilder::new().force()))?;
+        }
+
+        Ok(())
     }
 
     fn head_ref_name(&self) -> Result<String> {
@@ -86,6 +345,8 @@ impl Git {
     fn try_fetch(&self, mut locked: bool, mut changes: Option<Oid>) -> Result<(bool, Option<Oid>)> {
         let ref_name = self.head_ref_name()?;
         let upstream_ref_name = self.get_head_upstream_ref()?;
+        let lock_ref = lock_ref(&self.cfg);
+        let tags = tags_refspec(&self.cfg);
 
         debug!("fetch: {ref_name} <- {upstream_ref_name}");
 
@@ -101,7 +362,7 @@ impl Git {
                 if name == upstream_ref_name && changes.is_none() {
                     changes = Some(old);
                 }
-                if name == LOCK_REF {
+                if name == lock_ref {
                     locked = !new.is_zero();
                     debug!("repo is locked: {locked}");
                 }
@@ -115,11 +376,7 @@ impl Git {
 
         debug!("fetch {ref_name} from {:?} with tags", remote.url());
 
-        remote.fetch::<&str>(
-            &[&ref_name, "refs/tags/*:refs/tags/*"],
-            Some(&mut opts),
-            None,
-        )?;
+        remote.fetch::<&str>(&[&ref_name, &tags], Some(&mut opts), None)?;
 
         // make sure that the update_tips callback is gone, since it implicitly
         // borrows locked/changes.
@@ -133,16 +390,33 @@ impl Git {
 
         let (mut locked, mut changes) = self.try_fetch(false, None)?;
 
+        let timeout = self.cfg.sync.lock_timeout;
         let start = Instant::now();
-        while locked && start.elapsed() < MAX_LOCK_WAIT {
-            debug!("waiting for repo to unlock ...");
-            thread::sleep(Duration::from_secs(1));
+        let mut poll = Duration::from_secs(1);
+        while locked && start.elapsed() < timeout {
+            if self.clear_stale_lock()? {
+                locked = false;
+                break;
+            }
+            // back off, with some jitter, so that a lot of hosts waiting for
+            // the same lock don't all hit the remote at the same time.
+            let delay = random_range(poll / 2..=poll).min(timeout.saturating_sub(start.elapsed()));
+            debug!("waiting {delay:?} for repo to unlock ...");
+            thread::sleep(delay);
+            poll = (poll * 2).min(MAX_LOCK_POLL);
             (locked, changes) = self.try_fetch(locked, changes)?;
         }
 
+        if locked && self.clear_stale_lock()? {
+            locked = false;
+        }
+
         if locked {
+            let holder = self
+                .lock_holder()
+                .unwrap_or_else(|| "an unknown host".to_string());
             return Err(Error::Generic(format!(
-                "repo did not unlock within {}",
+                "repo did not unlock within {} (locked by {holder})",
                 format_duration(start.elapsed())
             )));
         }
@@ -152,6 +426,60 @@ impl Git {
         Ok(changes)
     }
 
+    /// Get the host that holds the lock, from the message of the lock commit.
+    fn lock_holder(&self) -> Option<String> {
+        self.lock_info().map(|(_, host, _)| host)
+    }
+
+    /// Get the lock's oid, and the host that holds the lock and when it was
+    /// taken, from the message of the lock commit. Locks taken by older
+    /// versions don't include the time in the message, so the commit time is
+    /// used instead.
+    fn lock_info(&self) -> Option<(Oid, String, DateTime<Utc>)> {
+        let lock = self.repo.find_reference(&lock_ref(&self.cfg)).ok()?;
+        let oid = lock.target()?;
+        let commit = lock.peel_to_commit().ok()?;
+        let holder = commit.message()?.strip_prefix("lock for ")?.trim();
+        if let Some((host, ts)) = holder.rsplit_once(" at ")
+            && let Ok(ts) = DateTime::parse_from_rfc3339(ts)
+        {
+            return Some((oid, host.to_string(), ts.to_utc()));
+        }
+        let ts = Utc.timestamp_opt(commit.time().seconds(), 0).single()?;
+        Some((oid, holder.to_string(), ts))
+    }
+
+    /// If the lock is older than sync.lock_stale_after, then assume that the
+    /// host that took it died before unlocking, and remove it. Returns true
+    /// if the lock was removed.
+    fn clear_stale_lock(&self) -> Result<bool> {
+        let stale_after = self.cfg.sync.lock_stale_after;
+        if stale_after.is_zero() {
+            return Ok(false);
+        }
+        let Some((oid, holder, ts)) = self.lock_info() else {
+            return Ok(false);
+        };
+        let age = (Utc::now() - ts).to_std().unwrap_or_default();
+        if age < stale_after {
+            return Ok(false);
+        }
+        // the holder may have refreshed the lock, or another host may have
+        // cleared it and taken it, since it was fetched, in which case it
+        // isn't stale any more.
+        if self.remote_lock()? != Some(oid) {
+            debug!("lock has changed since it was fetched, not removing it");
+            return Ok(false);
+        }
+        warn!(
+            "Removing stale lock held by {holder} since {} ({} ago), the rebuild it was for may not have finished",
+            ts.to_rfc3339_opts(SecondsFormat::Secs, true),
+            format_duration(Duration::from_secs(age.as_secs()))
+        );
+        self.unlock()?;
+        Ok(true)
+    }
+
     fn rebase(&self, old: Option<Oid>) -> Result<()> {
         debug!("start rebase (old: {old:?})");
 
@@ -234,6 +562,7 @@ impl Git {
         // remote repo.
         match self.fetch() {
             Ok(Some(old)) => self.rebase(Some(old)),
+            Ok(None) if self.behind_upstream()? => self.rebase(None),
             Ok(None) => Ok(()),
             Err(Error::Git(e)) if e.class() == ErrorClass::Net => {
                 warn!("Fetch encountered network error, continuing");
@@ -243,25 +572,79 @@ impl Git {
         }
     }
 
+    /// Wait until a fetch shows that the upstream branch hasn't changed since
+    /// the previous one, giving up after timeout.
+    fn wait_for_stable_upstream(&self, timeout: Duration) -> Result<()> {
+        if timeout.is_zero() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        self.try_fetch(true, None)?;
+        let mut tip = self.get_head_upstream_target()?;
+        loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                warn!(
+                    "upstream still changing after {}, continuing anyway",
+                    format_duration(start.elapsed())
+                );
+                return Ok(());
+            }
+            thread::sleep(DRAIN_POLL.min(remaining));
+            self.try_fetch(true, None)?;
+            let new_tip = self.get_head_upstream_target()?;
+            if new_tip == tip {
+                debug!("upstream stable at {tip:?}");
+                return Ok(());
+            }
+            debug!("upstream changed: {tip:?} -> {new_tip:?}");
+            tip = new_tip;
+        }
+    }
+
     fn locked_pull(&self) -> Result<()> {
-        if let (_, Some(old)) = self.try_fetch(false, None)? {
-            self.rebase(Some(old))?;
+        match self.try_fetch(false, None)? {
+            (_, Some(old)) => self.rebase(Some(old)),
+            _ if self.behind_upstream()? => self.rebase(None),
+            _ => Ok(()),
         }
-        Ok(())
     }
 
+    /// Check if the upstream branch has commits that are not in HEAD, which
+    /// can happen when a previous fetch (e.g. from a sync preview) updated the
+    /// upstream branch without rebasing on top of it.
+    fn behind_upstream(&self) -> Result<bool> {
+        let upstream = match self.get_head_upstream_target()? {
+            Some(oid) => oid,
+            None => return Ok(false),
+        };
+        let head = match self.repo.head()?.target() {
+            Some(oid) => oid,
+            None => return Ok(false),
+        };
+        Ok(head != upstream && !self.repo.graph_descendant_of(head, upstream)?)
+    }
+
+    /// Push, and if another host has pushed first then pull their changes
+    /// and try again, up to sync.push_retries times.
     fn push(&self) -> Result<()> {
-        match self.try_push() {
-            Err(Error::Git(e)) => {
-                if e.code() == ErrorCode::NotFastForward {
-                    debug!("push failed due to NotFasForward, try pull ...");
+        let mut retries = 0;
+        loop {
+            match self.try_push() {
+                Err(Error::Git(e))
+                    if e.code() == ErrorCode::NotFastForward
+                        && retries < self.cfg.sync.push_retries =>
+                {
+                    retries += 1;
+                    debug!("push failed due to NotFastForward, try pull (retry {retries}) ...");
+                    if retries > 1 {
+                        thread::sleep(random_range(Duration::ZERO..=MAX_PUSH_RETRY_DELAY));
+                    }
                     self.pull()?;
-                    self.try_push()
-                } else {
-                    Err(Error::Git(e))
                 }
+                r => return r,
             }
-            r => r,
         }
     }
 
@@ -328,12 +711,12 @@ impl Git {
                         update.dst_refname(),
                         update.dst()
                     );
-                    if let Some(src_ref) = update.src_refname() {
-                        if let Some(remote_target) = remote_target {
-                            if src_ref == head_ref && update.src() != remote_target {
-                                return Err(git2::Error::from_str("remote oid has changed"));
-                            }
-                        }
+                    if let Some(src_ref) = update.src_refname()
+                        && let Some(remote_target) = remote_target
+                        && src_ref == head_ref
+                        && update.src() != remote_target
+                    {
+                        return Err(git2::Error::from_str("remote oid has changed"));
                     }
                 }
                 Ok(())
@@ -348,7 +731,29 @@ impl Git {
         Ok(remote.push(&[&refspec], Some(&mut opts))?)
     }
 
-    fn tip(&self) -> Result<Option<Commit>> {
+    /// Find the commit for an earlier point in the history, which is either a
+    /// git ref (e.g. HEAD~3 or a commit ID), or an RFC 3339 timestamp, in
+    /// which case the most recent commit made at or before then is used.
+    fn commit_at(&self, at: &str) -> Result<Commit<'_>> {
+        let Ok(ts) = DateTime::parse_from_rfc3339(at) else {
+            return Ok(self.repo.revparse_single(at)?.peel_to_commit()?);
+        };
+        let ts = ts.timestamp();
+        let mut walk = self.repo.revwalk()?;
+        walk.set_sorting(Sort::TIME)?;
+        walk.push_head()?;
+        for oid in walk {
+            let commit = self.repo.find_commit(oid?)?;
+            if commit.time().seconds() <= ts {
+                return Ok(commit);
+            }
+        }
+        Err(Error::Generic(format!(
+            "the sync repo has no commits from before {at}"
+        )))
+    }
+
+    fn tip(&self) -> Result<Option<Commit<'_>>> {
         let oid = match self.repo.head() {
             Ok(head) => head.target(),
             Err(e) => match e.code() {
@@ -363,6 +768,16 @@ impl Git {
         })
     }
 
+    fn commit_message(&self, host: &str, entries: usize) -> String {
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        self.cfg
+            .sync
+            .commit_template
+            .replace("{host}", host)
+            .replace("{count}", &entries.to_string())
+            .replace("{timestamp}", &timestamp)
+    }
+
     fn commit(&self, message: &str, force: bool) -> Result<Option<Oid>> {
         let mut index = self.repo.index()?;
         let tree = self.repo.find_tree(index.write_tree()?)?;
@@ -385,6 +800,67 @@ impl Git {
         Ok(Some(commit))
     }
 
+    fn commit_for_host(&self, host: &str, message: &str, force: bool) -> Result<Option<Oid>> {
+        if !force && let Some(tip) = self.squashable_tip(host)? {
+            let mut index = self.repo.index()?;
+            let tree = self.repo.find_tree(index.write_tree()?)?;
+            if tree.id() == tip.tree_id() {
+                return Ok(None);
+            }
+            if self.only_touches_host(&tip.tree()?, &tree, host)? {
+                // rather than stacking up another commit that hasn't been
+                // pushed yet, fold the new changes into the existing one so
+                // that we only push (and others only rebase) one commit.
+                let author = self.repo.signature()?;
+                let commit = tip.amend(
+                    Some("HEAD"),
+                    Some(&author),
+                    Some(&author),
+                    None,
+                    Some(message),
+                    Some(&tree),
+                )?;
+                debug!("Amended commit {} -> {commit:?}", tip.id());
+                return Ok(Some(commit));
+            }
+        }
+        self.commit(message, force)
+    }
+
+    /// Get the tip commit, if it hasn't been pushed yet, and only contains
+    /// changes to the files for the given host.
+    fn squashable_tip(&self, host: &str) -> Result<Option<Commit<'_>>> {
+        let tip = match self.tip()? {
+            Some(tip) => tip,
+            None => return Ok(None),
+        };
+        if tip.parent_count() != 1 {
+            return Ok(None);
+        }
+        if let Some(upstream) = self.get_head_upstream_target()?
+            && (tip.id() == upstream || self.repo.graph_descendant_of(upstream, tip.id())?)
+        {
+            // the tip has already been pushed
+            return Ok(None);
+        }
+        let parent = tip.parent(0)?;
+        if !self.only_touches_host(&parent.tree()?, &tip.tree()?, host)? {
+            return Ok(None);
+        }
+        Ok(Some(tip))
+    }
+
+    fn only_touches_host(&self, old: &Tree, new: &Tree, host: &str) -> Result<bool> {
+        let prefix = Path::new("hosts.v1").join(host);
+        let diff = self.repo.diff_tree_to_tree(Some(old), Some(new), None)?;
+        Ok(diff.deltas().all(|delta| {
+            [delta.old_file().path(), delta.new_file().path()]
+                .into_iter()
+                .flatten()
+                .all(|path| path.starts_with(&prefix))
+        }))
+    }
+
     fn commit_no_parent(&self, message: &str) -> Result<Oid> {
         let mut index = self.repo.index()?;
         let tree = self.repo.find_tree(index.write_tree()?)?;
@@ -417,6 +893,81 @@ impl Git {
         Ok(commit)
     }
 
+    /// Push a new lock commit, recording this host and the current time.
+    /// Unless replace is set this fails if the repo is already locked.
+    fn push_lock(&self, replace: bool) -> Result<Oid> {
+        let mut index = Index::new()?;
+        let oid = index.write_tree_to(&self.repo)?;
+        let tree = self.repo.find_tree(oid)?;
+
+        debug!("lock tree: {tree:?}");
+
+        let message = format!(
+            "lock for {} at {}",
+            self.cfg.hostname.to_string_lossy(),
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+
+        let author = self.repo.signature()?;
+        let commit = self
+            .repo
+            .commit(None, &author, &author, &message, &tree, &[])?;
+        debug!("Created commit {commit:?}");
+
+        let cm = CredsManager::new(&self.cfg)?;
+
+        let mut cbs = RemoteCallbacks::new();
+        cbs.credentials(|url, username, types| cm.lookup(url, username, types))
+            .push_update_reference(|name, status| {
+                debug!("update reference: name: {name} status: {status:?}");
+                if let Some(msg) = status {
+                    Err(git2::Error::from_str(msg))
+                } else {
+                    Ok(())
+                }
+            })
+            .update_tips(|name, old, new| {
+                debug!("update tip: name: {name} old: {old:?} new: {new:?}");
+                true
+            });
+
+        let mut opts = PushOptions::new();
+        opts.remote_callbacks(cbs);
+
+        let mut remote = self.repo.find_remote("origin")?;
+
+        // only the host that holds the lock replaces it, to refresh it.
+        let force = if replace { "+" } else { "" };
+        let refspec = format!("{force}{commit:?}:{}", lock_ref(&self.cfg));
+
+        remote.push(&[&refspec], Some(&mut opts))?;
+
+        Ok(commit)
+    }
+
+    /// Get the commit that the lock points to on the remote, without fetching
+    /// it, or None if the remote isn't locked.
+    fn remote_lock(&self) -> Result<Option<Oid>> {
+        let cm = CredsManager::new(&self.cfg)?;
+
+        let mut cbs = RemoteCallbacks::new();
+        cbs.credentials(|url, username, types| cm.lookup(url, username, types));
+
+        let mut remote = self.repo.find_remote("origin")?;
+        let mut conn = remote.connect_auth(Direction::Fetch, Some(cbs), None)?;
+
+        let lock_ref = lock_ref(&self.cfg);
+        let lock = conn
+            .remote()
+            .list()?
+            .iter()
+            .find(|head| head.name() == lock_ref)
+            .map(|head| head.oid());
+        debug!("remote lock: {lock:?}");
+
+        Ok(lock)
+    }
+
     fn unlock(&self) -> Result<()> {
         let cm = CredsManager::new(&self.cfg)?;
 
@@ -440,7 +991,7 @@ impl Git {
 
         let mut remote = self.repo.find_remote("origin")?;
 
-        let refspec = format!(":{LOCK_REF}");
+        let refspec = format!(":{}", lock_ref(&self.cfg));
 
         remote.push(&[&refspec], Some(&mut opts))?;
 
@@ -481,6 +1032,24 @@ impl Git {
     }
 }
 
+/// The tag used to lock the sync repo, which is namespaced if sync.namespace
+/// is set.
+fn lock_ref(cfg: &Config) -> String {
+    match cfg.sync.namespace.as_str() {
+        "" => LOCK_REF.to_string(),
+        namespace => format!("refs/tags/vellum/{namespace}/lock"),
+    }
+}
+
+/// The refspec used to fetch the tags, which only fetches our namespace if
+/// sync.namespace is set, so that other groups' locks are ignored.
+fn tags_refspec(cfg: &Config) -> String {
+    match cfg.sync.namespace.as_str() {
+        "" => "refs/tags/*:refs/tags/*".to_string(),
+        namespace => format!("refs/tags/vellum/{namespace}/*:refs/tags/vellum/{namespace}/*"),
+    }
+}
+
 impl fmt::Debug for Git {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Sync{{cfg: {:?}, repo: {:?}", self.cfg, self.repo.path())
@@ -493,7 +1062,7 @@ impl Syncer for Git {
         Ok(self.path.clone())
     }
 
-    fn push_changes(&self, host: &str, force: bool) -> Result<()> {
+    fn push_changes(&self, host: &str, entries: usize, force: bool) -> Result<()> {
         let mut index = self.repo.index()?;
 
         // TODO(jp3): This should only be adding paths for the host being
@@ -501,13 +1070,14 @@ impl Syncer for Git {
         index.add_all(["*"].iter(), IndexAddOption::FORCE, None)?;
         index.write()?;
 
+        let message = self.commit_message(host, entries);
         let message = if force {
-            format!("update {host} (forced)")
+            format!("{message} (forced)")
         } else {
-            format!("update {host}")
+            message
         };
 
-        self.commit(&message, force)?;
+        self.commit_for_host(host, &message, force)?;
 
         let changes = self.unpushed_changes()?;
         debug!("unpushed changes: {changes}");
@@ -519,47 +1089,161 @@ impl Syncer for Git {
     }
 
     fn lock<'a>(&'a self) -> Result<Box<dyn LockedSyncer + 'a>> {
-        let mut index = Index::new()?;
-        let oid = index.write_tree_to(&self.repo)?;
-        let tree = self.repo.find_tree(oid)?;
+        let lock = self.push_lock(false)?;
+        Ok(Box::new(GitGuard::new(self, lock)))
+    }
 
-        debug!("lock tree: {tree:?}");
+    fn pending(&self) -> Result<usize> {
+        self.unpushed_changes()
+    }
 
-        let message = format!("lock for {}", self.cfg.hostname.to_string_lossy());
+    fn incoming(&self) -> Result<Vec<Vec<u8>>> {
+        self.try_fetch(false, None)?;
 
-        let author = self.repo.signature()?;
-        let commit = self
+        let upstream = match self.get_head_upstream_target()? {
+            Some(oid) => self.repo.find_commit(oid)?.tree()?,
+            None => return Ok(Vec::new()),
+        };
+        let local = match self.tip()? {
+            Some(tip) => Some(tip.tree()?),
+            None => None,
+        };
+
+        let diff = self
             .repo
-            .commit(None, &author, &author, &message, &tree, &[])?;
-        debug!("Created commit {commit:?}");
+            .diff_tree_to_tree(local.as_ref(), Some(&upstream), None)?;
+
+        let mut files = Vec::new();
+        for delta in diff.deltas() {
+            let new = delta.new_file();
+            let is_history = new
+                .path()
+                .is_some_and(|path| path.starts_with("hosts.v1") || path.starts_with("hosts"));
+            if !new.exists() || !is_history {
+                continue;
+            }
+            debug!("incoming changes to {:?}", new.path());
+            files.push(self.repo.find_blob(new.id())?.content().to_vec());
+        }
 
-        let cm = CredsManager::new(&self.cfg)?;
+        Ok(files)
+    }
 
-        let mut cbs = RemoteCallbacks::new();
-        cbs.credentials(|url, username, types| cm.lookup(url, username, types))
-            .push_update_reference(|name, status| {
-                debug!("update reference: name: {name} status: {status:?}");
-                if let Some(msg) = status {
-                    Err(git2::Error::from_str(msg))
-                } else {
+    fn snapshot(&self, at: &str, dest: &Path) -> Result<()> {
+        let commit = self.commit_at(at)?;
+        info!(
+            "Reading history at commit {} ({})",
+            commit.id(),
+            commit.summary().unwrap_or_default()
+        );
+        let tree = commit.tree()?;
+        let mut result = Ok(());
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let is_history = dir.starts_with("hosts/") || dir.starts_with("hosts.v1/");
+            if !is_history || entry.kind() != Some(ObjectType::Blob) {
+                return TreeWalkResult::Ok;
+            }
+            let path = dest.join(dir).join(entry.name().unwrap_or_default());
+            result = self
+                .repo
+                .find_blob(entry.id())
+                .map_err(Error::from)
+                .and_then(|blob| {
+                    fs::create_dir_all(dest.join(dir))?;
+                    fs::write(&path, blob.content())?;
                     Ok(())
-                }
-            })
-            .update_tips(|name, old, new| {
-                debug!("update tip: name: {name} old: {old:?} new: {new:?}");
-                true
-            });
+                });
+            match result {
+                Ok(()) => TreeWalkResult::Ok,
+                Err(_) => TreeWalkResult::Abort,
+            }
+        })?;
+        result
+    }
+}
 
-        let mut opts = PushOptions::new();
-        opts.remote_callbacks(cbs);
+/// A git Syncer that doesn't touch the remote until it is first used, so that
+/// the server can start without network access. If the sync repo has already
+/// been cloned and set up then it is opened straight away, otherwise cloning
+/// it (or pushing the first commit to a new repo) is left for the first sync.
+pub struct LazyGit {
+    cfg: Config,
+    git: OnceCell<Git>,
+}
 
-        let mut remote = self.repo.find_remote("origin")?;
+impl LazyGit {
+    pub fn new(cfg: &Config) -> Result<Self> {
+        let git = OnceCell::new();
+        if fs::exists(cfg.sync_repo_path())? {
+            let existing = Git::open(cfg)?;
+            if existing.tip()?.is_some() {
+                let _ = git.set(existing);
+            }
+        } else if let Err(e) = fs::write(
+            cfg.clone_progress_path(),
+            "Waiting for the first sync to clone the sync repo",
+        ) {
+            // the repo is cloned by the first sync, the progress file lets
+            // clients that are waiting for the server know that it is coming.
+            debug!("failed to write clone progress: {e}");
+        }
+        Ok(Self {
+            cfg: cfg.clone(),
+            git,
+        })
+    }
 
-        let refspec = format!("{commit:?}:{LOCK_REF}");
+    pub(super) fn path(&self) -> PathBuf {
+        self.cfg.sync_path()
+    }
 
-        remote.push(&[&refspec], Some(&mut opts))?;
+    fn git(&self) -> Result<&Git> {
+        if let Some(git) = self.git.get() {
+            return Ok(git);
+        }
+        debug!("first use of the sync repo, setting it up");
+        let git = Git::new(&self.cfg)?;
+        Ok(self.git.get_or_init(|| git))
+    }
+}
 
-        Ok(Box::new(GitGuard::new(self)))
+impl fmt::Debug for LazyGit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.git.get() {
+            Some(git) => write!(f, "LazyGit{{{git:?}}}"),
+            None => write!(f, "LazyGit{{path: {:?}, not opened}}", self.path()),
+        }
+    }
+}
+
+impl Syncer for LazyGit {
+    fn refresh(&self) -> Result<PathBuf> {
+        self.git()?.refresh()
+    }
+
+    fn push_changes(&self, host: &str, entries: usize, force: bool) -> Result<()> {
+        self.git()?.push_changes(host, entries, force)
+    }
+
+    fn lock<'a>(&'a self) -> Result<Box<dyn LockedSyncer + 'a>> {
+        self.git()?.lock()
+    }
+
+    fn pending(&self) -> Result<usize> {
+        // nothing can have been committed before the repo is set up, and this
+        // shouldn't be what clones it.
+        match self.git.get() {
+            Some(git) => git.pending(),
+            None => Ok(0),
+        }
+    }
+
+    fn incoming(&self) -> Result<Vec<Vec<u8>>> {
+        self.git()?.incoming()
+    }
+
+    fn snapshot(&self, at: &str, dest: &Path) -> Result<()> {
+        self.git()?.snapshot(at, dest)
     }
 }
 
@@ -589,8 +1273,9 @@ impl CredsManager {
         } else if types.is_ssh_key() {
             let username =
                 username.ok_or_else(|| git2::Error::from_str("missing username for ssh auth"))?;
-            if !self.cfg.sync.ssh_key.is_empty() {
-                let privatekey = Path::new(&self.cfg.sync.ssh_key);
+            let ssh_key = self.cfg.sync_ssh_key();
+            if !ssh_key.is_empty() {
+                let privatekey = Path::new(ssh_key);
                 Cred::ssh_key(username, None, privatekey, None)
             } else {
                 Cred::ssh_key_from_agent(username)
@@ -608,11 +1293,32 @@ impl CredsManager {
 #[derive(Debug)]
 struct GitGuard<'a> {
     git: &'a Git,
+    // the lock commit that we pushed, and when we pushed it.
+    lock: Cell<Oid>,
+    locked_at: Cell<Instant>,
 }
 
 impl<'a> GitGuard<'a> {
-    fn new(git: &'a Git) -> Self {
-        Self { git }
+    fn new(git: &'a Git, lock: Oid) -> Self {
+        Self {
+            git,
+            lock: Cell::new(lock),
+            locked_at: Cell::new(Instant::now()),
+        }
+    }
+
+    /// Make sure that the remote is still locked by us, as another host may
+    /// have removed the lock as stale if we took too long.
+    fn check_lock(&self) -> Result<()> {
+        if self.git.remote_lock()? == Some(self.lock.get()) {
+            return Ok(());
+        }
+        Err(Error::Generic(format!(
+            "lost the lock on the sync repo after {} (it may have been removed as stale by another host, see sync.lock_stale_after)",
+            format_duration(Duration::from_secs(
+                self.locked_at.get().elapsed().as_secs()
+            ))
+        )))
     }
 }
 
@@ -622,7 +1328,11 @@ impl LockedSyncer for GitGuard<'_> {
         Ok(self.git.path.clone())
     }
 
-    fn push_changes(&self, host: &str) -> Result<()> {
+    fn drain(&self, timeout: Duration) -> Result<()> {
+        self.git.wait_for_stable_upstream(timeout)
+    }
+
+    fn push_changes(&self, host: &str) -> Result<Option<String>> {
         let mut index = self.git.repo.index()?;
 
         index.add_all(["*"].iter(), IndexAddOption::FORCE, None)?;
@@ -630,9 +1340,38 @@ impl LockedSyncer for GitGuard<'_> {
 
         let message = format!("rebuild full history from {host}");
 
-        self.git.commit_no_parent(&message)?;
+        let commit = self.git.commit_no_parent(&message)?;
+        self.check_lock()?;
         self.git.force_push()?;
 
+        Ok(Some(commit.to_string()))
+    }
+
+    fn keep_alive(&self) -> Result<()> {
+        let stale_after = self.git.cfg.sync.lock_stale_after;
+        if stale_after.is_zero()
+            || self.locked_at.get().elapsed() < stale_after / LOCK_REFRESH_DIVISOR
+        {
+            return Ok(());
+        }
+        self.check_lock()?;
+        debug!("refreshing the lock");
+        self.lock.set(self.git.push_lock(true)?);
+        self.locked_at.set(Instant::now());
+        Ok(())
+    }
+
+    fn commit_changes(&self, message: &str) -> Result<()> {
+        let mut index = self.git.repo.index()?;
+
+        index.add_all(["*"].iter(), IndexAddOption::FORCE, None)?;
+        // add_all doesn't notice files that have been removed
+        index.update_all(["*"].iter(), None)?;
+        index.write()?;
+
+        if self.git.commit(message, false)?.is_some() {
+            self.git.try_push()?;
+        }
         Ok(())
     }
 
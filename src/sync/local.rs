@@ -1,12 +1,19 @@
 use std::{
-    fs,
+    fs::{self, File},
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
+use log::debug;
+
 use crate::error::Result;
 
 use super::{LockedSyncer, Syncer};
 
+// Marker file that is touched every time changes are "pushed", so that we can
+// tell which files have changed since.
+const MARKER: &str = ".vellum-synced";
+
 #[derive(Debug, Clone)]
 pub struct Local {
     path: PathBuf,
@@ -23,6 +30,46 @@ impl Local {
     pub(super) fn path(&self) -> PathBuf {
         self.path.clone()
     }
+
+    fn last_push(&self) -> Result<Option<SystemTime>> {
+        let marker = self.path.join(MARKER);
+        if !fs::exists(&marker)? {
+            return Ok(None);
+        }
+        Ok(Some(fs::metadata(marker)?.modified()?))
+    }
+
+    fn mark_pushed(&self) -> Result<()> {
+        fs::create_dir_all(&self.path)?;
+        File::create(self.path.join(MARKER))?;
+        Ok(())
+    }
+
+    fn count_modified<P: AsRef<Path>>(path: P, since: Option<SystemTime>) -> Result<usize> {
+        let mut count = 0;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                count += Self::count_modified(entry.path(), since)?;
+            } else if since.is_none_or(|since| meta.modified().is_ok_and(|m| m > since)) {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn read_all<P: AsRef<Path>>(path: P, files: &mut Vec<Vec<u8>>) -> Result<()> {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.metadata()?.is_dir() {
+                Self::read_all(entry.path(), files)?;
+            } else {
+                files.push(fs::read(entry.path())?);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Syncer for Local {
@@ -30,13 +77,43 @@ impl Syncer for Local {
         Ok(self.path.clone())
     }
 
-    fn push_changes(&self, _host: &str, _force: bool) -> Result<()> {
-        Ok(())
+    fn push_changes(&self, _host: &str, _entries: usize, force: bool) -> Result<()> {
+        if !force && self.pending()? == 0 {
+            debug!("no changes to push");
+            return Ok(());
+        }
+        self.mark_pushed()
     }
 
     fn lock<'a>(&'a self) -> Result<Box<dyn super::LockedSyncer + 'a>> {
         Ok(Box::new(self.clone()))
     }
+
+    fn pending(&self) -> Result<usize> {
+        let since = self.last_push()?;
+        let mut count = 0;
+        for dir in ["hosts", "hosts.v1"] {
+            let path = self.path.join(dir);
+            if fs::exists(&path)? {
+                count += Self::count_modified(path, since)?;
+            }
+        }
+        Ok(count)
+    }
+
+    fn incoming(&self) -> Result<Vec<Vec<u8>>> {
+        // there is no upstream to fetch from, but the directory may be shared
+        // with other hosts, so just return everything and let the caller work
+        // out what is new.
+        let mut files = Vec::new();
+        for dir in ["hosts", "hosts.v1"] {
+            let path = self.path.join(dir);
+            if fs::exists(&path)? {
+                Self::read_all(path, &mut files)?;
+            }
+        }
+        Ok(files)
+    }
 }
 
 impl LockedSyncer for Local {
@@ -44,7 +121,20 @@ impl LockedSyncer for Local {
         Ok(self.path.clone())
     }
 
-    fn push_changes(&self, _host: &str) -> Result<()> {
+    fn drain(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+
+    fn push_changes(&self, _host: &str) -> Result<Option<String>> {
+        self.mark_pushed()?;
+        Ok(None)
+    }
+
+    fn commit_changes(&self, _message: &str) -> Result<()> {
+        self.mark_pushed()
+    }
+
+    fn keep_alive(&self) -> Result<()> {
         Ok(())
     }
 
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::{config::Config, error::Result};
+
+use super::{LockedSyncer, Syncer, get_syncer};
+
+// The directories that hold the history files, which are the only parts of the
+// sync directory that are mirrored.
+const HISTORY_DIRS: &[&str] = &["hosts", "hosts.v1"];
+
+/// A Syncer that wraps the primary Syncer, and copies the history files to
+/// each of the mirrors after pushing them (including the pushes made whilst
+/// it is locked, e.g. by a rebuild). Everything else only uses the primary.
+#[derive(Debug)]
+pub struct Mirrored {
+    primary: Box<dyn Syncer>,
+    path: PathBuf,
+    host: String,
+    mirrors: Vec<Mirror>,
+}
+
+/// The lock on the primary Syncer, which updates the mirrors after each push.
+#[derive(Debug)]
+struct MirroredLock<'a> {
+    primary: Box<dyn LockedSyncer + 'a>,
+    mirrored: &'a Mirrored,
+}
+
+/// A single mirror, the Syncer is only created when it is first needed, so
+/// that a mirror that is unavailable when the server starts doesn't stop it
+/// from starting.
+struct Mirror {
+    cfg: Config,
+    syncer: Mutex<Option<(Box<dyn Syncer>, PathBuf)>>,
+}
+
+impl fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Mirror{{path: {:?}}}", self.cfg.sync_path())
+    }
+}
+
+impl Mirrored {
+    pub fn new(cfg: &Config, primary: Box<dyn Syncer>, path: PathBuf) -> Self {
+        let mirrors = cfg
+            .sync
+            .mirrors
+            .iter()
+            .map(|mirror| Mirror {
+                cfg: cfg.mirror_config(mirror),
+                syncer: Mutex::new(None),
+            })
+            .collect();
+        Self {
+            primary,
+            path,
+            host: cfg.hostname.to_string_lossy().to_string(),
+            mirrors,
+        }
+    }
+
+    /// Update all of the mirrors, failures are only logged since the mirrors
+    /// are there for when the primary is unavailable.
+    fn update_mirrors(&self, host: &str, entries: usize, force: bool) {
+        for mirror in self.mirrors.iter() {
+            if let Err(e) = mirror.update(&self.path, host, entries, force) {
+                warn!("Failed to update mirror {mirror:?}: {e}");
+            }
+        }
+    }
+}
+
+impl Mirror {
+    /// Copy the history files from path to the mirror, and push them.
+    fn update(&self, path: &Path, host: &str, entries: usize, force: bool) -> Result<()> {
+        let mut syncer = self.syncer.lock().unwrap();
+        let (syncer, mirror_path) = match syncer.as_mut() {
+            Some(syncer) => syncer,
+            None => syncer.insert(get_syncer(&self.cfg)?),
+        };
+        syncer.refresh()?;
+        for dir in HISTORY_DIRS {
+            copy_tree(&path.join(dir), &mirror_path.join(dir))?;
+        }
+        syncer.push_changes(host, entries, force)
+    }
+}
+
+/// Make the to directory a copy of the from directory, only writing the files
+/// that have changed. Like rsync, files with the same size and modification
+/// time are assumed to be unchanged, so the copies are given the modification
+/// time of the original.
+fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    if !fs::exists(from)? {
+        if fs::exists(to)? {
+            fs::remove_dir_all(to)?;
+        }
+        return Ok(());
+    }
+    fs::create_dir_all(to)?;
+    let mut names = HashSet::new();
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            copy_tree(&entry.path(), &dest)?;
+        } else if !unchanged(&metadata, &dest)? {
+            debug!("mirror {dest:?}");
+            fs::copy(entry.path(), &dest)?;
+            File::options()
+                .write(true)
+                .open(&dest)?
+                .set_modified(metadata.modified()?)?;
+        }
+        names.insert(entry.file_name());
+    }
+    for entry in fs::read_dir(to)? {
+        let entry = entry?;
+        if names.contains(&entry.file_name()) {
+            continue;
+        }
+        debug!("remove mirrored {:?}", entry.path());
+        match entry.metadata()?.is_dir() {
+            true => fs::remove_dir_all(entry.path())?,
+            false => fs::remove_file(entry.path())?,
+        }
+    }
+    Ok(())
+}
+
+/// Does dest have the same size and modification time as the file with the
+/// given metadata?
+fn unchanged(metadata: &fs::Metadata, dest: &Path) -> Result<bool> {
+    let dest = match fs::metadata(dest) {
+        Ok(dest) => dest,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(
+        dest.is_file()
+            && dest.len() == metadata.len()
+            && dest.modified()? == metadata.modified()?,
+    )
+}
+
+impl Syncer for Mirrored {
+    fn refresh(&self) -> Result<PathBuf> {
+        self.primary.refresh()
+    }
+
+    fn push_changes(&self, host: &str, entries: usize, force: bool) -> Result<()> {
+        // update the mirrors even if the push fails, since they are there for
+        // when the primary is unavailable.
+        let result = self.primary.push_changes(host, entries, force);
+        self.update_mirrors(host, entries, force);
+        result
+    }
+
+    fn lock<'a>(&'a self) -> Result<Box<dyn LockedSyncer + 'a>> {
+        Ok(Box::new(MirroredLock {
+            primary: self.primary.lock()?,
+            mirrored: self,
+        }))
+    }
+
+    fn pending(&self) -> Result<usize> {
+        self.primary.pending()
+    }
+
+    fn incoming(&self) -> Result<Vec<Vec<u8>>> {
+        self.primary.incoming()
+    }
+
+    fn snapshot(&self, at: &str, dest: &Path) -> Result<()> {
+        self.primary.snapshot(at, dest)
+    }
+}
+
+impl LockedSyncer for MirroredLock<'_> {
+    fn refresh(&self) -> Result<PathBuf> {
+        self.primary.refresh()
+    }
+
+    fn drain(&self, timeout: Duration) -> Result<()> {
+        self.primary.drain(timeout)
+    }
+
+    fn push_changes(&self, host: &str) -> Result<Option<String>> {
+        // the primary's history has been replaced, so the mirrors' has to be
+        // too.
+        let result = self.primary.push_changes(host);
+        self.mirrored.update_mirrors(host, 0, true);
+        result
+    }
+
+    fn commit_changes(&self, message: &str) -> Result<()> {
+        let result = self.primary.commit_changes(message);
+        self.mirrored.update_mirrors(&self.mirrored.host, 0, false);
+        result
+    }
+
+    fn keep_alive(&self) -> Result<()> {
+        self.primary.keep_alive()
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.primary.unlock()
+    }
+}
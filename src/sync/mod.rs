@@ -1,32 +1,90 @@
-use std::{fmt, path::PathBuf};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use log::debug;
 
-use crate::{config::Config, error::Result};
+use crate::{
+    config::{Config, SyncBackend},
+    error::{Error, Result},
+};
 
 mod git;
 mod local;
+mod mirror;
+mod webdav;
 
 pub trait Syncer: fmt::Debug + Send {
     fn refresh(&self) -> Result<PathBuf>;
 
-    fn push_changes(&self, host: &str, force: bool) -> Result<()>;
+    /// Push any changes, entries is the number of new entries that have been
+    /// written since the last push, for use in the commit message.
+    fn push_changes(&self, host: &str, entries: usize, force: bool) -> Result<()>;
 
     fn lock<'a>(&'a self) -> Result<Box<dyn LockedSyncer + 'a>>;
+
+    /// Returns the number of changes that have been saved locally, but not yet
+    /// pushed.
+    fn pending(&self) -> Result<usize>;
+
+    /// Fetch any upstream changes without applying them, and return the
+    /// contents of the upstream history files that differ from the local ones.
+    fn incoming(&self) -> Result<Vec<Vec<u8>>>;
+
+    /// Write the history files as they were at an earlier point (a git ref,
+    /// or an RFC 3339 timestamp) into dest, without changing the sync repo.
+    fn snapshot(&self, _at: &str, _dest: &Path) -> Result<()> {
+        Err(Error::from_str(
+            "reading the history at an earlier point needs git sync to be enabled",
+        ))
+    }
 }
 
 pub trait LockedSyncer: fmt::Debug {
     fn refresh(&self) -> Result<PathBuf>;
 
-    fn push_changes(&self, host: &str) -> Result<()>;
+    /// Wait for any syncs that were already in progress when the lock was
+    /// taken to finish, by waiting for the upstream history to stop changing.
+    /// Gives up waiting after timeout.
+    fn drain(&self, timeout: Duration) -> Result<()>;
+
+    /// Push the changes, replacing the existing upstream history. Returns the
+    /// ID of the commit that was pushed, if there is one.
+    fn push_changes(&self, host: &str) -> Result<Option<String>>;
+
+    /// Commit and push the changes on top of the existing upstream history
+    /// (including any removed files), without rewriting it.
+    fn commit_changes(&self, message: &str) -> Result<()>;
+
+    /// Refresh the lock if it has been held for a while, so that other hosts
+    /// don't remove it as stale during a long rebuild. Fails if the lock has
+    /// already been lost.
+    fn keep_alive(&self) -> Result<()>;
 
     fn unlock(&self) -> Result<()>;
 }
 
 pub fn get_syncer(cfg: &Config) -> Result<(Box<dyn Syncer>, PathBuf)> {
-    if cfg.sync.enabled {
+    let (s, path) = get_primary_syncer(cfg)?;
+    if cfg.sync.mirrors.is_empty() {
+        return Ok((s, path));
+    }
+    debug!("Mirroring to {} mirrors", cfg.sync.mirrors.len());
+    let s = mirror::Mirrored::new(cfg, s, path.clone());
+    Ok((Box::new(s), path))
+}
+
+fn get_primary_syncer(cfg: &Config) -> Result<(Box<dyn Syncer>, PathBuf)> {
+    if cfg.sync.enabled && cfg.sync.backend == SyncBackend::Webdav {
+        debug!("Using WebDAV Syncer");
+        let s = webdav::WebDav::new(cfg)?;
+        let path = s.path();
+        Ok((Box::new(s), path))
+    } else if cfg.sync.enabled {
         debug!("Using git Syncer");
-        let s = git::Git::new(cfg)?;
+        let s = git::LazyGit::new(cfg)?;
         let path = s.path();
         Ok((Box::new(s), path))
     } else {
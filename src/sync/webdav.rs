@@ -0,0 +1,1072 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use aws_lc_rs::digest::{SHA256, digest};
+use chrono::{DateTime, SecondsFormat, Utc};
+use humantime::format_duration;
+use log::{debug, warn};
+use rand::random_range;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use which::which;
+
+use crate::{
+    config::Config,
+    error::{Error, Result},
+};
+
+use super::{LockedSyncer, Syncer};
+
+// the directories that hold the history files, which are the only parts of the
+// sync directory that are synced.
+const HISTORY_DIRS: &[&str] = &["hosts", "hosts.v1"];
+
+// file in the sync directory that records the ETag and hash of each file as
+// of the last sync, so that we can tell what has changed on either side.
+const STATE: &str = ".vellum-webdav.json";
+
+// the file on the server that is created to lock the history during a
+// rebuild, relative to sync.url.
+const LOCK: &str = "lock";
+
+// a held lock is refreshed once this fraction of sync.lock_stale_after has
+// passed, so that other hosts don't remove it as stale.
+const LOCK_REFRESH_DIVISOR: u32 = 4;
+
+// the longest we wait between checks to see if the history has been unlocked.
+const MAX_LOCK_POLL: Duration = Duration::from_secs(30);
+
+// how often we list the files on the server when waiting for in progress syncs
+// to finish before a rebuild.
+const DRAIN_POLL: Duration = Duration::from_secs(1);
+
+// the longest we wait to connect to the server, sync.webdav.timeout limits the
+// whole request.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:getetag/><d:resourcetype/></d:prop></d:propfind>"#;
+
+/// A file as it was at the last sync.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Synced {
+    // not all servers return the ETag when a file is written, in which case
+    // the file is fetched again on the next sync to get it.
+    etag: Option<String>,
+    hash: String,
+}
+
+/// A Syncer that keeps the history files in a directory on a WebDAV server,
+/// using curl to make the requests. Files are only written if they haven't
+/// changed on the server since they were last fetched (using If-Match), and
+/// the history is locked by creating a lock file (using If-None-Match).
+pub struct WebDav {
+    cfg: Config,
+    url: String,
+    base_path: String,
+    path: PathBuf,
+    state: Mutex<BTreeMap<String, Synced>>,
+}
+
+impl fmt::Debug for WebDav {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // leave out the cfg, so that the credentials aren't logged
+        write!(f, "WebDav{{url: {:?}, path: {:?}}}", self.url, self.path)
+    }
+}
+
+struct Response {
+    status: u16,
+    etag: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    fn check(self, method: &str, path: &str) -> Result<Self> {
+        if self.success() {
+            return Ok(self);
+        }
+        Err(Error::Generic(format!(
+            "WebDAV {method} of {path:?} failed with HTTP status {}",
+            self.status
+        )))
+    }
+}
+
+/// The lock file on the server.
+struct Lock {
+    holder: String,
+    ts: Option<DateTime<Utc>>,
+    etag: Option<String>,
+}
+
+impl WebDav {
+    pub fn new(cfg: &Config) -> Result<Self> {
+        if which("curl").is_err() {
+            return Err(Error::from_str(
+                "WebDAV sync needs curl, which wasn't found in PATH",
+            ));
+        }
+        let url = cfg.sync_url();
+        if url.is_empty() {
+            return Err(Error::from_str(
+                "sync.url must be set to the WebDAV directory to sync with",
+            ));
+        }
+        let url = format!("{}/", url.trim_end_matches('/'));
+        let base_path = url_path(&url);
+
+        // the credentials are passed to curl in a config file, which can't
+        // hold a value that spans lines.
+        let auth = &cfg.sync.webdav;
+        for (name, value) in [
+            ("token", &auth.token),
+            ("username", &auth.username),
+            ("password", &auth.password),
+        ] {
+            if value.chars().any(char::is_control) {
+                return Err(Error::Generic(format!(
+                    "sync.webdav.{name} can't contain control characters (e.g. newlines)"
+                )));
+            }
+        }
+
+        let path = cfg.sync_path();
+        fs::create_dir_all(&path)?;
+        let state = match fs::read(path.join(STATE)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Self {
+            cfg: cfg.clone(),
+            url,
+            base_path,
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub(super) fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    /// Make a request with curl. The credentials are passed to curl on stdin,
+    /// so that they don't show up in the process list.
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[&str],
+        body: Option<&[u8]>,
+    ) -> Result<Response> {
+        let url = format!("{}{}", self.url, encode_path(path));
+        debug!("WebDAV {method} {url}");
+
+        let header_file = NamedTempFile::new()?;
+        let body_file = match body {
+            Some(data) => {
+                let mut f = NamedTempFile::new()?;
+                f.write_all(data)?;
+                f.flush()?;
+                Some(f)
+            }
+            None => None,
+        };
+
+        let mut cmd = Command::new("curl");
+        cmd.args(["--silent", "--show-error", "--config", "-"])
+            .args(["--request", method]);
+        let timeout = self.cfg.sync.webdav.timeout;
+        if !timeout.is_zero() {
+            // a server that accepts the connection but never answers would
+            // otherwise hold the sync lock forever.
+            let connect = timeout.min(CONNECT_TIMEOUT);
+            cmd.arg("--max-time")
+                .arg(format!("{:.3}", timeout.as_secs_f64()))
+                .arg("--connect-timeout")
+                .arg(format!("{:.3}", connect.as_secs_f64()));
+        }
+        cmd.arg("--dump-header").arg(header_file.path());
+        for header in headers {
+            cmd.arg("--header").arg(header);
+        }
+        if let Some(f) = &body_file {
+            let mut arg = std::ffi::OsString::from("@");
+            arg.push(f.path());
+            cmd.arg("--data-binary").arg(arg);
+        }
+        let mut child = cmd
+            .arg(&url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Generic(format!("failed to run curl: {e}")))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(self.curl_config().as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(Error::Generic(format!(
+                "WebDAV {method} of {path:?} failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        let (status, etag) = parse_headers(&fs::read_to_string(header_file.path())?);
+        debug!("WebDAV {method} {url}: {status}");
+        if status == 0 {
+            return Err(Error::Generic(format!(
+                "WebDAV {method} of {path:?} failed: no response from the server"
+            )));
+        }
+        Ok(Response {
+            status,
+            etag,
+            body: output.stdout,
+        })
+    }
+
+    /// The curl config that sets the credentials from sync.webdav.
+    fn curl_config(&self) -> String {
+        let auth = &self.cfg.sync.webdav;
+        if !auth.token.is_empty() {
+            format!(
+                "header = \"Authorization: Bearer {}\"\n",
+                curl_quote(&auth.token)
+            )
+        } else if !auth.username.is_empty() {
+            format!(
+                "user = \"{}:{}\"\n",
+                curl_quote(&auth.username),
+                curl_quote(&auth.password)
+            )
+        } else {
+            String::new()
+        }
+    }
+
+    /// Write a file, creating the directories above it if they don't exist.
+    fn put(&self, path: &str, data: &[u8], condition: Option<&str>) -> Result<Response> {
+        let mut headers = vec!["Content-Type: application/octet-stream"];
+        headers.extend(condition);
+        let response = self.request("PUT", path, &headers, Some(data))?;
+        if response.status != 409 {
+            return Ok(response);
+        }
+        // a 409 means that the parent directory is missing
+        let mut dir = String::new();
+        self.mkcol(&dir)?;
+        for part in path.split('/').take(path.split('/').count() - 1) {
+            dir = format!("{dir}{part}/");
+            self.mkcol(&dir)?;
+        }
+        self.request("PUT", path, &headers, Some(data))
+    }
+
+    fn mkcol(&self, dir: &str) -> Result<()> {
+        let response = self.request("MKCOL", dir, &[], None)?;
+        // 405 means that it already exists
+        if response.status != 405 {
+            response.check("MKCOL", dir)?;
+        }
+        Ok(())
+    }
+
+    /// List the history files on the server, with their ETags.
+    fn list(&self) -> Result<BTreeMap<String, String>> {
+        let mut files = BTreeMap::new();
+        // not all servers support a Depth of infinity, so walk the directories
+        let mut dirs: Vec<String> = HISTORY_DIRS.iter().map(|dir| format!("{dir}/")).collect();
+        while let Some(dir) = dirs.pop() {
+            let response = self.request(
+                "PROPFIND",
+                &dir,
+                &["Depth: 1", "Content-Type: application/xml"],
+                Some(PROPFIND.as_bytes()),
+            )?;
+            if response.status == 404 {
+                continue;
+            }
+            let response = response.check("PROPFIND", &dir)?;
+            for (href, etag, collection) in parse_multistatus(&response.body) {
+                let Some(path) = self.relative(&href) else {
+                    continue;
+                };
+                let path = path.trim_end_matches('/');
+                if path.len() <= dir.len() || !path.starts_with(&dir) {
+                    // the directory itself
+                    continue;
+                }
+                if collection {
+                    dirs.push(format!("{path}/"));
+                } else if let Some(etag) = etag {
+                    files.insert(path.to_string(), etag);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Convert a href from a PROPFIND response into a path relative to the
+    /// sync url.
+    fn relative(&self, href: &str) -> Option<String> {
+        let path = match href.contains("://") {
+            true => url_path(href),
+            false => percent_decode(href),
+        };
+        path.strip_prefix(&self.base_path).map(|p| p.to_string())
+    }
+
+    /// The history files in the sync directory, with the hash of their
+    /// contents.
+    fn local_files(&self) -> Result<BTreeMap<String, String>> {
+        let mut files = BTreeMap::new();
+        for dir in HISTORY_DIRS {
+            let path = self.path.join(dir);
+            if fs::exists(&path)? {
+                read_hashes(&path, dir, &mut files)?;
+            }
+        }
+        Ok(files)
+    }
+
+    fn save_state(&self, state: &BTreeMap<String, Synced>) -> Result<()> {
+        fs::write(self.path.join(STATE), serde_json::to_vec(state)?)?;
+        Ok(())
+    }
+
+    /// Fetch the files that have changed on the server. Files that have also
+    /// been changed locally are replaced by the copy from the server, since it
+    /// may have been rewritten by a rebuild on another host.
+    fn pull(&self) -> Result<()> {
+        let remote = self.list()?;
+        let local = self.local_files()?;
+        let mut state = self.state.lock().unwrap();
+        let result = self.apply_remote(&remote, &local, &mut state, |path| {
+            self.request("GET", path, &[], None)?.check("GET", path)
+        });
+        self.save_state(&state)?;
+        result
+    }
+
+    /// Bring the local files up to date with the remote ones (a map of path
+    /// to ETag), using fetch to get the ones whose ETag has changed.
+    fn apply_remote<F>(
+        &self,
+        remote: &BTreeMap<String, String>,
+        local: &BTreeMap<String, String>,
+        state: &mut BTreeMap<String, Synced>,
+        fetch: F,
+    ) -> Result<()>
+    where
+        F: Fn(&str) -> Result<Response>,
+    {
+        for (path, etag) in remote {
+            let synced = state.get(path).cloned();
+            if synced
+                .as_ref()
+                .is_some_and(|s| s.etag.as_ref() == Some(etag))
+            {
+                continue;
+            }
+            let response = fetch(path)?;
+            let remote_hash = hash(&response.body);
+            let etag = response.etag.or_else(|| Some(etag.clone()));
+            if let Some(local_hash) = local.get(path)
+                && synced.as_ref().is_none_or(|s| &s.hash != local_hash)
+            {
+                // the ETag changes without the file changing if we didn't have
+                // one from the last write, or the state wasn't saved after a
+                // push, so only a change of the content is a conflict.
+                if *local_hash == remote_hash {
+                    debug!("{path} is already the same as the server copy");
+                    state.insert(
+                        path.clone(),
+                        Synced {
+                            etag,
+                            hash: remote_hash,
+                        },
+                    );
+                    continue;
+                }
+                if let Some(synced) = synced
+                    && synced.hash == remote_hash
+                {
+                    debug!("{path} hasn't changed on the server, keeping the local changes");
+                    state.insert(path.clone(), Synced { etag, ..synced });
+                    continue;
+                }
+                warn!("{path} has changed on the server and locally, keeping the server copy");
+            }
+            let dest = self.path.join(path);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&dest, &response.body)?;
+            state.insert(
+                path.clone(),
+                Synced {
+                    etag,
+                    hash: remote_hash,
+                },
+            );
+        }
+
+        // files that have been removed from the server (e.g. by a rebuild on
+        // another host) are removed locally, unless they have local changes.
+        let removed: Vec<String> = state
+            .keys()
+            .filter(|path| !remote.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in removed {
+            let synced = state.remove(&path).unwrap();
+            if local.get(&path) == Some(&synced.hash) {
+                debug!("{path} removed from the server");
+                fs::remove_file(self.path.join(&path))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The local files that have been added, changed or removed since the
+    /// last sync.
+    fn local_changes(&self) -> Result<Vec<String>> {
+        let local = self.local_files()?;
+        let state = self.state.lock().unwrap();
+        let mut changes: Vec<String> = local
+            .iter()
+            .filter(|(path, hash)| state.get(*path).is_none_or(|s| &s.hash != *hash))
+            .map(|(path, _)| path.clone())
+            .collect();
+        changes.extend(
+            state
+                .keys()
+                .filter(|path| !local.contains_key(*path))
+                .cloned(),
+        );
+        Ok(changes)
+    }
+
+    /// Write the local changes to the server, returning the number of files
+    /// that were written or removed.
+    fn push(&self) -> Result<usize> {
+        let local = self.local_files()?;
+        let mut state = self.state.lock().unwrap();
+        let result = self.push_files(&local, &mut state);
+        self.save_state(&state)?;
+        result
+    }
+
+    fn push_files(
+        &self,
+        local: &BTreeMap<String, String>,
+        state: &mut BTreeMap<String, Synced>,
+    ) -> Result<usize> {
+        let mut pushed = 0;
+        for (path, hash) in local {
+            let synced = state.get(path);
+            if synced.is_some_and(|s| &s.hash == hash) {
+                continue;
+            }
+            // only replace the version of the file that we last saw, or if
+            // it's a new file, only create it if it doesn't already exist.
+            let condition = match synced {
+                Some(Synced {
+                    etag: Some(etag), ..
+                }) => Some(format!("If-Match: {etag}")),
+                Some(_) => None,
+                None => Some("If-None-Match: *".to_string()),
+            };
+            let data = fs::read(self.path.join(path))?;
+            let response = self.put(path, &data, condition.as_deref())?;
+            if response.status == 412 {
+                return Err(Error::Generic(format!(
+                    "{path} was changed on the server during the sync, it will be pushed again on the next sync"
+                )));
+            }
+            let response = response.check("PUT", path)?;
+            state.insert(
+                path.clone(),
+                Synced {
+                    etag: response.etag,
+                    hash: hash.clone(),
+                },
+            );
+            pushed += 1;
+        }
+
+        let removed: Vec<String> = state
+            .keys()
+            .filter(|path| !local.contains_key(*path))
+            .cloned()
+            .collect();
+        for path in removed {
+            let condition = state[&path]
+                .etag
+                .as_ref()
+                .map(|etag| format!("If-Match: {etag}"));
+            self.delete(&path, condition.as_deref())?;
+            state.remove(&path);
+            pushed += 1;
+        }
+        Ok(pushed)
+    }
+
+    fn delete(&self, path: &str, condition: Option<&str>) -> Result<()> {
+        let headers: Vec<&str> = condition.into_iter().collect();
+        let response = self.request("DELETE", path, &headers, None)?;
+        match response.status {
+            404 => Ok(()),
+            412 => Err(Error::Generic(format!(
+                "{path} was changed on the server during the sync, it will be removed on the next sync"
+            ))),
+            _ => response.check("DELETE", path).map(|_| ()),
+        }
+    }
+
+    fn lock_info(&self) -> Result<Option<Lock>> {
+        let response = self.request("GET", LOCK, &[], None)?;
+        if response.status == 404 {
+            return Ok(None);
+        }
+        let response = response.check("GET", LOCK)?;
+        let message = String::from_utf8_lossy(&response.body);
+        let holder = message.trim().strip_prefix("lock for ").unwrap_or("");
+        let (holder, ts) = match holder.rsplit_once(" at ") {
+            Some((host, ts)) => (host, DateTime::parse_from_rfc3339(ts).ok()),
+            None => (holder, None),
+        };
+        Ok(Some(Lock {
+            holder: match holder.is_empty() {
+                true => "an unknown host".to_string(),
+                false => holder.to_string(),
+            },
+            ts: ts.map(|ts| ts.to_utc()),
+            etag: response.etag,
+        }))
+    }
+
+    /// If the lock is older than sync.lock_stale_after, then assume that the
+    /// host that took it died before unlocking, and remove it. Returns true
+    /// if the lock was removed.
+    fn clear_stale_lock(&self, lock: &Lock) -> Result<bool> {
+        let stale_after = self.cfg.sync.lock_stale_after;
+        let Some(ts) = lock.ts else {
+            return Ok(false);
+        };
+        let age = (Utc::now() - ts).to_std().unwrap_or_default();
+        if stale_after.is_zero() || age < stale_after {
+            return Ok(false);
+        }
+        warn!(
+            "Removing stale lock held by {} since {} ({} ago), the rebuild it was for may not have finished",
+            lock.holder,
+            ts.to_rfc3339_opts(SecondsFormat::Secs, true),
+            format_duration(Duration::from_secs(age.as_secs()))
+        );
+        self.unlock(lock.etag.as_deref())?;
+        Ok(true)
+    }
+
+    /// Wait for a rebuild on another host to finish, giving up after
+    /// sync.lock_timeout.
+    fn wait_for_unlock(&self) -> Result<()> {
+        let timeout = self.cfg.sync.lock_timeout;
+        let start = Instant::now();
+        let mut poll = Duration::from_secs(1);
+        loop {
+            let Some(lock) = self.lock_info()? else {
+                return Ok(());
+            };
+            if self.clear_stale_lock(&lock)? {
+                return Ok(());
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::Generic(format!(
+                    "repo did not unlock within {} (locked by {})",
+                    format_duration(Duration::from_secs(start.elapsed().as_secs())),
+                    lock.holder
+                )));
+            }
+            // back off, with some jitter, so that a lot of hosts waiting for
+            // the same lock don't all hit the server at the same time.
+            let delay = random_range(poll / 2..=poll).min(timeout.saturating_sub(start.elapsed()));
+            debug!("waiting {delay:?} for repo to unlock ...");
+            thread::sleep(delay);
+            poll = (poll * 2).min(MAX_LOCK_POLL);
+        }
+    }
+
+    /// Write the lock file, recording this host and the current time. With an
+    /// etag this replaces our existing lock, otherwise it is only created if
+    /// the server isn't already locked.
+    fn put_lock(&self, etag: Option<&str>) -> Result<Response> {
+        let message = format!(
+            "lock for {} at {}",
+            self.cfg.hostname.to_string_lossy(),
+            Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        let condition = match etag {
+            Some(etag) => format!("If-Match: {etag}"),
+            None => "If-None-Match: *".to_string(),
+        };
+        self.put(LOCK, message.as_bytes(), Some(&condition))
+    }
+
+    fn unlock(&self, etag: Option<&str>) -> Result<()> {
+        let condition = etag.map(|etag| format!("If-Match: {etag}"));
+        self.delete(LOCK, condition.as_deref())
+    }
+
+    /// Wait until the files on the server stop changing, giving up after
+    /// timeout.
+    fn wait_for_stable_upstream(&self, timeout: Duration) -> Result<()> {
+        if timeout.is_zero() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        let mut files = self.list()?;
+        loop {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                warn!(
+                    "upstream still changing after {}, continuing anyway",
+                    format_duration(start.elapsed())
+                );
+                return Ok(());
+            }
+            thread::sleep(DRAIN_POLL.min(remaining));
+            let new_files = self.list()?;
+            if new_files == files {
+                debug!("upstream stable");
+                return Ok(());
+            }
+            debug!("upstream changed");
+            files = new_files;
+        }
+    }
+}
+
+impl Syncer for WebDav {
+    fn refresh(&self) -> Result<PathBuf> {
+        self.wait_for_unlock()?;
+        self.pull()?;
+        Ok(self.path.clone())
+    }
+
+    fn push_changes(&self, _host: &str, _entries: usize, _force: bool) -> Result<()> {
+        let pushed = self.push()?;
+        debug!("pushed {pushed} files");
+        Ok(())
+    }
+
+    fn lock<'a>(&'a self) -> Result<Box<dyn LockedSyncer + 'a>> {
+        let response = self.put_lock(None)?;
+        if response.status == 412 {
+            let holder = self
+                .lock_info()?
+                .map(|lock| lock.holder)
+                .unwrap_or_else(|| "an unknown host".to_string());
+            return Err(Error::Generic(format!(
+                "repo is already locked by {holder}"
+            )));
+        }
+        let response = response.check("PUT", LOCK)?;
+        Ok(Box::new(WebDavGuard {
+            webdav: self,
+            etag: RefCell::new(response.etag),
+            locked_at: Cell::new(Instant::now()),
+        }))
+    }
+
+    fn pending(&self) -> Result<usize> {
+        Ok(self.local_changes()?.len())
+    }
+
+    fn incoming(&self) -> Result<Vec<Vec<u8>>> {
+        let remote = self.list()?;
+        let local = self.local_files()?;
+        let state = self.state.lock().unwrap().clone();
+        let mut files = Vec::new();
+        for (path, etag) in remote {
+            if state
+                .get(&path)
+                .is_some_and(|s| s.etag.as_ref() == Some(&etag))
+            {
+                continue;
+            }
+            let response = self.request("GET", &path, &[], None)?.check("GET", &path)?;
+            if local.get(&path) != Some(&hash(&response.body)) {
+                debug!("incoming changes to {path}");
+                files.push(response.body);
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[derive(Debug)]
+struct WebDavGuard<'a> {
+    webdav: &'a WebDav,
+    // the etag of the lock file that we wrote, and when we wrote it.
+    etag: RefCell<Option<String>>,
+    locked_at: Cell<Instant>,
+}
+
+impl WebDavGuard<'_> {
+    fn lost_lock(&self) -> Error {
+        Error::Generic(format!(
+            "lost the lock on the server after {} (it may have been removed as stale by another host, see sync.lock_stale_after)",
+            format_duration(Duration::from_secs(
+                self.locked_at.get().elapsed().as_secs()
+            ))
+        ))
+    }
+
+    /// Make sure that the server is still locked by us, as another host may
+    /// have removed the lock as stale if we took too long.
+    fn check_lock(&self) -> Result<()> {
+        let lock = self.webdav.lock_info()?;
+        match lock {
+            Some(lock) if lock.etag == *self.etag.borrow() => Ok(()),
+            _ => Err(self.lost_lock()),
+        }
+    }
+}
+
+impl LockedSyncer for WebDavGuard<'_> {
+    fn refresh(&self) -> Result<PathBuf> {
+        self.webdav.pull()?;
+        Ok(self.webdav.path.clone())
+    }
+
+    fn drain(&self, timeout: Duration) -> Result<()> {
+        self.webdav.wait_for_stable_upstream(timeout)
+    }
+
+    fn push_changes(&self, _host: &str) -> Result<Option<String>> {
+        self.check_lock()?;
+        self.webdav.push()?;
+        // replace the history on the server, so remove anything that we don't
+        // have locally, even if we have never seen it.
+        let local = self.webdav.local_files()?;
+        for path in self.webdav.list()?.into_keys() {
+            if !local.contains_key(&path) {
+                debug!("remove {path} from the server");
+                self.webdav.delete(&path, None)?;
+            }
+        }
+        Ok(None)
+    }
+
+    fn commit_changes(&self, message: &str) -> Result<()> {
+        let pushed = self.webdav.push()?;
+        debug!("pushed {pushed} files: {message}");
+        Ok(())
+    }
+
+    fn keep_alive(&self) -> Result<()> {
+        let stale_after = self.webdav.cfg.sync.lock_stale_after;
+        if stale_after.is_zero()
+            || self.locked_at.get().elapsed() < stale_after / LOCK_REFRESH_DIVISOR
+        {
+            return Ok(());
+        }
+        let Some(etag) = self.etag.borrow().clone() else {
+            // without an ETag the lock can't be replaced safely.
+            debug!("can't refresh the lock, the server didn't return an ETag for it");
+            return Ok(());
+        };
+        debug!("refreshing the lock");
+        let response = self.webdav.put_lock(Some(&etag))?;
+        if response.status == 412 {
+            return Err(self.lost_lock());
+        }
+        *self.etag.borrow_mut() = response.check("PUT", LOCK)?.etag;
+        self.locked_at.set(Instant::now());
+        Ok(())
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.webdav.unlock(self.etag.borrow().as_deref())
+    }
+}
+
+/// Hash the files below dir, adding them to files with their path relative to
+/// the sync directory (using / as the separator).
+fn read_hashes(dir: &Path, rel: &str, files: &mut BTreeMap<String, String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let rel = format!("{rel}/{}", entry.file_name().to_string_lossy());
+        if entry.metadata()?.is_dir() {
+            read_hashes(&entry.path(), &rel, files)?;
+        } else {
+            files.insert(rel, hash(&fs::read(entry.path())?));
+        }
+    }
+    Ok(())
+}
+
+fn hash(data: &[u8]) -> String {
+    digest(&SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Get the status and ETag of the final response from the headers that curl
+/// dumped (there can be more than one response, e.g. 100 Continue).
+fn parse_headers(headers: &str) -> (u16, Option<String>) {
+    let mut status = 0;
+    let mut etag = None;
+    for line in headers.lines() {
+        if line.starts_with("HTTP/") {
+            status = line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0);
+            etag = None;
+        } else if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("etag")
+        {
+            etag = Some(value.trim().to_string());
+        }
+    }
+    (status, etag)
+}
+
+/// Get the href, ETag and whether it is a directory for each resource in a
+/// PROPFIND multistatus response. The namespace prefix varies between
+/// servers, so any prefix is accepted.
+fn parse_multistatus(body: &[u8]) -> Vec<(String, Option<String>, bool)> {
+    let body = String::from_utf8_lossy(body);
+    let response = Regex::new(r"(?s)<(?:[\w-]+:)?response\b.*?</(?:[\w-]+:)?response>").unwrap();
+    let href = Regex::new(r"(?s)<(?:[\w-]+:)?href>\s*(.*?)\s*</").unwrap();
+    let etag = Regex::new(r"(?s)<(?:[\w-]+:)?getetag>\s*(.*?)\s*</").unwrap();
+    let collection = Regex::new(r"<(?:[\w-]+:)?collection\b").unwrap();
+    response
+        .find_iter(&body)
+        .filter_map(|m| {
+            let m = m.as_str();
+            let href = unescape_xml(href.captures(m)?.get(1)?.as_str());
+            let etag = etag
+                .captures(m)
+                .and_then(|c| c.get(1))
+                .map(|e| unescape_xml(e.as_str()))
+                .filter(|e| !e.is_empty());
+            Some((href, etag, collection.is_match(m)))
+        })
+        .collect()
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#34;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// The decoded path of a URL.
+fn url_path(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    match rest.find('/') {
+        Some(i) => percent_decode(&rest[i..]),
+        None => "/".to_string(),
+    }
+}
+
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            b => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(b) = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(b);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// Quote a value for a curl config file.
+fn curl_quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "hosts.v1/host/2024-01-02";
+
+    /// Run apply_remote for a single file, which had the content synced (and
+    /// ETag) at the last sync, and now has the content local on disk and
+    /// server on the server. Returns the local file and its state afterwards.
+    fn apply(synced: &[u8], etag: Option<&str>, local: &[u8], server: &[u8]) -> (Vec<u8>, Synced) {
+        let dir = tempfile::tempdir().unwrap();
+        let webdav = WebDav {
+            cfg: Config::default(),
+            url: "http://localhost/dav/".to_string(),
+            base_path: "/dav/".to_string(),
+            path: dir.path().to_path_buf(),
+            state: Mutex::new(BTreeMap::new()),
+        };
+        let dest = dir.path().join(FILE);
+        fs::create_dir_all(dest.parent().unwrap()).unwrap();
+        fs::write(&dest, local).unwrap();
+
+        let mut state = BTreeMap::from([(
+            FILE.to_string(),
+            Synced {
+                etag: etag.map(String::from),
+                hash: hash(synced),
+            },
+        )]);
+        let remote = BTreeMap::from([(FILE.to_string(), "\"2\"".to_string())]);
+        let local = webdav.local_files().unwrap();
+        webdav
+            .apply_remote(&remote, &local, &mut state, |path| {
+                assert_eq!(path, FILE);
+                Ok(Response {
+                    status: 200,
+                    etag: None,
+                    body: server.to_vec(),
+                })
+            })
+            .unwrap();
+        (fs::read(&dest).unwrap(), state.remove(FILE).unwrap())
+    }
+
+    #[test]
+    fn apply_remote_unchanged_locally() {
+        let (data, synced) = apply(b"old", Some("\"1\""), b"old", b"new");
+        assert_eq!(data, b"new");
+        assert_eq!(synced.hash, hash(b"new"));
+        assert_eq!(synced.etag.as_deref(), Some("\"2\""));
+    }
+
+    #[test]
+    fn apply_remote_conflict_keeps_server_copy() {
+        let (data, synced) = apply(b"old", Some("\"1\""), b"mine", b"theirs");
+        assert_eq!(data, b"theirs");
+        assert_eq!(synced.hash, hash(b"theirs"));
+    }
+
+    #[test]
+    fn apply_remote_keeps_local_changes_without_etag() {
+        // the server didn't return an ETag when the file was last written, so
+        // it looks like it has changed, but the content hasn't.
+        let (data, synced) = apply(b"old", None, b"mine", b"old");
+        assert_eq!(data, b"mine");
+        // the local changes still need to be pushed.
+        assert_eq!(synced.hash, hash(b"old"));
+        assert_eq!(synced.etag.as_deref(), Some("\"2\""));
+    }
+
+    #[test]
+    fn apply_remote_already_pushed() {
+        // a push that wrote the file, but the state wasn't saved afterwards.
+        let (data, synced) = apply(b"old", Some("\"1\""), b"mine", b"mine");
+        assert_eq!(data, b"mine");
+        assert_eq!(synced.hash, hash(b"mine"));
+    }
+
+    #[test]
+    fn multistatus() {
+        let body = br#"<?xml version="1.0" encoding="utf-8"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response>
+    <d:href>/dav/hosts.v1/</d:href>
+    <d:propstat><d:prop>
+      <d:getetag/>
+      <d:resourcetype><d:collection/></d:resourcetype>
+    </d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/dav/hosts.v1/my%20host/2024-01-02</d:href>
+    <d:propstat><d:prop>
+      <d:getetag>&quot;abc&amp;123&quot;</d:getetag>
+      <d:resourcetype/>
+    </d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+        assert_eq!(
+            parse_multistatus(body),
+            vec![
+                ("/dav/hosts.v1/".to_string(), None, true),
+                (
+                    "/dav/hosts.v1/my%20host/2024-01-02".to_string(),
+                    Some("\"abc&123\"".to_string()),
+                    false
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn multistatus_other_prefix() {
+        let body = br#"<D:multistatus xmlns:D="DAV:"><D:response><D:href>http://example.com/a/b</D:href><D:propstat><D:prop><D:getetag>"1"</D:getetag></D:prop></D:propstat></D:response></D:multistatus>"#;
+        assert_eq!(
+            parse_multistatus(body),
+            vec![(
+                "http://example.com/a/b".to_string(),
+                Some("\"1\"".to_string()),
+                false
+            )]
+        );
+    }
+
+    #[test]
+    fn multistatus_empty() {
+        assert!(parse_multistatus(b"").is_empty());
+        assert!(parse_multistatus(b"<d:multistatus xmlns:d=\"DAV:\"/>").is_empty());
+    }
+
+    #[test]
+    fn quote() {
+        assert_eq!(curl_quote("plain"), "plain");
+        assert_eq!(curl_quote(r#"a"b"#), r#"a\"b"#);
+        assert_eq!(curl_quote(r"a\b"), r"a\\b");
+        assert_eq!(curl_quote(r#"\""#), r#"\\\""#);
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    env,
+    ffi::OsStr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process,
+    time::Duration,
+};
+
+use log::{debug, warn};
+
+use crate::error::Result;
+
+/// Tell systemd that the service has finished starting up.
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Tell systemd that the service is still alive.
+pub fn watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// If systemd has enabled the watchdog for this process, returns how often it
+/// expects to be pinged.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid != process::id().to_string()
+    {
+        return None;
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    match usec {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+fn notify(state: &str) {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) if !path.is_empty() => path,
+        _ => return,
+    };
+    debug!("sd_notify: {state}");
+    if let Err(e) = send(&path, state) {
+        warn!("Failed to notify systemd: {e}");
+    }
+}
+
+fn send(path: &OsStr, state: &str) -> Result<()> {
+    // a leading @ means that the socket is in the abstract namespace
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    let sock = UnixDatagram::unbound()?;
+    sock.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}